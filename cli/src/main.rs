@@ -4,10 +4,21 @@ extern crate rustico_ui_common;
 
 use rustico_core::nes::NesState;
 use rustico_core::palettes::NTSC_PAL;
-use rustico_core::cartridge::mapper_from_file;
+use rustico_core::patch::apply_patch;
+use rustico_core::archive::extract_rom;
+use rustico_core::archive::is_archive;
+use rustico_core::archive::split_entry_path;
+use rustico_core::game_db;
+use rustico_core::game_db::GameDatabase;
+use rustico_core::rom_info::rom_info;
+use rustico_core::rom_info::RomInfo;
+use rustico_core::apu::AudioChannelState;
+use rustico_core::vgm;
 
 use rustico_ui_common::application::RuntimeState as RusticoRuntimeState;
+use rustico_ui_common::audio_file::AudioFileWriter;
 use rustico_ui_common::events;
+use rustico_ui_common::midi_file::MidiRecorder;
 use rustico_ui_common::panel::Panel;
 use rustico_ui_common::piano_roll_window::PianoRollWindow;
 use rustico_ui_common::event_window::EventWindow;
@@ -27,8 +38,17 @@ pub struct CliRuntimeState {
   pub event_viewer_panel: EventWindow,
   pub game_file: Option<File>,
   pub piano_file: Option<File>,
-  pub audio_file: Option<File>,
+  pub audio_file: Option<AudioFileWriter>,
+  pub stem_path: Option<String>,
+  pub stem_files: Vec<AudioFileWriter>,
+  pub vgm_path: Option<String>,
+  pub midi_path: Option<String>,
+  pub midi_recorder: Option<MidiRecorder>,
   pub event_file: Option<File>,
+  pub cartridge_path: Option<String>,
+  pub patch_paths: Vec<String>,
+  pub nsf_loops: u8,
+  pub nsf_min_loop_seconds: Option<f64>,
 }
 
 impl CliRuntimeState {
@@ -40,7 +60,16 @@ impl CliRuntimeState {
       game_file: None,
       piano_file: None,
       audio_file: None,
+      stem_path: None,
+      stem_files: Vec::new(),
+      vgm_path: None,
+      midi_path: None,
+      midi_recorder: None,
       event_file: None,
+      cartridge_path: None,
+      patch_paths: Vec::new(),
+      nsf_loops: 2,
+      nsf_min_loop_seconds: None,
     }
   }
 }
@@ -60,12 +89,16 @@ pub fn dispatch_event(state: &mut CliRuntimeState, event: events::Event) {
   }
 }
 
-fn load_cartridge(nes: &mut NesState, cartridge_path: &str) {
-  // Read in the ROM file and attempt to create a new NesState:
-  let file = File::open(cartridge_path);
+fn read_cartridge(cartridge_path: &str, patch_paths: &Vec<String>) -> Vec<u8> {
+  // Files inside an archive are named like "games.zip#game.nes"; without an entry name
+  // we take the first ROM in the archive
+  let (file_path, entry_name) = split_entry_path(cartridge_path);
+
+  // Read in the ROM file:
+  let file = File::open(&file_path);
   match file {
     Err(why) => {
-      panic!("Couldn't open {}: {}", cartridge_path, why);
+      panic!("Couldn't open {}: {}", file_path, why);
     },
     Ok(_) => (),
   };
@@ -74,24 +107,52 @@ fn load_cartridge(nes: &mut NesState, cartridge_path: &str) {
   let mut cartridge = Vec::new();
   match file.unwrap().read_to_end(&mut cartridge) {
     Err(why) => {
-      panic!("Couldn't read from {}: {}", cartridge_path, why);
+      panic!("Couldn't read from {}: {}", file_path, why);
     },
     Ok(_) => {
+      if is_archive(&cartridge) {
+        match extract_rom(&cartridge, entry_name.as_ref().map(|name| name.as_str())) {
+          Ok(entry) => {
+            println!("Extracted {} from {}", entry.name, file_path);
+            cartridge = entry.data;
+          },
+          Err(why) => panic!("Couldn't extract a ROM from {}: {}", file_path, why)
+        }
+      }
       println!("Loading {}...", cartridge_path);
-      let maybe_mapper = mapper_from_file(&cartridge);
-      match maybe_mapper {
-            Ok(mapper) => {
-              *nes = NesState::new(mapper);
-              nes.power_on();
-            },
-        Err(why) => {
-          panic!("{}", why);
+      for patch_path in patch_paths {
+        let patch = match std::fs::read(patch_path) {
+          Ok(patch) => patch,
+          Err(why) => panic!("Couldn't read patch {}: {}", patch_path, why)
+        };
+        match apply_patch(&cartridge, &patch) {
+          Ok(patched_cartridge) => {
+            println!("Applied patch {}", patch_path);
+            cartridge = patched_cartridge;
+          },
+          Err(why) => panic!("Couldn't apply patch {}: {}", patch_path, why)
         }
       }
+      return cartridge;
     },
   };
 }
 
+fn load_cartridge(state: &mut CliRuntimeState, cartridge_path: &str) {
+  let cartridge = read_cartridge(cartridge_path, &state.patch_paths);
+  // The runtime keeps the audio setup and power-on configuration, and enables the EPSM if the header asks for it
+  for response in state.core.load_cartridge(cartridge_path.to_string(), &cartridge) {
+    match response {
+      events::Event::CartridgeRejected(_, why) => panic!("[{}] {}", why.code(), why),
+      other => dispatch_event(state, other)
+    }
+  }
+  // As the LoadCartridge event does, so settings for the mapper reach the new one
+  for event in state.core.settings.apply_settings() {
+    dispatch_event(state, event);
+  }
+}
+
 // Note: Later we should use the ui-common library, and dump panels instead of just the game screen. That
 // will be very flexible and useful.
 fn dump_frame(state: &mut CliRuntimeState) {
@@ -115,19 +176,120 @@ fn dump_frame(state: &mut CliRuntimeState) {
 
 fn dump_audio(state: &mut CliRuntimeState) {
   match &mut state.audio_file {
-    Some(file) => {
-      if state.core.nes.apu.buffer_full {
-        let buffer_size = state.core.nes.apu.output_buffer.len();
-        for i in 0 .. buffer_size {
-          let _ = file.write_all(&state.core.nes.apu.output_buffer[i].to_be_bytes());
-        }
-        state.core.nes.apu.buffer_full = false;
+    Some(writer) => {
+      let samples = state.core.nes.apu.consume_samples();
+      match writer.write_samples(&samples) {
+        Err(why) => {panic!("Couldn't write audio: {}", why)},
+        Ok(_) => {}
+      }
+    },
+    None => {}
+  }
+}
+
+// "song.wav" becomes "song-2A03-Pulse_1.wav" and so on
+fn stem_filename(stem_path: &str, chip: &str, name: &str) -> String {
+  let (base, extension) = match stem_path.rfind('.') {
+    Some(i) if stem_path[i..].eq_ignore_ascii_case(".wav") || stem_path[i..].eq_ignore_ascii_case(".flac") => {
+      (&stem_path[.. i], &stem_path[i ..])
+    },
+    _ => (stem_path, ".wav")
+  };
+  let label: String = format!("{}-{}", chip, name).chars().map(|c| if c.is_alphanumeric() || c == '-' {c} else {'_'}).collect();
+  return format!("{}-{}{}", base, label, extension);
+}
+
+fn dump_stems(state: &mut CliRuntimeState) {
+  let stem_path = match state.stem_path {
+    Some(ref stem_path) => stem_path.clone(),
+    None => return
+  };
+  let sample_rate = state.core.nes.apu.sample_rate as u32;
+  let stems = match state.core.nes.apu.stems {
+    Some(ref mut stems) => stems,
+    None => return
+  };
+  // Channels which turn up partway through are padded so that all the stems line up
+  let samples_so_far = match state.stem_files.first() {
+    Some(first_writer) => first_writer.samples_written as usize,
+    None => 0
+  };
+  for (i, stem) in stems.iter_mut().enumerate() {
+    if i >= state.stem_files.len() {
+      let filename = stem_filename(&stem_path, &stem.chip, &stem.name);
+      let mut writer = match AudioFileWriter::create(&filename, sample_rate, 1) {
+        Ok(writer) => writer,
+        Err(why) => panic!("{}", why)
+      };
+      let _ = writer.write_samples(&vec![0i16; samples_so_far]);
+      state.stem_files.push(writer);
+    }
+    match state.stem_files[i].write_samples(&stem.samples) {
+      Err(why) => {panic!("Couldn't write stem: {}", why)},
+      Ok(_) => {}
+    }
+    stem.samples.clear();
+  }
+}
+
+fn finish_stems(state: &mut CliRuntimeState) {
+  for mut writer in state.stem_files.drain(..) {
+    match writer.finish() {
+      Err(why) => {println!("Couldn't finish writing stem: {}", why)},
+      Ok(_) => {}
+    }
+  }
+}
+
+fn finish_audio(state: &mut CliRuntimeState) {
+  match state.audio_file.take() {
+    Some(mut writer) => {
+      match writer.finish() {
+        Err(why) => {println!("Couldn't finish writing audio: {}", why)},
+        Ok(_) => {}
       }
     },
     None => {}
   }
 }
 
+fn dump_midi(state: &mut CliRuntimeState) {
+  match state.midi_recorder {
+    Some(ref mut recorder) => {
+      let nes = &state.core.nes;
+      let mut channels: Vec<&dyn AudioChannelState> = Vec::new();
+      channels.extend(nes.apu.channels());
+      channels.extend(nes.mapper.channels());
+      recorder.record_frame(nes.master_clock, &channels);
+    },
+    None => {}
+  }
+}
+
+fn finish_midi(state: &mut CliRuntimeState) {
+  match (state.midi_path.take(), state.midi_recorder.take()) {
+    (Some(midi_path), Some(mut recorder)) => {
+      match std::fs::write(&midi_path, recorder.to_bytes(state.core.nes.master_clock)) {
+        Err(why) => {println!("Couldn't write {}: {}", midi_path, why)},
+        Ok(_) => {println!("Wrote {}", midi_path)}
+      }
+    },
+    _ => {}
+  }
+}
+
+fn finish_vgm(state: &mut CliRuntimeState) {
+  match (state.vgm_path.take(), vgm::stop_logging(&mut state.core.nes)) {
+    (Some(vgm_path), Some(vgm_file)) => {
+      match std::fs::write(&vgm_path, &vgm_file) {
+        Err(why) => {println!("Couldn't write {}: {}", vgm_path, why)},
+        Ok(_) => {println!("Wrote {}", vgm_path)}
+      }
+    },
+    _ => {}
+  }
+}
+
 fn dump_panel(file_handle: &mut Option<File>, panel: & dyn Panel) {
   match file_handle {
     Some(file) => {
@@ -167,11 +329,53 @@ fn run(state: &mut CliRuntimeState, frames: u64) {
     // If there are any outstanding dump configurations, process those
     dump_frame(state);
     dump_audio(state);
+    dump_stems(state);
+    dump_midi(state);
     dump_panel(&mut state.piano_file, &state.piano_roll_panel);
     dump_panel(&mut state.event_file, &state.event_viewer_panel);
   }
 }
 
+fn format_cycles(cycles: u64) -> String {
+  let seconds = cycles as f64 / 1_789_773.0;
+  return format!("{}:{:05.2}", (seconds / 60.0) as u64, seconds % 60.0);
+}
+
+// Plays one NSF track until the player moves on, so that whatever is being dumped
+// covers the whole song, loops and fade included.
+fn play_track(state: &mut CliRuntimeState, track_index: u8) {
+  state.core.nes.mapper.nsf_set_track(track_index);
+  state.core.nes.mapper.nsf_set_loop_count(state.nsf_loops);
+  match state.nsf_min_loop_seconds {
+    Some(seconds) => state.core.nes.mapper.nsf_set_min_loop_seconds(seconds),
+    None => {}
+  }
+  let mut last_timing = match state.core.nes.mapper.nsf_track_timing() {
+    Some(timing) => timing,
+    None => {
+      println!("Not an NSF, ignoring playtrack");
+      return;
+    }
+  };
+  loop {
+    run(state, 1);
+    let timing = state.core.nes.mapper.nsf_track_timing().unwrap();
+    if timing.track != last_timing.track || timing.elapsed_cycles < last_timing.elapsed_cycles {
+      break;
+    }
+    last_timing = timing;
+  }
+  match last_timing.detected_loop {
+    Some((start, length)) => {
+      println!("Track {}: loop of {} starting at {}, played for {}",
+        track_index, format_cycles(length), format_cycles(start), format_cycles(last_timing.elapsed_cycles));
+    },
+    None => {
+      println!("Track {}: no loop found, played for {}", track_index, format_cycles(last_timing.elapsed_cycles));
+    }
+  }
+}
+
 fn reset(nes: &mut NesState) {
   nes.reset();
 }
@@ -254,6 +458,104 @@ fn save_blargg(nes: &mut NesState, output_filename: &str) {
   }
 }
 
+fn json_string(text: &str) -> String {
+  let mut escaped = String::from("\"");
+  for c in text.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+      c => escaped.push(c)
+    }
+  }
+  escaped.push('"');
+  return escaped;
+}
+
+// One line per file, so a whole collection can be piped through a JSON lines reader
+fn rom_info_json(path: &str, info: &Result<RomInfo, String>) -> String {
+  let info = match info {
+    Ok(info) => info,
+    Err(reason) => {
+      return format!("{{\"file\":{},\"error\":{}}}", json_string(path), json_string(reason));
+    }
+  };
+  let fields: Vec<String> = info.fields.iter()
+    .map(|&(ref name, ref value)| format!("{}:{}", json_string(name), json_string(value)))
+    .collect();
+  let hashes: Vec<String> = info.hashes.iter()
+    .map(|&(ref name, ref hashes)| format!("{}:{{\"size\":{},\"crc32\":\"{:08X}\",\"sha1\":\"{}\",\"md5\":\"{}\"}}",
+      json_string(name), hashes.size, hashes.crc32, hashes.sha1, hashes.md5))
+    .collect();
+  let mapper = match (info.mapper_number, info.submapper_number, info.mapper_name.as_ref()) {
+    (Some(number), Some(submapper), Some(name)) => format!("{{\"number\":{},\"submapper\":{},\"name\":{}}}", number, submapper, json_string(name)),
+    _ => "null".to_string()
+  };
+  return format!("{{\"file\":{},\"format\":{},\"fields\":{{{}}},\"hashes\":{{{}}},\"mapper\":{},\"supported\":{},\"verdict\":{}}}",
+    json_string(path), json_string(&info.format), fields.join(","), hashes.join(","), mapper, info.supported, json_string(&info.verdict));
+}
+
+fn rom_info_text(path: &str, info: &Result<RomInfo, String>) -> String {
+  let info = match info {
+    Ok(info) => info,
+    Err(reason) => {
+      return format!("{}: {}\n", path, reason);
+    }
+  };
+  let mut text = format!("{}\n  format: {}\n", path, info.format);
+  for &(ref name, ref value) in info.fields.iter() {
+    text += &format!("  {}: {}\n", name, value);
+  }
+  for &(ref name, ref hashes) in info.hashes.iter() {
+    text += &format!("  {} ({} bytes)\n    crc32: {:08X}\n    sha1:  {}\n    md5:   {}\n", name, hashes.size, hashes.crc32, hashes.sha1, hashes.md5);
+  }
+  match info.mapper_name.as_ref() {
+    Some(name) => {text += &format!("  mapper name: {}\n", name);},
+    None => {}
+  }
+  text += &format!("  verdict: {}\n", info.verdict);
+  return text;
+}
+
+// info [--json] [--database <nes20db.xml>] <file> [<file> ...]
+// Describes each file without running it. This needs no emulator, so it's handled
+// before one is created, which also keeps the loader's logging out of the output.
+fn info_command(mut args: Vec<String>) {
+  let mut json = false;
+  while args.len() > 0 && args[0].starts_with("--") {
+    let option = args.remove(0);
+    match option.as_str() {
+      "--json" => {json = true;},
+      "--database" if args.len() > 0 => {
+        let database_path = args.remove(0);
+        match std::fs::read_to_string(&database_path) {
+          Ok(xml) => game_db::use_database(GameDatabase::from_xml(&xml)),
+          Err(why) => panic!("Couldn't read {}: {}", database_path, why)
+        }
+      },
+      _ => panic!("Unrecognized info option: {}", option)
+    }
+  }
+  if args.len() == 0 {
+    panic!("Usage: rustico-cli info [--json] [--database <nes20db.xml>] <file> [<file> ...]");
+  }
+  for path in args {
+    let (file_path, entry_name) = split_entry_path(&path);
+    let info = match std::fs::read(&file_path) {
+      Ok(data) => rom_info(&data, entry_name.as_ref().map(|name| name.as_str())),
+      Err(why) => Err(format!("Couldn't read {}: {}", file_path, why))
+    };
+    if json {
+      println!("{}", rom_info_json(&path, &info));
+    } else {
+      print!("{}", rom_info_text(&path, &info));
+    }
+  }
+}
+
 fn command_file(state: &mut CliRuntimeState, command_path: &str) {
   let file = File::open(command_path);
   match file {
@@ -277,11 +579,30 @@ fn process_command_list(state: &mut CliRuntimeState, mut command_list: Vec<Strin
     let command = command_list.remove(0);
     match command.as_ref() {
       "cart" | "cartridge" | "rom" => {
-        // TODO: implement this with the standard event instead
+        // Not the LoadCartridge event, since the CLI should stop on a bad cartridge
         let cartridge_path = command_list.remove(0);
-        load_cartridge(&mut state.core.nes, cartridge_path.as_ref());
+        // The log belongs to the old cartridge, so it ends here
+        finish_vgm(state);
+        load_cartridge(state, cartridge_path.as_ref());
+        // The new mapper starts out at the stock mix
+        state.core.apply_expansion_mix();
+        state.cartridge_path = Some(cartridge_path);
         state.core.running = true;
       },
+      "patch" => {
+        // Patches apply to the cartridge as it's loaded. If one is already running,
+        // reload it so the new patch takes effect.
+        let patch_path = command_list.remove(0);
+        state.patch_paths.push(patch_path);
+        match state.cartridge_path.clone() {
+          Some(cartridge_path) => {
+            finish_vgm(state);
+            load_cartridge(state, cartridge_path.as_ref());
+            state.core.apply_expansion_mix();
+          },
+          None => {}
+        }
+      },
       "config"  => {
         let config_path = command_list.remove(0);
         state.core.settings.load(&config_path.into());
@@ -296,12 +617,28 @@ fn process_command_list(state: &mut CliRuntimeState, mut command_list: Vec<Strin
       "reset" => {
         // TODO: implement this with the standard event instead
         reset(&mut state.core.nes);
+      },
+      "powercycle" => {
+        dispatch_event(state, events::Event::NesPowerCycle);
       }
       "track" => {
         let track_index: u8 = command_list.remove(0).parse().unwrap();
         state.core.nes.mapper.nsf_set_track(track_index);
         state.core.nes.mapper.nsf_manual_mode();
       }
+      "loops" => {
+        state.nsf_loops = command_list.remove(0).parse().unwrap();
+        state.core.nes.mapper.nsf_set_loop_count(state.nsf_loops);
+      }
+      "minloop" => {
+        let seconds: f64 = command_list.remove(0).parse().unwrap();
+        state.nsf_min_loop_seconds = Some(seconds);
+        state.core.nes.mapper.nsf_set_min_loop_seconds(seconds);
+      }
+      "playtrack" => {
+        let track_index: u8 = command_list.remove(0).parse().unwrap();
+        play_track(state, track_index);
+      }
       "tap" => {
         let button = command_list.remove(0);
         let frames: u64 = command_list.remove(0).parse().unwrap();
@@ -346,15 +683,75 @@ fn process_command_list(state: &mut CliRuntimeState, mut command_list: Vec<Strin
       },
       "audio" => {
         let output_path = command_list.remove(0);
-        match File::create(&output_path) {
+        finish_audio(state);
+        // Don't let samples from before this point leak into the new file
+        let _ = state.core.nes.apu.consume_samples();
+        let channels = state.core.nes.apu.output_channels() as u16;
+        match AudioFileWriter::create(&output_path, state.core.nes.apu.sample_rate as u32, channels) {
           Err(why) => {
-            panic!("Couldn't open {}: {}", output_path, why);
+            panic!("{}", why);
           },
-          Ok(file) => {
-            state.audio_file = Some(file);
+          Ok(writer) => {
+            state.audio_file = Some(writer);
           }
         }
       }
+      "stems" => {
+        // Every channel goes to its own file, alongside (not instead of) any audio command
+        let stem_path = command_list.remove(0);
+        finish_stems(state);
+        state.core.nes.apu.disable_stems();
+        state.core.nes.apu.enable_stems();
+        state.stem_path = Some(stem_path);
+      }
+      "vgm" => {
+        // Logs sound chip writes from this point on, saved when the next log starts or we exit
+        let vgm_path = command_list.remove(0);
+        finish_vgm(state);
+        vgm::start_logging(&mut state.core.nes);
+        state.vgm_path = Some(vgm_path);
+      }
+      "midi" => {
+        // Transcribes every channel to its own track from this point on
+        let midi_path = command_list.remove(0);
+        finish_midi(state);
+        state.midi_recorder = Some(MidiRecorder::new(state.core.nes.master_clock));
+        state.midi_path = Some(midi_path);
+      }
+      "stereo" => {
+        // Pan and gain come from [audio.channels.<chip>.<name>] in the settings, see "config"
+        let stereo = match command_list.remove(0).as_ref() {
+          "on" => true,
+          "off" => false,
+          other => panic!("stereo takes on or off, not {}", other)
+        };
+        if state.audio_file.is_some() {
+          println!("Can't switch between mono and stereo while recording audio, ignoring");
+        } else {
+          dispatch_event(state, events::Event::StoreBooleanSetting("audio.stereo".to_string(), stereo));
+          for event in state.core.settings.apply_settings() {
+            dispatch_event(state, event);
+          }
+        }
+      }
+      "synthesis" => {
+        // blip is by far the cheapest, for running many instances at once
+        let synthesis = command_list.remove(0);
+        match synthesis.as_ref() {
+          "hq" | "lq" | "blip" => {
+            dispatch_event(state, events::Event::StoreStringSetting("audio.synthesis".to_string(), synthesis));
+          },
+          other => panic!("synthesis takes hq, lq or blip, not {}", other)
+        }
+      }
+      "samplerate" => {
+        let sample_rate: u64 = command_list.remove(0).parse().unwrap();
+        if state.audio_file.is_some() || state.stem_path.is_some() {
+          println!("Can't change the sample rate while recording audio, ignoring");
+        } else {
+          state.core.nes.apu.set_sample_rate(sample_rate);
+        }
+      }
       "#" => {
         // A comment! Everything on this line is discarded
         return;
@@ -375,10 +772,20 @@ fn main() {
     panic!("Usage: rustico-cli <commands>");
   }
 
-  let mut state = CliRuntimeState::new();
-
   // Pop off the name of the program
   let _ = args.remove(0);
 
+  if args[0] == "info" {
+    let _ = args.remove(0);
+    info_command(args);
+    return;
+  }
+
+  let mut state = CliRuntimeState::new();
+
   process_command_list(&mut state, args);
+  finish_audio(&mut state);
+  finish_stems(&mut state);
+  finish_vgm(&mut state);
+  finish_midi(&mut state);
 }
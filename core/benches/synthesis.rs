@@ -0,0 +1,81 @@
+// Times each audio synthesis mode over the same few seconds of music, with the APU driven
+// directly so that nothing else is measured. Run with: cargo bench --bench synthesis, adding
+// -- <mode> to time just one of them.
+
+extern crate rustico_core;
+
+use rustico_core::apu::ApuState;
+use rustico_core::apu::FilterType;
+use rustico_core::mmc::mapper::Mapper;
+use rustico_core::mmc::none::NoneMapper;
+
+use std::time::Instant;
+
+const CYCLES_PER_FRAME: u64 = 29780;
+const FRAMES: u64 = 60 * 20;
+const RUNS: usize = 5;
+
+// A bass line on the triangle and an arpeggio on pulse 1, with a noise hit every beat
+fn play_frame(apu: &mut ApuState, frame: u64) {
+    if frame == 0 {
+        apu.write_register(0x4015, 0x0F);
+        apu.write_register(0x4000, 0xB8);
+        apu.write_register(0x4008, 0xFF);
+        apu.write_register(0x400C, 0x34);
+    }
+    if frame % 8 == 0 {
+        let arpeggio = [0x0FD, 0x0C9, 0x0A9, 0x07E];
+        let period = arpeggio[((frame / 8) % 4) as usize];
+        apu.write_register(0x4002, (period & 0xFF) as u8);
+        apu.write_register(0x4003, 0xF8 | (period >> 8) as u8);
+    }
+    if frame % 32 == 0 {
+        let bass = [0x3F9, 0x352, 0x2F9, 0x352];
+        let period = bass[((frame / 32) % 4) as usize];
+        apu.write_register(0x400A, (period & 0xFF) as u8);
+        apu.write_register(0x400B, 0xF8 | (period >> 8) as u8);
+    }
+    if frame % 16 == 0 {
+        apu.write_register(0x400E, 0x04);
+        apu.write_register(0x400F, 0x18);
+    }
+}
+
+// Reports the fastest of a few runs, since anything else on the machine only ever slows one down
+fn run(name: &str, configure: &dyn Fn(&mut ApuState)) {
+    let mut fastest = std::f64::MAX;
+    let mut samples = 0;
+    for _ in 0 .. RUNS {
+        let mut apu = ApuState::new();
+        configure(&mut apu);
+        let mut mapper: Box<dyn Mapper> = Box::new(NoneMapper::new());
+        samples = 0;
+        let start = Instant::now();
+        for frame in 0 .. FRAMES {
+            play_frame(&mut apu, frame);
+            for _ in 0 .. CYCLES_PER_FRAME {
+                apu.clock_apu(&mut *mapper);
+            }
+            samples += apu.consume_samples().len();
+        }
+        fastest = fastest.min(start.elapsed().as_secs_f64());
+    }
+    let emulated = (FRAMES * CYCLES_PER_FRAME) as f64 / 1_789_773.0;
+    println!("{:<6} {:>8.1} ms  {:>6.1}x realtime  {} samples", name, fastest * 1000.0, emulated / fastest, samples);
+}
+
+fn main() {
+    // cargo passes --bench along, so skip the flags
+    let only: Option<String> = std::env::args().skip(1).find(|arg| !arg.starts_with("-"));
+    let modes: [(&str, &dyn Fn(&mut ApuState)); 3] = [
+        ("hq", &|apu: &mut ApuState| apu.set_filter(FilterType::FamiCom, true)),
+        ("lq", &|apu: &mut ApuState| apu.set_filter(FilterType::FamiCom, false)),
+        ("blip", &|apu: &mut ApuState| apu.enable_blip()),
+    ];
+    for &(name, configure) in modes.iter() {
+        match only {
+            Some(ref only) if only != name => continue,
+            _ => run(name, configure)
+        }
+    }
+}
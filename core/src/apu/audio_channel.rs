@@ -21,6 +21,8 @@ pub enum Timbre {
     DutyIndex { index: usize, max: usize },
     LsfrMode { index: usize, max: usize },
     PatchIndex { index: usize, max: usize },
+    // Damp, attack, decay, sustain or release, for chips being studied
+    EnvelopePhase { index: usize, max: usize },
 }
 
 pub trait AudioChannelState {
@@ -38,6 +40,9 @@ pub trait AudioChannelState {
     fn unmute(&mut self);
 
     fn playing(&self) -> bool { return false; }
+    // False for generators that are only heard through other channels (the 5B's noise and
+    // envelope), which have no output of their own to render or pan
+    fn has_output(&self) -> bool { return true; }
     fn rate(&self) -> PlaybackRate { return PlaybackRate::SampleRate{frequency: 0.0}; }
     fn volume(&self) -> Option<Volume> {return None}
     fn timbre(&self) -> Option<Timbre> {return None}
@@ -0,0 +1,185 @@
+// Band-limited step synthesis, in the style of blargg's blip_buf. Rather than filtering the
+// signal at the CPU clock rate and then decimating, every change in level is drawn straight
+// into the output as a band-limited step, at its exact position between output samples.
+// Changes are handed over along with the clock they happen on, and the clocks in between
+// are never visited one by one, so most cycles cost nothing at all.
+
+// Steps are taken from the mixed output rather than from each channel on its own, which keeps
+// the 2A03's non-linear mixer (and whatever the mapper does) exactly as it was. Reference:
+// http://www.slack.net/~ant/bl-synth/
+
+use std::f64::consts::PI;
+
+// Output samples each step is spread across. The step's center lands in the middle of these,
+// so everything comes out delayed by half this many samples.
+const KERNEL_WIDTH: usize = 16;
+// Positions between output samples a step can land on
+const KERNEL_PHASES: usize = 64;
+// Deltas waiting to be summed into samples; needs room for the kernel and then some
+const BUFFER_SIZE: usize = 64;
+// A little under Nyquist, to leave room for the window's transition
+const CUTOFF: f64 = 0.45;
+
+fn windowed_sinc(x: f64) -> f64 {
+    let half_width = (KERNEL_WIDTH / 2) as f64;
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {2.0 * CUTOFF} else {(2.0 * PI * CUTOFF * x).sin() / (PI * x)};
+    // Blackman
+    let n = (x + half_width) / (2.0 * half_width);
+    let window = 0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos();
+    return sinc * window;
+}
+
+// The band-limited step, sampled every 1/KERNEL_PHASES of an output sample from one end of the
+// window to the other. It's the windowed sinc, integrated.
+fn generate_step_response() -> Vec<f64> {
+    let half_width = (KERNEL_WIDTH / 2) as f64;
+    let spacing = 1.0 / KERNEL_PHASES as f64;
+    let substeps = 8;
+    let dx = spacing / substeps as f64;
+    let mut step_response = vec![0.0];
+    let mut total = 0.0;
+    for point in 0 .. KERNEL_WIDTH * KERNEL_PHASES {
+        let start = -half_width + point as f64 * spacing;
+        for substep in 0 .. substeps {
+            let x = start + substep as f64 * dx;
+            total += (windowed_sinc(x) + windowed_sinc(x + dx)) * dx / 2.0;
+        }
+        step_response.push(total);
+    }
+    return step_response;
+}
+
+fn generate_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    // Each tap is how much the step rises over one output sample, so that summing them back
+    // up gives the step itself. (Sampling the sinc directly would be off at high frequencies,
+    // since a running sum isn't quite an integral.)
+    let step_response = generate_step_response();
+    let mut kernel = Vec::new();
+    for phase in 0 .. KERNEL_PHASES {
+        let mut taps = [0.0f64; KERNEL_WIDTH];
+        let mut previous = 0.0;
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let current = step_response[(i + 1) * KERNEL_PHASES - phase];
+            *tap = current - previous;
+            previous = current;
+        }
+        // Every phase has to add up to exactly one, or steps would leave the level off
+        // by a little each time, and that adds up
+        let total: f64 = taps.iter().sum();
+        let mut normalized = [0.0f32; KERNEL_WIDTH];
+        for (normalized_tap, tap) in normalized.iter_mut().zip(taps.iter()) {
+            *normalized_tap = (tap / total) as f32;
+        }
+        kernel.push(normalized);
+    }
+    return kernel;
+}
+
+pub struct BlipSynth {
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    deltas: [f32; BUFFER_SIZE],
+    // Index in deltas of the next output sample
+    read_index: usize,
+    sample_rate: f32,
+    samples_per_clock: f64,
+    nominal_samples_per_clock: f64,
+    // Where base_clock fell, in output samples after the sample that was next at the time.
+    // Positions are worked out from here rather than added up clock by clock, so that
+    // synths fed at different moments still agree on them.
+    base_clock: u64,
+    base_position: f64,
+    // Output samples finished since base_clock
+    samples_since_base: u64,
+    last_input: f32,
+    level: f32,
+}
+
+impl BlipSynth {
+    // Starts counting at start_clock, which should be the clock the input is first given for
+    pub fn new(clock_rate: f32, sample_rate: f32, start_clock: u64) -> BlipSynth {
+        return BlipSynth {
+            kernel: generate_kernel(),
+            deltas: [0.0; BUFFER_SIZE],
+            read_index: 0,
+            sample_rate: sample_rate,
+            samples_per_clock: sample_rate as f64 / clock_rate as f64,
+            nominal_samples_per_clock: sample_rate as f64 / clock_rate as f64,
+            base_clock: start_clock,
+            base_position: 0.0,
+            samples_since_base: 0,
+            last_input: 0.0,
+            level: 0.0,
+        };
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        return self.sample_rate;
+    }
+
+    pub fn last_input(&self) -> f32 {
+        return self.last_input;
+    }
+
+    // In output samples after read_index, as of the start of the given clock
+    fn position_at(&self, clock: u64) -> f64 {
+        return self.base_position + (clock - self.base_clock) as f64 * self.samples_per_clock - self.samples_since_base as f64;
+    }
+
+    // Finishes the next output sample, if it's done by the start of the given clock. Call this
+    // until it returns None before changing the input or taking the output.
+    pub fn next_sample_before(&mut self, clock: u64) -> Option<f32> {
+        // Nothing else can reach the next sample once we're a whole sample past it. Waiting
+        // another half a sample keeps us clear of the APU's own sample timing, which lands
+        // on the whole samples, so every sample it takes is a new one.
+        let clocks_needed = (1.5 + self.samples_since_base as f64 - self.base_position) / self.samples_per_clock;
+        let finished_at = self.base_clock + clocks_needed.ceil().max(0.0) as u64;
+        if finished_at > clock {
+            return None;
+        }
+        self.samples_since_base += 1;
+        self.level += self.deltas[self.read_index];
+        self.deltas[self.read_index] = 0.0;
+        self.read_index = (self.read_index + 1) % BUFFER_SIZE;
+        return Some(self.level);
+    }
+
+    // The input from the start of the given clock on
+    pub fn set_input(&mut self, clock: u64, input: f32) {
+        if input != self.last_input {
+            let position = self.position_at(clock);
+            self.add_delta(position, input - self.last_input);
+            self.last_input = input;
+        }
+    }
+
+    fn add_delta(&mut self, position: f64, delta: f32) {
+        let whole_samples = position as usize;
+        let phase = ((position - whole_samples as f64) * KERNEL_PHASES as f64) as usize;
+        let taps = &self.kernel[phase.min(KERNEL_PHASES - 1)];
+        let start = self.read_index + whole_samples;
+        for (i, tap) in taps.iter().enumerate() {
+            self.deltas[(start + i) % BUFFER_SIZE] += tap * delta;
+        }
+    }
+
+    // Follows the APU's sample timing when a shell speeds it up or slows it down, so that
+    // it still hands over exactly one new sample each time one is taken. The new rate
+    // applies from the given clock on.
+    pub fn set_rate_adjustment(&mut self, clock: u64, rate_adjustment: f64) {
+        self.base_position = self.position_at(clock);
+        self.base_clock = clock;
+        self.samples_since_base = 0;
+        self.samples_per_clock = self.nominal_samples_per_clock * rate_adjustment;
+    }
+
+    // Lines up with another synth running at the same rates, so their samples come out together
+    pub fn match_timing(&mut self, other: &BlipSynth) {
+        self.base_clock = other.base_clock;
+        self.base_position = other.base_position;
+        self.samples_since_base = other.samples_since_base;
+        self.samples_per_clock = other.samples_per_clock;
+    }
+}
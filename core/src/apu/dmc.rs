@@ -201,7 +201,7 @@ impl AudioChannelState for DmcState {
 
     fn amplitude(&self) -> f32 {
         let buffer = self.output_buffer.buffer();
-        let mut index = (self.output_buffer.index() - 256) % buffer.len();
+        let mut index = (self.output_buffer.index() + buffer.len() - 256) % buffer.len();
         let mut max = buffer[index];
         let mut min = buffer[index];
         for _i in 0 .. 256 {
@@ -0,0 +1,1149 @@
+// Expansion Port Sound Module: a YMF288 (OPN3-L) or YM2608 (OPNA) wired up to the
+// Famicom expansion port, and written to through the otherwise unused $401C-$401F
+// range. Unlike every other expansion chip we emulate, this one doesn't live on the
+// cartridge, so it hangs off of the APU rather than any particular mapper.
+// Reference: https://www.nesdev.org/wiki/Expansion_Port_Sound_Module
+
+use apu::AudioChannelState;
+use apu::PlaybackRate;
+use apu::Volume;
+use apu::Timbre;
+use apu::RingBuffer;
+use apu::filters;
+use apu::filters::DspFilter;
+
+use mmc::fme7::YM2149F;
+
+// The module is clocked at 8 MHz, independently of the console
+const EPSM_CLOCK_RATE: u64 = 8_000_000;
+
+// FM channels produce one sample every 144 master clocks, about 55.5 kHz
+const FM_CLOCK_DIVIDER: u16 = 144;
+const FM_SAMPLE_RATE: f32 = (EPSM_CLOCK_RATE as f32) / (FM_CLOCK_DIVIDER as f32);
+
+// The SSG runs at master / 4, and our YM2149F expects to be clocked twice that often
+// (it divides by 16 and 32 internally, the real thing by 8 and 16)
+const SSG_CLOCK_DIVIDER: u8 = 2;
+
+// Attenuation is 10 bits, in steps of roughly 0.094 dB
+const MAX_ATTENUATION: u16 = 0x3FF;
+
+fn generate_logsin_lut() -> Vec<u16> {
+    let mut logsin_lut = vec!(0u16; 256);
+    for n in 0 ..= 255 {
+        let i = n as f32 + 0.5;
+        let x = i * (std::f32::consts::PI / 2.0) / 256.0;
+        logsin_lut[n] = (f32::log2(f32::sin(x)) * -256.0).round() as u16;
+    }
+    return logsin_lut;
+}
+
+fn generate_exp_lut() -> Vec<u16> {
+    let mut exp_lut = vec!(0u16; 256);
+    for n in 0 ..= 255 {
+        let i = n as f32 / 256.0;
+        exp_lut[n] = ((f32::exp2(i) * 1024.0) - 1024.0).round() as u16
+    }
+    return exp_lut;
+}
+
+// Detune amounts, indexed by DT & 0x3 and then the 5-bit key code. DT bit 2
+// selects the sign.
+pub const DETUNE_LUT: [u8; 4 * 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+
+    0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2,
+    2, 3, 3, 3, 4, 4, 4, 5, 5, 6, 6, 7, 8, 8, 8, 8,
+
+    1, 1, 1, 1, 2, 2, 2, 2, 2, 3, 3, 3, 4, 4, 4, 5,
+    5, 6, 6, 7, 8, 8, 9,10,11,12,13,14,16,16,16,16,
+
+    2, 2, 2, 2, 2, 3, 3, 3, 4, 4, 4, 5, 5, 6, 6, 7,
+    8, 8, 9,10,11,12,13,14,16,17,19,20,22,22,22,22,
+];
+
+// Envelope increments for each group of 4 rates, as a function of the global
+// envelope counter. Rows 0-3 cover every rate below 48, where the counter is
+// shifted to slow things down; rows 4-15 cover rates 48-59, and row 16 is 60+
+pub const ENVELOPE_INCREMENT_LUT: [u8; 17 * 8] = [
+    0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 1, 1, 0, 1,
+    0, 1, 1, 1, 0, 1, 1, 1,
+    0, 1, 1, 1, 1, 1, 1, 1,
+
+    1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 2, 1, 1, 1, 2,
+    1, 2, 1, 2, 1, 2, 1, 2,
+    1, 2, 2, 2, 1, 2, 2, 2,
+
+    2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 4, 2, 2, 2, 4,
+    2, 4, 2, 4, 2, 4, 2, 4,
+    2, 4, 4, 4, 2, 4, 4, 4,
+
+    4, 4, 4, 4, 4, 4, 4, 4,
+    4, 4, 4, 8, 4, 4, 4, 8,
+    4, 8, 4, 8, 4, 8, 4, 8,
+    4, 8, 8, 8, 4, 8, 8, 8,
+
+    8, 8, 8, 8, 8, 8, 8, 8,
+];
+
+// LFO period, in FM samples per step, for each setting of $22
+pub const LFO_PERIOD_LUT: [u16; 8] = [109, 78, 72, 68, 63, 45, 9, 6];
+// Right shift applied to the 0-126 LFO amplitude for each AMS setting
+pub const AMS_SHIFT_LUT: [u8; 4] = [8, 3, 1, 0];
+// Maximum vibrato depth in cents for each PMS setting
+pub const PMS_DEPTH_LUT: [f32; 8] = [0.0, 3.4, 6.7, 10.0, 14.0, 20.0, 40.0, 80.0];
+
+// Registers address operators in S1, S3, S2, S4 order; we store them in S1-S4 order
+const SLOT_TO_OPERATOR: [usize; 4] = [0, 2, 1, 3];
+
+pub fn amplitude_from_db(db: f32) -> f32 {
+    return f32::powf(10.0, db / 20.0);
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum OpnEnvState {
+    Attack,
+    Decay,
+    Sustain,
+    Release
+}
+
+pub struct OpnOperator {
+    // $30
+    pub detune: u8,
+    pub multiple: u8,
+    // $40
+    pub total_level: u8,
+    // $50
+    pub key_scale: u8,
+    pub attack_rate: u8,
+    // $60
+    pub am_enabled: bool,
+    pub decay_rate: u8,
+    // $70
+    pub sustain_rate: u8,
+    // $80
+    pub sustain_level: u8,
+    pub release_rate: u8,
+
+    pub phase: u32,
+    pub env_level: u16,
+    pub env_state: OpnEnvState,
+    pub key_on: bool,
+}
+
+impl OpnOperator {
+    pub fn new() -> OpnOperator {
+        return OpnOperator {
+            detune: 0,
+            multiple: 0,
+            total_level: 0,
+            key_scale: 0,
+            attack_rate: 0,
+            am_enabled: false,
+            decay_rate: 0,
+            sustain_rate: 0,
+            sustain_level: 0,
+            release_rate: 0,
+
+            phase: 0,
+            env_level: MAX_ATTENUATION,
+            env_state: OpnEnvState::Release,
+            key_on: false,
+        }
+    }
+
+    fn effective_rate(&self, rate: u8, key_code: u8) -> u8 {
+        if rate == 0 {
+            return 0;
+        }
+        let key_scaling = key_code >> (3 - self.key_scale);
+        let result = rate * 2 + key_scaling;
+        if result > 63 {
+            return 63;
+        }
+        return result;
+    }
+
+    fn sustain_attenuation(&self) -> u16 {
+        // SL is in 3 dB steps, with 15 being a special case for ~93 dB
+        if self.sustain_level == 15 {
+            return 0x3E0;
+        }
+        return (self.sustain_level as u16) << 5;
+    }
+
+    pub fn handle_key_on(&mut self, new_key_on: bool, key_code: u8) {
+        if new_key_on && !self.key_on {
+            self.phase = 0;
+            self.env_state = OpnEnvState::Attack;
+            if self.effective_rate(self.attack_rate, key_code) >= 62 {
+                self.env_level = 0;
+            }
+        }
+        if !new_key_on && self.key_on {
+            self.env_state = OpnEnvState::Release;
+        }
+        self.key_on = new_key_on;
+    }
+
+    pub fn update_envelope(&mut self, env_counter: u32, key_code: u8) {
+        if self.env_state == OpnEnvState::Attack && self.env_level == 0 {
+            self.env_state = OpnEnvState::Decay;
+        }
+        if self.env_state == OpnEnvState::Decay && self.env_level >= self.sustain_attenuation() {
+            self.env_state = OpnEnvState::Sustain;
+        }
+
+        let rate = match self.env_state {
+            OpnEnvState::Attack => self.attack_rate,
+            OpnEnvState::Decay => self.decay_rate,
+            OpnEnvState::Sustain => self.sustain_rate,
+            OpnEnvState::Release => self.release_rate * 2 + 1,
+        };
+        let effective_rate = self.effective_rate(rate, key_code);
+        let increment = envelope_increment(effective_rate, env_counter) as i32;
+
+        if self.env_state == OpnEnvState::Attack {
+            if effective_rate >= 62 {
+                self.env_level = 0;
+            } else if increment > 0 {
+                // Attack is exponential, and approaches 0 quickly from the top
+                let current_level = self.env_level as i32;
+                let new_level = current_level + (((!current_level) * increment) >> 4);
+                self.env_level = if new_level < 0 {0} else {new_level as u16};
+            }
+        } else {
+            let new_level = self.env_level + increment as u16;
+            self.env_level = if new_level > MAX_ATTENUATION {MAX_ATTENUATION} else {new_level};
+        }
+    }
+
+    pub fn advance_phase(&mut self, phase_step: u32) {
+        self.phase = (self.phase + phase_step) & 0xFFFFF;
+    }
+
+    pub fn attenuation(&self, am_attenuation: u16) -> u16 {
+        let total = self.env_level + ((self.total_level as u16) << 3) + if self.am_enabled {am_attenuation} else {0};
+        if total > MAX_ATTENUATION {
+            return MAX_ATTENUATION;
+        }
+        return total;
+    }
+}
+
+pub fn envelope_increment(rate: u8, env_counter: u32) -> u8 {
+    if rate == 0 {
+        return 0;
+    }
+    if rate < 48 {
+        let shift = 11 - (rate as u32 / 4);
+        if (env_counter & ((1 << shift) - 1)) != 0 {
+            return 0;
+        }
+        let row = (rate & 0x3) as usize;
+        let column = ((env_counter >> shift) & 0x7) as usize;
+        return ENVELOPE_INCREMENT_LUT[row * 8 + column];
+    }
+    if rate < 60 {
+        let row = 4 + (rate - 48) as usize;
+        let column = (env_counter & 0x7) as usize;
+        return ENVELOPE_INCREMENT_LUT[row * 8 + column];
+    }
+    return ENVELOPE_INCREMENT_LUT[16 * 8];
+}
+
+pub struct EpsmFmChannel {
+    pub channel_index: u8,
+    pub operators: Vec<OpnOperator>,
+
+    pub fnum: u16,
+    pub block: u8,
+    pub algorithm: u8,
+    pub feedback: u8,
+    pub pan_left: bool,
+    pub pan_right: bool,
+    pub ams: u8,
+    pub pms: u8,
+
+    pub operator_1_history: [i16; 2],
+    pub current_output: i16,
+    pub reference_phase: u32,
+
+    pub output_buffer: RingBuffer,
+    pub edge_buffer: RingBuffer,
+    pub last_edge: bool,
+    pub debug_filter: filters::HighPassIIR,
+    pub debug_disable: bool,
+}
+
+impl EpsmFmChannel {
+    pub fn new(channel_index: u8) -> EpsmFmChannel {
+        return EpsmFmChannel {
+            channel_index: channel_index,
+            operators: vec![OpnOperator::new(), OpnOperator::new(), OpnOperator::new(), OpnOperator::new()],
+
+            fnum: 0,
+            block: 0,
+            algorithm: 0,
+            feedback: 0,
+            // Both outputs are enabled at power on
+            pan_left: true,
+            pan_right: true,
+            ams: 0,
+            pms: 0,
+
+            operator_1_history: [0i16; 2],
+            current_output: 0,
+            reference_phase: 0,
+
+            output_buffer: RingBuffer::new(32768),
+            edge_buffer: RingBuffer::new(32768),
+            last_edge: false,
+            debug_filter: filters::HighPassIIR::new(44100.0, 300.0),
+            debug_disable: false,
+        }
+    }
+
+    pub fn key_code(&self) -> u8 {
+        // Block, followed by two bits which crudely indicate where this fnum lies within the octave
+        let f11 = (self.fnum >> 10) & 0x1;
+        let f10 = (self.fnum >> 9) & 0x1;
+        let f9 =  (self.fnum >> 8) & 0x1;
+        let f8 =  (self.fnum >> 7) & 0x1;
+        let n3 = (f11 & (f10 | f9 | f8)) | ((f11 ^ 0x1) & f10 & f9 & f8);
+        return (self.block << 2) | ((f11 as u8) << 1) | (n3 as u8);
+    }
+
+    pub fn handle_key_on(&mut self, operator_mask: u8) {
+        let key_code = self.key_code();
+        for i in 0 .. 4 {
+            let new_key_on = (operator_mask & (1 << i)) != 0;
+            self.operators[i].handle_key_on(new_key_on, key_code);
+        }
+    }
+
+    fn phase_step(&self, operator: &OpnOperator, effective_fnum: u32) -> u32 {
+        let key_code = self.key_code();
+        let base_step = ((effective_fnum << self.block) >> 1) as i32;
+        let detune_amount = DETUNE_LUT[((operator.detune & 0x3) as usize) * 32 + key_code as usize] as i32;
+        let detuned_step = if (operator.detune & 0x4) != 0 {
+            base_step - detune_amount
+        } else {
+            base_step + detune_amount
+        };
+        let detuned_step = (detuned_step as u32) & 0x1FFFF;
+        if operator.multiple == 0 {
+            return detuned_step >> 1;
+        }
+        return detuned_step * operator.multiple as u32;
+    }
+
+    pub fn effective_fnum(&self, pm_weight: f32) -> u32 {
+        if self.pms == 0 || pm_weight == 0.0 {
+            return self.fnum as u32;
+        }
+        let cents = PMS_DEPTH_LUT[self.pms as usize] * pm_weight;
+        return ((self.fnum as f32) * f32::exp2(cents / 1200.0)) as u32;
+    }
+
+    pub fn update(&mut self, env_counter: u32, envelope_tick: bool, am_level: u16, pm_weight: f32, logsin_lut: &[u16], exp_lut: &[u16]) {
+        let key_code = self.key_code();
+        let effective_fnum = self.effective_fnum(pm_weight);
+        for i in 0 .. 4 {
+            if envelope_tick {
+                self.operators[i].update_envelope(env_counter, key_code);
+            }
+            let phase_step = self.phase_step(&self.operators[i], effective_fnum);
+            self.operators[i].advance_phase(phase_step);
+        }
+
+        // Used purely for the debug waveform: track the fundamental so we can find edges
+        let reference_step = (effective_fnum << self.block) >> 1;
+        if self.reference_phase + reference_step > 0xFFFFF {
+            self.last_edge = true;
+        }
+        self.reference_phase = (self.reference_phase + reference_step) & 0xFFFFF;
+
+        let am_attenuation = am_level >> AMS_SHIFT_LUT[self.ams as usize];
+        self.compute_output(am_attenuation, logsin_lut, exp_lut);
+    }
+
+    fn operator_output(&self, operator_index: usize, modulation: i32, am_attenuation: u16, logsin_lut: &[u16], exp_lut: &[u16]) -> i16 {
+        let operator = &self.operators[operator_index];
+        let phase_index = (((operator.phase >> 10) as i32 + modulation) & 0x3FF) as usize;
+        let quadrant_index = phase_index & 0xFF;
+        let mirrored = (phase_index & 0x100) != 0;
+        let negative = (phase_index & 0x200) != 0;
+        let logsin = if mirrored {
+            logsin_lut[255 - quadrant_index]
+        } else {
+            logsin_lut[quadrant_index]
+        };
+
+        let total_attenuation = logsin as u32 + ((operator.attenuation(am_attenuation) as u32) << 2);
+        let integral = total_attenuation >> 8;
+        if integral > 12 {
+            return 0;
+        }
+        let fractional = (total_attenuation & 0xFF) as usize;
+        let magnitude = (((exp_lut[fractional ^ 0xFF] as u32) | 0x400) << 2) >> integral;
+        if negative {
+            return -(magnitude as i16);
+        }
+        return magnitude as i16;
+    }
+
+    pub fn compute_output(&mut self, am_attenuation: u16, logsin_lut: &[u16], exp_lut: &[u16]) {
+        let feedback = if self.feedback != 0 {
+            ((self.operator_1_history[0] as i32) + (self.operator_1_history[1] as i32)) >> (10 - self.feedback)
+        } else {
+            0
+        };
+        let op1 = self.operator_output(0, feedback, am_attenuation, logsin_lut, exp_lut);
+        self.operator_1_history[0] = self.operator_1_history[1];
+        self.operator_1_history[1] = op1;
+
+        // Modulating operators feed half their output into the phase of the next
+        let m1 = (op1 as i32) >> 1;
+        let output: i32 = match self.algorithm {
+            0 => {
+                // 1 -> 2 -> 3 -> 4
+                let op2 = self.operator_output(1, m1, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, (op2 as i32) >> 1, am_attenuation, logsin_lut, exp_lut);
+                self.operator_output(3, (op3 as i32) >> 1, am_attenuation, logsin_lut, exp_lut) as i32
+            },
+            1 => {
+                // (1 + 2) -> 3 -> 4
+                let op2 = self.operator_output(1, 0, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, ((op1 as i32) + (op2 as i32)) >> 1, am_attenuation, logsin_lut, exp_lut);
+                self.operator_output(3, (op3 as i32) >> 1, am_attenuation, logsin_lut, exp_lut) as i32
+            },
+            2 => {
+                // (1 + (2 -> 3)) -> 4
+                let op2 = self.operator_output(1, 0, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, (op2 as i32) >> 1, am_attenuation, logsin_lut, exp_lut);
+                self.operator_output(3, ((op1 as i32) + (op3 as i32)) >> 1, am_attenuation, logsin_lut, exp_lut) as i32
+            },
+            3 => {
+                // ((1 -> 2) + 3) -> 4
+                let op2 = self.operator_output(1, m1, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, 0, am_attenuation, logsin_lut, exp_lut);
+                self.operator_output(3, ((op2 as i32) + (op3 as i32)) >> 1, am_attenuation, logsin_lut, exp_lut) as i32
+            },
+            4 => {
+                // (1 -> 2) + (3 -> 4)
+                let op2 = self.operator_output(1, m1, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, 0, am_attenuation, logsin_lut, exp_lut);
+                let op4 = self.operator_output(3, (op3 as i32) >> 1, am_attenuation, logsin_lut, exp_lut);
+                (op2 as i32) + (op4 as i32)
+            },
+            5 => {
+                // 1 -> (2 + 3 + 4)
+                let op2 = self.operator_output(1, m1, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, m1, am_attenuation, logsin_lut, exp_lut);
+                let op4 = self.operator_output(3, m1, am_attenuation, logsin_lut, exp_lut);
+                (op2 as i32) + (op3 as i32) + (op4 as i32)
+            },
+            6 => {
+                // (1 -> 2) + 3 + 4
+                let op2 = self.operator_output(1, m1, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, 0, am_attenuation, logsin_lut, exp_lut);
+                let op4 = self.operator_output(3, 0, am_attenuation, logsin_lut, exp_lut);
+                (op2 as i32) + (op3 as i32) + (op4 as i32)
+            },
+            _ => {
+                // 1 + 2 + 3 + 4
+                let op2 = self.operator_output(1, 0, am_attenuation, logsin_lut, exp_lut);
+                let op3 = self.operator_output(2, 0, am_attenuation, logsin_lut, exp_lut);
+                let op4 = self.operator_output(3, 0, am_attenuation, logsin_lut, exp_lut);
+                (op1 as i32) + (op2 as i32) + (op3 as i32) + (op4 as i32)
+            }
+        };
+
+        // The accumulator clips at 14 bits
+        self.current_output = if output > 8191 {
+            8191
+        } else if output < -8192 {
+            -8192
+        } else {
+            output as i16
+        };
+    }
+
+    pub fn output(&self) -> i16 {
+        return self.current_output;
+    }
+
+    // We don't model stereo (yet), so fold the two sides together
+    pub fn mono_output(&self) -> i32 {
+        let mut output = 0;
+        if self.pan_left {
+            output += self.current_output as i32;
+        }
+        if self.pan_right {
+            output += self.current_output as i32;
+        }
+        return output / 2;
+    }
+
+    fn carrier_mask(&self) -> Vec<usize> {
+        return match self.algorithm {
+            0 ..= 3 => vec![3],
+            4 => vec![1, 3],
+            5 | 6 => vec![1, 2, 3],
+            _ => vec![0, 1, 2, 3]
+        }
+    }
+
+    fn loudest_carrier_attenuation(&self) -> u16 {
+        let mut attenuation = MAX_ATTENUATION;
+        for i in self.carrier_mask() {
+            let carrier_attenuation = self.operators[i].attenuation(0);
+            if carrier_attenuation < attenuation {
+                attenuation = carrier_attenuation;
+            }
+        }
+        return attenuation;
+    }
+}
+
+impl AudioChannelState for EpsmFmChannel {
+    fn name(&self) -> String {
+        return format!("FM {}", self.channel_index);
+    }
+
+    fn chip(&self) -> String {
+        return "EPSM".to_string();
+    }
+
+    fn sample_buffer(&self) -> &RingBuffer {
+        return &self.output_buffer;
+    }
+
+    fn edge_buffer(&self) -> &RingBuffer {
+        return &self.edge_buffer;
+    }
+
+    fn record_current_output(&mut self) {
+        self.debug_filter.consume(self.output() as f32);
+        self.output_buffer.push((self.debug_filter.output() * -0.25) as i16);
+        self.edge_buffer.push(self.last_edge as i16);
+        self.last_edge = false;
+    }
+
+    fn min_sample(&self) -> i16 {
+        return -2048;
+    }
+
+    fn max_sample(&self) -> i16 {
+        return 2048;
+    }
+
+    fn muted(&self) -> bool {
+        return self.debug_disable;
+    }
+
+    fn mute(&mut self) {
+        self.debug_disable = true;
+    }
+
+    fn unmute(&mut self) {
+        self.debug_disable = false;
+    }
+
+    fn playing(&self) -> bool {
+        return
+            self.fnum > 0 &&
+            (self.pan_left || self.pan_right) &&
+            self.loudest_carrier_attenuation() < 0x300;
+    }
+
+    fn rate(&self) -> PlaybackRate {
+        let effective_frequency = (self.fnum as f32) * FM_SAMPLE_RATE * f32::exp2(self.block as f32 - 1.0) / f32::exp2(20.0);
+        return PlaybackRate::FundamentalFrequency {frequency: effective_frequency};
+    }
+
+    fn volume(&self) -> Option<Volume> {
+        // Every 64 steps of attenuation halves the output, so this is roughly logarithmic
+        let attenuation = self.loudest_carrier_attenuation();
+        let index = ((MAX_ATTENUATION - attenuation) >> 6) as usize;
+        return Some(Volume::VolumeIndex{ index: index, max: 15 });
+    }
+
+    fn timbre(&self) -> Option<Timbre> {
+        return Some(Timbre::PatchIndex{ index: self.algorithm as usize, max: 7 });
+    }
+}
+
+// The rhythm section plays samples from an internal ADPCM ROM, which we don't have.
+// Instead each instrument is synthesized with a rough approximation: a decaying tone,
+// noise, or some mix of the two. It won't fool anyone, but the timing, levels and
+// panning all follow the registers.
+pub const RHYTHM_BASS_DRUM: usize = 0;
+pub const RHYTHM_SNARE_DRUM: usize = 1;
+pub const RHYTHM_TOP_CYMBAL: usize = 2;
+pub const RHYTHM_HI_HAT: usize = 3;
+pub const RHYTHM_TOM: usize = 4;
+pub const RHYTHM_RIM_SHOT: usize = 5;
+
+pub struct RhythmVoice {
+    pub playing: bool,
+    pub elapsed: f32,
+    pub phase: f32,
+    pub level: u8,
+    pub pan_left: bool,
+    pub pan_right: bool,
+}
+
+impl RhythmVoice {
+    pub fn new() -> RhythmVoice {
+        return RhythmVoice {
+            playing: false,
+            elapsed: 0.0,
+            phase: 0.0,
+            level: 0,
+            pan_left: true,
+            pan_right: true,
+        }
+    }
+}
+
+pub struct EpsmRhythmChannel {
+    pub voices: Vec<RhythmVoice>,
+    pub total_level: u8,
+    pub noise_lfsr: u32,
+    pub current_output: i16,
+
+    pub output_buffer: RingBuffer,
+    pub edge_buffer: RingBuffer,
+    pub debug_filter: filters::HighPassIIR,
+    pub debug_disable: bool,
+}
+
+impl EpsmRhythmChannel {
+    pub fn new() -> EpsmRhythmChannel {
+        let mut voices = Vec::new();
+        for _ in 0 .. 6 {
+            voices.push(RhythmVoice::new());
+        }
+        return EpsmRhythmChannel {
+            voices: voices,
+            total_level: 0,
+            noise_lfsr: 0x1FFFF,
+            current_output: 0,
+
+            output_buffer: RingBuffer::new(32768),
+            edge_buffer: RingBuffer::new(32768),
+            debug_filter: filters::HighPassIIR::new(44100.0, 300.0),
+            debug_disable: false,
+        }
+    }
+
+    pub fn key_on(&mut self, data: u8) {
+        let dump = (data & 0b1000_0000) != 0;
+        for i in 0 .. 6 {
+            if (data & (1 << i)) != 0 {
+                if dump {
+                    self.voices[i].playing = false;
+                } else {
+                    self.voices[i].playing = true;
+                    self.voices[i].elapsed = 0.0;
+                    self.voices[i].phase = 0.0;
+                }
+            }
+        }
+    }
+
+    fn advance_noise(&mut self) -> f32 {
+        let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 0x1;
+        self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        return if (self.noise_lfsr & 0x1) != 0 {1.0} else {-1.0};
+    }
+
+    // Returns a sample in the range -1.0 .. 1.0, and whether the voice has finished
+    fn synthesize(voice: &mut RhythmVoice, instrument: usize, noise: f32) -> (f32, bool) {
+        let t = voice.elapsed;
+        let (frequency, tone_mix, decay_time) = match instrument {
+            RHYTHM_BASS_DRUM =>  (50.0 + 100.0 * f32::exp(-t / 0.03), 1.0, 0.20),
+            RHYTHM_SNARE_DRUM => (185.0, 0.4, 0.15),
+            RHYTHM_TOP_CYMBAL => (0.0, 0.0, 0.40),
+            RHYTHM_HI_HAT =>     (0.0, 0.0, 0.06),
+            RHYTHM_TOM =>        (90.0 + 40.0 * f32::exp(-t / 0.05), 1.0, 0.25),
+            RHYTHM_RIM_SHOT =>   (1700.0, 0.8, 0.02),
+            _ =>                 (0.0, 0.0, 0.001),
+        };
+        let envelope = f32::exp(-t / decay_time);
+        voice.phase += frequency / FM_SAMPLE_RATE;
+        voice.phase -= voice.phase.floor();
+        let tone = f32::sin(voice.phase * 2.0 * std::f32::consts::PI);
+        let sample = (tone * tone_mix + noise * (1.0 - tone_mix)) * envelope;
+        voice.elapsed += 1.0 / FM_SAMPLE_RATE;
+        return (sample, envelope < 0.001);
+    }
+
+    pub fn update(&mut self) {
+        let mut combined = 0.0;
+        for i in 0 .. 6 {
+            if !self.voices[i].playing {
+                continue;
+            }
+            let noise = self.advance_noise();
+            let (sample, finished) = EpsmRhythmChannel::synthesize(&mut self.voices[i], i, noise);
+            if finished {
+                self.voices[i].playing = false;
+            }
+            // Both level registers attenuate in 0.75 dB steps
+            let attenuation_db = 0.75 * ((63 - self.total_level as u32) + (31 - self.voices[i].level as u32)) as f32;
+            let mut pan = 0.0;
+            if self.voices[i].pan_left {
+                pan += 0.5;
+            }
+            if self.voices[i].pan_right {
+                pan += 0.5;
+            }
+            combined += sample * amplitude_from_db(-attenuation_db) * pan;
+        }
+        self.current_output = (combined * 8191.0).max(-8192.0).min(8191.0) as i16;
+    }
+
+    pub fn output(&self) -> i16 {
+        return self.current_output;
+    }
+}
+
+impl AudioChannelState for EpsmRhythmChannel {
+    fn name(&self) -> String {
+        return "Rhythm".to_string();
+    }
+
+    fn chip(&self) -> String {
+        return "EPSM".to_string();
+    }
+
+    fn sample_buffer(&self) -> &RingBuffer {
+        return &self.output_buffer;
+    }
+
+    fn edge_buffer(&self) -> &RingBuffer {
+        return &self.edge_buffer;
+    }
+
+    fn record_current_output(&mut self) {
+        self.debug_filter.consume(self.output() as f32);
+        self.output_buffer.push((self.debug_filter.output() * -0.25) as i16);
+        self.edge_buffer.push(0);
+    }
+
+    fn min_sample(&self) -> i16 {
+        return -2048;
+    }
+
+    fn max_sample(&self) -> i16 {
+        return 2048;
+    }
+
+    fn muted(&self) -> bool {
+        return self.debug_disable;
+    }
+
+    fn mute(&mut self) {
+        self.debug_disable = true;
+    }
+
+    fn unmute(&mut self) {
+        self.debug_disable = false;
+    }
+
+    fn playing(&self) -> bool {
+        return self.voices.iter().any(|voice| voice.playing);
+    }
+
+    fn rate(&self) -> PlaybackRate {
+        return PlaybackRate::SampleRate {frequency: FM_SAMPLE_RATE};
+    }
+
+    fn volume(&self) -> Option<Volume> {
+        return Some(Volume::VolumeIndex{ index: (self.total_level >> 2) as usize, max: 15 });
+    }
+}
+
+pub struct Epsm {
+    pub fm_channels: Vec<EpsmFmChannel>,
+    pub ssg: YM2149F,
+    pub rhythm: EpsmRhythmChannel,
+
+    pub address_latch: [u8; 2],
+    pub fnum_latch: [u8; 2],
+
+    pub lfo_enabled: bool,
+    pub lfo_rate: u8,
+    pub lfo_counter: u16,
+    pub lfo_step: u8,
+
+    pub env_counter: u32,
+    pub env_divider: u8,
+
+    // The console's clock, which the module is stepped by
+    pub cpu_clock_rate: u64,
+    pub clock_accumulator: u64,
+    pub fm_divider: u16,
+    pub ssg_divider: u8,
+
+    logsin_lut: Vec<u16>,
+    exp_lut: Vec<u16>,
+}
+
+impl Epsm {
+    pub fn new(cpu_clock_rate: u64) -> Epsm {
+        let mut fm_channels = Vec::new();
+        for i in 1 ..= 6 {
+            fm_channels.push(EpsmFmChannel::new(i));
+        }
+        let mut ssg = YM2149F::new("EPSM");
+        ssg.channel_a.name = "SSG A".to_string();
+        ssg.channel_b.name = "SSG B".to_string();
+        ssg.channel_c.name = "SSG C".to_string();
+
+        return Epsm {
+            fm_channels: fm_channels,
+            ssg: ssg,
+            rhythm: EpsmRhythmChannel::new(),
+
+            address_latch: [0u8; 2],
+            fnum_latch: [0u8; 2],
+
+            lfo_enabled: false,
+            lfo_rate: 0,
+            lfo_counter: 0,
+            lfo_step: 0,
+
+            env_counter: 0,
+            env_divider: 0,
+
+            cpu_clock_rate: cpu_clock_rate,
+            clock_accumulator: 0,
+            fm_divider: 0,
+            ssg_divider: 0,
+
+            logsin_lut: generate_logsin_lut(),
+            exp_lut: generate_exp_lut(),
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.clock_accumulator += EPSM_CLOCK_RATE;
+        while self.clock_accumulator >= self.cpu_clock_rate {
+            self.clock_accumulator -= self.cpu_clock_rate;
+            self.ssg_divider += 1;
+            if self.ssg_divider >= SSG_CLOCK_DIVIDER {
+                self.ssg_divider = 0;
+                self.ssg.clock();
+            }
+            self.fm_divider += 1;
+            if self.fm_divider >= FM_CLOCK_DIVIDER {
+                self.fm_divider = 0;
+                self.update_fm();
+            }
+        }
+    }
+
+    fn lfo_am_level(&self) -> u16 {
+        if !self.lfo_enabled {
+            return 0;
+        }
+        // Triangle, 0 - 126
+        if self.lfo_step < 64 {
+            return (self.lfo_step as u16) * 2;
+        }
+        return (127 - self.lfo_step as u16) * 2;
+    }
+
+    fn lfo_pm_weight(&self) -> f32 {
+        if !self.lfo_enabled {
+            return 0.0;
+        }
+        // Triangle, -1.0 - 1.0, starting from the center
+        let position = (self.lfo_step as f32) / 128.0;
+        if position < 0.25 {
+            return position * 4.0;
+        }
+        if position < 0.75 {
+            return 2.0 - position * 4.0;
+        }
+        return position * 4.0 - 4.0;
+    }
+
+    fn update_fm(&mut self) {
+        if self.lfo_enabled {
+            self.lfo_counter += 1;
+            if self.lfo_counter >= LFO_PERIOD_LUT[self.lfo_rate as usize] {
+                self.lfo_counter = 0;
+                self.lfo_step = (self.lfo_step + 1) & 0x7F;
+            }
+        }
+
+        // The envelope generator runs at one third of the FM sample rate
+        self.env_divider += 1;
+        let envelope_tick = self.env_divider >= 3;
+        if envelope_tick {
+            self.env_divider = 0;
+            self.env_counter += 1;
+        }
+
+        let am_level = self.lfo_am_level();
+        let pm_weight = self.lfo_pm_weight();
+        for channel in self.fm_channels.iter_mut() {
+            channel.update(self.env_counter, envelope_tick, am_level, pm_weight, &self.logsin_lut, &self.exp_lut);
+        }
+        self.rhythm.update();
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x401C => {self.address_latch[0] = data},
+            0x401D => {self.write_register(0, self.address_latch[0], data)},
+            0x401E => {self.address_latch[1] = data},
+            0x401F => {self.write_register(1, self.address_latch[1], data)},
+            _ => {}
+        }
+    }
+
+    pub fn write_register(&mut self, part: usize, register: u8, data: u8) {
+        match register {
+            0x00 ..= 0x0F => {
+                if part == 0 {
+                    self.ssg.execute_command(register, data);
+                }
+                // Part 2 holds the ADPCM registers on the YM2608; these are not emulated.
+            },
+            0x10 ..= 0x1F => {
+                if part == 0 {
+                    self.write_rhythm_register(register, data);
+                }
+            },
+            0x20 ..= 0x2F => {
+                if part == 0 {
+                    self.write_global_register(register, data);
+                }
+            },
+            0x30 ..= 0x9F => {
+                let channel_offset = (register & 0x3) as usize;
+                if channel_offset == 3 {
+                    return;
+                }
+                let channel_index = part * 3 + channel_offset;
+                let operator_index = SLOT_TO_OPERATOR[((register >> 2) & 0x3) as usize];
+                let operator = &mut self.fm_channels[channel_index].operators[operator_index];
+                match register & 0xF0 {
+                    0x30 => {
+                        operator.detune =   (data & 0b0111_0000) >> 4;
+                        operator.multiple =  data & 0b0000_1111;
+                    },
+                    0x40 => {
+                        operator.total_level = data & 0b0111_1111;
+                    },
+                    0x50 => {
+                        operator.key_scale =   (data & 0b1100_0000) >> 6;
+                        operator.attack_rate =  data & 0b0001_1111;
+                    },
+                    0x60 => {
+                        operator.am_enabled = (data & 0b1000_0000) != 0;
+                        operator.decay_rate =  data & 0b0001_1111;
+                    },
+                    0x70 => {
+                        operator.sustain_rate = data & 0b0001_1111;
+                    },
+                    0x80 => {
+                        operator.sustain_level = (data & 0b1111_0000) >> 4;
+                        operator.release_rate =   data & 0b0000_1111;
+                    },
+                    _ => {
+                        // $90: SSG-EG, which is not emulated
+                    }
+                }
+            },
+            0xA0 ..= 0xA2 => {
+                // Writing the low byte also applies the previously latched block and high bits
+                let channel = &mut self.fm_channels[part * 3 + (register & 0x3) as usize];
+                channel.fnum = (((self.fnum_latch[part] & 0b0000_0111) as u16) << 8) | (data as u16);
+                channel.block = (self.fnum_latch[part] & 0b0011_1000) >> 3;
+            },
+            0xA4 ..= 0xA6 => {
+                self.fnum_latch[part] = data;
+            },
+            0xB0 ..= 0xB2 => {
+                let channel = &mut self.fm_channels[part * 3 + (register & 0x3) as usize];
+                channel.feedback =  (data & 0b0011_1000) >> 3;
+                channel.algorithm =  data & 0b0000_0111;
+            },
+            0xB4 ..= 0xB6 => {
+                let channel = &mut self.fm_channels[part * 3 + (register & 0x3) as usize];
+                channel.pan_left =  (data & 0b1000_0000) != 0;
+                channel.pan_right = (data & 0b0100_0000) != 0;
+                channel.ams =       (data & 0b0011_0000) >> 4;
+                channel.pms =        data & 0b0000_0111;
+            },
+            _ => {
+                // $A8-$AE configure channel 3's special mode, which is not emulated
+            }
+        }
+    }
+
+    fn write_global_register(&mut self, register: u8, data: u8) {
+        match register {
+            0x22 => {
+                self.lfo_enabled = (data & 0b0000_1000) != 0;
+                self.lfo_rate =     data & 0b0000_0111;
+                if !self.lfo_enabled {
+                    self.lfo_counter = 0;
+                    self.lfo_step = 0;
+                }
+            },
+            0x28 => {
+                let channel_index = match data & 0b0000_0111 {
+                    0 => 0,
+                    1 => 1,
+                    2 => 2,
+                    4 => 3,
+                    5 => 4,
+                    6 => 5,
+                    _ => {return;}
+                };
+                self.fm_channels[channel_index].handle_key_on((data & 0b1111_0000) >> 4);
+            },
+            _ => {
+                // Timers, prescaler and IRQ control. The timers can't reach the CPU
+                // through the expansion port, so none of these matter to us.
+            }
+        }
+    }
+
+    fn write_rhythm_register(&mut self, register: u8, data: u8) {
+        match register {
+            0x10 => {
+                self.rhythm.key_on(data);
+            },
+            0x11 => {
+                self.rhythm.total_level = data & 0b0011_1111;
+            },
+            0x18 ..= 0x1D => {
+                let voice = &mut self.rhythm.voices[(register - 0x18) as usize];
+                voice.pan_left =  (data & 0b1000_0000) != 0;
+                voice.pan_right = (data & 0b0100_0000) != 0;
+                voice.level =      data & 0b0001_1111;
+            },
+            _ => {}
+        }
+    }
+
+    // Nobody has published measurements of the EPSM board's output level, and it depends
+    // on the resistors each builder fits. The FM section sits where the VRC7 does against
+    // the APU, which keeps songs ported between the two chips balanced the same way.
+    fn fm_level(fm_output: i32) -> f32 {
+        return (fm_output as f32) / 8192.0 / 6.0 * amplitude_from_db(11.0);
+    }
+
+    pub fn output(&self) -> f32 {
+        let mut fm_output = 0;
+        for channel in self.fm_channels.iter() {
+            if !channel.debug_disable {
+                fm_output += channel.mono_output();
+            }
+        }
+        if !self.rhythm.debug_disable {
+            fm_output += self.rhythm.output() as i32;
+        }
+
+        // The SSG is a YM2149F in all but name, and we reuse the 5B's mixing here.
+        let ssg_output = self.ssg.output() * 1.06;
+
+        return Epsm::fm_level(fm_output) + ssg_output;
+    }
+
+    // Each channel's term of output(), in the same order as channels(). Used to render
+    // the channels separately.
+    pub fn channel_outputs(&self, outputs: &mut Vec<f32>) {
+        for channel in self.fm_channels.iter() {
+            outputs.push(Epsm::fm_level(channel.mono_output()));
+        }
+        for amplitude in self.ssg.channel_amplitudes().iter() {
+            outputs.push(amplitude * 1.06);
+        }
+        outputs.push(Epsm::fm_level(self.rhythm.output() as i32));
+    }
+
+    pub fn record_output(&mut self) {
+        for channel in self.fm_channels.iter_mut() {
+            channel.record_current_output();
+        }
+        self.rhythm.record_current_output();
+        self.ssg.record_output();
+    }
+
+    pub fn channels(&self) -> Vec<& dyn AudioChannelState> {
+        let mut channels: Vec<& dyn AudioChannelState> = Vec::new();
+        for channel in self.fm_channels.iter() {
+            channels.push(channel);
+        }
+        channels.push(&self.ssg.channel_a);
+        channels.push(&self.ssg.channel_b);
+        channels.push(&self.ssg.channel_c);
+        channels.push(&self.rhythm);
+        return channels;
+    }
+
+    pub fn channels_mut(&mut self) -> Vec<&mut dyn AudioChannelState> {
+        let mut channels: Vec<&mut dyn AudioChannelState> = Vec::new();
+        for channel in self.fm_channels.iter_mut() {
+            channels.push(channel);
+        }
+        channels.push(&mut self.ssg.channel_a);
+        channels.push(&mut self.ssg.channel_b);
+        channels.push(&mut self.ssg.channel_c);
+        channels.push(&mut self.rhythm);
+        return channels;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_outputs_add_up_to_the_output() {
+        let mut epsm = Epsm::new(1_789_773);
+        // FM channel 1: every operator a carrier at full level, centered
+        epsm.write_register(0, 0xB0, 0x07);
+        epsm.write_register(0, 0xB4, 0xC0);
+        for slot in [0x00, 0x04, 0x08, 0x0C].iter() {
+            epsm.write_register(0, 0x40 + slot, 0x00);
+            epsm.write_register(0, 0x50 + slot, 0x1F);
+        }
+        epsm.write_register(0, 0xA4, 0x22);
+        epsm.write_register(0, 0xA0, 0x69);
+        epsm.write_register(0, 0x28, 0xF0);
+        // SSG channel A: tone only, at full volume
+        epsm.write_register(0, 0x00, 0x40);
+        epsm.write_register(0, 0x07, 0b0011_1110);
+        epsm.write_register(0, 0x08, 0x0F);
+
+        let mut heard = false;
+        let mut outputs = Vec::new();
+        for _ in 0 .. 20000 {
+            epsm.clock();
+            outputs.clear();
+            epsm.channel_outputs(&mut outputs);
+            assert_eq!(outputs.len(), epsm.channels().len());
+            let sum: f32 = outputs.iter().sum();
+            assert!((sum - epsm.output()).abs() < 1e-5);
+            heard |= outputs[0] != 0.0;
+        }
+        assert!(heard);
+    }
+}
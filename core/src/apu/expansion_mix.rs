@@ -0,0 +1,78 @@
+// How loud each expansion chip is against the APU, and the filtering on its way into the
+// mix. This differs between consoles and between boards, and nobody agrees on the right
+// numbers yet, so it's adjustable. A gain of 1.0 is the level each mapper has always mixed
+// at, which is also where the NSFe mixe defaults land.
+
+// Gains are indexed by the NSFe mixe device numbers, so a file's levels can stand in for
+// any of them. The APU entries are only used by the NSF player; everything else mixes
+// against the APU as it is.
+
+use apu::FilterType;
+use nsf::MIXE_APU_SQUARE;
+use nsf::MIXE_APU_TRIANGLE;
+use nsf::MIXE_VRC6;
+use nsf::MIXE_VRC7;
+use nsf::MIXE_FDS;
+use nsf::MIXE_MMC5;
+use nsf::MIXE_N163;
+use nsf::MIXE_S5B;
+
+pub const EXPANSION_MIX_CONSOLES: [&str; 2] = ["famicom", "nes"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExpansionMix {
+    pub gains: [f32; 8],
+    // Cutoff of the FDS RAM adapter's output filter, in Hz
+    pub fds_lowpass: f32,
+    pub filter_type: FilterType,
+}
+
+impl ExpansionMix {
+    pub fn new() -> ExpansionMix {
+        return ExpansionMix {
+            gains: [1.0; 8],
+            fds_lowpass: 2000.0,
+            filter_type: FilterType::FamiCom,
+        };
+    }
+
+    // The console only decides which output filter the mix goes through. Cartridge audio
+    // goes straight into the Famicom's mixer, which is what the stock levels were measured
+    // against. A front loader only hears it through a mod on the expansion port, and each
+    // mod fits whatever resistor its builder liked, so there's no level to give it here;
+    // set the gains to match the console in question.
+    pub fn for_console(name: &str) -> Option<ExpansionMix> {
+        match name {
+            "famicom" => Some(ExpansionMix::new()),
+            "nes" => {
+                let mut mix = ExpansionMix::new();
+                mix.filter_type = FilterType::Nes;
+                Some(mix)
+            },
+            _ => None
+        }
+    }
+
+    pub fn gain(&self, device: u8) -> f32 {
+        return self.gains[device as usize];
+    }
+}
+
+// The name a device's settings go by
+pub fn expansion_device_key(device: u8) -> &'static str {
+    match device {
+        MIXE_APU_SQUARE => "apu_square",
+        MIXE_APU_TRIANGLE => "apu_triangle",
+        MIXE_VRC6 => "vrc6",
+        MIXE_VRC7 => "vrc7",
+        MIXE_FDS => "fds",
+        MIXE_MMC5 => "mmc5",
+        MIXE_N163 => "n163",
+        MIXE_S5B => "s5b",
+        _ => "unknown"
+    }
+}
+
+pub fn expansion_device_from_key(key: &str) -> Option<u8> {
+    return (MIXE_APU_SQUARE ..= MIXE_S5B).find(|&device| expansion_device_key(device) == key);
+}
@@ -2,6 +2,8 @@
 
 use std::f32::consts::PI;
 
+use apu::blip::BlipSynth;
+
 pub trait DspFilter: Send {
     fn consume(&mut self, sample: f32);
     fn output(&self) -> f32;
@@ -161,7 +163,8 @@ impl DspFilter for LowPassFIR {
 }
 
 // essentially a thin wrapper around a DspFilter, with some bonus data to track
-// state when used in a larger chain
+// state when used in a larger chain. In a chain with a blip front end, sampling_period
+// goes unused: every stage runs once per finished sample.
 pub struct ChainedFilter {
     wrapped_filter: Box<dyn DspFilter>,
     sampling_period: f32,
@@ -170,6 +173,9 @@ pub struct ChainedFilter {
 
 pub struct FilterChain {
     filters: Vec<ChainedFilter>,
+    // Stands in for everything at the clock rate, when present. The filters after it
+    // then run once per finished sample.
+    blip: Option<BlipSynth>,
 }
 
 impl FilterChain {
@@ -181,10 +187,26 @@ impl FilterChain {
                 sampling_period: 1.0,
                 period_counter: 0.0,
             }],
+            blip: None,
         }
     }
 
+    // A chain with a blip front end is fed with consume_at, only when its input changes,
+    // and has to catch_up before its output is read. It has no use for delta_time.
+    pub fn with_blip(blip: BlipSynth) -> FilterChain {
+        let mut chain = FilterChain::new();
+        chain.blip = Some(blip);
+        return chain;
+    }
+
+    pub fn is_blip(&self) -> bool {
+        return self.blip.is_some();
+    }
+
     pub fn add(&mut self, filter: Box<dyn DspFilter>, sample_rate: f32) {
+        if let Some(ref blip) = self.blip {
+            assert!(sample_rate == blip.sample_rate(), "stages after a blip synth run at its output rate");
+        }
         self.filters.push(ChainedFilter {
             wrapped_filter: filter,
             sampling_period: (1.0 / sample_rate),
@@ -192,7 +214,68 @@ impl FilterChain {
         });
     }
 
+    // Has each stage sample its input at the same moments as another chain of the same shape
+    pub fn match_timing(&mut self, other: &FilterChain) {
+        for (filter, other_filter) in self.filters.iter_mut().zip(other.filters.iter()) {
+            filter.period_counter = other_filter.period_counter;
+        }
+        match (&mut self.blip, &other.blip) {
+            (Some(blip), Some(other_blip)) => blip.match_timing(other_blip),
+            _ => {}
+        }
+    }
+
+    // Only the blip front end needs to know; the other stages are simply read less or more often.
+    // The clock is when the new rate takes effect, which only the blip front end cares about.
+    pub fn set_rate_adjustment(&mut self, clock: u64, rate_adjustment: f64) {
+        if self.blip.is_some() {
+            self.catch_up(clock);
+        }
+        if let Some(ref mut blip) = self.blip {
+            blip.set_rate_adjustment(clock, rate_adjustment);
+        }
+    }
+
+    // Blip chains only: the input from the given clock on. Costs next to nothing when the
+    // input hasn't changed, so the clocks in between needn't be fed at all.
+    pub fn consume_at(&mut self, clock: u64, input_sample: f32) {
+        match self.blip {
+            Some(ref blip) => {
+                if input_sample == blip.last_input() {
+                    return;
+                }
+            },
+            None => panic!("only blip chains take their input by clock")
+        }
+        self.catch_up(clock);
+        if let Some(ref mut blip) = self.blip {
+            blip.set_input(clock, input_sample);
+        }
+    }
+
+    // Blip chains only: runs every sample finished by the start of the given clock through
+    // the stages, so that the output is current
+    pub fn catch_up(&mut self, clock: u64) {
+        loop {
+            let sample = match self.blip {
+                Some(ref mut blip) => blip.next_sample_before(clock),
+                None => panic!("only blip chains take their input by clock")
+            };
+            match sample {
+                Some(sample) => {
+                    self.filters[0].wrapped_filter.consume(sample);
+                    for i in 1 .. self.filters.len() {
+                        let previous_output = self.filters[i - 1].wrapped_filter.output();
+                        self.filters[i].wrapped_filter.consume(previous_output);
+                    }
+                },
+                None => return
+            }
+        }
+    }
+
     pub fn consume(&mut self, input_sample: f32, delta_time: f32) {
+        assert!(self.blip.is_none(), "blip chains are fed with consume_at");
         // Always advance the identity filter with the new current sample
         self.filters[0].wrapped_filter.consume(input_sample);
         // Now for every remaining filter in the chain, advance and sample the previous
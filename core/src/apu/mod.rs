@@ -4,12 +4,17 @@ use std::fs::OpenOptions;
 use std::io::prelude::*;
 
 mod audio_channel;
+mod blip;
 mod dmc;
+mod epsm;
+mod expansion_mix;
 pub mod filters;
 mod length_counter;
 mod noise;
 mod pulse;
 mod ring_buffer;
+mod stems;
+mod stereo;
 mod triangle;
 mod volume_envelope;
 
@@ -18,15 +23,25 @@ pub use self::audio_channel::PlaybackRate;
 pub use self::audio_channel::Volume;
 pub use self::audio_channel::Timbre;
 pub use self::dmc::DmcState;
+pub use self::epsm::Epsm;
+pub use self::expansion_mix::ExpansionMix;
+pub use self::expansion_mix::EXPANSION_MIX_CONSOLES;
+pub use self::expansion_mix::expansion_device_key;
+pub use self::expansion_mix::expansion_device_from_key;
 pub use self::noise::NoiseChannelState;
 pub use self::pulse::PulseChannelState;
 pub use self::ring_buffer::RingBuffer;
+pub use self::stems::Stem;
+pub use self::stereo::ChannelMix;
+pub use self::stereo::StereoMixer;
 pub use self::triangle::TriangleChannelState;
 
 pub use self::filters::DspFilter;
 pub use self::filters::FilterChain;
 
-#[derive(Clone, Copy)]
+use self::blip::BlipSynth;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FilterType {
     Nes,
     FamiCom,
@@ -50,14 +65,27 @@ pub struct ApuState {
     pub noise: NoiseChannelState,
     pub dmc: DmcState,
 
+    // Expansion port audio, which (unlike cartridge audio) isn't tied to the mapper
+    pub epsm: Option<Epsm>,
+
+    // Interleaved left and right samples, when the stereo mix is enabled
     pub staging_buffer: RingBuffer,
-    pub edge_buffer: RingBuffer,
     pub output_buffer: Vec<i16>,
+    // Always the mono mix, for display
+    pub mix_buffer: RingBuffer,
+    pub edge_buffer: RingBuffer,
     pub buffer_full: bool,
     pub sample_rate: u64,
     pub cpu_clock_rate: u64,
     pub generated_samples: u64,
     pub next_sample_at: u64,
+    // Shells nudge this to keep their audio queues steady; 1.0 is exactly sample_rate.
+    // Samples are scheduled from the last time it changed, so changes take effect smoothly.
+    pub rate_adjustment: f64,
+    schedule_start_cycle: u64,
+    schedule_start_sample: u64,
+    // How far past schedule_start_cycle that sample really falls, in 1/(rate in mHz) cycles
+    schedule_remainder: u64,
 
     // Lookup tables for emulating the mixer
     pub pulse_table: Vec<f32>,
@@ -69,6 +97,20 @@ pub struct ApuState {
     pub filter_type: FilterType,
     pub filter_chain: FilterChain,
     pub filter_hq: bool,
+    // Band-limited steps in place of either filter chain, for when speed matters most
+    pub filter_blip: bool,
+    // The 2A03's mix, and the channel positions it was worked out from
+    apu_mix: f32,
+    last_sequencer_state: u64,
+
+    // Every channel rendered separately, when enabled
+    pub stems: Option<Vec<Stem>>,
+    // Each channel's term of the mix, collected on every cycle for the stems and stereo mix
+    channel_outputs: Vec<f32>,
+
+    pub stereo: Option<StereoMixer>,
+    // Pan and gain by channel, kept whether or not the stereo mix is running
+    pub channel_mix: Vec<ChannelMix>,
 }
 
 fn generate_pulse_table() -> Vec<f32> {
@@ -180,6 +222,36 @@ fn construct_lq_filter_chain(clock_rate: f32, target_sample_rate: f32, filter_ty
     return chain;
 }
 
+fn construct_blip_filter_chain(clock_rate: f32, target_sample_rate: f32, filter_type: FilterType, start_cycle: u64) -> FilterChain {
+    // https://wiki.nesdev.org/w/index.php?title=APU_Mixer
+
+    // The blip synth produces band-limited samples at the target rate directly, so the
+    // hardware's filters are all that's left, and they run at the target rate too.
+    let mut chain = FilterChain::with_blip(BlipSynth::new(clock_rate, target_sample_rate, start_cycle));
+
+    match filter_type {
+        FilterType::Nes => {
+            // A first-order high-pass filter at 90 Hz
+            chain.add(Box::new(filters::HighPassIIR::new(target_sample_rate, 90.0)), target_sample_rate);
+            //  Another first-order high-pass filter at 440 Hz
+            chain.add(Box::new(filters::HighPassIIR::new(target_sample_rate, 440.0)), target_sample_rate);
+            // A first-order low-pass filter at 14 kHz
+            chain.add(Box::new(filters::LowPassIIR::new(target_sample_rate, 14000.0)), target_sample_rate);
+        },
+        FilterType::FamiCom => {
+            // A first-order high-pass filter at 37 Hz
+            chain.add(Box::new(filters::HighPassIIR::new(target_sample_rate, 37.0)), target_sample_rate);
+        }
+    }
+
+    return chain;
+}
+
+// In millihertz, so that small adjustments still register
+fn effective_sample_rate(sample_rate: u64, rate_adjustment: f64) -> u64 {
+    return (sample_rate as f64 * rate_adjustment * 1000.0) as u64;
+}
+
 impl ApuState {
     pub fn new() -> ApuState {
         let default_samplerate = 44100;
@@ -199,47 +271,344 @@ impl ApuState {
             triangle: TriangleChannelState::new("Triangle", "2A03", 1_789_773),
             noise: NoiseChannelState::new("Noise", "2A03"),
             dmc: DmcState::new("DMC", "2A03"),
+            epsm: None,
             staging_buffer: RingBuffer::new(output_buffer_size),
-            edge_buffer: RingBuffer::new(output_buffer_size),
             output_buffer: vec!(0i16; output_buffer_size),
+            mix_buffer: RingBuffer::new(output_buffer_size),
+            edge_buffer: RingBuffer::new(output_buffer_size),
             buffer_full: false,
             sample_rate: default_samplerate,
             cpu_clock_rate: 1_789_773,
             generated_samples: 0,
             next_sample_at: 0,
+            rate_adjustment: 1.0,
+            schedule_start_cycle: 0,
+            schedule_start_sample: 0,
+            schedule_remainder: 0,
             pulse_table: generate_pulse_table(),
             tnd_table: generate_tnd_table(),
 
             filter_type: FilterType::FamiCom,
             filter_chain: construct_hq_filter_chain(1789773.0, 44100.0, FilterType::FamiCom),
             filter_hq: true,
+            filter_blip: false,
+            apu_mix: 0.0,
+            last_sequencer_state: u64::MAX,
+
+            stems: None,
+            channel_outputs: Vec::new(),
+
+            stereo: None,
+            channel_mix: Vec::new(),
         }
     }
 
+    // In sample frames, so the buffers themselves are twice this size in stereo
     pub fn set_buffer_size(&mut self, buffer_size: usize) {
-        self.staging_buffer = RingBuffer::new(buffer_size);
-        self.output_buffer = vec!(0i16; buffer_size);
+        let interleaved_size = buffer_size * self.output_channels();
+        self.staging_buffer = RingBuffer::new(interleaved_size);
+        self.output_buffer = vec!(0i16; interleaved_size);
+        self.mix_buffer = RingBuffer::new(buffer_size);
         self.buffer_full = false;
     }
 
+    pub fn output_channels(&self) -> usize {
+        return if self.stereo.is_some() {2} else {1};
+    }
+
     pub fn set_sample_rate(&mut self, sample_rate: u64) {
-        self.sample_rate = sample_rate;
+        let rate_adjustment = self.rate_adjustment;
+        self.restart_sample_schedule(sample_rate, rate_adjustment);
         self.update_filter();
         let output_buffer_size = recommended_buffer_size(sample_rate);
         self.set_buffer_size(output_buffer_size);
     }
 
+    // Runs the output a little fast or slow, without rebuilding the filters. Meant for small
+    // corrections (a fraction of a percent) to keep up with the host's audio clock.
+    pub fn set_rate_adjustment(&mut self, rate_adjustment: f64) {
+        if rate_adjustment == self.rate_adjustment {
+            return;
+        }
+        let sample_rate = self.sample_rate;
+        self.restart_sample_schedule(sample_rate, rate_adjustment);
+        let current_cycle = self.current_cycle;
+        self.filter_chain.set_rate_adjustment(current_cycle, rate_adjustment);
+        if let Some(ref mut stems) = self.stems {
+            for stem in stems.iter_mut() {
+                stem.filter_chain.set_rate_adjustment(current_cycle, rate_adjustment);
+            }
+        }
+        if let Some(ref mut stereo) = self.stereo {
+            stereo.left_chain.set_rate_adjustment(current_cycle, rate_adjustment);
+            stereo.right_chain.set_rate_adjustment(current_cycle, rate_adjustment);
+        }
+    }
+
+    // The sample already scheduled stays where it is, and the ones after it follow the new rate.
+    // The fraction of a cycle that sample really falls on is carried over too; dropping it would
+    // leave us a little early after every change, and they happen often.
+    fn restart_sample_schedule(&mut self, sample_rate: u64, rate_adjustment: f64) {
+        let old_rate = effective_sample_rate(self.sample_rate, self.rate_adjustment);
+        let new_rate = effective_sample_rate(sample_rate, rate_adjustment);
+        let samples_since_start = self.generated_samples + 1 - self.schedule_start_sample;
+        let elapsed = self.schedule_remainder + samples_since_start * self.cpu_clock_rate * 1000;
+        self.schedule_start_cycle += elapsed / old_rate;
+        self.schedule_remainder = (elapsed % old_rate) * new_rate / old_rate;
+        self.schedule_start_sample = self.generated_samples + 1;
+        self.sample_rate = sample_rate;
+        self.rate_adjustment = rate_adjustment;
+    }
+
+    // The schedule starts over from each sample as it's scheduled, so the count of samples
+    // since the start never grows. (Left to grow, the elapsed time would overflow after a few
+    // days of output, and nothing else restarts the schedule while the rate holds steady.)
+    // Carrying the remainder over lands every sample on the same cycle it always would have.
+    fn schedule_next_sample(&mut self) {
+        let rate = effective_sample_rate(self.sample_rate, self.rate_adjustment);
+        let samples_since_start = self.generated_samples + 1 - self.schedule_start_sample;
+        let elapsed = self.schedule_remainder + samples_since_start * self.cpu_clock_rate * 1000;
+        self.next_sample_at = self.schedule_start_cycle + elapsed / rate;
+        self.schedule_start_cycle = self.next_sample_at;
+        self.schedule_remainder = elapsed % rate;
+        self.schedule_start_sample = self.generated_samples + 1;
+    }
+
     pub fn set_filter(&mut self, filter_type: FilterType, hq: bool) {
         self.filter_type = filter_type;
         self.filter_hq = hq;
         self.update_filter();
     }
 
+    pub fn enable_blip(&mut self) {
+        if !self.filter_blip {
+            self.filter_blip = true;
+            self.update_filter();
+        }
+    }
+
+    pub fn disable_blip(&mut self) {
+        if self.filter_blip {
+            self.filter_blip = false;
+            self.update_filter();
+        }
+    }
+
+    fn new_filter_chain(&self) -> FilterChain {
+        let mut chain = if self.filter_blip {
+            construct_blip_filter_chain(self.cpu_clock_rate as f32, self.sample_rate as f32, self.filter_type, self.current_cycle)
+        } else if self.filter_hq {
+            construct_hq_filter_chain(self.cpu_clock_rate as f32, self.sample_rate as f32, self.filter_type)
+        } else {
+            construct_lq_filter_chain(self.cpu_clock_rate as f32, self.sample_rate as f32, self.filter_type)
+        };
+        chain.set_rate_adjustment(self.current_cycle, self.rate_adjustment);
+        return chain;
+    }
+
     pub fn update_filter(&mut self) {
-        if self.filter_hq {
-            self.filter_chain = construct_hq_filter_chain(self.cpu_clock_rate as f32, self.sample_rate as f32, self.filter_type);
+        self.filter_chain = self.new_filter_chain();
+        let mut stems = self.stems.take();
+        if let Some(ref mut stems) = stems {
+            for stem in stems.iter_mut() {
+                stem.filter_chain = self.new_filter_chain();
+            }
+        }
+        self.stems = stems;
+        let mut stereo = self.stereo.take();
+        if let Some(ref mut stereo) = stereo {
+            stereo.left_chain = self.new_filter_chain();
+            stereo.right_chain = self.new_filter_chain();
+        }
+        self.stereo = stereo;
+    }
+
+    // Stems are created as their channels are first heard from, since some chips
+    // (the N163 in particular) change how many channels they have on the fly
+    pub fn enable_stems(&mut self) {
+        if self.stems.is_none() {
+            self.stems = Some(Vec::new());
+        }
+    }
+
+    pub fn disable_stems(&mut self) {
+        self.stems = None;
+    }
+
+    // Every channel with an output of its own, in the same order as channel_outputs
+    fn rendered_channels<'a>(&'a self, mapper: &'a dyn Mapper) -> Vec<&'a dyn AudioChannelState> {
+        let mut channels: Vec<&'a dyn AudioChannelState> = vec![&self.pulse_1, &self.pulse_2, &self.triangle, &self.noise, &self.dmc];
+        channels.extend(mapper.channels());
+        // The EPSM sits on the expansion port, so its channels come after the cartridge's
+        if let Some(ref epsm) = self.epsm {
+            channels.extend(epsm.channels());
+        }
+        channels.retain(|channel| channel.has_output());
+        return channels;
+    }
+
+    fn channel_identity(&self, mapper: &dyn Mapper, channel_index: usize) -> (String, String) {
+        return match self.rendered_channels(mapper).get(channel_index) {
+            Some(channel) => (channel.chip(), channel.name()),
+            None => (String::from("Unknown"), format!("Channel {}", channel_index + 1))
+        };
+    }
+
+    fn collect_channel_outputs(&mut self, mapper: &dyn Mapper) {
+        // However the mapper scales the 2A03 (NSF mixing levels, fades, etc) applies to each of its channels
+        let apu_gain = mapper.mix_expansion_audio(1.0) - mapper.mix_expansion_audio(0.0);
+        self.channel_outputs.clear();
+        self.channel_outputs.push(self.pulse_table[self.pulse_1.output() as usize] * apu_gain);
+        self.channel_outputs.push(self.pulse_table[self.pulse_2.output() as usize] * apu_gain);
+        self.channel_outputs.push(self.tnd_table[full_tnd_index(self.triangle.output() as usize, 0, 0)] * apu_gain);
+        self.channel_outputs.push(self.tnd_table[full_tnd_index(0, self.noise.output() as usize, 0)] * apu_gain);
+        self.channel_outputs.push(self.tnd_table[full_tnd_index(0, 0, self.dmc.output() as usize)] * apu_gain);
+        mapper.expansion_channel_outputs(&mut self.channel_outputs);
+        if let Some(ref epsm) = self.epsm {
+            epsm.channel_outputs(&mut self.channel_outputs);
+        }
+    }
+
+    fn clock_stems(&mut self, mapper: &dyn Mapper) {
+        let mut stems = match self.stems.take() {
+            Some(stems) => stems,
+            None => return
+        };
+
+        if self.channel_outputs.len() > stems.len() {
+            // Late arrivals start out silent, so that every stem stays the same length
+            let existing_samples = if stems.len() > 0 {stems[0].samples.len()} else {0};
+            for i in stems.len() .. self.channel_outputs.len() {
+                let (chip, name) = self.channel_identity(mapper, i);
+                let mut filter_chain = self.new_filter_chain();
+                filter_chain.match_timing(&self.filter_chain);
+                let mut stem = Stem::new(&chip, &name, filter_chain);
+                stem.samples.resize(existing_samples, 0);
+                stems.push(stem);
+            }
+        }
+
+        let delta_time = 1.0 / (self.cpu_clock_rate as f32);
+        for (i, stem) in stems.iter_mut().enumerate() {
+            // A channel which has gone away (N163 again) is silent
+            let output = if i < self.channel_outputs.len() {self.channel_outputs[i]} else {0.0};
+            if self.filter_blip {
+                stem.consume_at(self.current_cycle, output);
+            } else {
+                stem.consume(output, delta_time);
+            }
+        }
+        self.stems = Some(stems);
+    }
+
+    // The output buffers switch to interleaved stereo, so anything already queued is dropped
+    pub fn enable_stereo(&mut self) {
+        if self.stereo.is_none() {
+            let mut left_chain = self.new_filter_chain();
+            let mut right_chain = self.new_filter_chain();
+            left_chain.match_timing(&self.filter_chain);
+            right_chain.match_timing(&self.filter_chain);
+            self.stereo = Some(StereoMixer::new(left_chain, right_chain));
+            self.set_buffer_size(recommended_buffer_size(self.sample_rate));
+        }
+    }
+
+    pub fn disable_stereo(&mut self) {
+        if self.stereo.is_some() {
+            self.stereo = None;
+            self.set_buffer_size(recommended_buffer_size(self.sample_rate));
+        }
+    }
+
+    fn channel_mix_mut(&mut self, chip: &str, name: &str) -> &mut ChannelMix {
+        let existing = self.channel_mix.iter().position(|mix| mix.chip == chip && mix.name == name);
+        let index = match existing {
+            Some(index) => index,
+            None => {
+                self.channel_mix.push(ChannelMix::new(chip, name));
+                self.channel_mix.len() - 1
+            }
+        };
+        if let Some(ref mut stereo) = self.stereo {
+            stereo.channel_settings.clear();
+        }
+        return &mut self.channel_mix[index];
+    }
+
+    pub fn set_channel_pan(&mut self, chip: &str, name: &str, pan: f32) {
+        self.channel_mix_mut(chip, name).pan = pan.clamp(-1.0, 1.0);
+    }
+
+    pub fn set_channel_gain(&mut self, chip: &str, name: &str, gain: f32) {
+        self.channel_mix_mut(chip, name).gain = gain.max(0.0);
+    }
+
+    fn clock_stereo(&mut self, mapper: &dyn Mapper) {
+        let mut stereo = match self.stereo.take() {
+            Some(stereo) => stereo,
+            None => return
+        };
+
+        if stereo.channel_settings.len() != self.channel_outputs.len() {
+            // The channels changed, or their settings did
+            stereo.channel_settings.clear();
+            for i in 0 .. self.channel_outputs.len() {
+                let (chip, name) = self.channel_identity(mapper, i);
+                let gains = match self.channel_mix.iter().find(|mix| mix.chip == chip && mix.name == name) {
+                    Some(mix) => (mix.left_gain(), mix.right_gain()),
+                    None => (1.0, 1.0)
+                };
+                stereo.channel_settings.push(gains);
+            }
+            self.refresh_stereo_mutes(&mut stereo, mapper);
+        }
+
+        if self.filter_blip {
+            stereo.consume_at(self.current_cycle, &self.channel_outputs);
         } else {
-            self.filter_chain = construct_lq_filter_chain(self.cpu_clock_rate as f32, self.sample_rate as f32, self.filter_type);
+            stereo.consume(&self.channel_outputs, 1.0 / (self.cpu_clock_rate as f32));
+        }
+        self.stereo = Some(stereo);
+    }
+
+    fn refresh_stereo_mutes(&self, stereo: &mut StereoMixer, mapper: &dyn Mapper) {
+        let muted: Vec<bool> = self.rendered_channels(mapper).iter().map(|channel| channel.muted()).collect();
+        stereo.apply_mutes(&muted);
+    }
+
+    pub fn enable_epsm(&mut self) {
+        if self.epsm.is_none() {
+            self.epsm = Some(Epsm::new(self.cpu_clock_rate));
+        }
+    }
+
+    pub fn disable_epsm(&mut self) {
+        self.epsm = None;
+    }
+
+    // Carries the host's side of the audio over to a freshly built APU: the sample rate,
+    // filtering, optional outputs, mix, expansion port hardware, and which channels are muted
+    pub fn keep_host_settings(&mut self, previous: &ApuState) {
+        self.set_sample_rate(previous.sample_rate);
+        self.set_filter(previous.filter_type, previous.filter_hq);
+        if previous.filter_blip {
+            self.enable_blip();
+        }
+        if previous.epsm.is_some() {
+            self.enable_epsm();
+        }
+        if previous.stems.is_some() {
+            self.enable_stems();
+        }
+        self.channel_mix = previous.channel_mix.clone();
+        if previous.stereo.is_some() {
+            self.enable_stereo();
+        }
+        let muted_channels: Vec<bool> = previous.channels().iter().map(|channel| channel.muted()).collect();
+        for (channel, muted) in self.channels_mut().into_iter().zip(muted_channels) {
+            if muted {
+                channel.mute();
+            }
         }
     }
 
@@ -250,6 +619,9 @@ impl ApuState {
         channels.push(&self.triangle);
         channels.push(&self.pulse_1);
         channels.push(&self.pulse_2);
+        if let Some(ref epsm) = self.epsm {
+            channels.extend(epsm.channels());
+        }
         return channels;
     }
 
@@ -260,6 +632,9 @@ impl ApuState {
         channels.push(&mut self.triangle);
         channels.push(&mut self.pulse_1);
         channels.push(&mut self.pulse_2);
+        if let Some(ref mut epsm) = self.epsm {
+            channels.extend(epsm.channels_mut());
+        }
         return channels;
     }
 
@@ -308,6 +683,8 @@ impl ApuState {
     }
 
     pub fn write_register(&mut self, address: u16, data: u8) {
+        // Most writes change the mix somehow
+        self.last_sequencer_state = u64::MAX;
         let duty_table = [
             0b1000_0000,
             0b1100_0000,
@@ -315,6 +692,12 @@ impl ApuState {
             0b0011_1111,
         ];
         match address {
+            // EPSM, on the expansion port
+            0x401C ..= 0x401F => {
+                if let Some(ref mut epsm) = self.epsm {
+                    epsm.write(address, data);
+                }
+            },
             // Pulse Channel 1
             0x4000 => {
                 let duty_index =      (data & 0b1100_0000) >> 6;
@@ -574,6 +957,7 @@ impl ApuState {
     }
 
     pub fn clock_quarter_frame(&mut self) {
+        self.last_sequencer_state = u64::MAX;
         self.pulse_1.envelope.clock();
         self.pulse_2.envelope.clock();
         self.triangle.update_linear_counter();
@@ -582,6 +966,7 @@ impl ApuState {
     }
 
     pub fn clock_half_frame(&mut self) {
+        self.last_sequencer_state = u64::MAX;
         self.pulse_1.update_sweep();
         self.pulse_2.update_sweep();
 
@@ -592,6 +977,60 @@ impl ApuState {
         self.half_frame_counter += 1;
     }
 
+    // Where each 2A03 channel is in its waveform, packed together so that a step is easy to spot.
+    // Everything else the mix depends on only changes on a register write or a frame sequencer
+    // clock, and those forget the last state, so the mix is redone on the next cycle.
+    fn sequencer_state(&self) -> u64 {
+        let disabled =
+            (self.pulse_1.debug_disable as u64) |
+            ((self.pulse_2.debug_disable as u64) << 1) |
+            ((self.triangle.debug_disable as u64) << 2) |
+            ((self.noise.debug_disable as u64) << 3) |
+            ((self.dmc.debug_disable as u64) << 4);
+        return
+            (self.pulse_1.sequence_counter as u64) |
+            ((self.pulse_2.sequence_counter as u64) << 8) |
+            ((self.triangle.sequence_counter as u64) << 16) |
+            (((self.noise.shift_register & 0b1) as u64) << 24) |
+            ((self.dmc.output_level as u64) << 32) |
+            (disabled << 40);
+    }
+
+    fn mix_2a03(&self) -> f32 {
+        // Mix samples, using the LUT we generated earlier, based on documentation here:
+        // https://wiki.nesdev.com/w/index.php/APU_Mixer
+        let mut combined_pulse = 0;
+        if !(self.pulse_1.debug_disable) {
+            combined_pulse += self.pulse_1.output();
+        }
+        if !(self.pulse_2.debug_disable) {
+            combined_pulse += self.pulse_2.output();
+        }
+        let pulse_output = self.pulse_table[combined_pulse as usize];
+
+        let tri_output = if self.triangle.debug_disable {0} else {self.triangle.output()};
+        let noise_output = if self.noise.debug_disable {0} else {self.noise.output()};
+        let dmc_output = if self.dmc.debug_disable {0} else {self.dmc.output()};
+        let tnd_output = self.tnd_table[full_tnd_index(tri_output as usize, noise_output as usize, dmc_output as usize)];
+
+        return (pulse_output - 0.5) + (tnd_output - 0.5);
+    }
+
+    // Blip chains are only fed when their input changes, so before their output is read
+    // they need to finish any samples that came due in the meantime
+    fn catch_up_filters(&mut self, cycle: u64) {
+        self.filter_chain.catch_up(cycle);
+        if let Some(ref mut stems) = self.stems {
+            for stem in stems.iter_mut() {
+                stem.filter_chain.catch_up(cycle);
+            }
+        }
+        if let Some(ref mut stereo) = self.stereo {
+            stereo.left_chain.catch_up(cycle);
+            stereo.right_chain.catch_up(cycle);
+        }
+    }
+
     pub fn clock_apu(&mut self, mapper: &mut dyn Mapper) {
         self.clock_frame_sequencer();
 
@@ -607,41 +1046,71 @@ impl ApuState {
             self.dmc.clock(mapper);
         }
         
-        // Collect current samples from the various channels
-        let pulse_1_sample = self.pulse_1.output();
-        let pulse_2_sample = self.pulse_2.output();
-        let triangle_sample = self.triangle.output();
-        let noise_sample = self.noise.output();
-        let dmc_sample = self.dmc.output();
-
-        // Mix samples, using the LUT we generated earlier, based on documentation here:
-        // https://wiki.nesdev.com/w/index.php/APU_Mixer
-        let mut combined_pulse = 0;
-        if !(self.pulse_1.debug_disable) {
-            combined_pulse += pulse_1_sample;
+        // Blip chains only need to hear about changes, and the 2A03's channels step a few times
+        // per output sample at most, so its mix is only redone when one of them has.
+        if self.filter_blip {
+            let sequencer_state = self.sequencer_state();
+            if sequencer_state != self.last_sequencer_state {
+                self.last_sequencer_state = sequencer_state;
+                self.apu_mix = self.mix_2a03();
+            }
+        } else {
+            self.last_sequencer_state = u64::MAX;
+            self.apu_mix = self.mix_2a03();
         }
-        if !(self.pulse_2.debug_disable) {
-            combined_pulse += pulse_2_sample;
+        let current_2a03_sample = self.apu_mix;
+        // Only the mapper knows when its own chips change, so it's asked on every cycle
+        let mut current_dac_sample = mapper.mix_expansion_audio(current_2a03_sample) as f32;
+        if let Some(ref mut epsm) = self.epsm {
+            epsm.clock();
+            current_dac_sample += epsm.output();
         }
-        let pulse_output = self.pulse_table[combined_pulse as usize];
-        
-        let tri_output = if self.triangle.debug_disable {0} else {triangle_sample};
-        let noise_output = if self.noise.debug_disable {0} else {noise_sample};
-        let dmc_output = if self.dmc.debug_disable {0} else {dmc_sample};
-        let tnd_output = self.tnd_table[full_tnd_index(tri_output as usize, noise_output as usize, dmc_output as usize)];
-
-        let current_2a03_sample = (pulse_output - 0.5) + (tnd_output - 0.5);
-        let current_dac_sample = mapper.mix_expansion_audio(current_2a03_sample) as f32;
 
         // apply filters NEW
-        self.filter_chain.consume(current_dac_sample, 1.0 / (self.cpu_clock_rate as f32));
+        // (stems first, so that new ones start in step with the main chain)
+        if self.stems.is_some() || self.stereo.is_some() {
+            self.collect_channel_outputs(mapper);
+        }
+        self.clock_stems(mapper);
+        self.clock_stereo(mapper);
+        if self.filter_blip {
+            // Returns straight away unless the level has changed
+            self.filter_chain.consume_at(self.current_cycle, current_dac_sample);
+        } else {
+            self.filter_chain.consume(current_dac_sample, 1.0 / (self.cpu_clock_rate as f32));
+        }
 
         if self.current_cycle >= self.next_sample_at { 
+            if self.filter_blip {
+                // Everything up to the end of this cycle
+                let next_cycle = self.current_cycle + 1;
+                self.catch_up_filters(next_cycle);
+            }
             // decimate sample
             let composite_sample = (self.filter_chain.output() * 32767.0) as i16;
 
-            self.staging_buffer.push(composite_sample);
+            match self.stereo {
+                Some(ref stereo) => {
+                    self.staging_buffer.push(stereo.left_sample());
+                    self.staging_buffer.push(stereo.right_sample());
+                },
+                None => {
+                    self.staging_buffer.push(composite_sample);
+                }
+            }
+            self.mix_buffer.push(composite_sample);
             self.edge_buffer.push(true as i16);
+            // Mutes are picked up once per sample, rather than on every cycle
+            let mut stereo = self.stereo.take();
+            if let Some(ref mut stereo) = stereo {
+                self.refresh_stereo_mutes(stereo, mapper);
+            }
+            self.stereo = stereo;
+            if let Some(ref mut stems) = self.stems {
+                for stem in stems.iter_mut() {
+                    stem.record_sample();
+                }
+            }
 
             // Write debug buffers from these, regardless of enable / disable status
             self.pulse_1.record_current_output();
@@ -650,9 +1119,12 @@ impl ApuState {
             self.noise.record_current_output();
             self.dmc.record_current_output();
             mapper.record_expansion_audio_output(current_2a03_sample);
+            if let Some(ref mut epsm) = self.epsm {
+                epsm.record_output();
+            }
 
             self.generated_samples += 1;
-            self.next_sample_at = ((self.generated_samples + 1) * self.cpu_clock_rate) / self.sample_rate;
+            self.schedule_next_sample();
 
             if self.staging_buffer.index() == 0 {
                 self.output_buffer.copy_from_slice(self.staging_buffer.buffer());
@@ -742,7 +1214,7 @@ impl AudioChannelState for ApuState {
     }
 
     fn sample_buffer(&self) -> &RingBuffer {
-        return &self.staging_buffer;
+        return &self.mix_buffer;
     }
 
     fn record_current_output(&mut self) {
@@ -767,3 +1239,49 @@ impl AudioChannelState for ApuState {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starting_the_schedule_over_keeps_every_sample_on_time() {
+        let mut apu = ApuState::new();
+        apu.set_sample_rate(48000);
+        apu.set_rate_adjustment(1.0021);
+        let rate = effective_sample_rate(48000, 1.0021) as u128;
+        let start_cycle = apu.schedule_start_cycle as u128;
+        let start_remainder = apu.schedule_remainder as u128;
+        let start_sample = apu.schedule_start_sample;
+        for _ in 0 .. 1_000_000 {
+            apu.generated_samples += 1;
+            apu.schedule_next_sample();
+            // Where the sample would fall counting from the start, with room for any count
+            let samples_since_start = (apu.generated_samples + 1 - start_sample) as u128;
+            let elapsed = start_remainder + samples_since_start * apu.cpu_clock_rate as u128 * 1000;
+            assert_eq!(apu.next_sample_at as u128, start_cycle + elapsed / rate);
+        }
+    }
+
+    #[test]
+    fn a_rebuilt_apu_keeps_the_host_settings() {
+        let mut previous = ApuState::new();
+        previous.set_sample_rate(48000);
+        previous.enable_blip();
+        previous.enable_epsm();
+        previous.enable_stems();
+        previous.enable_stereo();
+        previous.pulse_2.mute();
+        let mut apu = ApuState::new();
+        apu.keep_host_settings(&previous);
+        assert_eq!(apu.sample_rate, 48000);
+        assert!(apu.filter_blip);
+        assert!(apu.epsm.is_some());
+        assert!(apu.stems.is_some());
+        assert!(apu.stereo.is_some());
+        let muted: Vec<bool> = apu.channels().iter().map(|channel| channel.muted()).collect();
+        let previous_muted: Vec<bool> = previous.channels().iter().map(|channel| channel.muted()).collect();
+        assert_eq!(muted, previous_muted);
+        assert!(apu.pulse_2.muted());
+    }
+}
@@ -0,0 +1,37 @@
+// A stem is one audio channel rendered on its own, for remixing outside the emulator.
+// Each gets its own copy of the filter chain, fed with that channel's term of the mix
+// on every CPU cycle (or, with blip chains, whenever it changes), so it's resampled and
+// filtered exactly like the combined output.
+
+use super::FilterChain;
+
+pub struct Stem {
+    pub chip: String,
+    pub name: String,
+    pub filter_chain: FilterChain,
+    // Samples produced since they were last collected
+    pub samples: Vec<i16>,
+}
+
+impl Stem {
+    pub fn new(chip: &str, name: &str, filter_chain: FilterChain) -> Stem {
+        return Stem {
+            chip: chip.to_string(),
+            name: name.to_string(),
+            filter_chain: filter_chain,
+            samples: Vec::new(),
+        };
+    }
+
+    pub fn consume(&mut self, sample: f32, delta_time: f32) {
+        self.filter_chain.consume(sample, delta_time);
+    }
+
+    pub fn consume_at(&mut self, clock: u64, sample: f32) {
+        self.filter_chain.consume_at(clock, sample);
+    }
+
+    pub fn record_sample(&mut self) {
+        self.samples.push((self.filter_chain.output() * 32767.0) as i16);
+    }
+}
@@ -0,0 +1,97 @@
+// An optional stereo stage, for listening on headphones. Each channel's term of the mix is
+// given a gain and a place between the speakers, and the two sides go through their own
+// copies of the filter chain.
+
+// Each channel is looked up in the mixer tables on its own, the same as the stems, which
+// loses the 2A03's slight compression between channels. Otherwise a stereo mix with every
+// channel centered matches the mono one: the pan law is a balance control, which leaves the
+// near side at full volume and only turns down the far one.
+
+use super::FilterChain;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMix {
+    pub chip: String,
+    pub name: String,
+    // -1.0 is hard left, 1.0 is hard right
+    pub pan: f32,
+    pub gain: f32,
+}
+
+impl ChannelMix {
+    pub fn new(chip: &str, name: &str) -> ChannelMix {
+        return ChannelMix {
+            chip: chip.to_string(),
+            name: name.to_string(),
+            pan: 0.0,
+            gain: 1.0,
+        };
+    }
+
+    pub fn left_gain(&self) -> f32 {
+        return self.gain * (1.0 - self.pan).min(1.0);
+    }
+
+    pub fn right_gain(&self) -> f32 {
+        return self.gain * (1.0 + self.pan).min(1.0);
+    }
+}
+
+pub struct StereoMixer {
+    pub left_chain: FilterChain,
+    pub right_chain: FilterChain,
+    // (left, right) for each channel, in the order their outputs are collected. Emptied
+    // whenever the settings change, so that it gets rebuilt.
+    pub channel_settings: Vec<(f32, f32)>,
+    // The same, with muted channels silenced
+    channel_gains: Vec<(f32, f32)>,
+}
+
+impl StereoMixer {
+    pub fn new(left_chain: FilterChain, right_chain: FilterChain) -> StereoMixer {
+        return StereoMixer {
+            left_chain: left_chain,
+            right_chain: right_chain,
+            channel_settings: Vec::new(),
+            channel_gains: Vec::new(),
+        };
+    }
+
+    pub fn apply_mutes(&mut self, muted: &[bool]) {
+        self.channel_gains.clear();
+        for (i, gains) in self.channel_settings.iter().enumerate() {
+            let channel_muted = i < muted.len() && muted[i];
+            self.channel_gains.push(if channel_muted {(0.0, 0.0)} else {*gains});
+        }
+    }
+
+    fn mix(&self, channel_outputs: &[f32]) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (output, gains) in channel_outputs.iter().zip(self.channel_gains.iter()) {
+            left += output * gains.0;
+            right += output * gains.1;
+        }
+        return (left, right);
+    }
+
+    pub fn consume(&mut self, channel_outputs: &[f32], delta_time: f32) {
+        let (left, right) = self.mix(channel_outputs);
+        self.left_chain.consume(left, delta_time);
+        self.right_chain.consume(right, delta_time);
+    }
+
+    pub fn consume_at(&mut self, clock: u64, channel_outputs: &[f32]) {
+        let (left, right) = self.mix(channel_outputs);
+        self.left_chain.consume_at(clock, left);
+        self.right_chain.consume_at(clock, right);
+    }
+
+    pub fn left_sample(&self) -> i16 {
+        return (self.left_chain.output() * 32767.0) as i16;
+    }
+
+    pub fn right_sample(&self) -> i16 {
+        return (self.right_chain.output() * 32767.0) as i16;
+    }
+}
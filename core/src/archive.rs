@@ -0,0 +1,520 @@
+// Reads cartridges out of .zip and .gz archives. Only what ROM sets actually use is
+// supported: stored and DEFLATE compressed entries, without encryption or ZIP64.
+// DEFLATE: https://www.rfc-editor.org/rfc/rfc1951
+// gzip: https://www.rfc-editor.org/rfc/rfc1952
+// ZIP: https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+
+use std::error::Error;
+use std::fmt;
+
+use hash::crc32;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    NotAnArchive,
+    NoRomFound,
+    EntryNotFound{name: String},
+    UnsupportedCompression{method: u16},
+    Corrupt{reason: String},
+}
+
+impl Error for ArchiveError {}
+
+impl fmt::Display for ArchiveError  {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::NotAnArchive => {write!(f, "Not a zip or gzip archive")},
+            ArchiveError::NoRomFound => {write!(f, "Archive does not contain a recognized ROM")},
+            ArchiveError::EntryNotFound{name} => {write!(f, "Archive has no entry named {}", name)},
+            ArchiveError::UnsupportedCompression{method} => {write!(f, "Unsupported compression method: {}", method)},
+            ArchiveError::Corrupt{reason} => {write!(f, "Corrupt archive: {}", reason)},
+        }
+    }
+}
+
+fn corrupt(reason: &str) -> ArchiveError {
+    return ArchiveError::Corrupt{reason: reason.to_string()};
+}
+
+// Comfortably above the largest cartridges and disk sets out there. Anything bigger is
+// corrupt or malicious, and inflating it could run us out of memory.
+pub const MAX_UNCOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+pub const ROM_EXTENSIONS: [&str; 6] = ["nes", "nsf", "nsfe", "fds", "unf", "unif"];
+
+pub fn is_rom_name(name: &str) -> bool {
+    let lowercase_name = name.to_lowercase();
+    for extension in ROM_EXTENSIONS.iter() {
+        if lowercase_name.ends_with(&format!(".{}", extension)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+pub fn is_zip(data: &[u8]) -> bool {
+    return data.starts_with(&[0x50, 0x4B, 0x03, 0x04]);
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    return data.starts_with(&[0x1F, 0x8B]);
+}
+
+pub fn is_archive(data: &[u8]) -> bool {
+    return is_zip(data) || is_gzip(data);
+}
+
+// ===== DEFLATE =====
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        return BitReader {
+            data: data,
+            position: 0,
+            bit_buffer: 0,
+            bit_count: 0,
+        };
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, ArchiveError> {
+        while self.bit_count < count {
+            if self.position >= self.data.len() {
+                return Err(corrupt("compressed data ended early"));
+            }
+            self.bit_buffer |= (self.data[self.position] as u32) << self.bit_count;
+            self.position += 1;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1u32 << count) - 1);
+        self.bit_buffer = if count == 32 {0} else {self.bit_buffer >> count};
+        self.bit_count -= count;
+        return Ok(value);
+    }
+
+    fn align_to_byte(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+}
+
+// Canonical Huffman table, stored as the number of codes of each length plus
+// the symbols in code order. Decoding walks one bit at a time; slow, but ROMs are small.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for i in 1 .. 16 {
+            offsets[i] = offsets[i - 1] + counts[i - 1];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        return Huffman {
+            counts: counts,
+            symbols: symbols,
+        };
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, ArchiveError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1 .. 16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        return Err(corrupt("invalid Huffman code"));
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for i in 0 .. 144 { lengths[i] = 8; }
+    for i in 144 .. 256 { lengths[i] = 9; }
+    for i in 256 .. 280 { lengths[i] = 7; }
+    for i in 280 .. 288 { lengths[i] = 8; }
+    return (Huffman::new(&lengths), Huffman::new(&[5u8; 30]));
+}
+
+fn dynamic_tables(reader: &mut BitReader) -> Result<(Huffman, Huffman), ArchiveError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for i in 0 .. code_length_count {
+        code_lengths[CODE_LENGTH_ORDER[i]] = reader.bits(3)? as u8;
+    }
+    let code_length_table = Huffman::new(&code_lengths);
+
+    let mut lengths: Vec<u8> = Vec::new();
+    while lengths.len() < literal_count + distance_count {
+        let symbol = code_length_table.decode(reader)?;
+        match symbol {
+            0 ..= 15 => lengths.push(symbol as u8),
+            16 => {
+                let previous = match lengths.last() {
+                    Some(length) => *length,
+                    None => {return Err(corrupt("repeat with no previous length"));}
+                };
+                for _ in 0 .. reader.bits(2)? + 3 {
+                    lengths.push(previous);
+                }
+            },
+            17 => {
+                for _ in 0 .. reader.bits(3)? + 3 {
+                    lengths.push(0);
+                }
+            },
+            _ => {
+                for _ in 0 .. reader.bits(7)? + 11 {
+                    lengths.push(0);
+                }
+            }
+        }
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(corrupt("too many code lengths"));
+    }
+    return Ok((Huffman::new(&lengths[.. literal_count]), Huffman::new(&lengths[literal_count ..])));
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), ArchiveError> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            if output.len() >= MAX_UNCOMPRESSED_SIZE {
+                return Err(corrupt("uncompressed data is too large"));
+            }
+            output.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let length_index = symbol - 257;
+            if length_index >= LENGTH_BASE.len() {
+                return Err(corrupt("invalid length symbol"));
+            }
+            let length = LENGTH_BASE[length_index] as usize + reader.bits(LENGTH_EXTRA[length_index] as u32)? as usize;
+            let distance_index = distances.decode(reader)? as usize;
+            if distance_index >= DISTANCE_BASE.len() {
+                return Err(corrupt("invalid distance symbol"));
+            }
+            let distance = DISTANCE_BASE[distance_index] as usize + reader.bits(DISTANCE_EXTRA[distance_index] as u32)? as usize;
+            if distance > output.len() {
+                return Err(corrupt("distance too far back"));
+            }
+            if output.len() + length > MAX_UNCOMPRESSED_SIZE {
+                return Err(corrupt("uncompressed data is too large"));
+            }
+            // Byte at a time, since the copy may overlap what it's producing
+            let start = output.len() - distance;
+            for i in 0 .. length {
+                let byte = output[start + i];
+                output.push(byte);
+            }
+        }
+    }
+}
+
+pub fn inflate(compressed: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let mut reader = BitReader::new(compressed);
+    let mut output: Vec<u8> = Vec::new();
+    loop {
+        let last_block = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                // Stored block
+                reader.align_to_byte();
+                if reader.position + 4 > compressed.len() {
+                    return Err(corrupt("stored block header ended early"));
+                }
+                let length = (compressed[reader.position] as usize) | ((compressed[reader.position + 1] as usize) << 8);
+                reader.position += 4;
+                if reader.position + length > compressed.len() {
+                    return Err(corrupt("stored block ended early"));
+                }
+                if output.len() + length > MAX_UNCOMPRESSED_SIZE {
+                    return Err(corrupt("uncompressed data is too large"));
+                }
+                output.extend_from_slice(&compressed[reader.position .. reader.position + length]);
+                reader.position += length;
+            },
+            1 => {
+                let (literals, distances) = fixed_tables();
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            },
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            },
+            _ => {return Err(corrupt("invalid block type"));}
+        }
+        if last_block {
+            return Ok(output);
+        }
+    }
+}
+
+// ===== Containers =====
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ArchiveError> {
+    if offset + 2 > data.len() {
+        return Err(corrupt("header ended early"));
+    }
+    return Ok((data[offset] as u16) | ((data[offset + 1] as u16) << 8));
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ArchiveError> {
+    if offset + 4 > data.len() {
+        return Err(corrupt("header ended early"));
+    }
+    return Ok(
+        (data[offset] as u32) |
+        ((data[offset + 1] as u32) << 8) |
+        ((data[offset + 2] as u32) << 16) |
+        ((data[offset + 3] as u32) << 24));
+}
+
+pub struct ArchiveEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    local_header_offset: usize,
+}
+
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, ArchiveError> {
+    // The central directory is the reliable list of entries; local headers may
+    // leave the sizes blank when the archive was written as a stream.
+    let mut end_of_directory = None;
+    let search_start = if data.len() > 0x10000 + 22 {data.len() - 0x10000 - 22} else {0};
+    for offset in (search_start .. data.len().saturating_sub(21)).rev() {
+        if read_u32(data, offset)? == 0x0605_4B50 {
+            end_of_directory = Some(offset);
+            break;
+        }
+    }
+    let end_of_directory = match end_of_directory {
+        Some(offset) => offset,
+        None => {return Err(corrupt("missing central directory"));}
+    };
+    let entry_count = read_u16(data, end_of_directory + 10)? as usize;
+    let mut offset = read_u32(data, end_of_directory + 16)? as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0 .. entry_count {
+        if read_u32(data, offset)? != 0x0201_4B50 {
+            return Err(corrupt("bad central directory entry"));
+        }
+        let name_length = read_u16(data, offset + 28)? as usize;
+        let extra_length = read_u16(data, offset + 30)? as usize;
+        let comment_length = read_u16(data, offset + 32)? as usize;
+        if offset + 46 + name_length > data.len() {
+            return Err(corrupt("entry name ended early"));
+        }
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(&data[offset + 46 .. offset + 46 + name_length]).into_owned(),
+            method: read_u16(data, offset + 10)?,
+            crc: read_u32(data, offset + 16)?,
+            compressed_size: read_u32(data, offset + 20)? as usize,
+            local_header_offset: read_u32(data, offset + 42)? as usize,
+        });
+        offset += 46 + name_length + extra_length + comment_length;
+    }
+    return Ok(entries);
+}
+
+fn extract_zip_entry(data: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, ArchiveError> {
+    let header = entry.local_header_offset;
+    if read_u32(data, header)? != 0x0403_4B50 {
+        return Err(corrupt("bad local file header"));
+    }
+    let name_length = read_u16(data, header + 26)? as usize;
+    let extra_length = read_u16(data, header + 28)? as usize;
+    let start = header + 30 + name_length + extra_length;
+    if start + entry.compressed_size > data.len() {
+        return Err(corrupt("entry data ended early"));
+    }
+    let compressed = &data[start .. start + entry.compressed_size];
+    let contents = match entry.method {
+        0 => compressed.to_vec(),
+        8 => inflate(compressed)?,
+        method => {return Err(ArchiveError::UnsupportedCompression{method: method});}
+    };
+    if crc32(&contents) != entry.crc {
+        return Err(corrupt(&format!("checksum mismatch in {}", entry.name)));
+    }
+    return Ok(contents);
+}
+
+pub fn zip_entry_names(data: &[u8]) -> Result<Vec<String>, ArchiveError> {
+    return Ok(zip_entries(data)?.into_iter().map(|entry| entry.name).collect());
+}
+
+fn extract_rom_from_zip(data: &[u8], entry_name: Option<&str>) -> Result<ArchiveEntry, ArchiveError> {
+    let entries = zip_entries(data)?;
+    for entry in entries.iter() {
+        let wanted = match entry_name {
+            Some(name) => entry.name == name,
+            None => is_rom_name(&entry.name)
+        };
+        if wanted {
+            return Ok(ArchiveEntry {
+                name: entry.name.clone(),
+                data: extract_zip_entry(data, entry)?,
+            });
+        }
+    }
+    return match entry_name {
+        Some(name) => Err(ArchiveError::EntryNotFound{name: name.to_string()}),
+        None => Err(ArchiveError::NoRomFound)
+    };
+}
+
+fn extract_rom_from_gzip(data: &[u8], entry_name: Option<&str>) -> Result<ArchiveEntry, ArchiveError> {
+    if data.len() < 18 || data[2] != 8 {
+        return Err(ArchiveError::UnsupportedCompression{method: data.get(2).cloned().unwrap_or(0) as u16});
+    }
+    let flags = data[3];
+    let mut offset = 10;
+    if flags & 0x04 != 0 {
+        // FEXTRA
+        offset += 2 + read_u16(data, offset)? as usize;
+        if offset > data.len() {
+            return Err(corrupt("extra field ended early"));
+        }
+    }
+    let mut name = String::new();
+    if flags & 0x08 != 0 {
+        // FNAME, the original file name
+        let name_end = match data[offset ..].iter().position(|&b| b == 0) {
+            Some(length) => offset + length,
+            None => {return Err(corrupt("file name ended early"));}
+        };
+        name = String::from_utf8_lossy(&data[offset .. name_end]).into_owned();
+        offset = name_end + 1;
+    }
+    if flags & 0x10 != 0 {
+        // FCOMMENT
+        match data[offset ..].iter().position(|&b| b == 0) {
+            Some(length) => {offset += length + 1},
+            None => {return Err(corrupt("comment ended early"));}
+        }
+    }
+    if flags & 0x02 != 0 {
+        // FHCRC
+        offset += 2;
+        if offset > data.len() {
+            return Err(corrupt("header checksum ended early"));
+        }
+    }
+    if offset + 8 > data.len() {
+        return Err(corrupt("header ended early"));
+    }
+
+    // gzip holds just the one file; if it has a name, it still needs to match
+    match entry_name {
+        Some(wanted_name) => {
+            if name != wanted_name {
+                return Err(ArchiveError::EntryNotFound{name: wanted_name.to_string()});
+            }
+        },
+        None => {
+            if name.len() > 0 && !is_rom_name(&name) {
+                return Err(ArchiveError::NoRomFound);
+            }
+        }
+    }
+
+    let contents = inflate(&data[offset .. data.len() - 8])?;
+    let expected_crc = read_u32(data, data.len() - 8)?;
+    if crc32(&contents) != expected_crc {
+        return Err(corrupt("checksum mismatch"));
+    }
+    return Ok(ArchiveEntry {
+        name: name,
+        data: contents,
+    });
+}
+
+// Finds a ROM inside an archive: the named entry if one is given, otherwise the
+// first entry with a cartridge file extension
+pub fn extract_rom(data: &[u8], entry_name: Option<&str>) -> Result<ArchiveEntry, ArchiveError> {
+    if is_zip(data) {
+        return extract_rom_from_zip(data, entry_name);
+    }
+    if is_gzip(data) {
+        return extract_rom_from_gzip(data, entry_name);
+    }
+    return Err(ArchiveError::NotAnArchive);
+}
+
+// Shells refer to a file inside an archive as "path/to/archive.zip#entry.nes"
+pub fn split_entry_path(path: &str) -> (String, Option<String>) {
+    match path.rfind('#') {
+        Some(index) => {
+            let archive_path = &path[.. index];
+            let lowercase_path = archive_path.to_lowercase();
+            if lowercase_path.ends_with(".zip") || lowercase_path.ends_with(".gz") {
+                return (archive_path.to_string(), Some(path[index + 1 ..].to_string()));
+            }
+            return (path.to_string(), None);
+        },
+        None => return (path.to_string(), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_an_extra_field_past_the_end() {
+        // A name follows the extra field, and it would be read from past the end
+        let mut data = vec![0x1F, 0x8B, 8, 0x04 | 0x08, 0, 0, 0, 0, 0, 0xFF];
+        data.extend(&[0xFF, 0xFF]);
+        data.extend(&[0u8; 8]);
+        match extract_rom(&data, None) {
+            Err(ArchiveError::Corrupt{..}) => {},
+            _ => panic!("expected a corrupt archive")
+        }
+    }
+}
@@ -177,6 +177,8 @@ pub fn opcode_bytes(opcode: Opcode) -> Result<Vec<u8>, String> {
         Opcode::Jmp(AddressingMode::Indirect(address)) =>      {Ok(vec![0x6C, low(address), high(address)])},
         Opcode::Jsr(AddressingMode::Absolute(address)) =>      {Ok(vec![0x20, low(address), high(address)])},
 
+        Opcode::Nop => {Ok(vec![0xEA])},
+
         Opcode::Pha => {Ok(vec![0x48])},
         Opcode::Php => {Ok(vec![0x08])},
         Opcode::Pla => {Ok(vec![0x68])},
@@ -1,91 +1,150 @@
 use mmc::mapper::*;
-use mmc::action53::Action53;
-use mmc::axrom::AxRom;
-use mmc::bnrom::BnRom;
-use mmc::cnrom::CnRom;
-use mmc::fme7::Fme7;
 use mmc::fds::FdsMapper;
-use mmc::gxrom::GxRom;
-use mmc::ines31::INes31;
-use mmc::mmc1::Mmc1;
-use mmc::mmc3::Mmc3;
-use mmc::mmc5::Mmc5;
-use mmc::n163::Namco163;
-use mmc::nrom::Nrom;
 use mmc::nsf::NsfMapper;
-use mmc::pxrom::PxRom;
-use mmc::rainbow::Rainbow;
-use mmc::uxrom::UxRom;
-use mmc::vrc6::Vrc6;
-use mmc::vrc7::Vrc7;
+use mmc::registry::MapperRegistry;
 
 use ines::INesCartridge;
+use ines::TRAINER_ADDRESS;
+use ines::TRAINER_SIZE;
+use ines::ConsoleType;
+use ines::EXTENDED_CONSOLE_EPSM;
+use ines::console_type_name;
+use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 use nsf::NsfFile;
 use fds::FdsFile;
+use unif::UnifFile;
+use game_db;
+
+use load_error::LoadError;
+use load_error::truncated;
 
 use std::io::Read;
 
-fn mapper_from_ines(ines: INesCartridge) -> Result<Box<dyn Mapper>, String> {
-    let mapper_number = ines.header.mapper_number();
-
-    let mapper: Box<dyn Mapper> = match mapper_number {
-        0 => Box::new(Nrom::from_ines(ines)?),
-        1 => Box::new(Mmc1::from_ines(ines)?),
-        2 => Box::new(UxRom::from_ines(ines)?),
-        3 => Box::new(CnRom::from_ines(ines)?),
-        4 => Box::new(Mmc3::from_ines(ines)?),
-        5 => Box::new(Mmc5::from_ines(ines)?),
-        7 => Box::new(AxRom::from_ines(ines)?),
-        9 => Box::new(PxRom::from_ines(ines)?),
-        19 => Box::new(Namco163::from_ines(ines)?),
-        24 => Box::new(Vrc6::from_ines(ines)?),
-        26 => Box::new(Vrc6::from_ines(ines)?),
-        28 => Box::new(Action53::from_ines(ines)?),
-        31 => Box::new(INes31::from_ines(ines)?),
-        34 => Box::new(BnRom::from_ines(ines)?),
-        66 => Box::new(GxRom::from_ines(ines)?),
-        69 => Box::new(Fme7::from_ines(ines)?),
-        85 => Box::new(Vrc7::from_ines(ines)?),
-        682 => Box::new(Rainbow::from_ines(ines)?),
-        _ => {
-            return Err(format!("Unsupported iNES mapper: {}", ines.header.mapper_number()));
-        }
-    };
+use ines::INesError;
+use nsf::NsfError;
+use fds::FdsError;
+use unif::UnifError;
 
-    println!("Successfully loaded mapper: {}", mapper_number);
 
-    return Ok(mapper);
+// The trainer goes into PRG RAM, where the game can bank and overwrite it as usual. PRG RAM
+// keeps putting it back after power-on fills and save loads, since the copier loaded it last.
+// Boards with no PRG RAM at all get a little RAM of their own for it, as the copier provided.
+fn load_trainer(mapper: &mut dyn Mapper, trainer: &[u8]) {
+    match mapper.prg_ram_mut() {
+        Some(prg_ram) if prg_ram.len() > 0 && !prg_ram.is_readonly() => {
+            // $6000 maps to the start of PRG RAM at power on, on every board we support
+            prg_ram.preload((TRAINER_ADDRESS - 0x6000) as usize, trainer);
+            println!("Trainer: loaded {} bytes at ${:04X}", trainer.len(), TRAINER_ADDRESS);
+            return;
+        },
+        _ => {}
+    }
+    match mapper.trainer_ram_mut() {
+        Some(trainer_ram) => {
+            *trainer_ram = MemoryBlock::new(&[0u8; TRAINER_SIZE], MemoryType::Ram);
+            trainer_ram.preload(0, trainer);
+            println!("Trainer: loaded {} bytes into RAM at ${:04X}", trainer.len(), TRAINER_ADDRESS);
+        },
+        None => {
+            println!("Trainer: this board has no PRG RAM at ${:04X}, ignoring it", TRAINER_ADDRESS);
+        }
+    }
 }
 
-pub fn mapper_from_reader(file_reader: &mut dyn Read) -> Result<Box<dyn Mapper>, String> {
+pub fn mapper_from_reader(file_reader: &mut dyn Read) -> Result<Box<dyn Mapper>, LoadError> {
+    return mapper_from_reader_with_registry(file_reader, &MapperRegistry::new());
+}
+
+pub fn mapper_from_reader_with_registry(file_reader: &mut dyn Read, registry: &MapperRegistry) -> Result<Box<dyn Mapper>, LoadError> {
     let mut entire_file = Vec::new();
     match file_reader.read_to_end(&mut entire_file) {
         Ok(_) => {/* proceed normally */},
         Err(e) => {
-            return Err(format!("Failed to read any data at all, giving up.{}\n", e));
+            return Err(truncated(&e.to_string()));
         }
     }
 
-    let mut errors = String::new();
+    // Try each format in turn. Once a header matches, that format's errors are final.
     match INesCartridge::from_reader(&mut entire_file.as_slice()) {
-        Ok(ines) => {return mapper_from_ines(ines);},
-        Err(e) => {errors += format!("ines: {}\n", e).as_str()}
+        Ok(mut ines) => {
+            for correction in game_db::correct_header(&mut ines) {
+                println!("Header corrected by database: {} was {}, now {}", correction.field, correction.old_value, correction.new_value);
+            }
+            // Consoles we don't emulate yet. Most of these games still run, so warn rather than refuse.
+            // The EPSM is emulated, and is otherwise a standard console.
+            let console_type = ines.header.console_type();
+            if console_type != ConsoleType::Nes && console_type != ConsoleType::Extended(EXTENDED_CONSOLE_EPSM) {
+                println!("Warning: cartridge targets {}, which is not emulated. Running as a standard NES.", console_type_name(ines.header.console_type()));
+            }
+            println!("iNes version: {}, prg rom size: {}, chr rom size: {}, trainer size: {}, misc size: {}",
+                ines.header.version(), ines.prg.len(), ines.chr.len(), ines.trainer.len(), ines.misc_rom.len());
+            let trainer = ines.trainer.clone();
+            if trainer.len() > 0 && ines.header.prg_ram_size() == 0 && ines.header.prg_sram_size() == 0 {
+                // The copier supplied RAM of its own for the trainer to live in. Boards which size
+                // PRG RAM from the header get it here; the rest map trainer RAM instead.
+                ines.header.set_prg_ram_sizes(8192, 0);
+            }
+            let mut mapper = registry.mapper_from_ines(ines)?;
+            if trainer.len() > 0 {
+                load_trainer(&mut *mapper, &trainer);
+            }
+            return Ok(mapper);
+        },
+        Err(INesError::InvalidHeader) => {},
+        Err(e) => {return Err(LoadError::from(e));}
     }
 
     match NsfFile::from_reader(&mut entire_file.as_slice()) {
         Ok(nsf) => {return Ok(Box::new(NsfMapper::from_nsf(nsf)?));},
-        Err(e) => {errors += format!("nsf: {}\n", e).as_str()}
+        Err(NsfError::InvalidHeader) => {},
+        Err(e) => {return Err(LoadError::from(e));}
     }
 
     match FdsFile::from_reader(&mut entire_file.as_slice()) {
-        Ok(nsf) => {return Ok(Box::new(FdsMapper::from_fds(nsf)?));},
-        Err(e) => {errors += format!("fds: {}\n", e).as_str()}
+        Ok(fds) => {return Ok(Box::new(FdsMapper::from_fds(fds)?));},
+        Err(FdsError::InvalidHeader) => {},
+        Err(e) => {return Err(LoadError::from(e));}
+    }
+
+    match UnifFile::from_reader(&mut entire_file.as_slice()) {
+        Ok(unif) => {return registry.mapper_from_unif(unif);},
+        Err(UnifError::InvalidHeader) => {},
+        Err(e) => {return Err(LoadError::from(e));}
     }
 
-    return Err(format!("Unable to open file as any known type, giving up.\n{}", errors));
+    return Err(LoadError::UnrecognizedFormat);
 }
 
-pub fn mapper_from_file(file_data: &[u8]) -> Result<Box<dyn Mapper>, String> {
+pub fn mapper_from_file(file_data: &[u8]) -> Result<Box<dyn Mapper>, LoadError> {
     let mut file_reader = file_data;
     return mapper_from_reader(&mut file_reader);
-}
\ No newline at end of file
+}
+
+pub fn mapper_from_file_with_registry(file_data: &[u8], registry: &MapperRegistry) -> Result<Box<dyn Mapper>, LoadError> {
+    let mut file_reader = file_data;
+    return mapper_from_reader_with_registry(&mut file_reader, registry);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boards_without_prg_ram_keep_the_trainer_at_7000() {
+        // UxROM, two PRG banks, CHR RAM, with a trainer
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 2, 0, 0x24, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let trainer: Vec<u8> = (0 .. TRAINER_SIZE).map(|i| i as u8).collect();
+        image.extend(&trainer);
+        image.extend(vec![0u8; 2 * 0x4000]);
+        let mut mapper = mapper_from_reader(&mut image.as_slice()).unwrap();
+
+        assert_eq!(mapper.debug_read_cpu(0x7000), Some(0x00));
+        assert_eq!(mapper.debug_read_cpu(0x71FF), Some(0xFF));
+        assert_eq!(mapper.debug_read_cpu(0x7200), None);
+
+        mapper.write_cpu(0x7000, 0x5A);
+        assert_eq!(mapper.debug_read_cpu(0x7000), Some(0x5A));
+        mapper.power_cycle();
+        assert_eq!(mapper.debug_read_cpu(0x7000), Some(0x00));
+    }
+}
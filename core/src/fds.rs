@@ -78,6 +78,10 @@ impl FdsFile {
 
         let mut disk_sides: Vec<Vec<u8>> = Vec::new();
 
+        if fds_data.len() < 16 {
+            return Err(FdsError::InvalidHeader);
+        }
+
         // First try the 16-byte header originating in fwNES
         let header = FdsHeader::from(&fds_data[0..16]);
         if header.magic_header_valid() {
@@ -99,8 +103,7 @@ impl FdsFile {
         // Second, see if the first 15 bytes correspond to the start of info block 1. If they do, this is
         // likely a raw dump. Assume disk sides as a multiple of 65500 bytes and complain if we have anything else
         let verification_string = "\x01*NINTENDO-HVC*";
-        let candidate_string = std::str::from_utf8(&fds_data[0..15]).expect("invalid utf8-sequence");
-        if candidate_string.eq(verification_string) {
+        if &fds_data[0..15] == verification_string.as_bytes() {
             for i in 0 .. fds_data.len() / 65500 {
                 let start = i * 65500;
                 let end = (i+1) * 65500;
@@ -0,0 +1,377 @@
+// Header database: known-good header fields for specific dumps, identified by checksum.
+// Many iNES 1.0 files in the wild have the wrong mirroring, a missing battery flag,
+// or a mapper number mangled by a dumper's signature. When we recognize the PRG and
+// CHR data, we trust the database over the header.
+
+use ines::INesCartridge;
+use ines::INesHeader;
+use ines::ConsoleType;
+use ines::CpuPpuTiming;
+use ines::console_type_name;
+use ines::expansion_device_name;
+use ines::timing_name;
+use mmc::mapper::Mirroring;
+use mmc::mapper::mirroring_mode_name;
+use hash::crc32;
+use hash::sha1;
+use hash::to_hex;
+
+use std::sync::RwLock;
+
+const EMBEDDED_DATABASE: &str = include_str!("../assets/nes20db.xml");
+
+lazy_static! {
+    // Parsed the first time a cartridge is loaded, rather than on every load. Shells can
+    // swap in a fuller database (like the NES 2.0 XML) with use_database.
+    static ref ACTIVE_DATABASE: RwLock<GameDatabase> = RwLock::new(GameDatabase::embedded());
+}
+
+#[derive(Clone, Default)]
+pub struct GameDbEntry {
+    pub crc32: u32,
+    pub sha1: Option<String>,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: Option<usize>,
+    pub chr_nvram_size: Option<usize>,
+    pub timing: Option<CpuPpuTiming>,
+    pub console_type: Option<ConsoleType>,
+    pub expansion_device: Option<u8>,
+}
+
+#[derive(Clone)]
+pub struct HeaderCorrection {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+// Just enough XML to read the database: self-closing elements with quoted
+// attributes, grouped into <game> blocks. Comments and text are skipped.
+fn parse_elements(xml: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let mut elements = Vec::new();
+    let mut remaining = xml;
+    loop {
+        let start = match remaining.find('<') {
+            Some(index) => index,
+            None => break
+        };
+        remaining = &remaining[start ..];
+        if remaining.starts_with("<!--") {
+            remaining = match remaining.find("-->") {
+                Some(end) => &remaining[end + 3 ..],
+                None => break
+            };
+            continue;
+        }
+        let end = match remaining.find('>') {
+            Some(index) => index,
+            None => break
+        };
+        let tag = remaining[1 .. end].trim_end_matches('/').trim();
+        remaining = &remaining[end + 1 ..];
+        if tag.starts_with('?') {
+            continue;
+        }
+
+        let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+        let name = tag[.. name_end].to_string();
+        let mut attributes = Vec::new();
+        let mut attribute_text = &tag[name_end ..];
+        loop {
+            let equals = match attribute_text.find("=\"") {
+                Some(index) => index,
+                None => break
+            };
+            let key = attribute_text[.. equals].trim().to_string();
+            let value_text = &attribute_text[equals + 2 ..];
+            let value_end = match value_text.find('"') {
+                Some(index) => index,
+                None => break
+            };
+            attributes.push((key, value_text[.. value_end].to_string()));
+            attribute_text = &value_text[value_end + 1 ..];
+        }
+        elements.push((name, attributes));
+    }
+    return elements;
+}
+
+fn attribute<'a>(attributes: &'a Vec<(String, String)>, key: &str) -> Option<&'a str> {
+    for &(ref name, ref value) in attributes {
+        if name == key {
+            return Some(value.as_str());
+        }
+    }
+    return None;
+}
+
+fn number<T: std::str::FromStr>(attributes: &Vec<(String, String)>, key: &str) -> Option<T> {
+    return attribute(attributes, key).and_then(|value| value.parse::<T>().ok());
+}
+
+pub struct GameDatabase {
+    pub entries: Vec<GameDbEntry>,
+}
+
+impl GameDatabase {
+    pub fn from_xml(xml: &str) -> GameDatabase {
+        let mut entries = Vec::new();
+        let mut current: Option<GameDbEntry> = None;
+        let mut has_checksum = false;
+        for (name, attributes) in parse_elements(xml) {
+            match name.as_str() {
+                "game" => {
+                    current = Some(GameDbEntry::default());
+                    has_checksum = false;
+                },
+                "/game" => {
+                    match current.take() {
+                        Some(entry) => {
+                            if has_checksum {
+                                entries.push(entry);
+                            }
+                        },
+                        None => {}
+                    }
+                },
+                _ => {
+                    let entry = match current.as_mut() {
+                        Some(entry) => entry,
+                        None => {continue;}
+                    };
+                    match name.as_str() {
+                        "rom" => {
+                            match attribute(&attributes, "crc32").and_then(|crc| u32::from_str_radix(crc, 16).ok()) {
+                                Some(crc) => {entry.crc32 = crc; has_checksum = true;},
+                                None => {}
+                            }
+                            entry.sha1 = attribute(&attributes, "sha1").map(|hash| hash.to_uppercase());
+                        },
+                        "pcb" => {
+                            entry.mapper = number(&attributes, "mapper");
+                            entry.submapper = number(&attributes, "submapper");
+                            entry.battery = number::<u8>(&attributes, "battery").map(|battery| battery != 0);
+                            entry.mirroring = match attribute(&attributes, "mirroring") {
+                                Some("H") => Some(Mirroring::Horizontal),
+                                Some("V") => Some(Mirroring::Vertical),
+                                Some("4") => Some(Mirroring::FourScreen),
+                                _ => None
+                            };
+                        },
+                        "prgram" => {entry.prg_ram_size = number(&attributes, "size")},
+                        "prgnvram" => {entry.prg_nvram_size = number(&attributes, "size")},
+                        "chrram" => {entry.chr_ram_size = number(&attributes, "size")},
+                        "chrnvram" => {entry.chr_nvram_size = number(&attributes, "size")},
+                        "console" => {
+                            entry.console_type = match number::<u8>(&attributes, "type") {
+                                Some(0) => Some(ConsoleType::Nes),
+                                Some(1) => Some(ConsoleType::VsSystem),
+                                Some(2) => Some(ConsoleType::Playchoice10),
+                                Some(extended) => Some(ConsoleType::Extended(extended)),
+                                None => None
+                            };
+                            entry.timing = match number::<u8>(&attributes, "region") {
+                                Some(0) => Some(CpuPpuTiming::Ntsc),
+                                Some(1) => Some(CpuPpuTiming::Pal),
+                                Some(2) => Some(CpuPpuTiming::MultipleRegion),
+                                Some(3) => Some(CpuPpuTiming::Dendy),
+                                _ => None
+                            };
+                        },
+                        "expansion" => {entry.expansion_device = number(&attributes, "type")},
+                        _ => {}
+                    }
+                }
+            }
+        }
+        return GameDatabase {
+            entries: entries,
+        };
+    }
+
+    pub fn embedded() -> GameDatabase {
+        return GameDatabase::from_xml(EMBEDDED_DATABASE);
+    }
+
+    pub fn find(&self, prg: &[u8], chr: &[u8]) -> Option<&GameDbEntry> {
+        let mut rom = prg.to_vec();
+        rom.extend(chr);
+        let rom_crc = crc32(&rom);
+        let mut rom_sha1: Option<String> = None;
+        for entry in self.entries.iter() {
+            if entry.crc32 != rom_crc {
+                continue;
+            }
+            match entry.sha1 {
+                Some(ref expected_sha1) => {
+                    // Only hash when we need to, most entries won't get this far
+                    if rom_sha1.is_none() {
+                        rom_sha1 = Some(to_hex(&sha1(&rom)));
+                    }
+                    if rom_sha1.as_ref() == Some(expected_sha1) {
+                        return Some(entry);
+                    }
+                },
+                None => {return Some(entry);}
+            }
+        }
+        return None;
+    }
+}
+
+fn record(corrections: &mut Vec<HeaderCorrection>, field: &str, old_value: String, new_value: String) {
+    if old_value != new_value {
+        corrections.push(HeaderCorrection{
+            field: field.to_string(),
+            old_value: old_value,
+            new_value: new_value,
+        });
+    }
+}
+
+fn describe_header(header: &INesHeader) -> Vec<(&'static str, String)> {
+    return vec![
+        ("mapper", format!("{}", header.mapper_number())),
+        ("submapper", format!("{}", header.submapper_number())),
+        ("mirroring", mirroring_mode_name(header.mirroring()).to_string()),
+        ("battery", format!("{}", header.has_sram())),
+        ("prg ram", format!("{}", header.prg_ram_size())),
+        ("prg nvram", format!("{}", header.prg_sram_size())),
+        ("chr ram", format!("{}", header.chr_ram_size())),
+        ("chr nvram", format!("{}", header.chr_sram_size())),
+        ("timing", timing_name(header.timing()).to_string()),
+        ("console type", console_type_name(header.console_type())),
+        ("expansion device", expansion_device_name(header.default_expansion_device())),
+    ];
+}
+
+// Applies a database entry to the cartridge's header, returning whichever fields changed
+pub fn apply_entry(ines: &mut INesCartridge, entry: &GameDbEntry) -> Vec<HeaderCorrection> {
+    let original = describe_header(&ines.header);
+    let header = &mut ines.header;
+    header.upgrade_to_ines2();
+    match (entry.mapper, entry.submapper) {
+        (Some(mapper), submapper) => header.set_mapper(mapper, submapper.unwrap_or(0)),
+        (None, Some(submapper)) => {
+            let mapper = header.mapper_number();
+            header.set_mapper(mapper, submapper);
+        },
+        (None, None) => {}
+    }
+    match entry.mirroring {
+        Some(mirroring) => header.set_mirroring(mirroring),
+        None => {}
+    }
+    match entry.battery {
+        Some(battery) => {
+            header.set_battery(battery);
+            // A battery with no sizes listed means the work RAM we assumed was actually saved
+            if battery && entry.prg_nvram_size.is_none() && header.prg_sram_size() == 0 {
+                let prg_ram_size = header.prg_ram_size();
+                header.set_prg_ram_sizes(0, prg_ram_size);
+            }
+        },
+        None => {}
+    }
+    if entry.prg_ram_size.is_some() || entry.prg_nvram_size.is_some() {
+        let prg_ram_size = entry.prg_ram_size.unwrap_or(0);
+        let prg_nvram_size = entry.prg_nvram_size.unwrap_or(0);
+        header.set_prg_ram_sizes(prg_ram_size, prg_nvram_size);
+    }
+    if entry.chr_ram_size.is_some() || entry.chr_nvram_size.is_some() {
+        let chr_ram_size = entry.chr_ram_size.unwrap_or(0);
+        let chr_nvram_size = entry.chr_nvram_size.unwrap_or(0);
+        header.set_chr_ram_sizes(chr_ram_size, chr_nvram_size);
+    }
+    match entry.timing {
+        Some(timing) => header.set_timing(timing),
+        None => {}
+    }
+    match entry.console_type {
+        Some(console_type) => header.set_console_type(console_type),
+        None => {}
+    }
+    match entry.expansion_device {
+        Some(device) => header.set_default_expansion_device(device),
+        None => {}
+    }
+
+    let corrected = describe_header(header);
+    let mut corrections = Vec::new();
+    for i in 0 .. original.len() {
+        record(&mut corrections, original[i].0, original[i].1.clone(), corrected[i].1.clone());
+    }
+    return corrections;
+}
+
+// Replaces the database that correct_header consults
+pub fn use_database(database: GameDatabase) {
+    *ACTIVE_DATABASE.write().unwrap() = database;
+}
+
+// Looks the cartridge up in the active database, and fixes its header if we know better
+pub fn correct_header(ines: &mut INesCartridge) -> Vec<HeaderCorrection> {
+    let database = ACTIVE_DATABASE.read().unwrap();
+    return match database.find(&ines.prg, &ines.chr) {
+        Some(entry) => apply_entry(ines, entry),
+        None => Vec::new()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ines::test_cartridge;
+
+    // The same layout the NES 2.0 XML database uses
+    fn database_for(ines: &INesCartridge) -> GameDatabase {
+        let mut rom = ines.prg.clone();
+        rom.extend(&ines.chr);
+        let xml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+  <game>
+    <!-- Test Cartridge -->
+    <prgrom size="16384" crc32="{prg_crc:08X}"/>
+    <chrrom size="8192" crc32="{chr_crc:08X}"/>
+    <rom size="24576" crc32="{crc:08X}" sha1="{sha1}"/>
+    <pcb mapper="0" submapper="0" mirroring="V" battery="1"/>
+    <console type="0" region="0"/>
+    <expansion type="1"/>
+  </game>
+</nes20db>"#,
+            prg_crc = crc32(&ines.prg), chr_crc = crc32(&ines.chr),
+            crc = crc32(&rom), sha1 = to_hex(&sha1(&rom)));
+        return GameDatabase::from_xml(&xml);
+    }
+
+    #[test]
+    fn corrects_a_known_bad_header() {
+        // NROM, whose header claims horizontal mirroring and no battery
+        let mut ines = test_cartridge(0, 1, 1);
+        // Straight to the database, since swapping the shared one would race other tests
+        let database = database_for(&ines);
+        let entry = database.find(&ines.prg, &ines.chr).unwrap().clone();
+
+        let corrections = apply_entry(&mut ines, &entry);
+        assert!(ines.header.mirroring() == Mirroring::Vertical);
+        assert!(ines.header.has_sram());
+        assert_eq!(ines.header.prg_sram_size(), 8192);
+        let fields: Vec<&str> = corrections.iter().map(|correction| correction.field.as_str()).collect();
+        assert!(fields.contains(&"mirroring"));
+        assert!(fields.contains(&"battery"));
+        assert!(!fields.contains(&"mapper"));
+    }
+
+    #[test]
+    fn ignores_a_mismatched_sha1() {
+        let ines = test_cartridge(0, 1, 1);
+        let mut database = database_for(&ines);
+        database.entries[0].sha1 = Some("0".repeat(40));
+        assert!(database.find(&ines.prg, &ines.chr).is_none());
+    }
+}
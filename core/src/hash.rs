@@ -0,0 +1,167 @@
+// Checksums used to identify ROM images. These are for matching files against
+// databases, not for anything security sensitive.
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for i in 0 .. 256 {
+        let mut crc = i as u32;
+        for _ in 0 .. 8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc = crc >> 1;
+            }
+        }
+        table[i] = crc;
+    }
+    return table;
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let table = crc32_table();
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc = table[((crc ^ (*byte as u32)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    return crc ^ 0xFFFF_FFFF;
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+    // Pad to a multiple of 64 bytes: a single 1 bit, zeroes, then the length in bits
+    let mut message = data.to_vec();
+    let bit_length = (data.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    for i in (0 .. 8).rev() {
+        message.push((bit_length >> (i * 8)) as u8);
+    }
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0 .. 16 {
+            w[i] =
+                ((chunk[i * 4] as u32) << 24) |
+                ((chunk[i * 4 + 1] as u32) << 16) |
+                ((chunk[i * 4 + 2] as u32) << 8) |
+                (chunk[i * 4 + 3] as u32);
+        }
+        for i in 16 .. 80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let mut a = h[0];
+        let mut b = h[1];
+        let mut c = h[2];
+        let mut d = h[3];
+        let mut e = h[4];
+        for i in 0 .. 80 {
+            let (f, k) = match i {
+                0 ..= 19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20 ..= 39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40 ..= 59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6u32)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for i in 0 .. 5 {
+        digest[i * 4] = (h[i] >> 24) as u8;
+        digest[i * 4 + 1] = (h[i] >> 16) as u8;
+        digest[i * 4 + 2] = (h[i] >> 8) as u8;
+        digest[i * 4 + 3] = h[i] as u8;
+    }
+    return digest;
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    let mut hex = String::new();
+    for byte in digest {
+        hex += &format!("{:02X}", byte);
+    }
+    return hex;
+}
+
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+        5,  9, 14, 20, 5,  9, 14, 20, 5,  9, 14, 20, 5,  9, 14, 20,
+        4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+        6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21];
+    // floor(abs(sin(i + 1)) * 2^32)
+    let mut k = [0u32; 64];
+    for i in 0 .. 64 {
+        k[i] = (((i + 1) as f64).sin().abs() * 4294967296.0) as u32;
+    }
+    let mut h: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+
+    // Same padding as SHA-1, except the length is little endian
+    let mut message = data.to_vec();
+    let bit_length = (data.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    for i in 0 .. 8 {
+        message.push((bit_length >> (i * 8)) as u8);
+    }
+
+    for chunk in message.chunks(64) {
+        let mut m = [0u32; 16];
+        for i in 0 .. 16 {
+            m[i] =
+                (chunk[i * 4] as u32) |
+                ((chunk[i * 4 + 1] as u32) << 8) |
+                ((chunk[i * 4 + 2] as u32) << 16) |
+                ((chunk[i * 4 + 3] as u32) << 24);
+        }
+
+        let mut a = h[0];
+        let mut b = h[1];
+        let mut c = h[2];
+        let mut d = h[3];
+        for i in 0 .. 64 {
+            let (f, g) = match i {
+                0 ..= 15 => ((b & c) | (!b & d), i),
+                16 ..= 31 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                32 ..= 47 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16)
+            };
+            let temp = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(a.wrapping_add(f).wrapping_add(k[i]).wrapping_add(m[g]).rotate_left(SHIFTS[i]));
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+    }
+
+    let mut digest = [0u8; 16];
+    for i in 0 .. 4 {
+        digest[i * 4] = h[i] as u8;
+        digest[i * 4 + 1] = (h[i] >> 8) as u8;
+        digest[i * 4 + 2] = (h[i] >> 16) as u8;
+        digest[i * 4 + 3] = (h[i] >> 24) as u8;
+    }
+    return digest;
+}
@@ -15,6 +15,7 @@ use memoryblock::MemoryType;
 pub enum INesError {
     InvalidHeader,
     Unimplemented,
+    InvalidHeaderField{field: String, reason: String},
     ReadError{reason: String}
 }
 
@@ -25,6 +26,7 @@ impl fmt::Display for INesError  {
         match self {
             INesError::InvalidHeader => {write!(f, "Invalid iNES Header")},
             INesError::Unimplemented => {write!(f, "Unimplemented (Lazy programmers!!1)")},
+            INesError::InvalidHeaderField{field, reason} => {write!(f, "Invalid {}: {}", field, reason)},
             INesError::ReadError{reason} => {write!(f, "Error reading cartridge: {}", reason)}
         }
     }
@@ -53,17 +55,121 @@ const INES_FLAGS_7: usize = 7;
 
 // here the constants diverge depending on type
 const INES1_PRG_RAM_SIZE: usize = 8;
-//const INES1_TV_SYSTEM: usize = 9;
+const INES1_TV_SYSTEM: usize = 9;
 //const INES1_FLAGS_10: usize = 10;
 
 const INES2_MAPPER_SUB_MSB: usize = 8;
 const INES2_PRG_CHR_MSB: usize = 9;
 const INES2_PRG_RAM: usize = 10;
 const INES2_CHR_RAM: usize = 11;
-//const INES2_CPU_PPU_TIMING: usize = 12;
-//const INES2_SYSTEM_TYPE: usize = 13;
-//const INES2_MISC_ROM_COUNT: usize = 14;
-//const INES2_DEFAULT_EXPANSION: usize = 15;
+const INES2_CPU_PPU_TIMING: usize = 12;
+const INES2_SYSTEM_TYPE: usize = 13;
+const INES2_MISC_ROM_COUNT: usize = 14;
+const INES2_DEFAULT_EXPANSION: usize = 15;
+
+// Default expansion devices the shells can provide
+pub const EXPANSION_DEVICE_UNSPECIFIED: u8 = 0x00;
+pub const EXPANSION_DEVICE_STANDARD_CONTROLLERS: u8 = 0x01;
+
+// Extended console types, from header byte 13 when flags 7 says so. The EPSM has no
+// expansion device number of its own; NES 2.0 assigns it this console type instead.
+pub const EXTENDED_CONSOLE_EPSM: u8 = 0x4;
+
+// Some iNES files carry a 512-byte trainer, which copier hardware loaded into $7000-$71FF
+// before starting the game. Mostly these hold patches for hacked and translated ROMs.
+// Reference: https://www.nesdev.org/wiki/INES#Trainer
+pub const TRAINER_ADDRESS: u16 = 0x7000;
+pub const TRAINER_SIZE: usize = 512;
+
+// https://wiki.nesdev.com/w/index.php/NES_2.0#CPU.2FPPU_Timing
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CpuPpuTiming {
+    Ntsc,
+    Pal,
+    MultipleRegion,
+    Dendy,
+}
+
+pub fn timing_name(timing: CpuPpuTiming) -> &'static str {
+    return match timing {
+        CpuPpuTiming::Ntsc => "NTSC",
+        CpuPpuTiming::Pal => "PAL",
+        CpuPpuTiming::MultipleRegion => "Multiple Region",
+        CpuPpuTiming::Dendy => "Dendy",
+    };
+}
+
+// https://wiki.nesdev.com/w/index.php/NES_2.0#Extended_Console_Type
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended(u8),
+}
+
+pub fn console_type_name(console_type: ConsoleType) -> String {
+    return match console_type {
+        ConsoleType::Nes => "NES / Famicom".to_string(),
+        ConsoleType::VsSystem => "Vs. System".to_string(),
+        ConsoleType::Playchoice10 => "PlayChoice-10".to_string(),
+        ConsoleType::Extended(0x3) => "Famiclone with Decimal Mode".to_string(),
+        ConsoleType::Extended(0x4) => "NES / Famicom with EPSM".to_string(),
+        ConsoleType::Extended(0x5) => "V.R. Technology VT01".to_string(),
+        ConsoleType::Extended(0x6) => "V.R. Technology VT02".to_string(),
+        ConsoleType::Extended(0x7) => "V.R. Technology VT03".to_string(),
+        ConsoleType::Extended(0x8) => "V.R. Technology VT09".to_string(),
+        ConsoleType::Extended(0x9) => "V.R. Technology VT32".to_string(),
+        ConsoleType::Extended(0xA) => "V.R. Technology VT369".to_string(),
+        ConsoleType::Extended(0xB) => "UMC UM6578".to_string(),
+        ConsoleType::Extended(0xC) => "Famicom Network System".to_string(),
+        ConsoleType::Extended(other) => format!("Extended Console Type {}", other),
+    };
+}
+
+pub fn expansion_device_name(device: u8) -> String {
+    return match device {
+        0x00 => "Unspecified".to_string(),
+        0x01 => "Standard Controllers".to_string(),
+        0x02 => "NES Four Score / Satellite".to_string(),
+        0x03 => "Famicom Four Players Adapter".to_string(),
+        0x04 => "Vs. System (1P via $4016)".to_string(),
+        0x05 => "Vs. System (1P via $4017)".to_string(),
+        0x07 => "Vs. Zapper".to_string(),
+        0x08 => "Zapper ($4017)".to_string(),
+        0x09 => "Two Zappers".to_string(),
+        0x0A => "Bandai Hyper Shot".to_string(),
+        0x0B => "Power Pad Side A".to_string(),
+        0x0C => "Power Pad Side B".to_string(),
+        0x0D => "Family Trainer Side A".to_string(),
+        0x0E => "Family Trainer Side B".to_string(),
+        0x0F => "Arkanoid Vaus Controller (NES)".to_string(),
+        0x10 => "Arkanoid Vaus Controller (Famicom)".to_string(),
+        0x23 => "Family BASIC Keyboard".to_string(),
+        0x29 => "SNES Mouse".to_string(),
+        other => format!("Expansion Device 0x{:02X}", other),
+    };
+}
+
+// RAM sizes in NES 2.0 are stored as a shift count: 64 << shift, with 0 meaning none at all
+fn shift_count_size(shift_count: u8) -> usize {
+    if shift_count == 0 {
+        return 0;
+    }
+    return 64 << (shift_count as usize);
+}
+
+// Smallest shift count which holds the requested size
+pub fn size_to_shift_count(size: usize) -> u8 {
+    if size == 0 {
+        return 0;
+    }
+    let mut shift_count = 1;
+    while (64 << shift_count) < size && shift_count < 15 {
+        shift_count += 1;
+    }
+    return shift_count as u8;
+}
 
 impl INesHeader {
     pub fn from(raw_bytes: &[u8]) -> INesHeader {
@@ -122,11 +228,9 @@ impl INesHeader {
             let exponent = ((lsb & 0b1111_1100) >> 2) as u32;
             let base: usize = 2;
             let size = base.pow(exponent) * multiplier;
-            println!("base: {}, multiplier: {}, exponent: {}, size: {}", base, multiplier, exponent, size);
             return size;
         } else {
             // simple mode
-            println!("used simple mode!");
             return (((msb as usize) << 8) + (lsb as usize)) * 16 * 1024;
         }
     }
@@ -164,7 +268,7 @@ impl INesHeader {
             return base.pow(exponent) * multiplier;
         } else {
             // simple mode
-            return ((msb as usize) << 8) + (lsb as usize) * 8 * 1024;
+            return (((msb as usize) << 8) + (lsb as usize)) * 8 * 1024;
         }
     }
 
@@ -184,11 +288,7 @@ impl INesHeader {
     }
 
     fn _chr_ram_size_ines2(&self) -> usize {
-        let shift_count = self.raw_bytes[INES2_CHR_RAM] & 0b0000_1111;
-        if shift_count == 0 {
-            return 0;
-        }
-        return 64 << (shift_count as usize);
+        return shift_count_size(self.chr_ram_shift());
     }
 
     pub fn chr_ram_size(&self) -> usize {
@@ -205,17 +305,51 @@ impl INesHeader {
         if self.version() != 2 {
             return 0;
         }
-        let shift_count = (self.raw_bytes[INES2_CHR_RAM] & 0b1111_0000) >> 4;
-        if shift_count == 0 {
-            return 0;
+        return shift_count_size(self.chr_nvram_shift());
+    }
+
+    // The raw shift counts from header bytes 10 and 11. These are only meaningful
+    // for NES 2.0 headers, and read as 0 otherwise.
+
+    pub fn prg_ram_shift(&self) -> u8 {
+        match self.version() {
+            2 => self.raw_bytes[INES2_PRG_RAM] & 0b0000_1111,
+            _ => 0
+        }
+    }
+
+    pub fn prg_nvram_shift(&self) -> u8 {
+        match self.version() {
+            2 => (self.raw_bytes[INES2_PRG_RAM] & 0b1111_0000) >> 4,
+            _ => 0
+        }
+    }
+
+    pub fn chr_ram_shift(&self) -> u8 {
+        match self.version() {
+            2 => self.raw_bytes[INES2_CHR_RAM] & 0b0000_1111,
+            _ => 0
+        }
+    }
+
+    pub fn chr_nvram_shift(&self) -> u8 {
+        match self.version() {
+            2 => (self.raw_bytes[INES2_CHR_RAM] & 0b1111_0000) >> 4,
+            _ => 0
         }
-        return 64 << (shift_count as usize);
     }
 
     // https://wiki.nesdev.com/w/index.php/INES#Flags_6
 
     pub fn mirroring(&self) -> Mirroring {
         if self.raw_bytes[INES_FLAGS_6] & 0b0000_1000 != 0 {
+            if self.mapper_number() == 218 {
+                // See set_mirroring
+                if self.raw_bytes[INES_FLAGS_6] & 0b0000_0001 != 0 {
+                    return Mirroring::OneScreenUpper;
+                }
+                return Mirroring::OneScreenLower;
+            }
             return Mirroring::FourScreen;
         }
         if self.raw_bytes[INES_FLAGS_6] & 0b0000_0001 != 0 {
@@ -241,11 +375,7 @@ impl INesHeader {
     }
 
     fn _prg_ram_size_ines2(&self) -> usize  {
-        let shift_count = self.raw_bytes[INES2_PRG_RAM] & 0b0000_1111;
-        if shift_count == 0 {
-            return 0;
-        }
-        return 64 << (shift_count as usize);
+        return shift_count_size(self.prg_ram_shift());
     }
 
     pub fn prg_ram_size(&self) -> usize  {
@@ -269,11 +399,7 @@ impl INesHeader {
     }
 
     fn _prg_sram_size_ines2(&self) -> usize {
-        let shift_count = (self.raw_bytes[INES2_PRG_RAM] & 0b1111_0000) >> 4;
-        if shift_count == 0 {
-            return 0;
-        }
-        return 64 << (shift_count as usize);
+        return shift_count_size(self.prg_nvram_shift());
     }
 
     pub fn prg_sram_size(&self) -> usize  {
@@ -330,6 +456,166 @@ impl INesHeader {
             _ => 0
         }
     }
+
+    pub fn default_expansion_device(&self) -> u8 {
+        match self.version() {
+            2 => self.raw_bytes[INES2_DEFAULT_EXPANSION] & 0b0011_1111,
+            _ => 0
+        }
+    }
+
+    pub fn timing(&self) -> CpuPpuTiming {
+        match self.version() {
+            1 => {
+                // Flags 9 is rarely set, and only trustworthy when the padding is clean
+                if self.ines1_extended_attributes_valid() && (self.raw_bytes[INES1_TV_SYSTEM] & 0b0000_0001) != 0 {
+                    CpuPpuTiming::Pal
+                } else {
+                    CpuPpuTiming::Ntsc
+                }
+            },
+            2 => match self.raw_bytes[INES2_CPU_PPU_TIMING] & 0b0000_0011 {
+                0 => CpuPpuTiming::Ntsc,
+                1 => CpuPpuTiming::Pal,
+                2 => CpuPpuTiming::MultipleRegion,
+                _ => CpuPpuTiming::Dendy
+            },
+            _ => CpuPpuTiming::Ntsc
+        }
+    }
+
+    pub fn console_type(&self) -> ConsoleType {
+        let console_type = match self.raw_bytes[INES_FLAGS_7] & 0b0000_0011 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(self.raw_bytes[INES2_SYSTEM_TYPE] & 0b0000_1111)
+        };
+        match (self.version(), console_type) {
+            // iNES 1.0 has no extended type byte; treat these as a plain NES
+            (2, _) => console_type,
+            (1, ConsoleType::Extended(_)) => ConsoleType::Nes,
+            (1, _) => console_type,
+            _ => ConsoleType::Nes
+        }
+    }
+
+    pub fn vs_ppu_type(&self) -> u8 {
+        match (self.version(), self.console_type()) {
+            (2, ConsoleType::VsSystem) => self.raw_bytes[INES2_SYSTEM_TYPE] & 0b0000_1111,
+            _ => 0
+        }
+    }
+
+    pub fn vs_hardware_type(&self) -> u8 {
+        match (self.version(), self.console_type()) {
+            (2, ConsoleType::VsSystem) => (self.raw_bytes[INES2_SYSTEM_TYPE] & 0b1111_0000) >> 4,
+            _ => 0
+        }
+    }
+
+    pub fn misc_rom_count(&self) -> u8 {
+        match self.version() {
+            2 => self.raw_bytes[INES2_MISC_ROM_COUNT] & 0b0000_0011,
+            _ => 0
+        }
+    }
+
+    pub fn raw_bytes(&self) -> [u8; 16] {
+        return self.raw_bytes;
+    }
+
+    // Rewrites an iNES 1.0 header as an equivalent NES 2.0 header, so the fields below
+    // can be set precisely. Any DiskDude! style junk in the padding is dropped here.
+    pub fn upgrade_to_ines2(&mut self) {
+        if self.version() == 2 {
+            return;
+        }
+        let mapper_number = self.mapper_number();
+        let prg_ram_size = self.prg_ram_size();
+        let prg_sram_size = self.prg_sram_size();
+        let chr_ram_size = self.chr_ram_size();
+        let timing = self.timing();
+        let console_type = if self.ines1_extended_attributes_valid() {self.raw_bytes[INES_FLAGS_7] & 0b0000_0011} else {0};
+
+        for i in INES_FLAGS_7 .. 16 {
+            self.raw_bytes[i] = 0;
+        }
+        self.raw_bytes[INES_FLAGS_7] = 0b0000_1000 | console_type;
+        self.set_mapper(mapper_number, 0);
+        self.set_prg_ram_sizes(prg_ram_size, prg_sram_size);
+        self.set_chr_ram_sizes(chr_ram_size, 0);
+        self.set_timing(timing);
+    }
+
+    // The setters below assume a NES 2.0 header; call upgrade_to_ines2 first
+
+    pub fn set_mapper(&mut self, mapper_number: u16, submapper_number: u8) {
+        self.raw_bytes[INES_FLAGS_6] = (self.raw_bytes[INES_FLAGS_6] & 0b0000_1111) | (((mapper_number & 0x00F) as u8) << 4);
+        self.raw_bytes[INES_FLAGS_7] = (self.raw_bytes[INES_FLAGS_7] & 0b0000_1111) | ((mapper_number & 0x0F0) as u8);
+        self.raw_bytes[INES2_MAPPER_SUB_MSB] = (submapper_number << 4) | (((mapper_number & 0xF00) >> 8) as u8);
+    }
+
+    // One-screen mirroring can only be spelled out for mapper 218, which reads the four-screen
+    // bit together with bit 0 as a choice of nametable. Other boards with one-screen mirroring
+    // pick the nametable themselves, so their header leaves both bits clear.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        let flags = self.raw_bytes[INES_FLAGS_6] & 0b1111_0110;
+        let one_screen = if self.mapper_number() == 218 {0b0000_1000} else {0};
+        self.raw_bytes[INES_FLAGS_6] = match mirroring {
+            Mirroring::Horizontal => flags,
+            Mirroring::Vertical => flags | 0b0000_0001,
+            Mirroring::FourScreen => flags | 0b0000_1000,
+            Mirroring::OneScreenLower => flags | one_screen,
+            Mirroring::OneScreenUpper => flags | one_screen | (one_screen >> 3),
+        };
+    }
+
+    pub fn set_battery(&mut self, has_battery: bool) {
+        if has_battery {
+            self.raw_bytes[INES_FLAGS_6] |= 0b0000_0010;
+        } else {
+            self.raw_bytes[INES_FLAGS_6] &= 0b1111_1101;
+        }
+    }
+
+    pub fn set_prg_ram_sizes(&mut self, ram_size: usize, nvram_size: usize) {
+        self.raw_bytes[INES2_PRG_RAM] = (size_to_shift_count(nvram_size) << 4) | size_to_shift_count(ram_size);
+    }
+
+    pub fn set_chr_ram_sizes(&mut self, ram_size: usize, nvram_size: usize) {
+        self.raw_bytes[INES2_CHR_RAM] = (size_to_shift_count(nvram_size) << 4) | size_to_shift_count(ram_size);
+    }
+
+    pub fn set_timing(&mut self, timing: CpuPpuTiming) {
+        self.raw_bytes[INES2_CPU_PPU_TIMING] = match timing {
+            CpuPpuTiming::Ntsc => 0,
+            CpuPpuTiming::Pal => 1,
+            CpuPpuTiming::MultipleRegion => 2,
+            CpuPpuTiming::Dendy => 3,
+        };
+    }
+
+    pub fn set_console_type(&mut self, console_type: ConsoleType) {
+        let flags = self.raw_bytes[INES_FLAGS_7] & 0b1111_1100;
+        match console_type {
+            ConsoleType::Nes => {self.raw_bytes[INES_FLAGS_7] = flags},
+            ConsoleType::VsSystem => {self.raw_bytes[INES_FLAGS_7] = flags | 1},
+            ConsoleType::Playchoice10 => {self.raw_bytes[INES_FLAGS_7] = flags | 2},
+            ConsoleType::Extended(extended_type) => {
+                self.raw_bytes[INES_FLAGS_7] = flags | 3;
+                self.raw_bytes[INES2_SYSTEM_TYPE] = extended_type & 0b0000_1111;
+            }
+        }
+    }
+
+    pub fn set_default_expansion_device(&mut self, device: u8) {
+        self.raw_bytes[INES2_DEFAULT_EXPANSION] = (self.raw_bytes[INES2_DEFAULT_EXPANSION] & 0b1100_0000) | (device & 0b0011_1111);
+    }
+
+    pub fn requests_epsm(&self) -> bool {
+        return self.console_type() == ConsoleType::Extended(EXTENDED_CONSOLE_EPSM);
+    }
 }
 
 #[derive(Clone)]
@@ -347,38 +633,36 @@ pub struct INesCartridge {
 impl INesCartridge {
     pub fn from_reader(file_reader: &mut dyn Read) -> Result<INesCartridge, INesError> {
         let mut header_bytes = [0u8; 16];
-        file_reader.read_exact(&mut header_bytes)?;
+        // A file too short to hold the header isn't in this format at all
+        if file_reader.read_exact(&mut header_bytes).is_err() {
+            return Err(INesError::InvalidHeader);
+        }
 
         let header = INesHeader::from(&header_bytes);
         if !header.magic_header_valid() {
             return Err(INesError::InvalidHeader);
         }
-        println!("iNes version: {}", header.version());
 
-        let trainer_size = if header.has_trainer() {512} else {0};
+        let trainer_size = if header.has_trainer() {TRAINER_SIZE} else {0};
         let mut trainer: Vec<u8> = Vec::new();
         trainer.resize(trainer_size, 0);
-        file_reader.read_exact(&mut trainer)?;
-        println!("trainer size: {}", trainer.len());
+        file_reader.read_exact(&mut trainer).map_err(|_| INesError::ReadError{reason: format!("trainer is shorter than the header's {} bytes", trainer.len())})?;
 
         let mut prg: Vec<u8> = Vec::new();
         prg.resize(header.prg_size(), 0);
-        file_reader.read_exact(&mut prg)?;
+        file_reader.read_exact(&mut prg).map_err(|_| INesError::ReadError{reason: format!("PRG ROM is shorter than the header's {} bytes", prg.len())})?;
         if prg.len() == 0 {
-            return Err(INesError::ReadError{reason: format!("PRG ROM size is {}. This file is invalid, or at the very least quite unusual. Aborting.", prg.len())});
+            return Err(INesError::InvalidHeaderField{field: "PRG ROM size".to_string(), reason: "PRG ROM size is 0. This file is invalid, or at the very least quite unusual. Aborting.".to_string()});
         }
-        println!("prg rom size: {}", prg.len());
 
         let mut chr: Vec<u8> = Vec::new();
         chr.resize(header.chr_rom_size(), 0);
-        file_reader.read_exact(&mut chr)?;
-        println!("chr rom size: {}", chr.len());
+        file_reader.read_exact(&mut chr).map_err(|_| INesError::ReadError{reason: format!("CHR ROM is shorter than the header's {} bytes", chr.len())})?;
 
         // If there is any remaining data at this point, it becomes misc_rom and,
         // currently, has no other special handling
         let mut misc: Vec<u8> = Vec::new();
         file_reader.read_to_end(&mut misc)?;
-        println!("misc_size: {}", misc.len());
 
         return Ok(INesCartridge {
             header: header,
@@ -436,19 +720,80 @@ impl INesCartridge {
         return blocks;
     }
 
-    pub fn prg_ram_block(&self) -> Result<MemoryBlock, String> {
+    pub fn prg_ram_block(&self) -> Result<MemoryBlock, INesError> {
         let blocks = self.prg_ram_blocks();
+        if blocks.len() == 2 {
+            // Boards like SOROM carry both work RAM and battery backed RAM. Our mappers
+            // expect a single chip, so the work RAM goes first and only the rest is saved.
+            return Ok(MemoryBlock::with_battery_backed_tail(blocks[0].as_vec(), blocks[1].as_vec()));
+        }
         if blocks.len() != 1 {
-            return Err(format!("Unsupported mixed PRG RAM types for mapper number {}", self.header.mapper_number()));
+            return Err(INesError::InvalidHeaderField{field: "PRG RAM size".to_string(), reason: format!("Unsupported mixed PRG RAM types for mapper number {}", self.header.mapper_number())});
         }
         return Ok(blocks[0].clone());
     }
 
-    pub fn chr_block(&self) -> Result<MemoryBlock, String> {
+    pub fn chr_block(&self) -> Result<MemoryBlock, INesError> {
         let blocks = self.chr_blocks();
         if blocks.len() != 1 {
-            return Err(format!("Unsupported mixed CHR types for mapper number {}", self.header.mapper_number()));
+            return Err(INesError::InvalidHeaderField{field: "CHR ROM and RAM sizes".to_string(), reason: format!("Unsupported mixed CHR types for mapper number {}", self.header.mapper_number())});
         }
         return Ok(blocks[0].clone());
     }
 }
+
+// A minimal iNES 1.0 cartridge for tests: the given mapper, 16k PRG banks and 8k CHR banks,
+// filled with a pattern so that the data isn't all zeroes
+#[cfg(test)]
+pub fn test_cartridge(mapper: u16, prg_banks: u8, chr_banks: u8) -> INesCartridge {
+    let flags_6 = ((mapper & 0x0F) << 4) as u8;
+    let flags_7 = (mapper & 0xF0) as u8;
+    let mut file = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, flags_6, flags_7, 0, 0, 0, 0, 0, 0, 0, 0];
+    for i in 0 .. (prg_banks as usize * 0x4000) + (chr_banks as usize * 0x2000) {
+        file.push((i * 7) as u8);
+    }
+    let mut reader: &[u8] = &file;
+    return INesCartridge::from_reader(&mut reader).unwrap();
+}
+
+// Common names for iNES mapper numbers, for display. Boards we don't emulate are
+// included too, so reports about unsupported games can say what they need.
+pub fn mapper_name(mapper_number: u16) -> &'static str {
+    return match mapper_number {
+        0 => "NROM",
+        1 => "MMC1 (SxROM)",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3 (TxROM)",
+        5 => "MMC5 (ExROM)",
+        7 => "AxROM",
+        9 => "MMC2 (PxROM)",
+        10 => "MMC4 (FxROM)",
+        11 => "Color Dreams",
+        13 => "CPROM",
+        16 => "Bandai FCG",
+        18 => "Jaleco SS88006",
+        19 => "Namco 129/163",
+        21 => "VRC4a / VRC4c",
+        22 => "VRC2a",
+        23 => "VRC2b / VRC4e",
+        24 => "VRC6a",
+        25 => "VRC4b / VRC4d",
+        26 => "VRC6b",
+        28 => "Action 53",
+        30 => "UNROM 512",
+        31 => "NSF subset",
+        34 => "BNROM / NINA-001",
+        66 => "GxROM",
+        68 => "Sunsoft-4",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica / Codemasters",
+        73 => "VRC3",
+        75 => "VRC1",
+        79 => "NINA-03 / NINA-06",
+        85 => "VRC7",
+        206 => "Namco 118 / DxROM",
+        682 => "Rainbow",
+        _ => "Unknown"
+    };
+}
@@ -1,11 +1,18 @@
+#[macro_use]
+extern crate lazy_static;
+
 pub mod addressing;
 pub mod apu;
+pub mod archive;
 pub mod asm;
 pub mod cartridge;
 pub mod cycle_cpu;
 pub mod fds;
+pub mod game_db;
+pub mod hash;
 pub mod tracked_events;
 pub mod ines;
+pub mod load_error;
 pub mod memory;
 pub mod memoryblock;
 pub mod mmc;
@@ -14,5 +21,10 @@ pub mod nsf;
 pub mod opcodes;
 pub mod opcode_info;
 pub mod palettes;
+pub mod patch;
+pub mod power_on;
+pub mod rom_info;
 pub mod ppu;
-pub mod unofficial_opcodes;
\ No newline at end of file
+pub mod unif;
+pub mod unofficial_opcodes;
+pub mod vgm;
\ No newline at end of file
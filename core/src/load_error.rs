@@ -0,0 +1,104 @@
+// Why a file couldn't be turned into something we can play. Each format has its own
+// parse errors; these are what the loader reports once it knows which format it has.
+
+use std::error::Error;
+use std::fmt;
+
+use ines::INesError;
+use ines::mapper_name;
+use nsf::NsfError;
+use fds::FdsError;
+use unif::UnifError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    UnrecognizedFormat,
+    UnsupportedMapper{number: u16, submapper: u8},
+    UnsupportedBoard{name: String},
+    Truncated{reason: String},
+    BiosRequired,
+    InvalidHeaderField{field: String, reason: String},
+    // The file, or the archive it was in, couldn't be read at all
+    Unreadable{reason: String},
+}
+
+impl Error for LoadError {}
+
+impl fmt::Display for LoadError  {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::UnrecognizedFormat => {write!(f, "Unrecognized file format. Supported formats are iNES, NES 2.0, NSF, FDS and UNIF.")},
+            LoadError::UnsupportedMapper{number, submapper} => {write!(f, "Mapper {} ({}), submapper {} is not supported yet.", number, mapper_name(*number), submapper)},
+            LoadError::UnsupportedBoard{name} => {write!(f, "UNIF board {} is not supported yet.", name)},
+            LoadError::Truncated{reason} => {write!(f, "The file is incomplete: {}. It may be a bad dump or a partial download.", reason)},
+            LoadError::BiosRequired => {write!(f, "This game needs the Famicom Disk System BIOS. Place disksys.rom next to the game.")},
+            LoadError::InvalidHeaderField{field, reason} => {write!(f, "Invalid header field {}: {}", field, reason)},
+            LoadError::Unreadable{reason} => {write!(f, "Couldn't read the file: {}", reason)},
+        }
+    }
+}
+
+impl LoadError {
+    // A short, stable name for each kind of failure, for scripts to match against
+    pub fn code(&self) -> &'static str {
+        return match self {
+            LoadError::UnrecognizedFormat => "unrecognized_format",
+            LoadError::UnsupportedMapper{..} => "unsupported_mapper",
+            LoadError::UnsupportedBoard{..} => "unsupported_board",
+            LoadError::Truncated{..} => "truncated",
+            LoadError::BiosRequired => "bios_required",
+            LoadError::InvalidHeaderField{..} => "invalid_header_field",
+            LoadError::Unreadable{..} => "unreadable",
+        };
+    }
+}
+
+pub fn truncated(reason: &str) -> LoadError {
+    return LoadError::Truncated{reason: reason.to_string()};
+}
+
+// Each format's own error, once we know the file is in that format. An invalid header
+// just means it isn't, and the loader moves on to the next one.
+impl From<INesError> for LoadError {
+    fn from(error: INesError) -> Self {
+        return match error {
+            INesError::InvalidHeader => LoadError::UnrecognizedFormat,
+            INesError::Unimplemented => LoadError::UnrecognizedFormat,
+            INesError::InvalidHeaderField{field, reason} => LoadError::InvalidHeaderField{field: field, reason: reason},
+            INesError::ReadError{reason} => truncated(&reason),
+        };
+    }
+}
+
+impl From<NsfError> for LoadError {
+    fn from(error: NsfError) -> Self {
+        return match error {
+            NsfError::InvalidHeader => LoadError::UnrecognizedFormat,
+            NsfError::Unimplemented => LoadError::UnrecognizedFormat,
+            NsfError::MissingChunk{chunk} => LoadError::InvalidHeaderField{field: chunk, reason: "required chunk is missing".to_string()},
+            NsfError::MalformedChunk{chunk, reason} => LoadError::InvalidHeaderField{field: chunk, reason: reason},
+            NsfError::UnsupportedChunk{chunk} => LoadError::InvalidHeaderField{field: chunk, reason: "required chunk is not supported".to_string()},
+            NsfError::ReadError{reason} => truncated(&reason),
+        };
+    }
+}
+
+impl From<FdsError> for LoadError {
+    fn from(error: FdsError) -> Self {
+        return match error {
+            FdsError::InvalidHeader => LoadError::UnrecognizedFormat,
+            FdsError::ReadError{reason} => truncated(&reason),
+        };
+    }
+}
+
+impl From<UnifError> for LoadError {
+    fn from(error: UnifError) -> Self {
+        return match error {
+            UnifError::InvalidHeader => LoadError::UnrecognizedFormat,
+            UnifError::UnknownBoard{name} => LoadError::UnsupportedBoard{name: name},
+            UnifError::MissingChunk{chunk} => LoadError::InvalidHeaderField{field: chunk, reason: "required chunk is missing".to_string()},
+            UnifError::ReadError{reason} => truncated(&reason),
+        };
+    }
+}
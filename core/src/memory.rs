@@ -1,4 +1,5 @@
 use nes::NesState;
+use vgm;
 
 pub struct CpuMemory {
     pub iram_raw: Vec<u8>,
@@ -181,6 +182,7 @@ fn _read_byte(nes: &NesState, address: u16, mapped_byte: u8) -> u8 {
 pub fn write_byte(nes: &mut NesState, address: u16, data: u8) {
     // Track every byte written, unconditionally
     // (filtering is done inside the tracker)
+    vgm::snoop_dmc_samples(nes, address, data);
     nes.event_tracker.snoop_cpu_write(nes.registers.pc, address, data);
 
     // The mapper *always* sees the write. Even to RAM, and even to internal registers.
@@ -315,6 +317,10 @@ pub fn write_byte(nes: &mut NesState, address: u16, data: u8) {
         0x4017 => {
             nes.apu.write_register(address, data);
         },
+        0x401C ..= 0x401F => {
+            // Expansion port audio (EPSM)
+            nes.apu.write_register(address, data);
+        },
         _ => () // Do nothing!
     }
 }
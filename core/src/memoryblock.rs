@@ -7,7 +7,11 @@
 pub struct MemoryBlock {
     bytes: Vec<u8>,
     readonly: bool,
-    volatile: bool
+    volatile: bool,
+    // Where the battery backed part of a mixed block begins; zero for everything else
+    battery_start: usize,
+    // Put back at this offset whenever the block is cleared or loaded from a save
+    preload: Option<(usize, Vec<u8>)>,
 }
 
 #[derive(PartialEq)]
@@ -23,6 +27,23 @@ impl MemoryBlock {
             bytes: data.to_vec(),
             readonly: memory_type == MemoryType::Rom,
             volatile: memory_type != MemoryType::NvRam,
+            battery_start: 0,
+            preload: None,
+        }
+    }
+
+    /// Boards like SOROM carry work RAM and battery backed RAM side by side. Mappers
+    /// see them as one chip with the work RAM first, but only the battery backed part
+    /// is saved or survives a power cycle, so save files hold just that.
+    pub fn with_battery_backed_tail(ram: &[u8], nvram: &[u8]) -> MemoryBlock {
+        let mut bytes = ram.to_vec();
+        bytes.extend(nvram);
+        return MemoryBlock {
+            bytes: bytes,
+            readonly: false,
+            volatile: false,
+            battery_start: ram.len(),
+            preload: None,
         }
     }
 
@@ -38,6 +59,60 @@ impl MemoryBlock {
         return self.readonly;
     }
 
+    /// The bytes which lose their contents when the console is switched off
+    pub fn volatile_bytes_mut(&mut self) -> &mut [u8] {
+        if self.readonly {
+            return &mut [];
+        }
+        if self.volatile {
+            return &mut self.bytes;
+        }
+        return &mut self.bytes[.. self.battery_start];
+    }
+
+    /// Volatile RAM loses its contents when the console is switched off. ROM and
+    /// battery backed RAM are left alone.
+    pub fn power_cycle(&mut self) {
+        for byte in self.volatile_bytes_mut().iter_mut() {
+            *byte = 0;
+        }
+        self.restore_preload();
+    }
+
+    /// Data that's in the block from the moment the console starts, whatever the block held
+    /// before, the way copier hardware loaded trainers. It stays in place through power
+    /// cycles and save loads.
+    pub fn preload(&mut self, offset: usize, data: &[u8]) {
+        self.preload = Some((offset, data.to_vec()));
+        self.restore_preload();
+    }
+
+    pub fn restore_preload(&mut self) {
+        let (offset, data) = match self.preload.take() {
+            Some(preload) => preload,
+            None => {return;}
+        };
+        for i in 0 .. data.len() {
+            self.wrapping_write(offset + i, data[i]);
+        }
+        self.preload = Some((offset, data));
+    }
+
+    /// What goes in the save file: the whole block, unless only part of it has a battery
+    pub fn battery_backed_bytes(&self) -> Vec<u8> {
+        return self.bytes[self.battery_start ..].to_vec();
+    }
+
+    pub fn load_battery_backed_bytes(&mut self, data: Vec<u8>) {
+        if self.battery_start == 0 {
+            self.bytes = data;
+        } else {
+            let saved_len = std::cmp::min(data.len(), self.bytes.len() - self.battery_start);
+            self.bytes[self.battery_start .. self.battery_start + saved_len].copy_from_slice(&data[.. saved_len]);
+        }
+        self.restore_preload();
+    }
+
     pub fn bounded_read(&self, address: usize) -> Option<u8> {
         if address >= self.len() {
             return None;
@@ -1,6 +1,7 @@
 // A very simple Mapper with no esoteric features or bank switching.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/NROM
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 
@@ -23,7 +24,7 @@ pub struct Action53 {
 }
 
 impl Action53 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Action53, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Action53, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -95,6 +96,36 @@ impl Mapper for Action53 {
             _ => Mirroring::Horizontal // unreachable
         }
     }
+
+    fn reset(&mut self) {
+        // The outer bank returns to the last 32k, which is where the menu lives
+        self.prg_outer_bank = 0xFF;
+        self.prg_mode = 0;
+        self.prg_outer_bank_size = 0;
+    }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.register_select = 0;
+        self.mirroring_mode = 0;
+        self.chr_ram_a13_a14 = 0;
+        self.prg_inner_bank = 0xFF;
+        self.prg_outer_bank = 0xFF;
+        self.prg_mode = 0;
+        self.prg_outer_bank_size = 0;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
     
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
@@ -1,8 +1,10 @@
 // AxROM, bank switchable PRG ROM, 8kb CHR RAM, basic single-screen mirroring.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/AxROM
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 
 use mmc::mapper::*;
 use mmc::mirroring;
@@ -13,10 +15,11 @@ pub struct AxRom {
     pub mirroring: Mirroring,
     pub prg_bank: usize,
     pub vram: Vec<u8>,
+    pub trainer_ram: MemoryBlock,
 }
 
 impl AxRom {
-    pub fn from_ines(ines: INesCartridge) -> Result<AxRom, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<AxRom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let chr_block = ines.chr_block()?;
 
@@ -26,6 +29,7 @@ impl AxRom {
             mirroring: Mirroring::OneScreenUpper,
             prg_bank: 0x07,
             vram: vec![0u8; 0x1000],
+            trainer_ram: MemoryBlock::new(&[], MemoryType::Ram),
         });
     }
 }
@@ -35,6 +39,28 @@ impl Mapper for AxRom {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.chr];
+    }
+
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.trainer_ram);
+    }
+
+    fn reset(&mut self) {
+        // Discrete logic with no reset input, so the bank registers keep their values
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr.power_cycle();
+        self.trainer_ram.power_cycle();
+        self.mirroring = Mirroring::OneScreenUpper;
+        self.prg_bank = 0x07;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn print_debug_status(&self) {
         println!("======= AxROM =======");
         println!("PRG Bank: {}, Mirroring Mode: {}", self.prg_bank, mirroring_mode_name(self.mirroring));
@@ -43,6 +69,7 @@ impl Mapper for AxRom {
 
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_read(address as usize - 0x7000),
             0x8000 ..= 0xFFFF => {self.prg_rom.banked_read(0x8000, self.prg_bank, (address - 0x8000) as usize)},
             _ => None
         }
@@ -50,6 +77,7 @@ impl Mapper for AxRom {
 
     fn write_cpu(&mut self, address: u16, data: u8) {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_write(address as usize - 0x7000, data),
             0x8000 ..= 0xFFFF => {
                 self.prg_bank = (data & 0x07) as usize;
                 if data & 0x10 == 0 {
@@ -3,8 +3,10 @@
 // dependency free for my own sanity.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/BNROM
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 
 use mmc::mapper::*;
 use mmc::mirroring;
@@ -15,10 +17,11 @@ pub struct BnRom {
     pub mirroring: Mirroring,
     pub prg_bank: usize,
     pub vram: Vec<u8>,
+    pub trainer_ram: MemoryBlock,
 }
 
 impl BnRom {
-    pub fn from_ines(ines: INesCartridge) -> Result<BnRom, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<BnRom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let chr_block = ines.chr_block()?;
 
@@ -28,6 +31,7 @@ impl BnRom {
             mirroring: ines.header.mirroring(),
             prg_bank: 0x07,
             vram: vec![0u8; 0x1000],
+            trainer_ram: MemoryBlock::new(&[], MemoryType::Ram),
         });
     }
 }
@@ -37,6 +41,27 @@ impl Mapper for BnRom {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.chr];
+    }
+
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.trainer_ram);
+    }
+
+    fn reset(&mut self) {
+        // Discrete logic with no reset input, so the bank registers keep their values
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr.power_cycle();
+        self.trainer_ram.power_cycle();
+        self.prg_bank = 0x07;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn print_debug_status(&self) {
         println!("======= BNROM =======");
         println!("PRG Bank: {}, Mirroring Mode: {}", self.prg_bank, mirroring_mode_name(self.mirroring));
@@ -45,6 +70,7 @@ impl Mapper for BnRom {
 
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_read(address as usize - 0x7000),
             0x8000 ..= 0xFFFF => {self.prg_rom.banked_read(0x8000, self.prg_bank, (address - 0x8000) as usize)},
             _ => None
         }
@@ -52,6 +78,7 @@ impl Mapper for BnRom {
 
     fn write_cpu(&mut self, address: u16, data: u8) {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_write(address as usize - 0x7000, data),
             0x8000 ..= 0xFFFF => {self.prg_bank = data as usize;}
             _ => {}
         }
@@ -1,8 +1,10 @@
 // CnROM, 16-32kb PRG ROM, up to 2048k CHR ROM
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/INES_Mapper_003
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 
 use mmc::mapper::*;
 use mmc::mirroring;
@@ -13,10 +15,11 @@ pub struct CnRom {
     pub mirroring: Mirroring,
     pub chr_bank: usize,
     pub vram: Vec<u8>,
+    pub trainer_ram: MemoryBlock,
 }
 
 impl CnRom {
-        pub fn from_ines(ines: INesCartridge) -> Result<CnRom, String> {
+        pub fn from_ines(ines: INesCartridge) -> Result<CnRom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let chr_block = ines.chr_block()?;
 
@@ -26,6 +29,7 @@ impl CnRom {
             mirroring: ines.header.mirroring(),
             chr_bank: 0x00,
             vram: vec![0u8; 0x1000],
+            trainer_ram: MemoryBlock::new(&[], MemoryType::Ram),
         });
     }
 }
@@ -41,8 +45,30 @@ impl Mapper for CnRom {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.chr];
+    }
+
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.trainer_ram);
+    }
+
+    fn reset(&mut self) {
+        // Discrete logic with no reset input, so the bank registers keep their values
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr.power_cycle();
+        self.trainer_ram.power_cycle();
+        self.chr_bank = 0x00;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_read(address as usize - 0x7000),
             0x8000 ..= 0xFFFF => {self.prg_rom.wrapping_read((address - 0x8000) as usize)},
             _ => None
         }
@@ -50,6 +76,7 @@ impl Mapper for CnRom {
 
     fn write_cpu(&mut self, address: u16, data: u8) {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_write(address as usize - 0x7000, data),
             0x8000 ..= 0xFFFF => {
                 self.chr_bank = data as usize;
             }
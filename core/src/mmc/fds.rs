@@ -1,12 +1,15 @@
 // A very simple Mapper with no esoteric features or bank switching.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/NROM
 
+use load_error::LoadError;
 use fds::FdsFile;
+use nsf::MIXE_FDS;
 
 use mmc::mapper::*;
 use mmc::mirroring;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use apu::PlaybackRate;
 use apu::Volume;
 use apu::Timbre;
@@ -63,10 +66,15 @@ pub struct FdsMapper {
     debug_mode: bool,
 
     audio: FdsAudio,
+    mix_gain: f32,
 }
 
 impl FdsMapper {
-    pub fn from_fds(fds: FdsFile) -> Result<FdsMapper, String> {
+    pub fn from_fds(fds: FdsFile) -> Result<FdsMapper, LoadError> {
+        if fds.disk_sides.len() == 0 {
+            return Err(LoadError::Truncated{reason: "no complete disk sides".to_string()});
+        }
+
         // FOR NOW, use just the first disk and ignore the rest
         let mut expanded_disks = Vec::new();
         for i in 0 .. fds.disk_sides.len() {
@@ -117,6 +125,7 @@ impl FdsMapper {
             debug_mode: false,
 
             audio: FdsAudio::new(),
+            mix_gain: 1.0,
         });
     }
 
@@ -291,6 +300,45 @@ impl Mapper for FdsMapper {
         return self.mirroring;
     }
 
+    fn reset(&mut self) {
+        // The RAM adapter sees the reset line too; the drive stops and pending IRQs are dropped
+        self.timer_enabled = false;
+        self.timer_pending = false;
+        self.motor_on = false;
+        self.rewinding = false;
+        self.disk_irq_enabled = false;
+        self.disk_irq_pending = false;
+        self.byte_transfer_flag = false;
+        self.transfer_reset_flag = false;
+        self.transfer_active_flag = false;
+        self.disk_ready_flag = false;
+        self.write_mode = false;
+        self.motor_delay_counter = 448;
+    }
+
+    fn power_cycle(&mut self) {
+        // The inserted disk keeps whatever was written to it, everything else starts over
+        self.reset();
+        self.prg_ram = vec![0u8; 0x8000];
+        self.chr = vec![0u8; 0x2000];
+        self.vram = vec![0u8; 0x1000];
+        self.mirroring = Mirroring::Horizontal;
+        self.timer_reload_value = 0;
+        self.timer_current_value = 0;
+        self.timer_repeat = false;
+        self.enable_disk_registers = true;
+        self.write_buffer = 0;
+        self.read_buffer = 0;
+        self.expansion_port_buffer = 0;
+        self.head_position = 0;
+        self.checksum = 0;
+        self.crc_control = false;
+        self.old_4025 = 0;
+        let lowpass_cutoff = self.audio.lowpass_cutoff;
+        self.audio = FdsAudio::new();
+        self.audio.set_lowpass(lowpass_cutoff);
+    }
+
     fn clock_cpu(&mut self) {
         self.clock_timer_irq();
         self.update_disk_sides();
@@ -303,13 +351,22 @@ impl Mapper for FdsMapper {
         
         // The maximum volume of the FDS signal on a Famicom is roughly 2.4x the maximum volume of the APU square
         let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
-        let fds_weight = nes_pulse_full_volume * 2.4;
+        let fds_weight = nes_pulse_full_volume * 2.4 * self.mix_gain;
 
         return 
             (fds_sample * fds_weight) + 
             nes_sample;
     }
 
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        outputs.push(self.mix_expansion_audio(0.0));
+    }
+
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gain = mix.gain(MIXE_FDS);
+        self.audio.set_lowpass(mix.fds_lowpass);
+    }
+
     fn irq_flag(&self) -> bool {
         return self.timer_pending || self.disk_irq_pending;
     }
@@ -704,6 +761,7 @@ pub struct FdsAudio {
     wave_position: usize,
     
     output_filter: filters::LowPassIIR,
+    pub lowpass_cutoff: f32,
     current_output: f32,
 
     debug_disable: bool,
@@ -759,6 +817,7 @@ impl FdsAudio {
             wave_position: 0,
 
             output_filter: filters::LowPassIIR::new(1_789_773.0, 2000.0),
+            lowpass_cutoff: 2000.0,
             current_output: 0.0,
 
             debug_disable: false,
@@ -929,6 +988,13 @@ impl FdsAudio {
         }
     }
 
+    pub fn set_lowpass(&mut self, cutoff_frequency: f32) {
+        if cutoff_frequency != self.lowpass_cutoff {
+            self.output_filter = filters::LowPassIIR::new(1_789_773.0, cutoff_frequency);
+            self.lowpass_cutoff = cutoff_frequency;
+        }
+    }
+
     pub fn output(&self) -> f32 {
         if self.debug_disable {
             return 0.0;
@@ -1,13 +1,16 @@
-// Sunsoft FME-7, 5A, and 5B (notably lacking expansion audio for now)
+// Sunsoft FME-7, 5A, and 5B
 // Reference implementation: https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use nsf::MIXE_S5B;
 
 use mmc::mapper::*;
 use mmc::mirroring;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use apu::PlaybackRate;
 use apu::Volume;
 use apu::Timbre;
@@ -32,10 +35,11 @@ pub struct Fme7 {
     pub irq_pending: bool,
     pub audio_command_select: u8,
     expansion_audio_chip: YM2149F,
+    mix_gain: f32,
 }
 
 impl Fme7 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Fme7, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Fme7, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -56,7 +60,8 @@ impl Fme7 {
             irq_counter: 0,
             irq_pending: false,
             audio_command_select: 0,
-            expansion_audio_chip: YM2149F::new(),
+            expansion_audio_chip: YM2149F::new("YM2149F"),
+            mix_gain: 1.0,
         });
     }
 
@@ -74,6 +79,38 @@ impl Mapper for Fme7 {
     fn mirroring(&self) -> Mirroring {
         return Mirroring::Horizontal;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr_rom];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The FME-7 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr_rom.power_cycle();
+        self.command = 0;
+        self.chr_banks = vec![0usize; 8];
+        self.prg_banks = vec![0usize; 4];
+        self.prg_ram_enabled = false;
+        self.prg_ram_selected = false;
+        self.mirroring = Mirroring::Vertical;
+        self.irq_enabled = false;
+        self.irq_counter_enabled = false;
+        self.irq_counter = 0;
+        self.irq_pending = false;
+        self.audio_command_select = 0;
+        self.expansion_audio_chip = YM2149F::new("YM2149F");
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
     
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
@@ -201,7 +238,18 @@ impl Mapper for Fme7 {
     }
 
     fn mix_expansion_audio(&self, nes_sample: f32) -> f32 {
-        return (self.expansion_audio_chip.output() - 0.5) * 1.06 - nes_sample;
+        return (self.expansion_audio_chip.output() - 0.5) * 1.06 * self.mix_gain - nes_sample;
+    }
+
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        // The noise and envelope are heard through these, and have no outputs of their own
+        for amplitude in self.expansion_audio_chip.channel_amplitudes().iter() {
+            outputs.push(amplitude * 1.06 * self.mix_gain);
+        }
+    }
+
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gain = mix.gain(MIXE_S5B);
     }
 
     fn channels(&self) ->  Vec<& dyn AudioChannelState> {
@@ -209,6 +257,8 @@ impl Mapper for Fme7 {
         channels.push(&self.expansion_audio_chip.channel_a);
         channels.push(&self.expansion_audio_chip.channel_b);
         channels.push(&self.expansion_audio_chip.channel_c);
+        channels.push(&self.expansion_audio_chip.noise);
+        channels.push(&self.expansion_audio_chip.envelope);
         return channels;
     }
 
@@ -217,6 +267,8 @@ impl Mapper for Fme7 {
         channels.push(&mut self.expansion_audio_chip.channel_a);
         channels.push(&mut self.expansion_audio_chip.channel_b);
         channels.push(&mut self.expansion_audio_chip.channel_c);
+        channels.push(&mut self.expansion_audio_chip.noise);
+        channels.push(&mut self.expansion_audio_chip.envelope);
         return channels;
     }
 
@@ -258,7 +310,18 @@ impl ToneGenerator {
     }
 }
 
+// The noise and the envelope aren't heard on their own; each is mixed into whichever of
+// A, B and C have it enabled. They still get channel entries, so that they show up in the
+// piano roll, and muting one silences everything it drives.
 pub struct NoiseGenerator {
+    pub name: String,
+    pub chip: String,
+    pub output_buffer: RingBuffer,
+    pub edge_buffer: RingBuffer,
+    pub muted: bool,
+    // Whether any channel can be heard through it, kept up to date by the chip
+    pub in_use: bool,
+
     pub period_compare: u16,
     pub period_current: u16,
     // Actually a 17bit register, higher bits are unused
@@ -266,8 +329,14 @@ pub struct NoiseGenerator {
 }
 
 impl NoiseGenerator {
-    pub fn new() -> NoiseGenerator {
+    pub fn new(chip_name: &str) -> NoiseGenerator {
         return NoiseGenerator {
+            name: String::from("Noise"),
+            chip: String::from(chip_name),
+            output_buffer: RingBuffer::new(32768),
+            edge_buffer: RingBuffer::new(32768),
+            muted: false,
+            in_use: false,
             period_compare: 0,
             period_current: 0,
             shift_register: 0b1_1111_1111_1111_1111,
@@ -275,9 +344,10 @@ impl NoiseGenerator {
     }
 
     pub fn advance_lfsr(&mut self) {
-        let tap16 = (self.shift_register & 0b0000_0000_0000_0000_0010) >> 1;
-        let tap13 = (self.shift_register & 0b0000_0000_0000_0001_0000) >> 4;
-        let new_bit_16 = tap13 ^ tap16;
+        // Bits 0 and 3 feed back into bit 16, as on the AY-3-8910
+        let tap0 = self.shift_register & 0b0000_0000_0000_0000_0001;
+        let tap3 = (self.shift_register & 0b0000_0000_0000_0000_1000) >> 3;
+        let new_bit_16 = tap0 ^ tap3;
         self.shift_register = self.shift_register >> 1;
         self.shift_register += new_bit_16 << 16
     }
@@ -293,9 +363,90 @@ impl NoiseGenerator {
     pub fn output(&self) -> u8 {
         return (self.shift_register & 0b1) as u8;
     }
+
+    pub fn record_sample(&mut self) {
+        self.output_buffer.push(if self.output() != 0 {64} else {-64});
+        self.edge_buffer.push(0);
+    }
+}
+
+impl AudioChannelState for NoiseGenerator {
+    fn name(&self) -> String {
+        return self.name.clone();
+    }
+
+    fn has_output(&self) -> bool {
+        return false;
+    }
+
+    fn chip(&self) -> String {
+        return self.chip.clone();
+    }
+
+    fn edge_buffer(&self) -> &RingBuffer {
+        return &self.edge_buffer;
+    }
+
+    fn sample_buffer(&self) -> &RingBuffer {
+        return &self.output_buffer;
+    }
+
+    fn record_current_output(&mut self) {
+        // not used, we do this manually in YM2149F
+    }
+
+    fn min_sample(&self) -> i16 {
+        return -128;
+    }
+
+    fn max_sample(&self) -> i16 {
+        return 128;
+    }
+
+    fn muted(&self) -> bool {
+        return self.muted;
+    }
+
+    fn mute(&mut self) {
+        self.muted = true;
+    }
+
+    fn unmute(&mut self) {
+        self.muted = false;
+    }
+
+    fn playing(&self) -> bool {
+        return self.in_use;
+    }
+
+    fn rate(&self) -> PlaybackRate {
+        // Period 0 behaves like period 1. Shorter periods get higher indices, like the 2A03's noise.
+        let period = self.period_compare.max(1) as usize;
+        return PlaybackRate::LfsrRate{index: 32 - period, max: 31};
+    }
+
+    fn volume(&self) -> Option<Volume> {
+        return None;
+    }
+
+    fn timbre(&self) -> Option<Timbre> {
+        return None;
+    }
+
+    fn amplitude(&self) -> f32 {
+        return if self.in_use {1.0} else {0.0};
+    }
 }
 
 pub struct EnvelopeGenerator {
+    pub name: String,
+    pub chip: String,
+    pub output_buffer: RingBuffer,
+    pub edge_buffer: RingBuffer,
+    pub muted: bool,
+    pub in_use: bool,
+    pub last_edge: bool,
+
     pub period_compare: u16,
     pub period_current: u16,
     pub continue_flag: bool,
@@ -308,8 +459,15 @@ pub struct EnvelopeGenerator {
 }
 
 impl EnvelopeGenerator {
-    pub fn new() -> EnvelopeGenerator {
+    pub fn new(chip_name: &str) -> EnvelopeGenerator {
         return EnvelopeGenerator {
+            name: String::from("Envelope"),
+            chip: String::from(chip_name),
+            output_buffer: RingBuffer::new(32768),
+            edge_buffer: RingBuffer::new(32768),
+            muted: false,
+            in_use: false,
+            last_edge: false,
             period_compare: 0,
             period_current: 0,
             continue_flag: false,
@@ -323,7 +481,10 @@ impl EnvelopeGenerator {
     }
 
     pub fn restart_envelope(&mut self) {
+        // Writing the shape also starts a fresh period
+        self.period_current = 0;
         self.holding = false;
+        self.last_edge = true;
         if self.attack_flag {
             self.increasing = true;
             self.current_value = 0;
@@ -345,44 +506,29 @@ impl EnvelopeGenerator {
         }
 
         if (self.current_value == -1) || (self.current_value == 32) {
-            // We've reached a boundary; decide how to proceed
+            // We've reached a boundary; decide how to proceed. All 16 shapes fall out of this:
+            //   0-3, 9: \___    4-7, 15: /___    8: \\\\    10: \/\/
+            //   11: \‾‾‾        12: ////         13: /‾‾‾   14: /\/\
+            let end_value = if self.increasing {31} else {0};
             if !(self.continue_flag) {
-                // non-continue mode, choose a value to hold
-                // and exit immediately. Note an oddity here,
-                // we *always* hold the value 0 in non-continue
-                // mode.
+                // Without continue, every shape ends silent, whichever way it was going
                 self.current_value = 0;
                 self.holding = true;
-            } else {
-                if self.hold_flag {
-                    // Hold this value, with an optional flip first
-                    // (this is the only way to get the more intuitive
-                    // "increase and hold" behavior)
-                    self.holding = true;
-                    if self.alternate_flag {
-                        if self.attack_flag {
-                            self.current_value = 0;
-                        } else {
-                            self.current_value = 31;
-                        }
-                    }
-                }
-
-                // Deal with switching directions, and fix the 5-bit overflow
-                if self.alternate_flag {
-                    if self.increasing {
-                        self.current_value = 31;
-                    } else {
-                        self.current_value = 0;
-                    }
-                    self.increasing = !(self.increasing);
-                } else {
-                    if self.increasing {
-                        self.current_value = 0;
-                    } else {
-                        self.current_value = 31;
-                    }
+            } else if self.hold_flag {
+                // Alternate flips it one last time before it holds
+                self.current_value = if self.alternate_flag {31 - end_value} else {end_value};
+                self.holding = true;
+            } else if self.alternate_flag {
+                // Turn around, repeating the value we ended on
+                self.current_value = end_value;
+                self.increasing = !(self.increasing);
+                if self.increasing == self.attack_flag {
+                    self.last_edge = true;
                 }
+            } else {
+                // Wrap around to the start of the ramp
+                self.current_value = 31 - end_value;
+                self.last_edge = true;
             }
         }
     }
@@ -398,10 +544,102 @@ impl EnvelopeGenerator {
     pub fn output(&self) -> usize {
         return self.current_value as usize;
     }
+
+    pub fn shape(&self) -> usize {
+        return
+            (self.hold_flag as usize) |
+            ((self.alternate_flag as usize) << 1) |
+            ((self.attack_flag as usize) << 2) |
+            ((self.continue_flag as usize) << 3);
+    }
+
+    // A repeating shape has a pitch of its own, and is how the 5B plays its buzzy bass
+    pub fn repeating(&self) -> bool {
+        return self.continue_flag && !self.hold_flag;
+    }
+
+    pub fn record_sample(&mut self) {
+        self.output_buffer.push((self.current_value as i16 - 16) * 4);
+        self.edge_buffer.push(self.last_edge as i16);
+        self.last_edge = false;
+    }
+}
+
+impl AudioChannelState for EnvelopeGenerator {
+    fn name(&self) -> String {
+        return self.name.clone();
+    }
+
+    fn has_output(&self) -> bool {
+        return false;
+    }
+
+    fn chip(&self) -> String {
+        return self.chip.clone();
+    }
+
+    fn edge_buffer(&self) -> &RingBuffer {
+        return &self.edge_buffer;
+    }
+
+    fn sample_buffer(&self) -> &RingBuffer {
+        return &self.output_buffer;
+    }
+
+    fn record_current_output(&mut self) {
+        // not used, we do this manually in YM2149F
+    }
+
+    fn min_sample(&self) -> i16 {
+        return -128;
+    }
+
+    fn max_sample(&self) -> i16 {
+        return 128;
+    }
+
+    fn muted(&self) -> bool {
+        return self.muted;
+    }
+
+    fn mute(&mut self) {
+        self.muted = true;
+    }
+
+    fn unmute(&mut self) {
+        self.muted = false;
+    }
+
+    fn playing(&self) -> bool {
+        // A one-shot envelope is just shaping the volume of the channels using it, which
+        // they already show
+        return self.in_use && self.repeating();
+    }
+
+    fn rate(&self) -> PlaybackRate {
+        // Each step takes 16 CPU cycles per count of the period; a cycle is 32 steps, or 64
+        // when it goes up and back down
+        let steps = if self.alternate_flag {64.0} else {32.0};
+        let frequency = 1_789_773.0 / (16.0 * steps * (self.period_compare.max(1) as f32));
+        return PlaybackRate::FundamentalFrequency {frequency: frequency};
+    }
+
+    fn volume(&self) -> Option<Volume> {
+        return None;
+    }
+
+    fn timbre(&self) -> Option<Timbre> {
+        return Some(Timbre::PatchIndex{ index: self.shape(), max: 15 });
+    }
+
+    fn amplitude(&self) -> f32 {
+        return if self.playing() {1.0} else {0.0};
+    }
 }
 
 pub struct YmChannel {
     pub name: String,
+    pub chip: String,
     pub output_buffer: RingBuffer,
     pub edge_buffer: RingBuffer,
     pub debug_filter: filters::HighPassIIR,
@@ -417,9 +655,10 @@ pub struct YmChannel {
 }
 
 impl YmChannel {
-    pub fn new(channel_name: &str) -> YmChannel {
+    pub fn new(channel_name: &str, chip_name: &str) -> YmChannel {
         return YmChannel {
             name: String::from(channel_name),
+            chip: String::from(chip_name),
             output_buffer: RingBuffer::new(32768),
             edge_buffer: RingBuffer::new(32768),
             debug_filter: filters::HighPassIIR::new(44100.0, 300.0),
@@ -447,7 +686,7 @@ impl AudioChannelState for YmChannel {
     }
 
     fn chip(&self) -> String {
-        return "YM2149F".to_string();
+        return self.chip.clone();
     }
 
     fn edge_buffer(&self) -> &RingBuffer {
@@ -512,6 +751,13 @@ impl AudioChannelState for YmChannel {
     }
 }
 
+// Where the output stage starts to give out, and the most it can ever put out, as fractions of
+// all three channels at full volume. A single channel tops out at a third, below the knee.
+// These are guesses at the shape, not fitted to measurements, so the stage stays off by default
+// until it can be checked against recordings of the real chip.
+const OUTPUT_KNEE: f32 = 0.4;
+const OUTPUT_CEILING: f32 = 0.7;
+
 pub struct YM2149F {
     pub channel_a: YmChannel,
     pub channel_b: YmChannel,
@@ -520,21 +766,26 @@ pub struct YM2149F {
     pub envelope: EnvelopeGenerator,
     pub clock_divider_counter: u8,
     pub volume_lut: Vec<f32>,
+    // The 5B's output stage, as an experiment; see OUTPUT_KNEE. Chips without it mix linearly.
+    pub nonlinear_output: bool,
 }
 
 impl YM2149F {
-    pub fn new() -> YM2149F {
+    pub fn new(chip_name: &str) -> YM2149F {
         return YM2149F {
-            channel_a: YmChannel::new("A"),
-            channel_b: YmChannel::new("B"),
-            channel_c: YmChannel::new("C"),
-            noise: NoiseGenerator::new(),
-            envelope: EnvelopeGenerator::new(),
+            channel_a: YmChannel::new("A", chip_name),
+            channel_b: YmChannel::new("B", chip_name),
+            channel_c: YmChannel::new("C", chip_name),
+            noise: NoiseGenerator::new(chip_name),
+            envelope: EnvelopeGenerator::new(chip_name),
             clock_divider_counter: 0,
             volume_lut: YM2149F::generate_volume_lut(),
+            nonlinear_output: false,
         }
     }
 
+    // The DAC is logarithmic: the envelope's 32 levels are 1.5 dB apart, and a channel's
+    // 4-bit volume picks every other one of them, for 3 dB steps.
     pub fn generate_volume_lut() -> Vec<f32> {
         let mut lut = vec![0f32; 32];
         lut[0] = 0.0;
@@ -583,9 +834,18 @@ impl YM2149F {
         self.channel_a.effective_amplitude = self.volume_lut[self.channel_a.effective_volume];
         self.channel_b.effective_amplitude = self.volume_lut[self.channel_b.effective_volume];
         self.channel_c.effective_amplitude = self.volume_lut[self.channel_c.effective_volume];
+        let channels = [&self.channel_a, &self.channel_b, &self.channel_c];
+        self.noise.in_use = channels.iter().any(|channel| channel.noise_enabled && channel.effective_volume > 0);
+        self.envelope.in_use = channels.iter().any(|channel| channel.envelope_enabled);
     }
 
     pub fn channel_output(&self, channel: &YmChannel) -> usize {
+        // A muted generator silences the channels it drives
+        if (channel.noise_enabled && self.noise.muted) || (channel.envelope_enabled && self.envelope.muted) {
+            return 0;
+        }
+        // Each enabled source gates the signal; with neither enabled, the volume comes
+        // through as a constant level (which is how samples get played)
         let mut signal_bit = 1u8;
         if channel.tone_enabled {
             signal_bit &= channel.tone.output();
@@ -599,11 +859,48 @@ impl YM2149F {
         return 0;
     }
 
+    // On the 5B, the channels are summed ahead of an output stage that can't keep up with all
+    // three at once: anything one channel can reach comes through as it is, but louder than
+    // that is squashed, more and more as it nears the ceiling. The curve is an approximation
+    // with the right shape, not a fit to measurements.
+    fn output_stage(&self, level: f32) -> f32 {
+        if !self.nonlinear_output || level <= OUTPUT_KNEE {
+            return level;
+        }
+        let excess = level - OUTPUT_KNEE;
+        return OUTPUT_KNEE + excess / (1.0 + excess / (OUTPUT_CEILING - OUTPUT_KNEE));
+    }
+
+    fn linear_sum(&self) -> f32 {
+        let mut sum = 0.0;
+        for channel in [&self.channel_a, &self.channel_b, &self.channel_c].iter() {
+            if !channel.muted() {
+                sum += self.volume_lut[self.channel_output(channel)];
+            }
+        }
+        return sum / 3.0;
+    }
+
+    // Each channel's share of output(), with the output stage's squashing spread across the
+    // channels in proportion to their levels, so that the shares still add up to the whole.
+    // All three come together, since the squashing depends on their sum.
+    pub fn channel_amplitudes(&self) -> [f32; 3] {
+        let mut amplitudes = [0.0f32; 3];
+        for (amplitude, channel) in amplitudes.iter_mut().zip([&self.channel_a, &self.channel_b, &self.channel_c].iter()) {
+            *amplitude = self.volume_lut[self.channel_output(channel)] / 3.0;
+        }
+        let sum: f32 = amplitudes.iter().sum();
+        if self.nonlinear_output && sum > OUTPUT_KNEE {
+            let squash = self.output_stage(sum) / sum;
+            for amplitude in amplitudes.iter_mut() {
+                *amplitude *= squash;
+            }
+        }
+        return amplitudes;
+    }
+
     pub fn output(&self) -> f32 {
-        let channel_a = if self.channel_a.muted() {0.0} else {self.volume_lut[self.channel_output(&self.channel_a)]};
-        let channel_b = if self.channel_b.muted() {0.0} else {self.volume_lut[self.channel_output(&self.channel_b)]};
-        let channel_c = if self.channel_c.muted() {0.0} else {self.volume_lut[self.channel_output(&self.channel_c)]};
-        return (channel_a + channel_b + channel_c) / 3.0;
+        return self.output_stage(self.linear_sum());
     }
 
     pub fn record_output(&mut self) {
@@ -613,6 +910,8 @@ impl YM2149F {
         self.channel_b.record_sample((self.channel_b.debug_filter.output() * -4.0) as i16);
         self.channel_c.debug_filter.consume(self.channel_output(&self.channel_c) as f32);
         self.channel_c.record_sample((self.channel_c.debug_filter.output() * -4.0) as i16);
+        self.noise.record_sample();
+        self.envelope.record_sample();
     }
 
     pub fn execute_command(&mut self, command: u8, data: u8) {
@@ -674,4 +973,37 @@ impl YM2149F {
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ines::test_cartridge;
+
+    #[test]
+    fn noise_and_envelope_have_no_outputs_of_their_own() {
+        let mapper = Fme7::from_ines(test_cartridge(69, 2, 1)).unwrap();
+        let mut outputs = Vec::new();
+        mapper.expansion_channel_outputs(&mut outputs);
+        let channels = mapper.channels();
+        assert_eq!(channels.len(), 5);
+        assert_eq!(outputs.len(), channels.iter().filter(|channel| channel.has_output()).count());
+    }
+
+    #[test]
+    fn squashed_channels_still_add_up_to_the_output() {
+        let mut chip = YM2149F::new("YM2149F");
+        chip.nonlinear_output = true;
+        // Tone and noise off, so each channel puts out its volume as a constant level
+        chip.execute_command(0x7, 0x3F);
+        chip.execute_command(0x8, 0x0F);
+        chip.execute_command(0x9, 0x0F);
+        chip.execute_command(0xA, 0x0C);
+        chip.clock();
+        let amplitudes = chip.channel_amplitudes();
+        let total: f32 = amplitudes.iter().sum();
+        assert!(chip.output() < chip.linear_sum());
+        assert!((total - chip.output()).abs() < 1e-6);
+        assert!(amplitudes[0] == amplitudes[1] && amplitudes[2] < amplitudes[0]);
+    }
+}
@@ -1,8 +1,10 @@
 // GxRom, simple bank switchable 32kb PRG ROM and 8k CHR ROM
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/GxROM
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 
 use mmc::mapper::*;
 use mmc::mirroring;
@@ -14,10 +16,11 @@ pub struct GxRom {
     pub prg_bank: usize,
     pub chr_bank: usize,
     pub vram: Vec<u8>,
+    pub trainer_ram: MemoryBlock,
 }
 
 impl GxRom {
-    pub fn from_ines(ines: INesCartridge) -> Result<GxRom, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<GxRom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let chr_block = ines.chr_block()?;
 
@@ -28,6 +31,7 @@ impl GxRom {
             prg_bank: 0x00,
             chr_bank: 0x00,
             vram: vec![0u8; 0x1000],
+            trainer_ram: MemoryBlock::new(&[], MemoryType::Ram),
         });
     }
 }
@@ -43,8 +47,31 @@ impl Mapper for GxRom {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.chr];
+    }
+
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.trainer_ram);
+    }
+
+    fn reset(&mut self) {
+        // Discrete logic with no reset input, so the bank registers keep their values
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr.power_cycle();
+        self.trainer_ram.power_cycle();
+        self.prg_bank = 0x00;
+        self.chr_bank = 0x00;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_read(address as usize - 0x7000),
             0x8000 ..= 0xFFFF => {self.prg_rom.banked_read(0x8000, self.prg_bank, (address - 0x8000) as usize)},
             _ => None
         }
@@ -52,6 +79,7 @@ impl Mapper for GxRom {
 
     fn write_cpu(&mut self, address: u16, data: u8) {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_write(address as usize - 0x7000, data),
             0x8000 ..= 0xFFFF => {
                 self.prg_bank = ((data & 0b0011_0000) >> 4) as usize;
                 self.chr_bank =  (data & 0b0000_0011) as usize;
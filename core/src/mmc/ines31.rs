@@ -2,8 +2,10 @@
 // of NSF music. It implements a common subset of the features used by NSFs. 
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/INES_Mapper_031
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 
 use mmc::mapper::*;
 use mmc::mirroring;
@@ -14,10 +16,11 @@ pub struct INes31 {
     pub mirroring: Mirroring,
     pub vram: Vec<u8>,
     pub prg_banks: Vec<usize>,
+    pub trainer_ram: MemoryBlock,
 }
 
 impl INes31 {
-    pub fn from_ines(ines: INesCartridge) -> Result<INes31, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<INes31, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let chr_block = ines.chr_block()?;
 
@@ -27,6 +30,7 @@ impl INes31 {
             mirroring: ines.header.mirroring(),
             vram: vec![0u8; 0x1000],
             prg_banks: vec![255usize; 8],
+            trainer_ram: MemoryBlock::new(&[], MemoryType::Ram),
         })
     }
 }
@@ -41,9 +45,31 @@ impl Mapper for INes31 {
     fn mirroring(&self) -> Mirroring {
         return self.mirroring;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.chr];
+    }
+
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.trainer_ram);
+    }
+
+    fn reset(&mut self) {
+        // The board has no reset input, so the banks stay where they were
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr.power_cycle();
+        self.trainer_ram.power_cycle();
+        self.prg_banks = vec![255usize; 8];
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
     
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_read(address as usize - 0x7000),
             0x8000 ..= 0x8FFF => self.prg_rom.banked_read(0x1000, self.prg_banks[0], (address as usize) - 0x8000),
             0x9000 ..= 0x9FFF => self.prg_rom.banked_read(0x1000, self.prg_banks[1], (address as usize) - 0x9000),
             0xA000 ..= 0xAFFF => self.prg_rom.banked_read(0x1000, self.prg_banks[2], (address as usize) - 0xA000),
@@ -66,6 +92,7 @@ impl Mapper for INes31 {
             0x5FFD => {self.prg_banks[5] = data as usize},
             0x5FFE => {self.prg_banks[6] = data as usize},
             0x5FFF => {self.prg_banks[7] = data as usize},
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_write(address as usize - 0x7000, data),
             _ => {}
         }
     }
@@ -0,0 +1,195 @@
+// Finds the point where an NSF track starts repeating itself. Every register write to the
+// APU and expansion chips is hashed, one hash per call to PLAY. Once some stretch of those
+// hashes is immediately followed by an exact copy of itself, we call that the loop.
+
+// A phrase repeated back to back early in a song looks exactly like a loop, and no amount
+// of watching can tell the two apart until the music moves on. Loops shorter than the
+// minimum are ignored for this reason, and a loop which stops repeating is forgotten again.
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DetectedLoop {
+    // Both measured in frames, that is, calls to PLAY since the track began
+    pub start_frame: usize,
+    pub length_frames: usize,
+}
+
+// Where an NSF track is up to, and how long the player intends to let it run
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NsfTrackTiming {
+    pub track: u8,
+    pub elapsed_cycles: u64,
+    // Including the fade. None when the player won't move on by itself.
+    pub length_cycles: Option<u64>,
+    pub fade_cycles: u64,
+    // (start, length) in cycles, once a loop has been found
+    pub detected_loop: Option<(u64, u64)>,
+}
+
+pub struct LoopDetector {
+    frame_hashes: Vec<u64>,
+    // active_frames[i] counts the frames before i with any writes at all
+    active_frames: Vec<usize>,
+    current_hash: u64,
+    current_writes: usize,
+    // For each distance back, how many of the most recent frames have matched
+    match_lengths: Vec<usize>,
+    min_loop_frames: usize,
+    max_loop_frames: usize,
+    detected: Option<DetectedLoop>,
+}
+
+impl LoopDetector {
+    pub fn new(min_loop_frames: usize, max_loop_frames: usize) -> LoopDetector {
+        return LoopDetector {
+            frame_hashes: Vec::new(),
+            active_frames: vec![0],
+            current_hash: FNV_OFFSET_BASIS,
+            current_writes: 0,
+            match_lengths: Vec::new(),
+            min_loop_frames: min_loop_frames.max(1),
+            max_loop_frames: max_loop_frames,
+            detected: None,
+        };
+    }
+
+    // Loops already found may be too short now, so this starts the search over
+    pub fn set_min_loop_frames(&mut self, min_loop_frames: usize) {
+        self.min_loop_frames = min_loop_frames.max(1);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.frame_hashes.clear();
+        self.active_frames = vec![0];
+        self.current_hash = FNV_OFFSET_BASIS;
+        self.current_writes = 0;
+        self.match_lengths.clear();
+        self.detected = None;
+    }
+
+    fn hash_byte(&mut self, byte: u8) {
+        self.current_hash ^= byte as u64;
+        self.current_hash = self.current_hash.wrapping_mul(FNV_PRIME);
+    }
+
+    pub fn record_write(&mut self, address: u16, data: u8) {
+        self.hash_byte((address & 0xFF) as u8);
+        self.hash_byte((address >> 8) as u8);
+        self.hash_byte(data);
+        self.current_writes += 1;
+    }
+
+    pub fn frames(&self) -> usize {
+        return self.frame_hashes.len();
+    }
+
+    pub fn detected_loop(&self) -> Option<DetectedLoop> {
+        return self.detected;
+    }
+
+    // Call once per PLAY, after the writes for that frame have been recorded
+    pub fn end_frame(&mut self) {
+        let frame_hash = self.current_hash;
+        let active_count = self.active_frames[self.active_frames.len() - 1] + if self.current_writes > 0 {1} else {0};
+        self.frame_hashes.push(frame_hash);
+        self.active_frames.push(active_count);
+        self.current_hash = FNV_OFFSET_BASIS;
+        self.current_writes = 0;
+
+        let newest = self.frame_hashes.len() - 1;
+        let max_distance = newest.min(self.max_loop_frames);
+        if self.match_lengths.len() < max_distance + 1 {
+            self.match_lengths.resize(max_distance + 1, 0);
+        }
+        for distance in self.min_loop_frames ..= max_distance {
+            if self.frame_hashes[newest - distance] == frame_hash {
+                self.match_lengths[distance] += 1;
+            } else {
+                self.match_lengths[distance] = 0;
+            }
+        }
+
+        match self.detected {
+            Some(detected) => {
+                if self.match_lengths[detected.length_frames] == 0 {
+                    // The music went somewhere new, so that was only a repeated phrase
+                    self.detected = None;
+                }
+            },
+            None => {}
+        }
+
+        if self.detected.is_none() {
+            // The shortest loop that has repeated in full wins
+            for distance in self.min_loop_frames ..= max_distance {
+                let matched = self.match_lengths[distance];
+                if matched >= distance {
+                    let start_frame = newest + 1 - matched - distance;
+                    // A loop of nothing but silence is better left to the silence detector
+                    let active = self.active_frames[start_frame + distance] - self.active_frames[start_frame];
+                    if active > 0 {
+                        self.detected = Some(DetectedLoop{start_frame: start_frame, length_frames: distance});
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each frame writes one register, so frames with equal values hash alike
+    fn play(detector: &mut LoopDetector, frames: &[u8]) {
+        for &value in frames {
+            detector.record_write(0x4000, value);
+            detector.end_frame();
+        }
+    }
+
+    #[test]
+    fn finds_a_short_loop_after_an_intro() {
+        // One second at 60 Hz is the shortest loop the NSF player looks for
+        let mut detector = LoopDetector::new(60, 18000);
+        let intro: Vec<u8> = (200 .. 220).collect();
+        let phrase: Vec<u8> = (0 .. 90).collect();
+        play(&mut detector, &intro);
+        play(&mut detector, &phrase);
+        assert_eq!(detector.detected_loop(), None);
+        play(&mut detector, &phrase);
+        assert_eq!(detector.detected_loop(), Some(DetectedLoop{start_frame: 20, length_frames: 90}));
+    }
+
+    #[test]
+    fn ignores_loops_below_the_minimum() {
+        let mut detector = LoopDetector::new(60, 18000);
+        let phrase: Vec<u8> = (0 .. 30).collect();
+        for _ in 0 .. 4 {
+            play(&mut detector, &phrase);
+        }
+        // Four times through a half second phrase is two loops of a second each
+        assert_eq!(detector.detected_loop(), Some(DetectedLoop{start_frame: 0, length_frames: 60}));
+        // Raising the minimum starts over, and now it takes three times through for each loop
+        detector.set_min_loop_frames(90);
+        for _ in 0 .. 6 {
+            play(&mut detector, &phrase);
+        }
+        assert_eq!(detector.detected_loop(), Some(DetectedLoop{start_frame: 0, length_frames: 90}));
+    }
+
+    #[test]
+    fn forgets_a_phrase_once_the_music_moves_on() {
+        let mut detector = LoopDetector::new(60, 18000);
+        let phrase: Vec<u8> = (0 .. 60).collect();
+        play(&mut detector, &phrase);
+        play(&mut detector, &phrase);
+        assert!(detector.detected_loop().is_some());
+        play(&mut detector, &[250]);
+        assert_eq!(detector.detected_loop(), None);
+    }
+}
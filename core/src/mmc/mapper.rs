@@ -1,4 +1,9 @@
 use apu::AudioChannelState;
+use apu::ExpansionMix;
+use memoryblock::MemoryBlock;
+use mmc::loop_detector::NsfTrackTiming;
+use mmc::mirroring::NametableMapping;
+use mmc::vrc7::Vrc7PatchSet;
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum Mirroring {
@@ -28,7 +33,20 @@ pub trait Mapper: Send {
     fn debug_read_cpu(&self, address: u16) -> Option<u8>;
     fn debug_read_ppu(&self, address: u16) -> Option<u8>;
     fn print_debug_status(&self) {}
+    // The reset button only reaches the CPU, but some boards watch for it anyway. Every
+    // board says what it does here, even if that's nothing, so none can forget to.
+    fn reset(&mut self);
+    // Return every register, and any volatile memory, to its power-on state
+    fn power_cycle(&mut self);
+    // PRG and CHR RAM on the cartridge, so the power-on fill can reach it
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {return Vec::new();}
+    // The RAM normally mapped at $6000-$7FFF, if the board has any
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {return None;}
+    // Boards with no PRG RAM map this at $7000-$71FF instead, standing in for the RAM a copier
+    // provided for the trainer. It stays empty, and reads as open bus, unless one is loaded.
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {return None;}
     fn mirroring(&self) -> Mirroring;
+    fn nametable_mapping(&self) -> NametableMapping {return NametableMapping::from_mirroring(self.mirroring());}
     fn has_sram(&self) -> bool {return false;}
     fn get_sram(&self) -> Vec<u8> {return vec![0u8; 0];}
     fn load_sram(&mut self, _: Vec<u8>) {}
@@ -38,9 +56,25 @@ pub trait Mapper: Send {
     fn channels(&self) ->  Vec<& dyn AudioChannelState> {return Vec::new();}
     fn channels_mut(&mut self) ->  Vec<&mut dyn AudioChannelState> {return Vec::new();}
     fn record_expansion_audio_output(&mut self, _nes_sample: f32) {}
+    // Each channel's own term of mix_expansion_audio, in the same order as channels(), skipping
+    // any channel without an output of its own. Used to render the channels separately.
+    fn expansion_channel_outputs(&self, _outputs: &mut Vec<f32>) {}
+    // Levels and filtering for whichever expansion chips the board has
+    fn set_expansion_mix(&mut self, _mix: &ExpansionMix) {}
     fn nsf_set_track(&mut self, _track_index: u8) {}
     fn nsf_manual_mode(&mut self) {}
+    // How many times a looping track plays before it fades out
+    fn nsf_set_loop_count(&mut self, _loops: u8) {}
+    // Repeats shorter than this aren't taken for the track's loop
+    fn nsf_set_min_loop_seconds(&mut self, _seconds: f64) {}
+    fn nsf_track_timing(&self) -> Option<NsfTrackTiming> {return None;}
     fn audio_multiplexing(&mut self, _emulate: bool) {}
+    // Which instrument ROM the VRC7 plays from
+    fn vrc7_patch_set(&mut self, _patch_set: Vrc7PatchSet) {}
+    // Reports the VRC7's envelope phases as timbre, for comparing against hardware
+    fn vrc7_adsr_research(&mut self, _enabled: bool) {}
+    // Runs the VRC7's FM core as a full YM2413, with nine channels and rhythm mode
+    fn vrc7_ym2413_mode(&mut self, _enabled: bool) {}
     fn needs_bios(&self) -> bool {return false;}
     fn load_bios(&mut self, _: Vec<u8>) {}
     fn switch_disk(&mut self, _: usize) {}
@@ -1,6 +1,9 @@
 // Set of helper functions to assist mappers with a few of the most
 // common mirroring modes. Less common mirroring modes and more complex
 // logic may still be implemented within individual mappers as needed.
+use mmc::mapper::Mirroring;
+use memoryblock::MemoryBlock;
+
 const NT_OFFSET: (u16, u16, u16, u16) = (0x000, 0x400, 0x800, 0xC00);
 
 pub fn horizontal_mirroring(read_address: u16) -> u16 {
@@ -67,3 +70,97 @@ pub fn four_banks(read_address: u16) -> u16 {
         _ => return 0, // wat
     }
 }
+
+// Mappers which need finer control than the Mirroring enum allows can describe
+// each of the four nametable quadrants independently. Most of the simple boards
+// still use the helpers above; this is for the boards that can point a quadrant
+// somewhere other than the console's internal 2k of nametable RAM (CIRAM).
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NametableSource {
+    // The two 1k pages of CIRAM inside the console
+    CiramA,
+    CiramB,
+    // A 1k page of RAM on the cartridge
+    CartRam(usize),
+    // A 1k page of CHR ROM (or CHR RAM, on boards that allow it)
+    Rom(usize),
+    // A fixed tile and attribute, returned for every fetch. Writes are ignored.
+    Fill{tile: u8, attribute: u8},
+}
+
+pub fn nametable_source_name(source: NametableSource) -> String {
+    match source {
+        NametableSource::CiramA => "CIRAM A".to_string(),
+        NametableSource::CiramB => "CIRAM B".to_string(),
+        NametableSource::CartRam(page) => format!("RAM {:02X}", page),
+        NametableSource::Rom(page) => format!("ROM {:02X}", page),
+        NametableSource::Fill{tile, attribute} => format!("FILL {:02X}:{}", tile, attribute),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct NametableMapping {
+    pub quadrants: [NametableSource; 4],
+}
+
+impl NametableMapping {
+    pub fn new(q0: NametableSource, q1: NametableSource, q2: NametableSource, q3: NametableSource) -> NametableMapping {
+        return NametableMapping {
+            quadrants: [q0, q1, q2, q3]
+        };
+    }
+
+    pub fn from_mirroring(mirroring: Mirroring) -> NametableMapping {
+        use self::NametableSource::*;
+        match mirroring {
+            Mirroring::Horizontal =>     NametableMapping::new(CiramA, CiramA, CiramB, CiramB),
+            Mirroring::Vertical =>       NametableMapping::new(CiramA, CiramB, CiramA, CiramB),
+            Mirroring::OneScreenLower => NametableMapping::new(CiramA, CiramA, CiramA, CiramA),
+            Mirroring::OneScreenUpper => NametableMapping::new(CiramB, CiramB, CiramB, CiramB),
+            // Four screen boards carry an extra 2k of RAM for the lower half. Mappers which keep
+            // all 4k in one buffer (most of them) can treat this as CIRAM + the 3rd and 4th pages
+            Mirroring::FourScreen =>     NametableMapping::new(CiramA, CiramB, CartRam(2), CartRam(3)),
+        }
+    }
+
+    pub fn source(&self, address: u16) -> NametableSource {
+        let quadrant = ((address & 0xFFF) >> 10) as usize;
+        return self.quadrants[quadrant];
+    }
+
+    pub fn read(&self, address: u16, ciram: &[u8], cart_ram: &[u8], rom: &MemoryBlock) -> Option<u8> {
+        let offset = (address & 0x3FF) as usize;
+        match self.source(address) {
+            NametableSource::CiramA => ciram.get(offset).cloned(),
+            NametableSource::CiramB => ciram.get(offset + 0x400).cloned(),
+            NametableSource::CartRam(page) => cart_ram.get(page * 0x400 + offset).cloned(),
+            NametableSource::Rom(page) => rom.banked_read(0x400, page, offset),
+            NametableSource::Fill{tile, attribute} => {
+                if offset < 0x3C0 {
+                    Some(tile)
+                } else {
+                    // Duplicate the 2-bit attribute across all four quadrants of the byte
+                    let attr = attribute & 0b11;
+                    Some(attr << 6 | attr << 4 | attr << 2 | attr)
+                }
+            }
+        }
+    }
+
+    pub fn write(&self, address: u16, data: u8, ciram: &mut [u8], cart_ram: &mut [u8], rom: &mut MemoryBlock) {
+        let offset = (address & 0x3FF) as usize;
+        match self.source(address) {
+            NametableSource::CiramA => {ciram[offset] = data},
+            NametableSource::CiramB => {ciram[offset + 0x400] = data},
+            NametableSource::CartRam(page) => {
+                let cart_ram_address = page * 0x400 + offset;
+                if cart_ram_address < cart_ram.len() {
+                    cart_ram[cart_ram_address] = data;
+                }
+            },
+            NametableSource::Rom(page) => {rom.banked_write(0x400, page, offset, data)},
+            NametableSource::Fill{..} => {}
+        }
+    }
+}
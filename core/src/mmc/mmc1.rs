@@ -1,6 +1,7 @@
 // Common mapper with bank switched PRG_ROM, CHR_ROM/RAM, and optional PRG RAM.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/MMC1
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 
@@ -30,7 +31,7 @@ pub struct Mmc1 {
 }
 
 impl Mmc1 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Mmc1, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Mmc1, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -76,6 +77,40 @@ impl Mapper for Mmc1 {
         return self.mirroring;
     }
 
+    fn reset(&mut self) {
+        // Same as writing a value with bit 7 set: the shift register clears, and
+        // the board returns to PRG mode 3 with the last bank fixed at $C000
+        self.shift_counter = 0;
+        self.shift_data = 0;
+        self.control = self.control | 0x0C;
+    }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.shift_counter = 0;
+        self.shift_data = 0;
+        self.chr_bank_0 = 0;
+        self.chr_bank_1 = 0;
+        self.prg_bank = 0x00;
+        self.prg_ram_enabled = true;
+        self.prg_ram_bank = 0;
+        self.control = 0x0C;
+        self.mirroring = Mirroring::Vertical;
+        self.last_write = false;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn read_cpu(&mut self, address: u16) -> Option<u8> {
         self.last_write = false;
         return self.debug_read_cpu(address);
@@ -286,10 +321,10 @@ impl Mapper for Mmc1 {
     }
 
     fn get_sram(&self) -> Vec<u8> {
-        return self.prg_ram.as_vec().clone();
+        return self.prg_ram.battery_backed_bytes();
     }
 
     fn load_sram(&mut self, sram_data: Vec<u8>) {
-        *self.prg_ram.as_mut_vec() = sram_data;
+        self.prg_ram.load_battery_backed_bytes(sram_data);
     }
 }
@@ -1,6 +1,7 @@
 // Advanced mapper with bank-switched PRG ROM and CHR ROM, and a scanline counter feeding into IRQ
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/MMC3
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 
@@ -45,7 +46,7 @@ pub struct Mmc3 {
 }
 
 impl Mmc3 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Mmc3, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Mmc3, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -178,6 +179,46 @@ impl Mapper for Mmc3 {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The MMC3 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.chr2_bank_0 = 0;
+        self.chr2_bank_1 = 0;
+        self.chr1_bank_2 = 0;
+        self.chr1_bank_3 = 0;
+        self.chr1_bank_4 = 0;
+        self.chr1_bank_5 = 0;
+        self.prg_bank_6 = 0;
+        self.prg_bank_7 = 0;
+        self.switch_chr_banks = false;
+        self.switch_prg_banks = false;
+        self.bank_select = 0;
+        self.irq_counter = 0;
+        self.irq_reload = 0;
+        self.irq_reload_requested = false;
+        self.irq_enabled = false;
+        self.irq_flag = false;
+        self.last_a12 = 0;
+        self.filtered_a12 = 0;
+        self.last_chr_read = 0;
+        self.low_a12_counter = 0;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn irq_flag(&self) -> bool {
         return self.irq_flag;
     }
@@ -358,10 +399,10 @@ impl Mapper for Mmc3 {
     }
 
     fn get_sram(&self) -> Vec<u8> {
-        return self.prg_ram.as_vec().clone();
+        return self.prg_ram.battery_backed_bytes();
     }
 
     fn load_sram(&mut self, sram_data: Vec<u8>) {
-        *self.prg_ram.as_mut_vec() = sram_data;
+        self.prg_ram.load_battery_backed_bytes(sram_data);
     }
 }
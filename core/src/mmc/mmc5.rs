@@ -1,13 +1,18 @@
 // Most powerful Nintendo produced mapper, supporting many advanced features
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/MMC5
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use nsf::MIXE_MMC5;
 
 use mmc::mapper::*;
+use mmc::mirroring::NametableMapping;
+use mmc::mirroring::NametableSource;
 use apu::PulseChannelState;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use apu::RingBuffer;
 use apu::filters;
 use apu::filters::DspFilter;
@@ -99,7 +104,7 @@ impl AudioChannelState for Mmc5PcmChannel {
 
     fn amplitude(&self) -> f32 {
         let buffer = self.output_buffer.buffer();
-        let mut index = (self.output_buffer.index() - 256) % buffer.len();
+        let mut index = (self.output_buffer.index() + buffer.len() - 256) % buffer.len();
         let mut max = buffer[index];
         let mut min = buffer[index];
         for _i in 0 .. 256 {
@@ -158,10 +163,11 @@ pub struct Mmc5 {
     pub pulse_2: PulseChannelState,
     pub audio_sequencer_counter: u16,
     pub pcm_channel: Mmc5PcmChannel,
+    pub mix_gain: f32,
 }
 
 impl Mmc5 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Mmc5, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Mmc5, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -217,6 +223,7 @@ impl Mmc5 {
             pulse_2: pulse2,
             audio_sequencer_counter: 0,
             pcm_channel: Mmc5PcmChannel::new(),
+            mix_gain: 1.0,
         })
     }
 
@@ -228,63 +235,30 @@ impl Mmc5 {
         return (self.prg_ram_magic_low == 0b10) && (self.prg_ram_magic_high == 0b01);
     }
 
-    // Nametable mapping helper functions, to assist with MMC5's arbitrary quadrant mapping
-    pub fn nametable_vram_low(&self, address: u16) -> u8 {
-        let masked_address = address & 0x3FF;
-        return self.vram[masked_address as usize];
-    }
-
-    pub fn nametable_vram_high(&self, address: u16) -> u8 {
-        let masked_address = address & 0x3FF;
-        return self.vram[masked_address as usize + 0x400];
-    }
-
-    pub fn nametable_ext1(&self, address: u16) -> u8 {
-        if self.extended_ram_mode == 0 || self.extended_ram_mode == 1 {
-            let masked_address = address & 0x3FF;
-            return self.extram[masked_address as usize];
-        } else {
-            return 0;
-        }
-    }
-
-    pub fn nametable_fixed(&self, address: u16) -> u8 {
-        let masked_address = address & 0x3FF;
-        if masked_address < 0x3C0 {
-            return self.fill_tile;
-        } else {
-            return self.fill_attr;
+    // MMC5 can map each quadrant to either CIRAM page, ExRAM, or the fill registers
+    pub fn nametable_source(&self, nametable_select: u8) -> NametableSource {
+        return match nametable_select {
+            0 => NametableSource::CiramA,
+            1 => NametableSource::CiramB,
+            2 => {
+                if self.extended_ram_mode == 0 || self.extended_ram_mode == 1 {
+                    NametableSource::CartRam(0)
+                } else {
+                    // ExRAM isn't available as a nametable in these modes, and reads back as 0
+                    NametableSource::Fill{tile: 0, attribute: 0}
+                }
+            },
+            _ => NametableSource::Fill{tile: self.fill_tile, attribute: self.fill_attr & 0b11},
         }
     }
 
     pub fn read_nametable(&self, address: u16) -> u8 {
-        let masked_address = address & 0xFFF;
-        let quadrant = masked_address / 0x400;
-        let nametable_select = (self.nametable_mapping >> (quadrant * 2)) & 0b11;
-        return match nametable_select {
-            0 => self.nametable_vram_low(masked_address),
-            1 => self.nametable_vram_high(masked_address),
-            2 => self.nametable_ext1(masked_address),
-            3 => self.nametable_fixed(masked_address),
-            _ => 0 // Shouldn't be reachable
-        }
+        return self.nametable_mapping().read(address, &self.vram, &self.extram, &self.chr).unwrap_or(0);
     }
 
     pub fn write_nametable(&mut self, address: u16, data: u8) {
-        let address_within_nametables = address & 0xFFF;
-        let address_within_quadrant = address & 0x3FF;
-        let quadrant = address_within_nametables / 0x400;
-        let nametable_select = (self.nametable_mapping >> (quadrant * 2)) & 0b11;
-        match nametable_select {
-            0 => {self.vram[address_within_quadrant as usize] = data;},
-            1 => {self.vram[address_within_quadrant as usize + 0x400] = data;},
-            2 => {
-                if self.extended_ram_mode == 0 || self.extended_ram_mode == 1 {
-                    self.extram[address_within_quadrant as usize] = data;
-                }
-            },
-            _ => {}
-        }
+        let mapping = self.nametable_mapping();
+        mapping.write(address, data, &mut self.vram, &mut self.extram, &mut self.chr);
     }
 
     pub fn read_prg_mode_0(&self, address: u16) -> u8 {
@@ -673,6 +647,74 @@ impl Mapper for Mmc5 {
     fn mirroring(&self) -> Mirroring {
         return self.mirroring;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The MMC5 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.ppuctrl_monitor = 0;
+        self.ppumask_monitor = 0;
+        self.prg_mode = 3;
+        self.chr_mode = 0;
+        self.prg_ram_magic_low = 0;
+        self.prg_ram_magic_high = 0;
+        self.extended_ram_mode = 0;
+        self.vram = vec![0u8; 0x1000];
+        self.extram = vec![0u8; 0x800];
+        self.nametable_mapping = 0;
+        self.fill_tile = 0;
+        self.fill_attr = 0;
+        self.prg_bank_a = 0;
+        self.prg_bank_b = 0;
+        self.prg_bank_c = 0;
+        self.prg_bank_d = 0x7F;
+        self.prg_ram_bank = 0;
+        self.prg_bank_a_isram = false;
+        self.prg_bank_b_isram = false;
+        self.prg_bank_c_isram = false;
+        self.chr_banks = vec![0usize; 8];
+        self.chr_ext_banks = vec![0usize; 8];
+        self.chr_last_write_ext = false;
+        self.ppu_read_mode = PpuMode::PpuData;
+        self.chr_bank_high_bits = 0;
+        self.irq_scanline_compare = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.in_frame = false;
+        self.current_scanline = 0;
+        self.last_ppu_fetch = 0;
+        self.last_bg_tile_fetch = 0;
+        self.consecutive_nametable_count = 0;
+        self.cpu_cycles_since_last_ppu_read = 0;
+        self.ppu_fetches_this_scanline = 0;
+        self.multiplicand_a = 0xFF;
+        self.multiplicand_b = 0xFF;
+        self.pulse_1 = PulseChannelState::new("Pulse 1", "MMC5", 1_789_773, false);
+        self.pulse_2 = PulseChannelState::new("Pulse 2", "MMC5", 1_789_773, false);
+        self.pulse_1.sweep_negate = true;
+        self.pulse_2.sweep_negate = true;
+        self.audio_sequencer_counter = 0;
+        self.pcm_channel = Mmc5PcmChannel::new();
+    }
+
+    fn nametable_mapping(&self) -> NametableMapping {
+        return NametableMapping::new(
+            self.nametable_source( self.nametable_mapping & 0b0000_0011),
+            self.nametable_source((self.nametable_mapping & 0b0000_1100) >> 2),
+            self.nametable_source((self.nametable_mapping & 0b0011_0000) >> 4),
+            self.nametable_source((self.nametable_mapping & 0b1100_0000) >> 6));
+    }
     
     fn read_cpu(&mut self, address: u16) -> Option<u8> {
         let data = self._read_cpu(address);
@@ -865,11 +907,21 @@ impl Mapper for Mmc5 {
         let pcm_output = if !self.pcm_channel.muted {(self.pcm_channel.level as f32 / 256.0) - 0.5} else {0.0};
 
         return 
-            (pulse_1_output + pulse_2_output) * 0.12 + 
-            pcm_output * 0.25 + 
+            ((pulse_1_output + pulse_2_output) * 0.12 + 
+            pcm_output * 0.25) * self.mix_gain + 
             nes_sample;
     }
 
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        outputs.push(((self.pulse_1.output() as f32 / 15.0) - 0.5) * 0.12 * self.mix_gain);
+        outputs.push(((self.pulse_2.output() as f32 / 15.0) - 0.5) * 0.12 * self.mix_gain);
+        outputs.push(((self.pcm_channel.level as f32 / 256.0) - 0.5) * 0.25 * self.mix_gain);
+    }
+
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gain = mix.gain(MIXE_MMC5);
+    }
+
     fn channels(&self) ->  Vec<& dyn AudioChannelState> {
         let mut channels: Vec<& dyn AudioChannelState> = Vec::new();
         channels.push(&self.pulse_1);
@@ -1,5 +1,6 @@
 pub mod mapper;
 pub mod mirroring;
+pub mod loop_detector;
 
 pub mod action53;
 pub mod axrom;
@@ -17,7 +18,9 @@ pub mod none;
 pub mod nrom;
 pub mod nsf;
 pub mod pxrom;
+pub mod registry;
 pub mod rainbow;
+pub mod sunsoft4;
 pub mod uxrom;
 pub mod vrc6;
 pub mod vrc7;
@@ -1,13 +1,18 @@
 // Namco 163 (and also 129), reference capabilities:
 // https://wiki.nesdev.com/w/index.php?title=INES_Mapper_019
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 use memoryblock::MemoryType;
+use nsf::MIXE_N163;
 
 use mmc::mapper::*;
+use mmc::mirroring::NametableMapping;
+use mmc::mirroring::NametableSource;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use apu::PlaybackRate;
 use apu::Volume;
 use apu::Timbre;
@@ -296,6 +301,19 @@ impl Namco163Audio {
         return mixed_sample / (self.enabled_channels() as f32);
     }
 
+    // Every enabled channel's share of the combined output, weighted against the APU pulse
+    pub fn channel_outputs(&self, outputs: &mut Vec<f32>, relative_mix: f32) {
+        let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
+        let n163_square_full_volume = 15.0 * 15.0;
+        let n163_weight = (nes_pulse_full_volume / n163_square_full_volume) * relative_mix;
+        let enabled_channels = self.enabled_channels();
+        let channels = [&self.channel1, &self.channel2, &self.channel3, &self.channel4,
+            &self.channel5, &self.channel6, &self.channel7, &self.channel8];
+        for channel in channels.iter().take(enabled_channels) {
+            outputs.push(channel.current_output / (enabled_channels as f32) * n163_weight);
+        }
+    }
+
     pub fn clock(&mut self) {
         if self.channel_delay_counter > 0 {
             self.channel_delay_counter -= 1;
@@ -367,6 +385,8 @@ pub struct Namco163 {
     pub nt_ram_at_1000: bool,
 
     pub audio_relative_mix: f32,
+    // On top of the board's own level, from the expansion mix settings
+    pub mix_gain: f32,
 }
 
 pub fn amplitude_from_db(db: f32) -> f32 {
@@ -388,7 +408,7 @@ pub fn n163_mixing_level(submapper: u8) -> f32 {
 }
 
 impl Namco163 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Namco163, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Namco163, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -415,6 +435,7 @@ impl Namco163 {
             nt_ram_at_1000: false,
 
             audio_relative_mix: n163_mixing_level(ines.header.submapper_number()),
+            mix_gain: 1.0,
         })
     }
 
@@ -436,6 +457,16 @@ impl Namco163 {
         }
     }
 
+    pub fn nametable_source(&self, bank_index: u8) -> NametableSource {
+        if bank_index >= 0xE0 {
+            if (bank_index & 0x1) == 0 {
+                return NametableSource::CiramA;
+            }
+            return NametableSource::CiramB;
+        }
+        return NametableSource::Rom(bank_index as usize);
+    }
+
     pub fn prg_ram_write_enabled(&self, address: u16) -> bool {
         if self.internal_ram_addr & 0xF0 != 0b0100_0000 {
             return false;
@@ -455,6 +486,44 @@ impl Mapper for Namco163 {
     fn mirroring(&self) -> Mirroring {
         return Mirroring::Horizontal;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The 163 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.vram.power_cycle();
+        self.expansion_audio_chip = Namco163Audio::new();
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.irq_counter = 0;
+        self.chr_banks = vec![0u8; 8];
+        self.nt_banks = vec![0u8; 4];
+        self.prg_banks = vec![0u8; 3];
+        self.internal_ram_addr = 0;
+        self.internal_ram_auto_increment = false;
+        self.sound_enabled = false;
+        self.nt_ram_at_0000 = false;
+        self.nt_ram_at_1000 = false;
+    }
+
+    fn nametable_mapping(&self) -> NametableMapping {
+        return NametableMapping::new(
+            self.nametable_source(self.nt_banks[0]),
+            self.nametable_source(self.nt_banks[1]),
+            self.nametable_source(self.nt_banks[2]),
+            self.nametable_source(self.nt_banks[3]));
+    }
     
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
@@ -503,10 +572,7 @@ impl Mapper for Namco163 {
             0x1400 => {self.read_banked_chr(address, self.chr_banks[5], self.nt_ram_at_1000)},
             0x1800 => {self.read_banked_chr(address, self.chr_banks[6], self.nt_ram_at_1000)},
             0x1C00 => {self.read_banked_chr(address, self.chr_banks[7], self.nt_ram_at_1000)},
-            0x2000 => {self.read_banked_chr(address, self.nt_banks[0], true)},
-            0x2400 => {self.read_banked_chr(address, self.nt_banks[1], true)},
-            0x2800 => {self.read_banked_chr(address, self.nt_banks[2], true)},
-            0x2C00 => {self.read_banked_chr(address, self.nt_banks[3], true)},
+            0x2000 ..= 0x2C00 => {self.nametable_mapping().read(address, self.vram.as_vec(), &[], &self.chr)},
             _ => {None}
         }
     }
@@ -522,10 +588,10 @@ impl Mapper for Namco163 {
             0x1400 => {self.write_banked_chr(address, self.chr_banks[5], self.nt_ram_at_1000, data)},
             0x1800 => {self.write_banked_chr(address, self.chr_banks[6], self.nt_ram_at_1000, data)},
             0x1C00 => {self.write_banked_chr(address, self.chr_banks[7], self.nt_ram_at_1000, data)},
-            0x2000 => {self.write_banked_chr(address, self.nt_banks[0], true, data)},
-            0x2400 => {self.write_banked_chr(address, self.nt_banks[1], true, data)},
-            0x2800 => {self.write_banked_chr(address, self.nt_banks[2], true, data)},
-            0x2C00 => {self.write_banked_chr(address, self.nt_banks[3], true, data)},
+            0x2000 ..= 0x2C00 => {
+                let mapping = self.nametable_mapping();
+                mapping.write(address, data, self.vram.as_mut_vec(), &mut [], &mut self.chr);
+            },
             _ => {}
         }
     }    
@@ -604,11 +670,19 @@ impl Mapper for Namco163 {
         
         // Normalize the N163 volume against APU pulse, then multiply that by our
         // desired relative mix:
-        let n163_weight = (nes_pulse_full_volume / n163_square_full_volume) * self.audio_relative_mix;
+        let n163_weight = (nes_pulse_full_volume / n163_square_full_volume) * self.audio_relative_mix * self.mix_gain;
 
         return nes_sample + (self.expansion_audio_chip.current_output * n163_weight);
     }
 
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        self.expansion_audio_chip.channel_outputs(outputs, self.audio_relative_mix * self.mix_gain);
+    }
+
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gain = mix.gain(MIXE_N163);
+    }
+
     fn record_expansion_audio_output(&mut self, _nes_sample: f32) {
         self.expansion_audio_chip.record_output();
     }
@@ -652,11 +726,11 @@ impl Mapper for Namco163 {
     }
 
     fn get_sram(&self) -> Vec<u8> {
-        return self.prg_ram.as_vec().clone();
+        return self.prg_ram.battery_backed_bytes();
     }
 
     fn load_sram(&mut self, sram_data: Vec<u8>) {
-        *self.prg_ram.as_mut_vec() = sram_data;
+        self.prg_ram.load_battery_backed_bytes(sram_data);
     }
 
     fn audio_multiplexing(&mut self, emulate: bool) {
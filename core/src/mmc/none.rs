@@ -14,6 +14,14 @@ impl NoneMapper {
 }
 
 impl Mapper for NoneMapper {
+    fn reset(&mut self) {
+        //Do nothing
+    }
+
+    fn power_cycle(&mut self) {
+        //Do nothing
+    }
+
     fn mirroring(&self) -> Mirroring {
         return Mirroring::Horizontal;
     }
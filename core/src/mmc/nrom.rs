@@ -1,6 +1,7 @@
 // A very simple Mapper with no esoteric features or bank switching.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/NROM
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 
@@ -17,7 +18,7 @@ pub struct Nrom {
 }
 
 impl Nrom {
-    pub fn from_ines(ines: INesCartridge) -> Result<Nrom, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Nrom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -44,6 +45,26 @@ impl Mapper for Nrom {
     fn mirroring(&self) -> Mirroring {
         return self.mirroring;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // Nothing on the board sees the reset button
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
     
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
@@ -2,16 +2,30 @@
 // player, so it will have some inherent limitations similar to most flashcarts.
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/NSF
 
+use load_error::LoadError;
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use asm::*;
 use asm::Opcode::*;
 use asm::AddressingMode::*;
 use memoryblock::MemoryBlock;
 use memoryblock::MemoryType;
+use mmc::loop_detector::LoopDetector;
+use mmc::loop_detector::NsfTrackTiming;
 use mmc::mapper::*;
 use mmc::mirroring;
 use nsf::NsfFile;
 use nsf::NsfHeader;
+use nsf::NsfMetadata;
+use nsf::MIXE_APU_SQUARE;
+use nsf::MIXE_APU_TRIANGLE;
+use nsf::MIXE_VRC6;
+use nsf::MIXE_VRC7;
+use nsf::MIXE_FDS;
+use nsf::MIXE_MMC5;
+use nsf::MIXE_N163;
+use nsf::MIXE_S5B;
+use nsf::MIXE_DEFAULT_LEVELS;
 
 // various expansion audio chips
 use mmc::vrc6::Vrc6PulseChannel;
@@ -26,6 +40,7 @@ use mmc::n163::Namco163Audio;
 use mmc::n163::n163_mixing_level;
 
 use mmc::vrc7::Vrc7Audio;
+use mmc::vrc7::Vrc7PatchSet;
 
 use mmc::fds::FdsAudio;
 
@@ -56,11 +71,28 @@ const PLAYER_CURRENT_TRACK: u16 = 0x01FD;
 const PLAYER_BUTTON_REPORT: u16 = 0x4902;
 const PLAYER_RESET_BANKS: u16 = 0x4903;
 const PLAYER_ORIGIN: u16 = 0x4A00;
+const PLAYER_NMI: u16 = PLAYER_ORIGIN + 3;
 const PLAYER_SIZE: u16 = 0x0200;
 const PLAYER_END: u16 = PLAYER_ORIGIN + PLAYER_SIZE - 1;
 
 const JOYPAD1: u16 = 0x4016;
 
+// NSF2 IRQ timer
+const IRQ_RELOAD_LOW: u16 = 0x401B;
+const IRQ_RELOAD_HIGH: u16 = 0x401C;
+const IRQ_CONTROL: u16 = 0x401D;
+
+const CPU_CLOCK_RATE: u64 = 1_789_773;
+
+// Loop detection limits, in seconds of music. Plenty of tracks loop in a second or two,
+// and a phrase repeated early is forgotten once the music moves on, so the minimum only
+// needs to rule out single notes and drum hits. Players can raise it with nsf_set_min_loop_seconds.
+const MIN_LOOP_SECONDS: f64 = 1.0;
+const MAX_LOOP_SECONDS: f64 = 300.0;
+// The loop can't be known until it has been heard twice, so that's the least we can play
+const MIN_LOOP_COUNT: u8 = 2;
+const MAX_LOOP_COUNT: u8 = 9;
+
 //const BUTTON_A: u8      = 1 << 7;
 //const BUTTON_B: u8      = 1 << 6;
 //const BUTTON_SELECT: u8 = 1 << 5;
@@ -74,6 +106,46 @@ pub fn amplitude_from_db(db: f32) -> f32 {
     return f32::powf(10.0, db / 20.0);
 }
 
+fn milliseconds_to_cycles(milliseconds: u32) -> u64 {
+    return (milliseconds as u64) * CPU_CLOCK_RATE / 1000;
+}
+
+// Our mix already matches the default mixe levels, so a file's level only needs
+// to apply the difference
+fn mixe_gain(tags: &NsfMetadata, device: u8) -> f32 {
+    return match tags.mixing_level(device) {
+        Some(level) => amplitude_from_db(((level as i32) - (MIXE_DEFAULT_LEVELS[device as usize] as i32)) as f32 / 100.0),
+        None => 1.0
+    };
+}
+
+// A file's own levels win, and anything it leaves out comes from the expansion mix settings
+fn nsf_mix_gains(tags: &NsfMetadata, mix: &ExpansionMix) -> [f32; 8] {
+    let mut mix_gains = [1.0f32; 8];
+    for device in MIXE_VRC6 ..= MIXE_S5B {
+        mix_gains[device as usize] = match tags.mixing_level(device) {
+            Some(_) => mixe_gain(tags, device),
+            None => mix.gain(device)
+        };
+    }
+    // The 2A03 reaches us already mixed, so its two levels can't be applied separately.
+    // The square level stands in for both, or the triangle level if that's all there is.
+    mix_gains[MIXE_APU_SQUARE as usize] = match (tags.mixing_level(MIXE_APU_SQUARE), tags.mixing_level(MIXE_APU_TRIANGLE)) {
+        (Some(_), _) => mixe_gain(tags, MIXE_APU_SQUARE),
+        (None, Some(_)) => mixe_gain(tags, MIXE_APU_TRIANGLE),
+        (None, None) => mix.gain(MIXE_APU_SQUARE)
+    };
+    return mix_gains;
+}
+
+fn playlist(header: &NsfHeader, tags: &NsfMetadata) -> Vec<u8> {
+    let songs: Vec<u8> = tags.playlist.iter().cloned().filter(|&song| song < header.total_songs()).collect();
+    if songs.len() > 0 {
+        return songs;
+    }
+    return (0 .. header.total_songs()).collect();
+}
+
 fn wait_for_ppu_ready() -> Opcode {
     return List(vec![
         Label(String::from("vwait1")),
@@ -210,6 +282,14 @@ fn init_track(init_address: u16) -> Opcode {
     ]);
 }
 
+fn call_play(play_address: u16, suppress_play: bool) -> Opcode {
+    // NSF2 files may ask for PLAY to be left alone entirely
+    if suppress_play {
+        return List(vec![]);
+    }
+    return Jsr(Absolute(play_address));
+}
+
 fn poll_input() -> Opcode {
     return List(vec![
         // Repeatedly attempt the joypad read until we get the same value twice
@@ -269,7 +349,7 @@ fn switch_tracks(init_address: u16) -> Opcode  {
     ]);
 }
 
-fn playback_loop(play_address: u16) -> Opcode {
+fn playback_loop(play_address: u16, suppress_play: bool) -> Opcode {
     return List(vec![
         // setup playback counter wait condition
         Lda(Absolute(PLAYER_PLAYBACK_COUNTER)),
@@ -285,7 +365,7 @@ fn playback_loop(play_address: u16) -> Opcode {
         Sta(Absolute(PLAYER_COUNTER_COMPARE)),
         // Pop A off the stack, and call the play address
         Pla,
-        Jsr(Absolute(play_address)), // not yet
+        call_play(play_address, suppress_play),
         // Preserve A, since we are about to clobber it
         Pha,
         // Poll for input (clobbers only A)
@@ -296,10 +376,97 @@ fn playback_loop(play_address: u16) -> Opcode {
     ]);
 }
 
-fn nsf_player(init_address: u16, play_address: u16) -> Vec<Opcode> {
+// NSF2 allows INIT to never return, so PLAY and the player's own housekeeping
+// move into the NMI handler instead
+fn non_returning_init(init_address: u16) -> Opcode {
+    return List(vec![
+        Label(String::from("start_track")),
+        Lda(Absolute(PLAYER_PLAYBACK_COUNTER)),
+        Sta(Absolute(PLAYER_COUNTER_COMPARE)),
+        // Enable NMI, keeping the font pattern table selected
+        Lda(Immediate(0b1000_1000)),
+        Sta(Absolute(PPUCTRL)),
+        init_track(init_address),
+        // If INIT does return after all, idle here and let NMI carry on
+        Label(String::from("idle_loop")),
+        Jmp(AbsoluteLabel(String::from("idle_loop"))),
+    ]);
+}
+
+fn nmi_handler(play_address: u16, suppress_play: bool) -> Opcode {
+    return List(vec![
+        Label(String::from("nmi_handler")),
+        Pha,
+        Txa,
+        Pha,
+        Tya,
+        Pha,
+        // Call PLAY once for every tick of the playback counter we haven't seen yet. Vblank
+        // runs ever so slightly slower than the usual play rate, so now and then that's two.
+        Label(String::from("nmi_play_loop")),
+        Lda(Absolute(PLAYER_PLAYBACK_COUNTER)),
+        Cmp(Absolute(PLAYER_COUNTER_COMPARE)),
+        Beq(RelativeLabel(String::from("nmi_done_playing"))),
+        Inc(Absolute(PLAYER_COUNTER_COMPARE)),
+        call_play(play_address, suppress_play),
+        Jmp(AbsoluteLabel(String::from("nmi_play_loop"))),
+        Label(String::from("nmi_done_playing")),
+        Jsr(AbsoluteLabel(String::from("readjoy_safe"))),
+        Lda(Absolute(PLAYER_TRACK_SELECT)),
+        Cmp(Absolute(PLAYER_CURRENT_TRACK)),
+        Beq(RelativeLabel(String::from("nmi_exit"))),
+        // Switching tracks: INIT never returned, so abandon whatever it was doing, stack and all.
+        // Clearing memory takes a while, so keep NMI out of the way until INIT is called again.
+        Sta(Absolute(PLAYER_CURRENT_TRACK)),
+        Sta(Absolute(PLAYER_RESET_BANKS)),
+        Lda(Immediate(0b0000_1000)),
+        Sta(Absolute(PPUCTRL)),
+        Ldx(Immediate(0xF0)),
+        Txs,
+        Jsr(AbsoluteLabel(String::from("initialize_apu"))),
+        Jsr(AbsoluteLabel(String::from("initialize_memory"))),
+        Jmp(AbsoluteLabel(String::from("start_track"))),
+        Label(String::from("nmi_exit")),
+        Pla,
+        Tay,
+        Pla,
+        Tax,
+        Pla,
+        Rti,
+    ]);
+}
+
+fn nsf_player(header: &NsfHeader) -> Vec<Opcode> {
+    let init_address = header.init_address();
+    let play_address = header.play_address();
+    let suppress_play = header.suppress_play();
+    let (nmi_entry, playback, nmi_routine) = if header.non_returning_init() {
+        (
+            Jmp(AbsoluteLabel(String::from("nmi_handler"))),
+            non_returning_init(init_address),
+            nmi_handler(play_address, suppress_play),
+        )
+    } else {
+        (
+            // NMI stays disabled, but keep the layout the same
+            List(vec![Rti, Nop, Nop]),
+            List(vec![
+                init_track(init_address),
+                // This loop will never exit, it drives the playback indefinitely
+                playback_loop(play_address, suppress_play),
+            ]),
+            List(vec![]),
+        )
+    };
+
     vec![
-        // Disable IRQ-based interrupts (We don't need them; NSF code by spec
-        // shouldn't use them, and if it does, shenanigans.)
+        // Entry points: reset at PLAYER_ORIGIN, and NMI right after it at PLAYER_NMI
+        Jmp(AbsoluteLabel(String::from("reset"))),
+        nmi_entry,
+
+        Label(String::from("reset")),
+        // Disable IRQ-based interrupts. NSF code by spec shouldn't use them, unless it's
+        // an NSF2 file using the IRQ timer, and that will enable them itself.
         Sei,
         // Setup the stack frame at 0x01F0 (we'll use 0x01FF for our own single variable)
         Ldx(Immediate(0xF0)),
@@ -308,12 +475,10 @@ fn nsf_player(init_address: u16, play_address: u16) -> Vec<Opcode> {
         wait_for_ppu_ready(),
         initialize_ppu(),
         Jsr(AbsoluteLabel(String::from("initialize_apu"))),
-        init_track(init_address),
-
-        // This loop will never exit, it drives the playback indefinitely
-        playback_loop(play_address),
+        playback,
 
         // subroutines
+        nmi_routine,
         poll_input(),
         switch_tracks(init_address),
         initialize_apu(),
@@ -322,6 +487,7 @@ fn nsf_player(init_address: u16, play_address: u16) -> Vec<Opcode> {
 }
 
 enum TrackAdvanceMode {
+    Loops,
     Timer,
     Silence,
     Manual
@@ -333,13 +499,21 @@ pub struct NsfMapper {
     chr: Vec<u8>,
     nsf_player: Vec<u8>,
     header: NsfHeader,
+    tags: NsfMetadata,
 
     // player state, mostly used to drive the GUI and switch tracks
     current_track: u8,
+    playlist: Vec<u8>,
+    playlist_position: usize,
     advance_mode: TrackAdvanceMode,
     current_cycles: u64,
     fade_cycles: u64,
     max_cycles: u64,
+    // used for tracks the file doesn't give a length or fade for
+    default_fade_cycles: u64,
+    default_max_cycles: u64,
+    loop_detector: LoopDetector,
+    loop_count: u8,
     current_sample: f32, // used for silence detection
     last_sample: f32,
     silence_counter: u64,
@@ -358,6 +532,17 @@ pub struct NsfMapper {
     mirroring: Mirroring,
     vram: Vec<u8>,
 
+    // NSF2 IRQ timer, and the writable vector that goes with it
+    irq_supported: bool,
+    irq_vector: [u8; 2],
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    // Relative levels from the mixe chunk, indexed by device
+    mix_gains: [f32; 8],
+
     vrc6_enabled: bool,
     vrc6_pulse1: Vrc6PulseChannel,
     vrc6_pulse2: Vrc6PulseChannel,
@@ -391,16 +576,19 @@ pub struct NsfMapper {
 }
 
 impl NsfMapper {
-    pub fn from_nsf(nsf: NsfFile) -> Result<NsfMapper, String> {
-        let nsf_player_opcodes = nsf_player(nsf.header.init_address(), nsf.header.play_address());
-        let mut nsf_player = assemble(nsf_player_opcodes, PLAYER_ORIGIN)?;
+    pub fn from_nsf(nsf: NsfFile) -> Result<NsfMapper, LoadError> {
+        let nsf_player_opcodes = nsf_player(&nsf.header);
+        let mut nsf_player = assemble(nsf_player_opcodes, PLAYER_ORIGIN).map_err(|reason| LoadError::InvalidHeaderField{field: "init or play address".to_string(), reason: reason})?;
+        if nsf_player.len() > PLAYER_SIZE as usize {
+            return Err(LoadError::InvalidHeaderField{field: "NSF2 flags".to_string(), reason: format!("player is {} bytes, which does not fit in its {} byte window", nsf_player.len(), PLAYER_SIZE)});
+        }
         nsf_player.resize(PLAYER_SIZE as usize, 0);
 
         let mut prg_rom = nsf.prg.clone();
         let mut prg_rom_banks = nsf.header.initial_banks();
         if !nsf.header.is_bank_switched() {
             if nsf.header.load_address() < 0x8000 {
-                return Err(format!("Load address {} is below 0x8000, this conflicts with player implementation. Refusing to load.", nsf.header.load_address()));
+                return Err(LoadError::InvalidHeaderField{field: "load address".to_string(), reason: format!("Load address {} is below 0x8000, this conflicts with player implementation. Refusing to load.", nsf.header.load_address())});
             }
 
             // Coerce this ROM into a bank switched format anyway, so the mapper logic becomes simplified
@@ -423,20 +611,32 @@ impl NsfMapper {
         mmc5_pulse_1.sweep_negate = true;
         mmc5_pulse_2.sweep_negate = true;
 
+        let mix_gains = nsf_mix_gains(&nsf.tags, &ExpansionMix::new());
+
         let mut mapper = NsfMapper {
             prg: MemoryBlock::new(&prg_rom, MemoryType::Ram),
             chr: font_chr,
             nsf_player: nsf_player,
             header: nsf.header,
+            playlist: playlist(&nsf.header, &nsf.tags),
+            tags: nsf.tags,
             playback_accumulator: 0.0,
             playback_period: cycles_per_play,
             playback_counter: 0,
 
-            current_track: nsf.header.starting_song(),
-            advance_mode: if nsf.header.total_songs() > 1 {TrackAdvanceMode::Timer} else {TrackAdvanceMode::Manual},
+            // Songs are numbered from 1, but some files leave the starting song at 0
+            current_track: nsf.header.starting_song().max(1),
+            playlist_position: 0,
+            advance_mode: if nsf.header.total_songs() > 1 {TrackAdvanceMode::Loops} else {TrackAdvanceMode::Manual},
             current_cycles: 0,
-            fade_cycles: 1_789_773 * 2,
-            max_cycles: 1_789_773 * 180,
+            fade_cycles: CPU_CLOCK_RATE * 2,
+            max_cycles: CPU_CLOCK_RATE * 180,
+            default_fade_cycles: CPU_CLOCK_RATE * 2,
+            default_max_cycles: CPU_CLOCK_RATE * 180,
+            loop_detector: LoopDetector::new(
+                (MIN_LOOP_SECONDS * CPU_CLOCK_RATE as f64 / cycles_per_play as f64) as usize,
+                (MAX_LOOP_SECONDS * CPU_CLOCK_RATE as f64 / cycles_per_play as f64) as usize),
+            loop_count: MIN_LOOP_COUNT,
             current_sample: 0.0,
             last_sample: 0.0,
             silence_counter: 0,
@@ -462,7 +662,7 @@ impl NsfMapper {
 
             s5b_enabled: nsf.header.s5b(),
             s5b_audio_command_select: 0,
-            s5b_expansion_audio_chip: YM2149F::new(),
+            s5b_expansion_audio_chip: YM2149F::new("YM2149F"),
 
             n163_enabled: nsf.header.n163(),
             n163_ram_addr: 0,
@@ -482,8 +682,19 @@ impl NsfMapper {
             mirroring: Mirroring::FourScreen,
             vram: vec![0u8; 0x1000],
             prg_ram: vec![0u8; 0x2000],
+
+            irq_supported: nsf.header.irq_support(),
+            irq_vector: [0u8; 2],
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+
+            mix_gains: mix_gains,
         };
 
+        let starting_song = mapper.header.starting_song();
+        mapper.select_track(starting_song);
         mapper.vrc6_write(0x9003, 0x00); // some NSF files expect VRC6 to already be enabled, so do that
         return Ok(mapper);
     }
@@ -560,23 +771,31 @@ impl NsfMapper {
         let copyright_holder = self.header.copyright_holder();
         self.draw_string(2, 14, 28, copyright_holder);
 
-        let current_seconds = self.current_cycles / 1_789_773;
-        let max_seconds = self.max_cycles / 1_789_773;
+        let track_title = self.tags.track_title((self.current_track - 1) as usize).map(|title| title.as_bytes().to_vec());
+        match track_title {
+            Some(title) => self.draw_string(2, 17, 28, title),
+            None => {}
+        }
 
-        let track_display = if self.header.total_songs() <= 1 {
+        let track_length = self.track_length().unwrap_or(self.max_cycles);
+        let current_seconds = self.current_cycles / CPU_CLOCK_RATE;
+        let max_seconds = track_length / CPU_CLOCK_RATE;
+
+        let track_display = if self.playlist.len() <= 1 {
             format!("{}", self.current_track)
         } else {
-            format!("{}  /  {}", self.current_track, self.header.total_songs())
+            format!("{}  /  {}", self.playlist_position + 1, self.playlist.len())
         };
         
         self.draw_string(4, 20, 6, "Track:".as_bytes().to_vec());
         self.draw_string(12, 20, track_display.len(), track_display.as_bytes().to_vec());
 
-        if self.header.total_songs() <= 1 {
+        if self.playlist.len() <= 1 {
             return;
         }
 
         let advance_mode_string = match self.advance_mode {
+            TrackAdvanceMode::Loops => "After Loops",
             TrackAdvanceMode::Timer => "After Length",
             TrackAdvanceMode::Silence => "After Silence",
             TrackAdvanceMode::Manual => "Manual"
@@ -592,13 +811,29 @@ impl NsfMapper {
             self.draw_string(12, 24, max_play_time.len(), max_play_time.as_bytes().to_vec());
         }
 
+        if matches!(self.advance_mode, TrackAdvanceMode::Loops) {
+            let loop_display = if self.has_fixed_length() {
+                String::from("(set by file)")
+            } else {
+                match self.detected_loop_cycles() {
+                    Some((_, loop_length)) => {
+                        let loop_seconds = loop_length / CPU_CLOCK_RATE;
+                        format!("(loop {}:{:02})", loop_seconds / 60, loop_seconds % 60)
+                    },
+                    None => String::from("(listening)")
+                }
+            };
+            let loops_display = format!("Loops:  {}  {}", self.loop_count, loop_display);
+            self.draw_string(4, 24, loops_display.len(), loops_display.as_bytes().to_vec());
+        }
+
         self.draw_string(2, (20 + self.gui_row * 2) as usize, 1, ">".as_bytes().to_vec());
 
         match self.advance_mode {
-            TrackAdvanceMode::Timer => {
+            TrackAdvanceMode::Timer | TrackAdvanceMode::Loops => {
                 let duration_display = format!("{} / {}", track_play_time, max_play_time);
                 self.draw_string(19, 27, duration_display.len(), duration_display.as_bytes().to_vec());
-                self.progress_bar(1, 27, 17, self.current_cycles as f32, self.max_cycles as f32);
+                self.progress_bar(1, 27, 17, self.current_cycles as f32, track_length as f32);
             },
             TrackAdvanceMode::Silence => {
                 let duration_display = format!("{}", track_play_time);
@@ -622,18 +857,16 @@ impl NsfMapper {
             0 => {
             /* Track select row */
               if (self.p1_pressed & BUTTON_RIGHT) != 0 {
-                    if self.current_track < self.header.total_songs() {
-                        self.current_track += 1;
-                        self.current_cycles = 0;
+                    if self.playlist_position + 1 < self.playlist.len() {
+                        self.select_playlist_position(self.playlist_position + 1);
                     }
                 }
                 if (self.p1_pressed & BUTTON_LEFT) != 0 {
-                    if self.current_track > 1 {
-                       self.current_track -= 1;
-                       self.current_cycles = 0;
+                    if self.playlist_position > 0 {
+                        self.select_playlist_position(self.playlist_position - 1);
                     }
                 }
-                if (self.p1_pressed & BUTTON_DOWN) != 0 && self.header.total_songs() > 1 {
+                if (self.p1_pressed & BUTTON_DOWN) != 0 && self.playlist.len() > 1 {
                     self.gui_row += 1;
                 }
             },
@@ -643,56 +876,143 @@ impl NsfMapper {
                     self.gui_row -= 1;
                 }
                 if (self.p1_pressed & BUTTON_RIGHT) != 0  {
-                    if matches!(self.advance_mode, TrackAdvanceMode::Silence) {
-                        self.advance_mode = TrackAdvanceMode::Manual
-                    }
-                    if matches!(self.advance_mode, TrackAdvanceMode::Timer) {
-                        self.advance_mode = TrackAdvanceMode::Silence
-                    }
+                    self.advance_mode = match self.advance_mode {
+                        TrackAdvanceMode::Loops => TrackAdvanceMode::Timer,
+                        TrackAdvanceMode::Timer => TrackAdvanceMode::Silence,
+                        _ => TrackAdvanceMode::Manual
+                    };
                 }
                 if (self.p1_pressed & BUTTON_LEFT) != 0 {
-                    if matches!(self.advance_mode, TrackAdvanceMode::Silence) {
-                        self.advance_mode = TrackAdvanceMode::Timer
-                    }
-                    if matches!(self.advance_mode, TrackAdvanceMode::Manual) {
-                        self.advance_mode = TrackAdvanceMode::Silence
-                    }
+                    self.advance_mode = match self.advance_mode {
+                        TrackAdvanceMode::Manual => TrackAdvanceMode::Silence,
+                        TrackAdvanceMode::Silence => TrackAdvanceMode::Timer,
+                        _ => TrackAdvanceMode::Loops
+                    };
                 }
-                if (self.p1_pressed & BUTTON_DOWN) != 0  && matches!(self.advance_mode, TrackAdvanceMode::Timer) {
+                if (self.p1_pressed & BUTTON_DOWN) != 0  && matches!(self.advance_mode, TrackAdvanceMode::Timer | TrackAdvanceMode::Loops) {
                     self.gui_row += 1;
                 }
 
             },
+            /* loop count row */
+            2 if matches!(self.advance_mode, TrackAdvanceMode::Loops) => {
+                if (self.p1_pressed & BUTTON_UP) != 0 {
+                    self.gui_row -= 1;
+                }
+                if (self.p1_pressed & BUTTON_RIGHT) != 0 && self.loop_count < MAX_LOOP_COUNT {
+                    self.loop_count += 1;
+                }
+                if (self.p1_pressed & BUTTON_LEFT) != 0 && self.loop_count > MIN_LOOP_COUNT {
+                    self.loop_count -= 1;
+                }
+            },
             /* timer duration row */
             2 => {
                 if (self.p1_pressed & BUTTON_UP) != 0 {
                     self.gui_row -= 1;
                 }
                 if (self.p1_pressed & BUTTON_RIGHT) != 0  {
-                    self.max_cycles += 1_789_773 * 30;
+                    self.max_cycles += CPU_CLOCK_RATE * 30;
                 }
-                if (self.p1_pressed & BUTTON_LEFT) != 0 && self.max_cycles > 1_789_773 * 30 {
-                    self.max_cycles -= 1_789_773 * 30;
+                if (self.p1_pressed & BUTTON_LEFT) != 0 && self.max_cycles > CPU_CLOCK_RATE * 30 {
+                    self.max_cycles -= CPU_CLOCK_RATE * 30;
+                }
+                // Tracks with a length of their own keep it; the rest follow this one
+                if self.tags.track_length((self.current_track - 1) as usize).is_none() {
+                    self.default_max_cycles = self.max_cycles;
                 }
             },
             _ => {}
         }
     }
 
+    // Switches to a song, numbered from 1, picking up its length and fade from the file
+    pub fn select_track(&mut self, track_number: u8) {
+        let track_number = track_number.max(1);
+        let song_index = track_number as usize - 1;
+        self.current_track = track_number;
+        self.current_cycles = 0;
+        self.silence_counter = 0;
+        self.loop_detector.reset();
+        self.fade_cycles = match self.tags.track_fade(song_index) {
+            Some(milliseconds) => milliseconds_to_cycles(milliseconds),
+            None => self.default_fade_cycles
+        };
+        // The file's length doesn't include the fade, which plays after it
+        self.max_cycles = match self.tags.track_length(song_index) {
+            Some(milliseconds) => milliseconds_to_cycles(milliseconds) + self.fade_cycles,
+            None => self.default_max_cycles
+        };
+        match self.playlist.iter().position(|&song| song as usize == song_index) {
+            Some(position) => {self.playlist_position = position;},
+            None => {}
+        }
+    }
+
+    pub fn select_playlist_position(&mut self, position: usize) {
+        let song = self.playlist[position];
+        self.select_track(song + 1);
+        // The same song can appear more than once, so don't rely on select_track's search
+        self.playlist_position = position;
+    }
+
     pub fn advance_track_with_wraparound(&mut self) {
-        if self.current_track < self.header.total_songs() {
-            self.current_track += 1;
+        if self.playlist_position + 1 < self.playlist.len() {
+            self.select_playlist_position(self.playlist_position + 1);
         } else {
-            self.current_track = 1;
+            self.select_playlist_position(0);
+        }
+    }
+
+    fn has_fixed_length(&self) -> bool {
+        return self.tags.track_length((self.current_track - 1) as usize).is_some();
+    }
+
+    fn seconds_to_frames(&self, seconds: f64) -> usize {
+        return (seconds * CPU_CLOCK_RATE as f64 / self.playback_period as f64) as usize;
+    }
+
+    fn frames_to_cycles(&self, frames: usize) -> u64 {
+        return ((frames as f64) * (self.playback_period as f64)) as u64;
+    }
+
+    // (start, length) of the loop, in cycles since the track began
+    fn detected_loop_cycles(&self) -> Option<(u64, u64)> {
+        return self.loop_detector.detected_loop().map(|detected| {
+            return (self.frames_to_cycles(detected.start_frame), self.frames_to_cycles(detected.length_frames));
+        });
+    }
+
+    // When the player will move on to the next track, fade included
+    pub fn track_length(&self) -> Option<u64> {
+        match self.advance_mode {
+            TrackAdvanceMode::Timer => return Some(self.max_cycles),
+            TrackAdvanceMode::Loops => {
+                // A length from the file beats our guess. Until we have a guess, the timer
+                // is the fallback for songs which never repeat.
+                if self.has_fixed_length() {
+                    return Some(self.max_cycles);
+                }
+                return match self.loop_detector.detected_loop() {
+                    Some(detected) => {
+                        let loops_end = detected.start_frame + detected.length_frames * (self.loop_count as usize);
+                        Some(self.frames_to_cycles(loops_end) + self.fade_cycles)
+                    },
+                    None => Some(self.max_cycles)
+                };
+            },
+            _ => return None
         }
-        self.current_cycles = 0;
     }
 
     pub fn update_player(&mut self) {
         match self.advance_mode {
-            TrackAdvanceMode::Timer => {
-                if self.current_cycles > self.max_cycles {
-                    self.advance_track_with_wraparound();
+            TrackAdvanceMode::Timer | TrackAdvanceMode::Loops => {
+                match self.track_length() {
+                    Some(length) if self.current_cycles > length => {
+                        self.advance_track_with_wraparound();
+                    },
+                    _ => {}
                 }
                 // *also* advance when the silence threshold is passed, for short tracks in an otherwise
                 // loopy album
@@ -1103,10 +1423,54 @@ impl NsfMapper {
         self.fds_audio.clock_cpu();
     }
 
+    // Writes which can change what we hear, for the loop detector
+    fn is_audio_register(&self, address: u16) -> bool {
+        return match address {
+            0x4000 ..= 0x4013 | 0x4015 | 0x4017 => true,
+            0x4040 ..= 0x408A => self.fds_enabled,
+            0x4800 | 0xF800 => self.n163_enabled,
+            0x5000 ..= 0x5015 => self.mmc5_enabled,
+            0x9000 ..= 0x9003 | 0xA000 ..= 0xA002 | 0xB000 ..= 0xB002 => self.vrc6_enabled,
+            0x9010 | 0x9030 => self.vrc7_enabled,
+            0xC000 | 0xE000 => self.s5b_enabled,
+            _ => false
+        };
+    }
+
+    fn irq_write(&mut self, address: u16, data: u8) {
+        match address {
+            IRQ_RELOAD_LOW => {self.irq_reload = (self.irq_reload & 0xFF00) | (data as u16);},
+            IRQ_RELOAD_HIGH => {self.irq_reload = (self.irq_reload & 0x00FF) | ((data as u16) << 8);},
+            IRQ_CONTROL => {
+                // Any write acknowledges the IRQ and restarts the counter
+                self.irq_enabled = (data & 0b0000_0001) != 0;
+                self.irq_counter = self.irq_reload;
+                self.irq_pending = false;
+            },
+            0xFFFE ..= 0xFFFF => {self.irq_vector[(address - 0xFFFE) as usize] = data;},
+            _ => {}
+        }
+    }
+
+    fn clock_irq(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.irq_pending = true;
+            self.irq_counter = self.irq_reload;
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
     fn fade_weight(&self) -> f32 {
         match self.advance_mode {
-            TrackAdvanceMode::Timer => {
-                let fade_start = self.max_cycles - self.fade_cycles;
+            TrackAdvanceMode::Timer | TrackAdvanceMode::Loops => {
+                if self.fade_cycles == 0 {
+                    return 1.0;
+                }
+                let fade_start = self.track_length().unwrap_or(self.max_cycles).saturating_sub(self.fade_cycles);
                 if self.current_cycles < fade_start {
                     return 1.0
                 }
@@ -1126,22 +1490,101 @@ impl NsfMapper {
 
 impl Mapper for NsfMapper {
     fn nsf_set_track(&mut self, track_index: u8) {
-        self.current_track = track_index;
+        self.select_track(track_index);
     }
 
     fn nsf_manual_mode(&mut self) {
         self.advance_mode = TrackAdvanceMode::Manual;
     }
 
+    fn nsf_set_loop_count(&mut self, loops: u8) {
+        self.loop_count = loops.max(MIN_LOOP_COUNT).min(MAX_LOOP_COUNT);
+        self.advance_mode = TrackAdvanceMode::Loops;
+    }
+
+    fn nsf_set_min_loop_seconds(&mut self, seconds: f64) {
+        let min_loop_frames = self.seconds_to_frames(seconds);
+        self.loop_detector.set_min_loop_frames(min_loop_frames);
+    }
+
+    fn nsf_track_timing(&self) -> Option<NsfTrackTiming> {
+        return Some(NsfTrackTiming {
+            track: self.current_track,
+            elapsed_cycles: self.current_cycles,
+            length_cycles: self.track_length(),
+            fade_cycles: self.fade_cycles,
+            detected_loop: self.detected_loop_cycles(),
+        });
+    }
+
     fn mirroring(&self) -> Mirroring {
         return self.mirroring;
     }
 
+    fn reset(&mut self) {
+        // The player re-runs INIT for the current track from its reset vector
+        self.current_cycles = 0;
+        self.silence_counter = 0;
+        self.playback_accumulator = 0.0;
+        self.playback_counter = 0;
+        self.gui_row = 0;
+        self.p1_held = 0;
+        self.p1_pressed = 0;
+    }
+
+    fn power_cycle(&mut self) {
+        self.reset();
+        let starting_song = self.header.starting_song();
+        self.select_track(starting_song);
+        self.irq_vector = [0u8; 2];
+        self.irq_reload = 0;
+        self.irq_counter = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.current_sample = 0.0;
+        self.last_sample = 0.0;
+        self.prg_ram = vec![0u8; 0x2000];
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+
+        self.vrc6_pulse1 = Vrc6PulseChannel::new("Pulse 1");
+        self.vrc6_pulse2 = Vrc6PulseChannel::new("Pulse 2");
+        self.vrc6_sawtooth = Vrc6SawtoothChannel::new();
+
+        self.mmc5_multiplicand_a = 0;
+        self.mmc5_multiplicand_b = 0;
+        self.mmc5_pulse_1 = PulseChannelState::new("Pulse 1", "MMC5", 1_789_773, false);
+        self.mmc5_pulse_2 = PulseChannelState::new("Pulse 2", "MMC5", 1_789_773, false);
+        self.mmc5_pulse_1.sweep_negate = true;
+        self.mmc5_pulse_2.sweep_negate = true;
+        self.mmc5_audio_sequencer_counter = 0;
+        self.mmc5_pcm_channel = Mmc5PcmChannel::new();
+        self.mmc5_exram = vec![0u8; 0x400];
+
+        self.s5b_audio_command_select = 0;
+        self.s5b_expansion_audio_chip = YM2149F::new("YM2149F");
+
+        self.n163_ram_addr = 0;
+        self.n163_ram_auto_increment = false;
+        self.n163_expansion_audio_chip = Namco163Audio::new();
+
+        self.vrc7_audio.power_cycle();
+        self.vrc7_audio_register = 0;
+
+        let fds_lowpass_cutoff = self.fds_audio.lowpass_cutoff;
+        self.fds_audio = FdsAudio::new();
+        self.fds_audio.set_lowpass(fds_lowpass_cutoff);
+
+        self.vrc6_write(0x9003, 0x00);
+    }
+
     fn clock_cpu(&mut self) {
         self.playback_accumulator += 1.0;
         if self.playback_accumulator > self.playback_period {
             self.playback_counter = self.playback_counter.wrapping_add(1);
             self.playback_accumulator -= self.playback_period;
+            self.loop_detector.end_frame();
             self.update_gui();
         }
 
@@ -1151,6 +1594,7 @@ impl Mapper for NsfMapper {
         self.clock_n163();
         self.clock_vrc7();
         self.clock_fds();
+        self.clock_irq();
         self.current_cycles += 1;
 
         if self.detect_silence() {
@@ -1160,18 +1604,69 @@ impl Mapper for NsfMapper {
         }
     }
 
+    fn irq_flag(&self) -> bool {
+        return self.irq_pending;
+    }
+
     fn mix_expansion_audio(&self, nes_sample: f32) -> f32 {
         let mixed_sample =  
-            self.vrc6_output() +
-            self.mmc5_output() +
-            self.s5b_output() +
-            self.n163_output() + 
-            self.vrc7_output() + 
-            self.fds_output() + 
-            nes_sample;
+            self.vrc6_output() * self.mix_gains[MIXE_VRC6 as usize] +
+            self.mmc5_output() * self.mix_gains[MIXE_MMC5 as usize] +
+            self.s5b_output() * self.mix_gains[MIXE_S5B as usize] +
+            self.n163_output() * self.mix_gains[MIXE_N163 as usize] + 
+            self.vrc7_output() * self.mix_gains[MIXE_VRC7 as usize] + 
+            self.fds_output() * self.mix_gains[MIXE_FDS as usize] + 
+            nes_sample * self.mix_gains[MIXE_APU_SQUARE as usize];
         return mixed_sample * self.fade_weight();
     }
 
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        let fade = self.fade_weight();
+        if self.vrc6_enabled {
+            let vrc6_gain = self.mix_gains[MIXE_VRC6 as usize] * fade;
+            let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
+            let vrc6_weight = nes_pulse_full_volume / 15.0;
+            outputs.push(self.vrc6_pulse1.output() as f32 * vrc6_weight * vrc6_gain);
+            outputs.push(self.vrc6_pulse2.output() as f32 * vrc6_weight * vrc6_gain);
+            outputs.push(self.vrc6_sawtooth.output() as f32 * vrc6_weight * vrc6_gain);
+        }
+        if self.mmc5_enabled {
+            let mmc5_gain = self.mix_gains[MIXE_MMC5 as usize] * fade;
+            outputs.push(((self.mmc5_pulse_1.output() as f32 / 15.0) - 0.5) * 0.12 * mmc5_gain);
+            outputs.push(((self.mmc5_pulse_2.output() as f32 / 15.0) - 0.5) * 0.12 * mmc5_gain);
+            outputs.push(((self.mmc5_pcm_channel.level as f32 / 256.0) - 0.5) * 0.25 * mmc5_gain);
+        }
+        if self.s5b_enabled {
+            let s5b_gain = self.mix_gains[MIXE_S5B as usize] * fade;
+            for amplitude in self.s5b_expansion_audio_chip.channel_amplitudes().iter() {
+                outputs.push(amplitude * -1.06 * s5b_gain);
+            }
+        }
+        if self.n163_enabled {
+            let n163_gain = self.mix_gains[MIXE_N163 as usize] * fade;
+            let first_channel = outputs.len();
+            self.n163_expansion_audio_chip.channel_outputs(outputs, self.n163_mix);
+            for output in outputs[first_channel ..].iter_mut() {
+                *output *= n163_gain;
+            }
+        }
+        if self.vrc7_enabled {
+            let vrc7_gain = self.mix_gains[MIXE_VRC7 as usize] * fade;
+            let vrc7_weight = amplitude_from_db(11.00 - 3.50 - 6.23) / 256.0 / 6.0;
+            for index in 0 .. self.vrc7_audio.channel_count() {
+                outputs.push(self.vrc7_audio.channel(index).output() as f32 * vrc7_weight * vrc7_gain);
+            }
+        }
+        if self.fds_enabled {
+            outputs.push(self.fds_output() * self.mix_gains[MIXE_FDS as usize] * fade);
+        }
+    }
+
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gains = nsf_mix_gains(&self.tags, mix);
+        self.fds_audio.set_lowpass(mix.fds_lowpass);
+    }
+
     fn channels(&self) ->  Vec<& dyn AudioChannelState> {
         let mut channels: Vec<& dyn AudioChannelState> = Vec::new();
         if self.vrc6_enabled {
@@ -1188,6 +1683,8 @@ impl Mapper for NsfMapper {
             channels.push(&self.s5b_expansion_audio_chip.channel_a);
             channels.push(&self.s5b_expansion_audio_chip.channel_b);
             channels.push(&self.s5b_expansion_audio_chip.channel_c);
+            channels.push(&self.s5b_expansion_audio_chip.noise);
+            channels.push(&self.s5b_expansion_audio_chip.envelope);
         }
         if self.n163_enabled {
             let mut n163_channels: Vec<& dyn AudioChannelState> = Vec::new();
@@ -1204,13 +1701,7 @@ impl Mapper for NsfMapper {
             channels.append(&mut n163_channels);
         }
         if self.vrc7_enabled {
-            let mut vrc7_channels: Vec<& dyn AudioChannelState> = Vec::new();
-            vrc7_channels.push(&self.vrc7_audio.channel1);
-            vrc7_channels.push(&self.vrc7_audio.channel2);
-            vrc7_channels.push(&self.vrc7_audio.channel3);
-            vrc7_channels.push(&self.vrc7_audio.channel4);
-            vrc7_channels.push(&self.vrc7_audio.channel5);
-            vrc7_channels.push(&self.vrc7_audio.channel6);
+            let mut vrc7_channels = self.vrc7_audio.channels();
             channels.append(&mut vrc7_channels);
         }
         if self.fds_enabled {
@@ -1235,6 +1726,8 @@ impl Mapper for NsfMapper {
             channels.push(&mut self.s5b_expansion_audio_chip.channel_a);
             channels.push(&mut self.s5b_expansion_audio_chip.channel_b);
             channels.push(&mut self.s5b_expansion_audio_chip.channel_c);
+            channels.push(&mut self.s5b_expansion_audio_chip.noise);
+            channels.push(&mut self.s5b_expansion_audio_chip.envelope);
         }
         if self.n163_enabled {
             let mut n163_channels: Vec<&mut dyn AudioChannelState> = Vec::new();
@@ -1251,13 +1744,7 @@ impl Mapper for NsfMapper {
             channels.append(&mut n163_channels);
         }
         if self.vrc7_enabled {
-            let mut vrc7_channels: Vec<&mut dyn AudioChannelState> = Vec::new();
-            vrc7_channels.push(&mut self.vrc7_audio.channel1);
-            vrc7_channels.push(&mut self.vrc7_audio.channel2);
-            vrc7_channels.push(&mut self.vrc7_audio.channel3);
-            vrc7_channels.push(&mut self.vrc7_audio.channel4);
-            vrc7_channels.push(&mut self.vrc7_audio.channel5);
-            vrc7_channels.push(&mut self.vrc7_audio.channel6);
+            let mut vrc7_channels = self.vrc7_audio.channels_mut();
             channels.append(&mut vrc7_channels);
         }
         if self.fds_enabled {
@@ -1323,9 +1810,12 @@ impl Mapper for NsfMapper {
             0xC000 ..= 0xCFFF => self.prg.banked_read(0x1000, self.prg_rom_banks[4], (address - 0xC000) as usize),
             0xD000 ..= 0xDFFF => self.prg.banked_read(0x1000, self.prg_rom_banks[5], (address - 0xD000) as usize),
             0xE000 ..= 0xEFFF => self.prg.banked_read(0x1000, self.prg_rom_banks[6], (address - 0xE000) as usize),
+            0xFFFA if self.header.non_returning_init() => {Some(((PLAYER_NMI & 0x00FF) >> 0) as u8)}, // nmi vector
+            0xFFFB if self.header.non_returning_init() => {Some(((PLAYER_NMI & 0xFF00) >> 8) as u8)},
             0xF000 ..= 0xFFFB => self.prg.banked_read(0x1000, self.prg_rom_banks[7], (address - 0xF000) as usize),
             0xFFFC => {Some(((PLAYER_ORIGIN & 0x00FF) >> 0) as u8)}, // reset vector
             0xFFFD => {Some(((PLAYER_ORIGIN & 0xFF00) >> 8) as u8)},
+            0xFFFE ..= 0xFFFF if self.irq_supported => Some(self.irq_vector[(address - 0xFFFE) as usize]),
             0xFFFE ..= 0xFFFF => self.prg.banked_read(0x1000, self.prg_rom_banks[7], (address - 0xF000) as usize),
             _ => None
         }
@@ -1354,6 +1844,12 @@ impl Mapper for NsfMapper {
             0x6000 ..= 0x7FFF => {self.prg_ram[(address - 0x6000) as usize] = data},
             _ => {}
         }
+        if self.irq_supported {
+            self.irq_write(address, data);
+        }
+        if self.is_audio_register(address) {
+            self.loop_detector.record_write(address, data);
+        }
         if self.vrc6_enabled {
             self.vrc6_write(address, data);
         }
@@ -1402,4 +1898,46 @@ impl Mapper for NsfMapper {
     fn audio_multiplexing(&mut self, emulate: bool) {
         self.n163_expansion_audio_chip.emulate_multiplexing = emulate;
     }
+
+    fn vrc7_patch_set(&mut self, patch_set: Vrc7PatchSet) {
+        self.vrc7_audio.set_patch_set(patch_set);
+    }
+
+    fn vrc7_adsr_research(&mut self, enabled: bool) {
+        self.vrc7_audio.set_adsr_research(enabled);
+    }
+
+    fn vrc7_ym2413_mode(&mut self, enabled: bool) {
+        self.vrc7_audio.set_ym2413_mode(enabled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A one song NSF that loads at $8000 and plays at 60 Hz
+    fn nsf_starting_at(starting_song: u8) -> NsfFile {
+        let mut file = vec![0u8; 0x80];
+        file[0 .. 5].copy_from_slice(b"NESM\x1A");
+        file[0x05] = 1;
+        file[0x06] = 1;
+        file[0x07] = starting_song;
+        file[0x08 .. 0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        file[0x6E .. 0x70].copy_from_slice(&16639u16.to_le_bytes());
+        file.extend(vec![0x60u8; 0x100]);
+        let mut reader: &[u8] = &file;
+        return NsfFile::from_reader(&mut reader).unwrap();
+    }
+
+    #[test]
+    fn starting_song_zero_plays_the_first_track() {
+        let mut mapper = NsfMapper::from_nsf(nsf_starting_at(0)).unwrap();
+        assert_eq!(mapper.current_track, 1);
+        mapper.update_display();
+        mapper.gui_row = 2;
+        mapper.process_input();
+        mapper.select_track(0);
+        assert_eq!(mapper.current_track, 1);
+    }
 }
@@ -1,6 +1,7 @@
 // MMC2, a somewhat advanced bank switcher with extended CHR memory
 // https://wiki.nesdev.com/w/index.php/MMC2
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 
@@ -23,7 +24,7 @@ pub struct PxRom {
 }
 
 impl PxRom {
-    pub fn from_ines(ines: INesCartridge) -> Result<PxRom, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<PxRom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -58,6 +59,34 @@ impl Mapper for PxRom {
     fn mirroring(&self) -> Mirroring {
         return self.mirroring;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The MMC2 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.mirroring = Mirroring::Vertical;
+        self.chr_0_latch = 0;
+        self.chr_0_fd_bank = 0;
+        self.chr_0_fe_bank = 0;
+        self.chr_1_latch = 0;
+        self.chr_1_fd_bank = 0;
+        self.chr_1_fe_bank = 0;
+        self.prg_bank = 0;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
   
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
@@ -9,13 +9,16 @@
 // notice removed. Until then, please be careful relying on this during
 // new homebrew development.
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
 use memoryblock::MemoryType;
+use nsf::MIXE_VRC6;
 
 use mmc::mapper::*;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use mmc::vrc6::Vrc6PulseChannel;
 use mmc::vrc6::Vrc6SawtoothChannel;
 
@@ -123,6 +126,7 @@ pub struct Rainbow {
     vrc6_exp6: bool,
     vrc6_exp9: bool,
     vrc6_zpcm: bool,
+    vrc6_mix_gain: f32,
 
     cpu_irq_counter: u16,
     cpu_irq_latch: u16,
@@ -178,7 +182,7 @@ pub struct Rainbow {
 }
 
 impl Rainbow {
-    pub fn from_ines(ines: INesCartridge) -> Result<Rainbow, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Rainbow, LoadError> {
         // PRG ROM should always be present. We assume it is self-flashable
         // for emulation purposes.
         let prg_rom_block = ines.prg_rom_block();
@@ -197,7 +201,7 @@ impl Rainbow {
         };
 
         if ines.header.chr_ram_size() > 0 && ines.header.chr_sram_size() > 0 {
-            return Err(format!("Rainbow: Unsupported mixed CHR types for mapper number {}", ines.header.mapper_number()));
+            return Err(LoadError::InvalidHeaderField{field: "CHR RAM size".to_string(), reason: format!("Rainbow: Unsupported mixed CHR types for mapper number {}", ines.header.mapper_number())});
         }
 
         let chr_ram_block = if ines.header.chr_ram_size() > 0 {
@@ -284,6 +288,7 @@ impl Rainbow {
             vrc6_exp6: true,
             vrc6_exp9: true,
             vrc6_zpcm: false,
+            vrc6_mix_gain: 1.0,
 
             cpu_irq_counter: 0xFFFF,
             cpu_irq_latch: 0xFFFF,
@@ -816,6 +821,128 @@ impl Mapper for Rainbow {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr_ram];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The Rainbow's FPGA has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr_ram.power_cycle();
+        self.ciram.power_cycle();
+        self.fpga_ram.power_cycle();
+        for i in 0 .. 4096 {
+            self.fpga_ram.wrapping_write(i, BOOTROM_FPGA[i]);
+        }
+
+        self.prg_rom_mode = PrgRomBankingMode::Mode0Bank1x32k;
+        self.prg_ram_mode = PrgRamBankingMode::Mode0Bank1x8k;
+        self.chr_mode = ChrBankingMode::Mode0Bank1x8k;
+        self.chr_chip = ChrChipSelect::ChrRom;
+
+        self.prg_bank_at_8000 = 0;
+        self.prg_bank_at_9000 = 0;
+        self.prg_bank_at_a000 = 0;
+        self.prg_bank_at_b000 = 0;
+        self.prg_bank_at_c000 = 0;
+        self.prg_bank_at_d000 = 0;
+        self.prg_bank_at_e000 = 0;
+        self.prg_bank_at_f000 = 0;
+
+        self.prg_ram_at_8000 = false;
+        self.prg_ram_at_9000 = false;
+        self.prg_ram_at_a000 = false;
+        self.prg_ram_at_b000 = false;
+        self.prg_ram_at_c000 = false;
+        self.prg_ram_at_d000 = false;
+        self.prg_ram_at_e000 = false;
+        self.prg_ram_at_f000 = false;
+
+        self.prg_bank_at_6000 = 0;
+        self.prg_bank_at_7000 = 0;
+
+        self.prg_ram_at_6000 = true;
+        self.prg_ram_at_7000 = true;
+        self.fpga_ram_at_6000 = false;
+        self.fpga_ram_at_7000 = false;
+
+        self.fpga_bank_at_5000 = 0;
+        self.chr_banks = vec![0usize; 16];
+        self.chr_bank_high_bits = 0;
+
+        self.window_split = false;
+        self.extended_sprites = false;
+
+        self.vrc6_pulse1 = Vrc6PulseChannel::new("Pulse 1");
+        self.vrc6_pulse2 = Vrc6PulseChannel::new("Pulse 2");
+        self.vrc6_sawtooth = Vrc6SawtoothChannel::new();
+
+        self.vrc6_exp6 = true;
+        self.vrc6_exp9 = true;
+        self.vrc6_zpcm = false;
+
+        self.cpu_irq_counter = 0xFFFF;
+        self.cpu_irq_latch = 0xFFFF;
+        self.cpu_irq_enable = false;
+        self.cpu_irq_auto_repeat = false;
+        self.cpu_irq_pending = false;
+
+        self.scanline_irq_enabled = false;
+        self.scanline_irq_pending = false;
+        self.scanline_irq_compare = 0xFF;
+        self.scanline_irq_offset = 0x87;
+        self.scanline_jitter_counter = 0;
+
+        self.ppu_read_mode = PpuMode::PpuData;
+        self.in_frame = false;
+        self.in_hblank = false;
+        self.current_scanline = 0;
+        self.consecutive_nametable_count = 0;
+        self.cpu_cycles_since_last_ppu_read = 0;
+        self.ppu_fetches_this_scanline = 0;
+        self.last_ppu_fetch = 0;
+        self.last_bg_tile_fetch = 0;
+
+        self.nametable_bank_at_2000 = 0;
+        self.nametable_bank_at_2400 = 0;
+        self.nametable_bank_at_2800 = 1;
+        self.nametable_bank_at_2c00 = 1;
+
+        self.nametable_chip_at_2000 = NametableChipSelect::CiRam;
+        self.nametable_chip_at_2400 = NametableChipSelect::CiRam;
+        self.nametable_chip_at_2800 = NametableChipSelect::CiRam;
+        self.nametable_chip_at_2c00 = NametableChipSelect::CiRam;
+
+        self.extended_attributes_2000 = false;
+        self.extended_attributes_2400 = false;
+        self.extended_attributes_2800 = false;
+        self.extended_attributes_2c00 = false;
+
+        self.extended_backgrounds_2000 = false;
+        self.extended_backgrounds_2400 = false;
+        self.extended_backgrounds_2800 = false;
+        self.extended_backgrounds_2c00 = false;
+
+        self.exram_bank_2000 = 0;
+        self.exram_bank_2400 = 0;
+        self.exram_bank_2800 = 0;
+        self.exram_bank_2c00 = 0;
+
+        self.vrc6_pulse1 = Vrc6PulseChannel::new("Pulse 1");
+        self.vrc6_pulse2 = Vrc6PulseChannel::new("Pulse 2");
+        self.vrc6_sawtooth = Vrc6SawtoothChannel::new();
+        self.vrc6_pulse1.write_register(3, 0x00);
+        self.vrc6_pulse2.write_register(3, 0x00);
+        self.vrc6_sawtooth.write_register(3, 0x00);
+    }
+
     fn clock_cpu(&mut self) {
         self.vrc6_pulse1.clock();
         self.vrc6_pulse2.clock();
@@ -836,13 +963,26 @@ impl Mapper for Rainbow {
 
             let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
             let vrc6_pulse_full_volume = 15.0 / 61.0;
-            let vrc6_weight = nes_pulse_full_volume / vrc6_pulse_full_volume;        
+            let vrc6_weight = nes_pulse_full_volume / vrc6_pulse_full_volume * self.vrc6_mix_gain;
             return nes_sample + vrc6_combined_sample * vrc6_weight;
         } else {
             return nes_sample;
         }
     }
 
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        let vrc6_enabled = self.vrc6_exp6 || self.vrc6_exp9;
+        let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
+        let vrc6_weight = if vrc6_enabled {nes_pulse_full_volume / 15.0 * self.vrc6_mix_gain} else {0.0};
+        outputs.push(self.vrc6_pulse1.output() as f32 * vrc6_weight);
+        outputs.push(self.vrc6_pulse2.output() as f32 * vrc6_weight);
+        outputs.push(self.vrc6_sawtooth.output() as f32 * vrc6_weight);
+    }
+
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.vrc6_mix_gain = mix.gain(MIXE_VRC6);
+    }
+
     fn read_cpu(&mut self, address: u16) -> Option<u8> {
         self.snoop_cpu_read(address);
         let data = self.debug_read_cpu(address);
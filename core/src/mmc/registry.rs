@@ -0,0 +1,206 @@
+// Maps iNES mapper numbers and UNIF board names to the code that constructs them. The
+// built-in boards are registered by MapperRegistry::new(); crates with boards of their own
+// can register more on top, without needing to change this one.
+
+use load_error::LoadError;
+use ines::INesCartridge;
+use unif::UnifBoard;
+use unif::UnifFile;
+use unif::find_board;
+use unif::normalize_board_name;
+
+use mmc::mapper::*;
+use mmc::action53::Action53;
+use mmc::axrom::AxRom;
+use mmc::bnrom::BnRom;
+use mmc::cnrom::CnRom;
+use mmc::fme7::Fme7;
+use mmc::gxrom::GxRom;
+use mmc::ines31::INes31;
+use mmc::mmc1::Mmc1;
+use mmc::mmc3::Mmc3;
+use mmc::mmc5::Mmc5;
+use mmc::n163::Namco163;
+use mmc::nrom::Nrom;
+use mmc::pxrom::PxRom;
+use mmc::rainbow::Rainbow;
+use mmc::sunsoft4::Sunsoft4;
+use mmc::uxrom::UxRom;
+use mmc::vrc6::Vrc6;
+use mmc::vrc7::Vrc7;
+
+// Constructors can capture state of their own, like a handle to whatever hardware or
+// configuration the board needs
+pub type MapperConstructor = Box<dyn Fn(INesCartridge) -> Result<Box<dyn Mapper>, LoadError> + Send + Sync>;
+
+// Lets each built-in's from_ines stand in as a MapperConstructor
+pub fn boxed<M: Mapper + 'static>(mapper: Result<M, LoadError>) -> Result<Box<dyn Mapper>, LoadError> {
+    return Ok(Box::new(mapper?));
+}
+
+struct INesEntry {
+    mapper: u16,
+    // None matches any submapper
+    submapper: Option<u8>,
+    constructor: MapperConstructor,
+}
+
+struct UnifEntry {
+    board_name: String,
+    constructor: MapperConstructor,
+}
+
+pub struct MapperRegistry {
+    ines_entries: Vec<INesEntry>,
+    unif_entries: Vec<UnifEntry>,
+}
+
+impl MapperRegistry {
+    // A registry with nothing in it, for shells that want full control
+    pub fn empty() -> MapperRegistry {
+        return MapperRegistry {
+            ines_entries: Vec::new(),
+            unif_entries: Vec::new(),
+        };
+    }
+
+    pub fn new() -> MapperRegistry {
+        let mut registry = MapperRegistry::empty();
+        registry.register(0, |ines| boxed(Nrom::from_ines(ines)));
+        registry.register(1, |ines| boxed(Mmc1::from_ines(ines)));
+        registry.register(2, |ines| boxed(UxRom::from_ines(ines)));
+        registry.register(3, |ines| boxed(CnRom::from_ines(ines)));
+        registry.register(4, |ines| boxed(Mmc3::from_ines(ines)));
+        registry.register(5, |ines| boxed(Mmc5::from_ines(ines)));
+        registry.register(7, |ines| boxed(AxRom::from_ines(ines)));
+        registry.register(9, |ines| boxed(PxRom::from_ines(ines)));
+        registry.register(19, |ines| boxed(Namco163::from_ines(ines)));
+        registry.register(24, |ines| boxed(Vrc6::from_ines(ines)));
+        registry.register(26, |ines| boxed(Vrc6::from_ines(ines)));
+        registry.register(28, |ines| boxed(Action53::from_ines(ines)));
+        registry.register(31, |ines| boxed(INes31::from_ines(ines)));
+        registry.register(34, |ines| boxed(BnRom::from_ines(ines)));
+        registry.register(66, |ines| boxed(GxRom::from_ines(ines)));
+        registry.register(68, |ines| boxed(Sunsoft4::from_ines(ines)));
+        registry.register(69, |ines| boxed(Fme7::from_ines(ines)));
+        registry.register(85, |ines| boxed(Vrc7::from_ines(ines)));
+        registry.register(682, |ines| boxed(Rainbow::from_ines(ines)));
+        return registry;
+    }
+
+    // Registers a constructor for every submapper of this mapper number. Registering a
+    // number again replaces the earlier constructor, including the built-ins.
+    pub fn register<F>(&mut self, mapper: u16, constructor: F)
+        where F: Fn(INesCartridge) -> Result<Box<dyn Mapper>, LoadError> + Send + Sync + 'static {
+        self.ines_entries.push(INesEntry{mapper: mapper, submapper: None, constructor: Box::new(constructor)});
+    }
+
+    // Registers a constructor for one submapper only. This takes priority over a
+    // constructor registered for the mapper as a whole.
+    pub fn register_submapper<F>(&mut self, mapper: u16, submapper: u8, constructor: F)
+        where F: Fn(INesCartridge) -> Result<Box<dyn Mapper>, LoadError> + Send + Sync + 'static {
+        self.ines_entries.push(INesEntry{mapper: mapper, submapper: Some(submapper), constructor: Box::new(constructor)});
+    }
+
+    // Registers a constructor for a UNIF board. The name is matched ignoring case and any
+    // "NES-", "UNL-" style prefix. The constructor receives the file converted to iNES; boards
+    // we don't otherwise know get mapper 0 and 8k of PRG RAM in that header.
+    pub fn register_unif_board<F>(&mut self, board_name: &str, constructor: F)
+        where F: Fn(INesCartridge) -> Result<Box<dyn Mapper>, LoadError> + Send + Sync + 'static {
+        self.unif_entries.push(UnifEntry{board_name: normalize_board_name(board_name), constructor: Box::new(constructor)});
+    }
+
+    pub fn find(&self, mapper: u16, submapper: u8) -> Option<&MapperConstructor> {
+        // Most recent registration wins, exact submapper matches first
+        for entry in self.ines_entries.iter().rev() {
+            if entry.mapper == mapper && entry.submapper == Some(submapper) {
+                return Some(&entry.constructor);
+            }
+        }
+        for entry in self.ines_entries.iter().rev() {
+            if entry.mapper == mapper && entry.submapper.is_none() {
+                return Some(&entry.constructor);
+            }
+        }
+        return None;
+    }
+
+    pub fn find_unif_board(&self, board_name: &str) -> Option<&MapperConstructor> {
+        let name = normalize_board_name(board_name);
+        for entry in self.unif_entries.iter().rev() {
+            if entry.board_name == name {
+                return Some(&entry.constructor);
+            }
+        }
+        // Otherwise boards with an iNES equivalent use whatever that mapper number has
+        // registered now, so overriding a mapper covers its UNIF boards too
+        return match find_board(board_name) {
+            Some(board) => self.find(board.mapper, board.submapper),
+            None => None
+        };
+    }
+
+    pub fn supports(&self, mapper: u16, submapper: u8) -> bool {
+        return self.find(mapper, submapper).is_some();
+    }
+
+    pub fn supports_unif_board(&self, board_name: &str) -> bool {
+        return self.find_unif_board(board_name).is_some();
+    }
+
+    pub fn mapper_from_ines(&self, ines: INesCartridge) -> Result<Box<dyn Mapper>, LoadError> {
+        let mapper_number = ines.header.mapper_number();
+        let submapper_number = ines.header.submapper_number();
+        let constructor = match self.find(mapper_number, submapper_number) {
+            Some(constructor) => constructor,
+            None => {return Err(LoadError::UnsupportedMapper{number: mapper_number, submapper: submapper_number});}
+        };
+
+        let mapper = constructor(ines)?;
+
+        println!("Successfully loaded mapper: {}, submapper: {}", mapper_number, submapper_number);
+
+        return Ok(mapper);
+    }
+
+    pub fn mapper_from_unif(&self, unif: UnifFile) -> Result<Box<dyn Mapper>, LoadError> {
+        let constructor = match self.find_unif_board(&unif.board_name) {
+            Some(constructor) => constructor,
+            None => {return Err(LoadError::UnsupportedBoard{name: unif.board_name.clone()});}
+        };
+        let ines = match find_board(&unif.board_name) {
+            Some(_) => unif.to_ines()?,
+            None => {
+                println!("UNIF board {} loaded by a registered constructor", unif.board_name);
+                unif.to_ines_as(UnifBoard{name: "", mapper: 0, submapper: 0, prg_ram_size: 0x2000})?
+            }
+        };
+        let mapper = constructor(ines)?;
+        println!("Successfully loaded UNIF board: {}", unif.board_name);
+        return Ok(mapper);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ines::test_cartridge;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn overriding_a_mapper_applies_to_its_unif_boards() {
+        let constructed = Arc::new(AtomicUsize::new(0));
+        let counter = constructed.clone();
+        let mut registry = MapperRegistry::new();
+        registry.register(0, move |ines| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            return boxed(Nrom::from_ines(ines));
+        });
+
+        let constructor = registry.find_unif_board("NES-NROM-256").unwrap();
+        assert!(constructor(test_cartridge(0, 1, 1)).is_ok());
+        assert_eq!(constructed.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,184 @@
+// Sunsoft-4, 16k PRG banking, 2k CHR banking, and nametables which may be mapped to CHR ROM
+// Reference capabilities: https://wiki.nesdev.com/w/index.php/INES_Mapper_068
+
+use load_error::LoadError;
+use ines::INesCartridge;
+use memoryblock::MemoryBlock;
+
+use mmc::mapper::*;
+use mmc::mirroring::NametableMapping;
+use mmc::mirroring::NametableSource;
+
+pub struct Sunsoft4 {
+    pub prg_rom: MemoryBlock,
+    pub prg_ram: MemoryBlock,
+    pub chr: MemoryBlock,
+    pub vram: Vec<u8>,
+    pub prg_bank: usize,
+    pub prg_ram_enable: bool,
+    pub chr_banks: Vec<usize>,
+    pub nametable_banks: Vec<usize>,
+    pub mirroring: Mirroring,
+    pub nametable_chrrom: bool,
+}
+
+impl Sunsoft4 {
+    pub fn from_ines(ines: INesCartridge) -> Result<Sunsoft4, LoadError> {
+        let prg_rom_block = ines.prg_rom_block();
+        let prg_ram_block = ines.prg_ram_block()?;
+        let chr_block = ines.chr_block()?;
+
+        return Ok(Sunsoft4 {
+            prg_rom: prg_rom_block.clone(),
+            prg_ram: prg_ram_block.clone(),
+            chr: chr_block.clone(),
+            vram: vec![0u8; 0x1000],
+            prg_bank: 0,
+            prg_ram_enable: false,
+            chr_banks: vec![0usize; 4],
+            nametable_banks: vec![0x80usize; 2],
+            mirroring: Mirroring::Vertical,
+            nametable_chrrom: false,
+        });
+    }
+}
+
+impl Mapper for Sunsoft4 {
+    fn print_debug_status(&self) {
+        println!("======= Sunsoft-4 =======");
+        println!("PRG Bank: {}, CHR Banks: {:?}, NT Banks: {:?}", self.prg_bank, self.chr_banks, self.nametable_banks);
+        println!("Mirroring Mode: {}, CHR ROM Nametables: {}", mirroring_mode_name(self.mirroring), self.nametable_chrrom);
+        println!("====================");
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        return self.mirroring;
+    }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The Sunsoft-4 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.prg_bank = 0;
+        self.prg_ram_enable = false;
+        self.chr_banks = vec![0usize; 4];
+        self.nametable_banks = vec![0x80usize; 2];
+        self.mirroring = Mirroring::Vertical;
+        self.nametable_chrrom = false;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn nametable_mapping(&self) -> NametableMapping {
+        let ciram_mapping = NametableMapping::from_mirroring(self.mirroring);
+        if !self.nametable_chrrom {
+            return ciram_mapping;
+        }
+        // The CIRAM page selected by mirroring picks which of the two ROM banks is used
+        let mut mapping = ciram_mapping;
+        for i in 0 .. 4 {
+            let bank = match ciram_mapping.quadrants[i] {
+                NametableSource::CiramA => self.nametable_banks[0],
+                _ => self.nametable_banks[1],
+            };
+            mapping.quadrants[i] = NametableSource::Rom(bank);
+        }
+        return mapping;
+    }
+
+    fn has_sram(&self) -> bool {
+        return true;
+    }
+
+    fn get_sram(&self) -> Vec<u8> {
+        return self.prg_ram.battery_backed_bytes();
+    }
+
+    fn load_sram(&mut self, sram_data: Vec<u8>) {
+        self.prg_ram.load_battery_backed_bytes(sram_data);
+    }
+
+    fn debug_read_cpu(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                if self.prg_ram_enable {
+                    self.prg_ram.wrapping_read((address - 0x6000) as usize)
+                } else {
+                    None
+                }
+            },
+            0x8000 ..= 0xBFFF => self.prg_rom.banked_read(0x4000, self.prg_bank, (address - 0x8000) as usize),
+            0xC000 ..= 0xFFFF => self.prg_rom.banked_read(0x4000, 0xFF, (address - 0xC000) as usize),
+            _ => None
+        }
+    }
+
+    fn write_cpu(&mut self, address: u16, data: u8) {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                if self.prg_ram_enable {
+                    self.prg_ram.wrapping_write((address - 0x6000) as usize, data);
+                }
+            },
+            0x8000 ..= 0x8FFF => {self.chr_banks[0] = data as usize},
+            0x9000 ..= 0x9FFF => {self.chr_banks[1] = data as usize},
+            0xA000 ..= 0xAFFF => {self.chr_banks[2] = data as usize},
+            0xB000 ..= 0xBFFF => {self.chr_banks[3] = data as usize},
+            // D7 is always set, so nametables can only come from the upper 128k of CHR ROM
+            0xC000 ..= 0xCFFF => {self.nametable_banks[0] = (data | 0x80) as usize},
+            0xD000 ..= 0xDFFF => {self.nametable_banks[1] = (data | 0x80) as usize},
+            0xE000 ..= 0xEFFF => {
+                self.mirroring = match data & 0b0000_0011 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    3 => Mirroring::OneScreenUpper,
+                    _ => Mirroring::Vertical // unreachable
+                };
+                self.nametable_chrrom = (data & 0b0001_0000) != 0;
+            },
+            0xF000 ..= 0xFFFF => {
+                self.prg_bank = (data & 0b0000_1111) as usize;
+                self.prg_ram_enable = (data & 0b0001_0000) != 0;
+            },
+            _ => {}
+        }
+    }
+
+    fn debug_read_ppu(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000 ..= 0x07FF => self.chr.banked_read(0x800, self.chr_banks[0], address as usize),
+            0x0800 ..= 0x0FFF => self.chr.banked_read(0x800, self.chr_banks[1], address as usize),
+            0x1000 ..= 0x17FF => self.chr.banked_read(0x800, self.chr_banks[2], address as usize),
+            0x1800 ..= 0x1FFF => self.chr.banked_read(0x800, self.chr_banks[3], address as usize),
+            0x2000 ..= 0x3FFF => self.nametable_mapping().read(address, &self.vram, &[], &self.chr),
+            _ => None
+        }
+    }
+
+    fn write_ppu(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000 ..= 0x07FF => self.chr.banked_write(0x800, self.chr_banks[0], address as usize, data),
+            0x0800 ..= 0x0FFF => self.chr.banked_write(0x800, self.chr_banks[1], address as usize, data),
+            0x1000 ..= 0x17FF => self.chr.banked_write(0x800, self.chr_banks[2], address as usize, data),
+            0x1800 ..= 0x1FFF => self.chr.banked_write(0x800, self.chr_banks[3], address as usize, data),
+            0x2000 ..= 0x3FFF => {
+                let mapping = self.nametable_mapping();
+                mapping.write(address, data, &mut self.vram, &mut [], &mut self.chr);
+            },
+            _ => {}
+        }
+    }
+}
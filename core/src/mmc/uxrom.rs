@@ -1,8 +1,10 @@
 // UxROM, simple bank switchable PRG ROM with the last page fixed
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/UxROM
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use memoryblock::MemoryType;
 
 use mmc::mapper::*;
 use mmc::mirroring;
@@ -13,10 +15,11 @@ pub struct UxRom {
     pub mirroring: Mirroring,
     pub prg_bank: usize,
     pub vram: Vec<u8>,
+    pub trainer_ram: MemoryBlock,
 }
 
 impl UxRom {
-    pub fn from_ines(ines: INesCartridge) -> Result<UxRom, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<UxRom, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let chr_block = ines.chr_block()?;
 
@@ -26,6 +29,7 @@ impl UxRom {
             mirroring: ines.header.mirroring(),
             prg_bank: 0x00,
             vram: vec![0u8; 0x1000],
+            trainer_ram: MemoryBlock::new(&[], MemoryType::Ram),
         })
     }
 }
@@ -42,8 +46,30 @@ impl Mapper for UxRom {
         return self.mirroring;
     }
 
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.chr];
+    }
+
+    fn trainer_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.trainer_ram);
+    }
+
+    fn reset(&mut self) {
+        // Discrete logic with no reset input, so the bank registers keep their values
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr.power_cycle();
+        self.trainer_ram.power_cycle();
+        self.prg_bank = 0x00;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_read(address as usize - 0x7000),
             0x8000 ..= 0xBFFF => self.prg_rom.banked_read(0x4000, self.prg_bank, address as usize - 0x8000),
             0xC000 ..= 0xFFFF => self.prg_rom.banked_read(0x4000, 0xFF, address as usize - 0xC000),
             _ => None
@@ -52,6 +78,7 @@ impl Mapper for UxRom {
 
     fn write_cpu(&mut self, address: u16, data: u8) {
         match address {
+            0x7000 ..= 0x71FF => self.trainer_ram.bounded_write(address as usize - 0x7000, data),
             0x8000 ..= 0xFFFF => {
                 self.prg_bank = data as usize;
             }
@@ -1,13 +1,17 @@
 // Vrc6, 
 // Reference capabilities: https://wiki.nesdev.com/w/index.php/VRC6
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use nsf::MIXE_VRC6;
 
 use mmc::mapper::*;
-use mmc::mirroring;
+use mmc::mirroring::NametableMapping;
+use mmc::mirroring::NametableSource;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use apu::PlaybackRate;
 use apu::Volume;
 use apu::Timbre;
@@ -401,10 +405,11 @@ pub struct Vrc6 {
     pub pulse1: Vrc6PulseChannel,
     pub pulse2: Vrc6PulseChannel,
     pub sawtooth: Vrc6SawtoothChannel,
+    pub mix_gain: f32,
 }
 
 impl Vrc6 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Vrc6, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Vrc6, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -437,6 +442,7 @@ impl Vrc6 {
             pulse1: Vrc6PulseChannel::new("Pulse 1"),
             pulse2: Vrc6PulseChannel::new("Pulse 2"),
             sawtooth: Vrc6SawtoothChannel::new(),
+            mix_gain: 1.0,
         });
     }
 
@@ -511,413 +517,163 @@ impl Vrc6 {
         }
     }
 
-    fn _mirroring_mode_0_read(&self, address: u16) -> Option<u8> {
-        let mirrored_address = address & 0x2FFF;
-        if self.nametable_chrrom {
-            match self.mirroring_mode {
-                0 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                1 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                2 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                3 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                _ => None
-            }
-        } else {
-            match self.mirroring_mode {
-                0 => Some(self.vram[mirroring::vertical_mirroring(address) as usize]),
-                1 => Some(self.vram[mirroring::horizontal_mirroring(address) as usize]),
-                2 => Some(self.vram[mirroring::one_screen_lower(address) as usize]),
-                3 => Some(self.vram[mirroring::one_screen_upper(address) as usize]),
-                _ => None
-            }
+    fn _nametable_mapping_from_registers(&self, registers: [usize; 4]) -> NametableMapping {
+        let mut mapping = NametableMapping::from_mirroring(Mirroring::Horizontal);
+        for i in 0 .. 4 {
+            mapping.quadrants[i] = if self.nametable_chrrom {
+                NametableSource::Rom(registers[i])
+            } else if (registers[i] & 0x1) == 0 {
+                NametableSource::CiramA
+            } else {
+                NametableSource::CiramB
+            };
         }
+        return mapping;
     }
 
-    fn _mirroring_mode_0_write(&mut self, address: u16, data: u8) {
-        if self.nametable_chrrom {
-            println!("Attempt to write to CHR ROM nametables!");
-        } else {
-            match self.mirroring_mode {
-                0 => self.vram[mirroring::vertical_mirroring(address) as usize] = data,
-                1 => self.vram[mirroring::horizontal_mirroring(address) as usize] = data,
-                2 => self.vram[mirroring::one_screen_lower(address) as usize] = data,
-                3 => self.vram[mirroring::one_screen_upper(address) as usize] = data,
-                _ => {}
-            }
-        }
+    fn _chr_nametable_mapping(&self, registers: [usize; 4]) -> NametableMapping {
+        return NametableMapping::new(
+            NametableSource::Rom(registers[0]),
+            NametableSource::Rom(registers[1]),
+            NametableSource::Rom(registers[2]),
+            NametableSource::Rom(registers[3]));
     }
 
-    fn _mirroring_mode_1_read(&self, address: u16) -> Option<u8> {
-        let mirrored_address = address & 0x2FFF;
-        let masked_address = (mirrored_address & 0b0011_1111_1111) as usize;
-        if self.nametable_chrrom {
-            match mirrored_address {
-                0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[4], address as usize -  0x2000),
-                0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[5], address as usize -  0x2400), 
-                0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[6], address as usize -  0x2800), 
-                0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7], address as usize -  0x2C00),
-                _ => None // never reached
-            }
-        } else {
-            let r4_lsb = (self.r[4] & 0x1) as usize;
-            let r5_lsb = (self.r[5] & 0x1) as usize;
-            let r6_lsb = (self.r[6] & 0x1) as usize;
-            let r7_lsb = (self.r[7] & 0x1) as usize;
-            match mirrored_address {
-                0x2000 ..= 0x23FF => Some(self.vram[masked_address + (r4_lsb << 10)]),
-                0x2400 ..= 0x27FF => Some(self.vram[masked_address + (r5_lsb << 10)]),
-                0x2800 ..= 0x2BFF => Some(self.vram[masked_address + (r6_lsb << 10)]),
-                0x2C00 ..= 0x2FFF => Some(self.vram[masked_address + (r7_lsb << 10)]),
-                _ => None // never reached
-            }
+    fn _clock_irq_prescaler(&mut self) {
+        self.irq_scanline_prescaler -= 3;
+        if self.irq_scanline_prescaler <= 0 {
+            self._clock_irq_counter();
+            self.irq_scanline_prescaler += 341;
         }
     }
 
-    fn _mirroring_mode_1_write(&mut self, address: u16, data: u8) {
-        if self.nametable_chrrom {
-            println!("Attempt to write to CHR ROM nametables!");
+    fn _clock_irq_counter(&mut self) {
+        if self.irq_counter == 0xFF {
+            self.irq_counter = self.irq_latch;
+            self.irq_pending = true;
         } else {
-            let mirrored_address = address & 0x2FFF;
-            let masked_address = (mirrored_address & 0b0011_1111_1111) as usize;
-
-            let r4_lsb = (self.r[4] & 0x1) as usize;
-            let r5_lsb = (self.r[5] & 0x1) as usize;
-            let r6_lsb = (self.r[6] & 0x1) as usize;
-            let r7_lsb = (self.r[7] & 0x1) as usize;
-
-            match mirrored_address {
-                0x2000 ..= 0x23FF => self.vram[masked_address + (r4_lsb << 10)] = data,
-                0x2400 ..= 0x27FF => self.vram[masked_address + (r5_lsb << 10)] = data,
-                0x2800 ..= 0x2BFF => self.vram[masked_address + (r6_lsb << 10)] = data,
-                0x2C00 ..= 0x2FFF => self.vram[masked_address + (r7_lsb << 10)] = data,
-                _ => {} // never reached
-            }
+            self.irq_counter += 1;
         }
     }
+}
 
-    fn _mirroring_mode_2_read(&self, address: u16) -> Option<u8> {
-        let mirrored_address = address & 0x2FFF;
-        let masked_address = (mirrored_address & 0b0011_1111_1111) as usize;
-        if self.nametable_chrrom {
-            match self.mirroring_mode {
-                0 | 2=> {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6], address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[7], address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[6], address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7], address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                1 | 3=> {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6], address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[6], address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[7], address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7], address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                _ => None
-            }
-        } else {
-            let r6_lsb = (self.r[6] & 0x1) as usize;
-            let r7_lsb = (self.r[7] & 0x1) as usize;
-
-            match self.mirroring_mode {
-                0 | 2 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => Some(self.vram[masked_address + (r6_lsb << 10)]),
-                        0x2400 ..= 0x27FF => Some(self.vram[masked_address + (r7_lsb << 10)]),
-                        0x2800 ..= 0x2BFF => Some(self.vram[masked_address + (r6_lsb << 10)]),
-                        0x2C00 ..= 0x2FFF => Some(self.vram[masked_address + (r7_lsb << 10)]),
-                        _ => None // never reached
-                    }
-                },
-                1 | 3 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => Some(self.vram[masked_address + (r6_lsb << 10)]),
-                        0x2400 ..= 0x27FF => Some(self.vram[masked_address + (r6_lsb << 10)]),
-                        0x2800 ..= 0x2BFF => Some(self.vram[masked_address + (r7_lsb << 10)]),
-                        0x2C00 ..= 0x2FFF => Some(self.vram[masked_address + (r7_lsb << 10)]),
-                        _ => None // never reached
-                    }
-                },
-                _ => None
-            }
-        }
+// Takes the sum of the raw channel levels
+fn mixed_vrc6_audio(vrc6_output: f32) -> f32 {
+    let vrc6_combined_sample = vrc6_output / 61.0;
+
+    let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
+    let vrc6_pulse_full_volume = 15.0 / 61.0;
+    let vrc6_weight = nes_pulse_full_volume / vrc6_pulse_full_volume;
+
+    return vrc6_combined_sample * vrc6_weight;
+}
+
+impl Mapper for Vrc6 {
+    fn mirroring(&self) -> Mirroring {
+        return self.mirroring;
     }
 
-    fn _mirroring_mode_2_write(&mut self, address: u16, data: u8) {
-        let mirrored_address = address & 0x2FFF;
-        let masked_address = (mirrored_address & 0b0011_1111_1111) as usize;
-        if self.nametable_chrrom {
-            match self.mirroring_mode {
-                0 | 2=> {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_write(0x400, self.r[6], address as usize -  0x2000, data),
-                        0x2400 ..= 0x27FF => self.chr.banked_write(0x400, self.r[7], address as usize -  0x2400, data), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_write(0x400, self.r[6], address as usize -  0x2800, data), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_write(0x400, self.r[7], address as usize -  0x2C00, data),
-                        _ => {} // never reached
-                    }
-                },
-                1 | 3=> {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_write(0x400, self.r[6], address as usize -  0x2000, data),
-                        0x2400 ..= 0x27FF => self.chr.banked_write(0x400, self.r[6], address as usize -  0x2400, data), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_write(0x400, self.r[7], address as usize -  0x2800, data), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_write(0x400, self.r[7], address as usize -  0x2C00, data),
-                        _ => {} // never reached
-                    }
-                },
-                _ => {}
-            }
-        } else {
-            let r6_lsb = (self.r[6] & 0x1) as usize;
-            let r7_lsb = (self.r[7] & 0x1) as usize;
-
-            match self.mirroring_mode {
-                0 | 2 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.vram[masked_address + (r6_lsb << 10)] = data,
-                        0x2400 ..= 0x27FF => self.vram[masked_address + (r7_lsb << 10)] = data,
-                        0x2800 ..= 0x2BFF => self.vram[masked_address + (r6_lsb << 10)] = data,
-                        0x2C00 ..= 0x2FFF => self.vram[masked_address + (r7_lsb << 10)] = data,
-                        _ => {} // never reached
-                    }
-                },
-                1 | 3 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.vram[masked_address + (r6_lsb << 10)] = data,
-                        0x2400 ..= 0x27FF => self.vram[masked_address + (r6_lsb << 10)] = data,
-                        0x2800 ..= 0x2BFF => self.vram[masked_address + (r7_lsb << 10)] = data,
-                        0x2C00 ..= 0x2FFF => self.vram[masked_address + (r7_lsb << 10)] = data,
-                        _ => {} // never reached
-                    }
-                },
-                _ => {}
-            }
-        }
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
     }
 
-    fn _mirroring_mode_3_read(&self, address: u16) -> Option<u8> {
-        //println!("mode 3 read with address: {}", address);
-        let mirrored_address = address & 0x2FFF;
-        if self.nametable_chrrom {
-            match self.mirroring_mode {
-                0 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                1 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                2 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[6] | 0x01, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] | 0x01, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                3 => {
-                    match mirrored_address {
-                        0x2000 ..= 0x23FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2000),
-                        0x2400 ..= 0x27FF => self.chr.banked_read(0x400, self.r[6] & 0xFE, address as usize -  0x2400), 
-                        0x2800 ..= 0x2BFF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2800), 
-                        0x2C00 ..= 0x2FFF => self.chr.banked_read(0x400, self.r[7] & 0xFE, address as usize -  0x2C00),
-                        _ => None // never reached
-                    }
-                },
-                _ => None
-            }
-        } else {
-            match self.mirroring_mode {
-                0 => Some(self.vram[mirroring::horizontal_mirroring(address) as usize]),
-                1 => Some(self.vram[mirroring::vertical_mirroring(address) as usize]),
-                2 => Some(self.vram[mirroring::one_screen_upper(address) as usize]),
-                3 => Some(self.vram[mirroring::one_screen_lower(address) as usize]),
-                _ => None
-            }
-        }
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
     }
 
-    fn _mirroring_mode_3_write(&mut self, address: u16, data: u8) {
-        if self.nametable_chrrom {
-            println!("Attempt to write to CHR ROM nametables!");
-        } else {
-            match self.mirroring_mode {
-                0 => self.vram[mirroring::horizontal_mirroring(address) as usize] = data,
-                1 => self.vram[mirroring::vertical_mirroring(address) as usize] = data,
-                2 => self.vram[mirroring::one_screen_upper(address) as usize] = data,
-                3 => self.vram[mirroring::one_screen_lower(address) as usize] = data,
-                _ => {}
-            }
+    fn reset(&mut self) {
+        // The VRC6 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.prg_ram_enable = false;
+        self.prg_bank_16 = 0;
+        self.prg_bank_8 = 0;
+        self.r = vec![0usize; 8];
+        self.ppu_banking_mode = 0;
+        self.mirroring_mode = 0;
+        self.nametable_chrrom = false;
+        self.chr_a10_rules = false;
+        self.b003_shadow = 0;
+        self.irq_scanline_prescaler = 0;
+        self.irq_latch = 0;
+        self.irq_scanline_mode = false;
+        self.irq_enable = false;
+        self.irq_enable_after_acknowledgement = false;
+        self.irq_pending = false;
+        self.irq_counter = 0;
+        self.pulse1 = Vrc6PulseChannel::new("Pulse 1");
+        self.pulse2 = Vrc6PulseChannel::new("Pulse 2");
+        self.sawtooth = Vrc6SawtoothChannel::new();
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
         }
     }
 
-     fn _a10_chr_address(&self, address: u16) -> usize {
-        let mirrored_address = address & 0x2FFF;
-        let masked_address = (mirrored_address & 0b0011_1111_1111) as usize;
+    fn nametable_mapping(&self) -> NametableMapping {
+        let r4 = self.r[4];
+        let r5 = self.r[5];
+        let r6 = self.r[6];
+        let r7 = self.r[7];
+
+        if !self.chr_a10_rules {
+            // A10 rules weirdness: which registers apply to each quadrant is chosen
+            // by a truth table over the low bits of $B003
+            let registers = match self.b003_shadow & 0xF {
+                0x1 | 0x5 | 0x9 | 0xD => [r4, r5, r6, r7],
+                0x2 | 0x3 | 0x4 | 0xA | 0xB | 0xC => [r6, r7, r6, r7],
+                _ => [r6, r6, r7, r7],
+            };
+            return self._nametable_mapping_from_registers(registers);
+        }
 
-        match self.b003_shadow & 0xF {
-            0x0 | 0x6 | 0x7 | 0x8 | 0xE | 0xF => {
-                match mirrored_address {
-                    0x2000 ..= 0x23FF => (self.r[6] << 10) + masked_address,
-                    0x2400 ..= 0x27FF => (self.r[6] << 10) + masked_address,
-                    0x2800 ..= 0x2BFF => (self.r[7] << 10) + masked_address,
-                    0x2C00 ..= 0x2FFF => (self.r[7] << 10) + masked_address,
-                    _ => 0 // unreachable
+        match self.ppu_banking_mode {
+            0 => {
+                if self.nametable_chrrom {
+                    match self.mirroring_mode {
+                        0 => self._chr_nametable_mapping([r6 & 0xFE, r6 | 0x01, r7 & 0xFE, r7 | 0x01]),
+                        1 => self._chr_nametable_mapping([r6 & 0xFE, r7 & 0xFE, r6 | 0x01, r7 | 0x01]),
+                        2 => self._chr_nametable_mapping([r6 & 0xFE, r6 & 0xFE, r7 & 0xFE, r7 & 0xFE]),
+                        _ => self._chr_nametable_mapping([r6 | 0x01, r7 | 0x01, r6 | 0x01, r7 | 0x01]),
+                    }
+                } else {
+                    match self.mirroring_mode {
+                        0 => NametableMapping::from_mirroring(Mirroring::Vertical),
+                        1 => NametableMapping::from_mirroring(Mirroring::Horizontal),
+                        2 => NametableMapping::from_mirroring(Mirroring::OneScreenLower),
+                        _ => NametableMapping::from_mirroring(Mirroring::OneScreenUpper),
+                    }
                 }
             },
-            0x1 | 0x5 | 0x9 | 0xD => {
-                match mirrored_address {
-                    0x2000 ..= 0x23FF => (self.r[4] << 10) + masked_address,
-                    0x2400 ..= 0x27FF => (self.r[5] << 10) + masked_address,
-                    0x2800 ..= 0x2BFF => (self.r[6] << 10) + masked_address,
-                    0x2C00 ..= 0x2FFF => (self.r[7] << 10) + masked_address,
-                    _ => 0 // unreachable
-                }
-            }
-            0x2 | 0x3 | 0x4 | 0xA | 0xB | 0xC => {
-                match mirrored_address {
-                    0x2000 ..= 0x23FF => (self.r[6] << 10) + masked_address,
-                    0x2400 ..= 0x27FF => (self.r[7] << 10) + masked_address,
-                    0x2800 ..= 0x2BFF => (self.r[6] << 10) + masked_address,
-                    0x2C00 ..= 0x2FFF => (self.r[7] << 10) + masked_address,
-                    _ => 0 // unreachable
-                }   
-            }
-            _ => 0 // unreachable
-        }
-    }   
-
-    fn _a10_nametable_address(&self, address: u16) -> usize {
-        let r4_lsb = (self.r[4] & 0x1) as usize;
-        let r5_lsb = (self.r[5] & 0x1) as usize;
-        let r6_lsb = (self.r[6] & 0x1) as usize;
-        let r7_lsb = (self.r[7] & 0x1) as usize;
-        let mirrored_address = address & 0x2FFF;
-        let masked_address = (address & 0b0011_1111_1111) as usize;
-
-        match self.b003_shadow & 0xF {
-            0x0 | 0x6 | 0x7 | 0x8 | 0xE | 0xF => {
-                match mirrored_address {
-                    0x2000 ..= 0x23FF => masked_address + (r6_lsb << 10),
-                    0x2400 ..= 0x27FF => masked_address + (r6_lsb << 10),
-                    0x2800 ..= 0x2BFF => masked_address + (r7_lsb << 10),
-                    0x2C00 ..= 0x2FFF => masked_address + (r7_lsb << 10),
-                    _ => 0 // unreachable
+            1 => self._nametable_mapping_from_registers([r4, r5, r6, r7]),
+            2 => {
+                match self.mirroring_mode {
+                    0 | 2 => self._nametable_mapping_from_registers([r6, r7, r6, r7]),
+                    _ => self._nametable_mapping_from_registers([r6, r6, r7, r7]),
                 }
             },
-            0x1 | 0x5 | 0x9 | 0xD => {
-                match mirrored_address {
-                    0x2000 ..= 0x23FF => masked_address + (r4_lsb << 10),
-                    0x2400 ..= 0x27FF => masked_address + (r5_lsb << 10),
-                    0x2800 ..= 0x2BFF => masked_address + (r6_lsb << 10),
-                    0x2C00 ..= 0x2FFF => masked_address + (r7_lsb << 10),
-                    _ => 0 // unreachable
+            _ => {
+                if self.nametable_chrrom {
+                    match self.mirroring_mode {
+                        0 => self._chr_nametable_mapping([r6 & 0xFE, r7 & 0xFE, r6 | 0x01, r7 | 0x01]),
+                        1 => self._chr_nametable_mapping([r6 & 0xFE, r6 | 0x01, r7 & 0xFE, r7 | 0x01]),
+                        2 => self._chr_nametable_mapping([r6 | 0x01, r7 | 0x01, r6 | 0x01, r7 | 0x01]),
+                        _ => self._chr_nametable_mapping([r6 & 0xFE, r6 & 0xFE, r7 & 0xFE, r7 & 0xFE]),
+                    }
+                } else {
+                    match self.mirroring_mode {
+                        0 => NametableMapping::from_mirroring(Mirroring::Horizontal),
+                        1 => NametableMapping::from_mirroring(Mirroring::Vertical),
+                        2 => NametableMapping::from_mirroring(Mirroring::OneScreenUpper),
+                        _ => NametableMapping::from_mirroring(Mirroring::OneScreenLower),
+                    }
                 }
             }
-            0x2 | 0x3 | 0x4 | 0xA | 0xB | 0xC => {
-                match mirrored_address {
-                    0x2000 ..= 0x23FF => masked_address + (r6_lsb << 10),
-                    0x2400 ..= 0x27FF => masked_address + (r7_lsb << 10),
-                    0x2800 ..= 0x2BFF => masked_address + (r6_lsb << 10),
-                    0x2C00 ..= 0x2FFF => masked_address + (r7_lsb << 10),
-                    _ => 0 // unreachable
-                }   
-            }
-            _ => 0 // unreachable
-        }
-    }
-
-    fn _a10_nametable_read(&self, address: u16) -> Option<u8> {
-        if self.nametable_chrrom {
-            let a10_rules_address = self._a10_chr_address(address);
-            return self.chr.wrapping_read(a10_rules_address);
-        } else {
-            let a10_rules_address = self._a10_nametable_address(address);
-            return Some(self.vram[a10_rules_address]);
-        }
-    }
-
-    fn _a10_nametable_write(&mut self, address: u16, data: u8) {
-        if self.nametable_chrrom {
-            println!("Attempt to write to CHR ROM nametables!");
-            return;
-        }
-        let a10_rules_address = self._a10_nametable_address(address);
-        self.vram[a10_rules_address] = data;
-    }
-
-    fn _clock_irq_prescaler(&mut self) {
-        self.irq_scanline_prescaler -= 3;
-        if self.irq_scanline_prescaler <= 0 {
-            self._clock_irq_counter();
-            self.irq_scanline_prescaler += 341;
         }
     }
 
-    fn _clock_irq_counter(&mut self) {
-        if self.irq_counter == 0xFF {
-            self.irq_counter = self.irq_latch;
-            self.irq_pending = true;
-        } else {
-            self.irq_counter += 1;
-        }
-    }
-}
-
-impl Mapper for Vrc6 {
-    fn mirroring(&self) -> Mirroring {
-        return self.mirroring;
-    }
-
     fn clock_cpu(&mut self) {
         if self.irq_enable {
             if self.irq_scanline_mode {
@@ -935,15 +691,17 @@ impl Mapper for Vrc6 {
         let pulse_1_output = if !self.pulse1.debug_disable {self.pulse1.output() as f32} else {0.0};
         let pulse_2_output = if !self.pulse2.debug_disable {self.pulse2.output() as f32} else {0.0};
         let sawtooth_output = if !self.sawtooth.debug_disable {self.sawtooth.output() as f32} else {0.0};
-        let vrc6_combined_sample = (pulse_1_output + pulse_2_output + sawtooth_output) / 61.0;
+        return mixed_vrc6_audio(pulse_1_output + pulse_2_output + sawtooth_output) * self.mix_gain + nes_sample;
+    }
 
-        let nes_pulse_full_volume = 95.88 / ((8128.0 / 15.0) + 100.0);
-        let vrc6_pulse_full_volume = 15.0 / 61.0;
-        let vrc6_weight = nes_pulse_full_volume / vrc6_pulse_full_volume;
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        outputs.push(mixed_vrc6_audio(self.pulse1.output() as f32) * self.mix_gain);
+        outputs.push(mixed_vrc6_audio(self.pulse2.output() as f32) * self.mix_gain);
+        outputs.push(mixed_vrc6_audio(self.sawtooth.output() as f32) * self.mix_gain);
+    }
 
-        return 
-            (vrc6_combined_sample * vrc6_weight) + 
-            nes_sample;
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gain = mix.gain(MIXE_VRC6);
     }
 
     fn irq_flag(&self) -> bool {
@@ -1051,23 +809,7 @@ impl Mapper for Vrc6 {
                     _ => None
                 }
             },
-            0x2000 ..= 0x3FFF => {
-                if self.chr_a10_rules {
-                    match self.ppu_banking_mode {
-                        0 => self._mirroring_mode_0_read(address),
-                        1 => self._mirroring_mode_1_read(address),
-                        2 => self._mirroring_mode_2_read(address),
-                        3 => self._mirroring_mode_3_read(address),
-                        _ => {
-                            //println!("Unimplemented mirroring mode {}! Bailing.", self.ppu_banking_mode);
-                            None
-                        }
-                    }
-                } else {
-                    // A10 rules weirdness
-                    return self._a10_nametable_read(address);
-                }
-            }
+            0x2000 ..= 0x3FFF => self.nametable_mapping().read(address, &self.vram, &[], &self.chr),
             _ => None
         }
     }
@@ -1075,21 +817,9 @@ impl Mapper for Vrc6 {
     fn write_ppu(&mut self, address: u16, data: u8) {
         match address {
             0x2000 ..= 0x3FFF => {
-                if self.chr_a10_rules {
-                    match self.ppu_banking_mode {
-                        0 => self._mirroring_mode_0_write(address, data),
-                        1 => self._mirroring_mode_1_write(address, data),
-                        2 => self._mirroring_mode_2_write(address, data),
-                        3 => self._mirroring_mode_3_write(address, data),
-                        _ => {
-                            //println!("Unimplemented mirroring mode {}! Bailing.", self.ppu_banking_mode);
-                        }
-                    }
-                } else {
-                    // A10 rules weirdness
-                    self._a10_nametable_write(address, data);
-                }
-            }
+                let mapping = self.nametable_mapping();
+                mapping.write(address, data, &mut self.vram, &mut [], &mut self.chr);
+            },
             _ => {}
         }
     }
@@ -1,13 +1,16 @@
 // https://www.nesdev.org/wiki/VRC7
 // https://www.nesdev.org/wiki/VRC7_audio
 
+use load_error::LoadError;
 use ines::INesCartridge;
 use memoryblock::MemoryBlock;
+use nsf::MIXE_VRC7;
 
 use mmc::mapper::*;
 use mmc::mirroring;
 
 use apu::AudioChannelState;
+use apu::ExpansionMix;
 use apu::PlaybackRate;
 use apu::Volume;
 use apu::Timbre;
@@ -38,10 +41,11 @@ pub struct Vrc7 {
     pub audio_register: u8,
 
     pub audio: Vrc7Audio,
+    pub mix_gain: f32,
 }
 
 impl Vrc7 {
-    pub fn from_ines(ines: INesCartridge) -> Result<Vrc7, String> {
+    pub fn from_ines(ines: INesCartridge) -> Result<Vrc7, LoadError> {
         let prg_rom_block = ines.prg_rom_block();
         let prg_ram_block = ines.prg_ram_block()?;
         let chr_block = ines.chr_block()?;
@@ -66,6 +70,7 @@ impl Vrc7 {
 
             audio: Vrc7Audio::new(),
             audio_register: 0,
+            mix_gain: 1.0,
         });
     }
 
@@ -91,6 +96,24 @@ pub fn amplitude_from_db(db: f32) -> f32 {
     return f32::powf(10.0, db / 20.0);
 }
 
+fn mixed_vrc7_audio(vrc7_output: i16) -> f32 {
+    let combined_vrc7_audio = vrc7_output as f32 
+        / 256.0 // to go from +256/-256 to +1/-1
+        / 6.0;  // number of vrc7 channels
+
+    // I measured the above mix with the db_vrc7.nes test from rainwarrior's 
+    // audio survey, found here https://forums.nesdev.org/viewtopic.php?t=17741
+    // and found that the VRC7 is 6.23 dB louder than the APU. 
+
+    // The NSFe defaults the VRC7 to +11 dB relative to the APU:
+    // https://www.nesdev.org/wiki/NSFe#mixe
+    // This also aligns neatly with several tests in that forum thread, so it's what
+    // I'll run with here.
+    let stock_vrc7_db = 6.23;
+    let desired_vrc7_db = 11.00;
+    return combined_vrc7_audio * amplitude_from_db(desired_vrc7_db - stock_vrc7_db);
+}
+
 impl Mapper for Vrc7 {
     fn print_debug_status(&self) {
         println!("======= VRC7 =======");
@@ -110,23 +133,29 @@ impl Mapper for Vrc7 {
     }
 
     fn mix_expansion_audio(&self, nes_sample: f32) -> f32 {
-        let combined_vrc7_audio = self.audio.output() as f32 
-            / 256.0 // to go from +256/-256 to +1/-1
-            / 6.0;  // number of vrc7 channels
+        return mixed_vrc7_audio(self.audio.output()) * self.mix_gain + nes_sample;
+    }
 
-        // I measured the above mix with the db_vrc7.nes test from rainwarrior's 
-        // audio survey, found here https://forums.nesdev.org/viewtopic.php?t=17741
-        // and found that the VRC7 is 6.23 dB louder than the APU. 
+    fn expansion_channel_outputs(&self, outputs: &mut Vec<f32>) {
+        for index in 0 .. self.audio.channel_count() {
+            outputs.push(mixed_vrc7_audio(self.audio.channel(index).output()) * self.mix_gain);
+        }
+    }
 
-        // The NSFe defaults the VRC7 to +11 dB relative to the APU:
-        // https://www.nesdev.org/wiki/NSFe#mixe
-        // This also aligns neatly with several tests in that forum thread, so it's what
-        // I'll run with here.
-        let stock_vrc7_db = 6.23;
-        let desired_vrc7_db = 11.00;
-        let mixed_vrc7_audio = combined_vrc7_audio * amplitude_from_db(desired_vrc7_db - stock_vrc7_db);
+    fn set_expansion_mix(&mut self, mix: &ExpansionMix) {
+        self.mix_gain = mix.gain(MIXE_VRC7);
+    }
+
+    fn vrc7_patch_set(&mut self, patch_set: Vrc7PatchSet) {
+        self.audio.set_patch_set(patch_set);
+    }
 
-        return mixed_vrc7_audio + nes_sample;
+    fn vrc7_adsr_research(&mut self, enabled: bool) {
+        self.audio.set_adsr_research(enabled);
+    }
+
+    fn vrc7_ym2413_mode(&mut self, enabled: bool) {
+        self.audio.set_ym2413_mode(enabled);
     }
 
     fn irq_flag(&self) -> bool {
@@ -136,6 +165,37 @@ impl Mapper for Vrc7 {
     fn mirroring(&self) -> Mirroring {
         return self.mirroring;
     }
+
+    fn cart_ram_mut(&mut self) -> Vec<&mut MemoryBlock> {
+        return vec![&mut self.prg_ram, &mut self.chr];
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut MemoryBlock> {
+        return Some(&mut self.prg_ram);
+    }
+
+    fn reset(&mut self) {
+        // The VRC7 has no reset input, so every register survives a reset
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_ram.power_cycle();
+        self.chr.power_cycle();
+        self.chr_banks = vec![0u8; 8];
+        self.prg_banks = vec![0u8; 3];
+        self.irq_scanline_prescaler = 0;
+        self.irq_latch = 0;
+        self.irq_scanline_mode = false;
+        self.irq_enable = false;
+        self.irq_enable_after_acknowledgement = false;
+        self.irq_pending = false;
+        self.irq_counter = 0;
+        self.audio.power_cycle();
+        self.audio_register = 0;
+        for byte in self.vram.iter_mut() {
+            *byte = 0;
+        }
+    }
     
     fn debug_read_cpu(&self, address: u16) -> Option<u8> {
         match address {
@@ -263,33 +323,19 @@ impl Mapper for Vrc7 {
     }
 
     fn get_sram(&self) -> Vec<u8> {
-        return self.prg_ram.as_vec().clone();
+        return self.prg_ram.battery_backed_bytes();
     }
 
     fn load_sram(&mut self, sram_data: Vec<u8>) {
-        *self.prg_ram.as_mut_vec() = sram_data;
+        self.prg_ram.load_battery_backed_bytes(sram_data);
     }
 
     fn channels(&self) ->  Vec<& dyn AudioChannelState> {
-        let mut channels: Vec<& dyn AudioChannelState> = Vec::new();
-        channels.push(&self.audio.channel1);
-        channels.push(&self.audio.channel2);
-        channels.push(&self.audio.channel3);
-        channels.push(&self.audio.channel4);
-        channels.push(&self.audio.channel5);
-        channels.push(&self.audio.channel6);
-        return channels;
+        return self.audio.channels();
     }
-    
+
     fn channels_mut(&mut self) ->  Vec<&mut dyn AudioChannelState> {
-        let mut channels: Vec<&mut dyn AudioChannelState> = Vec::new();
-        channels.push(&mut self.audio.channel1);
-        channels.push(&mut self.audio.channel2);
-        channels.push(&mut self.audio.channel3);
-        channels.push(&mut self.audio.channel4);
-        channels.push(&mut self.audio.channel5);
-        channels.push(&mut self.audio.channel6);        
-        return channels;
+        return self.audio.channels_mut();
     }
 
     fn record_expansion_audio_output(&mut self, _nes_sample: f32) {
@@ -370,6 +416,7 @@ pub const FM_LFO_LUT: [i16; 8 * 8] = [
     0, 3, 7, 3, 0,-3,-7,-3,
 ];
 
+// The VRC7's built-in instruments, as dumped from a decapped chip by Nuke.YKT in 2019
 pub const DEFAULT_PATCH_TABLE: [u8; 8 * 15] = [
     0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27, // Buzzy Bell
     0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12, // Guitar
@@ -388,21 +435,306 @@ pub const DEFAULT_PATCH_TABLE: [u8; 8 * 15] = [
     0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06  // Sweep
 ];
 
+// Okazaki's 2004 estimates for the VRC7, worked out by ear from recordings. Most players used
+// these until the real ones were dumped, so they're handy when comparing against older rips.
+pub const ESTIMATED_PATCH_TABLE: [u8; 8 * 15] = [
+    0x33, 0x01, 0x09, 0x0E, 0x94, 0x90, 0x40, 0x01,
+    0x13, 0x41, 0x0F, 0x0D, 0xCE, 0xD3, 0x43, 0x13,
+    0x01, 0x12, 0x1B, 0x06, 0xFF, 0xD2, 0x00, 0x32,
+    0x61, 0x61, 0x1B, 0x07, 0xAF, 0x63, 0x20, 0x28,
+    0x22, 0x21, 0x1E, 0x06, 0xF0, 0x76, 0x08, 0x28,
+    0x66, 0x21, 0x15, 0x00, 0x93, 0x94, 0x20, 0xF8,
+    0x21, 0x61, 0x1C, 0x07, 0x82, 0x81, 0x10, 0x17,
+    0x23, 0x21, 0x20, 0x1F, 0xC0, 0x71, 0x07, 0x47,
+    0x25, 0x31, 0x26, 0x05, 0x64, 0x41, 0x18, 0xF8,
+    0x17, 0x21, 0x28, 0x07, 0xFF, 0x83, 0x02, 0xF8,
+    0x97, 0x81, 0x25, 0x07, 0xCF, 0xC8, 0x02, 0x14,
+    0x21, 0x21, 0x54, 0x0F, 0x80, 0x7F, 0x07, 0x07,
+    0x01, 0x01, 0x56, 0x03, 0xD3, 0xB2, 0x43, 0x58,
+    0x31, 0x21, 0x0C, 0x03, 0x82, 0xC0, 0x40, 0x07,
+    0x21, 0x01, 0x0C, 0x03, 0xD4, 0xD3, 0x40, 0x84
+];
+
+// The YM2413 (OPLL) the VRC7 was cut down from, as dumped by plgDavid
+pub const YM2413_PATCH_TABLE: [u8; 8 * 15] = [
+    0x71, 0x61, 0x1E, 0x17, 0xD0, 0x78, 0x00, 0x17, // Violin
+    0x13, 0x41, 0x1A, 0x0D, 0xD8, 0xF7, 0x23, 0x13, // Guitar
+    0x13, 0x01, 0x99, 0x00, 0xF2, 0xC4, 0x21, 0x23, // Piano
+    0x11, 0x61, 0x0E, 0x07, 0x8D, 0x64, 0x70, 0x27, // Flute
+    0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28, // Clarinet
+    0x31, 0x22, 0x16, 0x05, 0xE0, 0x71, 0x00, 0x18, // Oboe
+    0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07, // Trumpet
+    0x33, 0x21, 0x2D, 0x13, 0xB0, 0x70, 0x00, 0x07, // Organ
+    0x61, 0x61, 0x1B, 0x06, 0x64, 0x65, 0x10, 0x17, // Horn
+    0x41, 0x61, 0x0B, 0x18, 0x85, 0xF0, 0x81, 0x07, // Synthesizer
+    0x33, 0x01, 0x83, 0x11, 0xEA, 0xEF, 0x10, 0x04, // Harpsichord
+    0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12, // Vibraphone
+    0x61, 0x50, 0x0C, 0x05, 0xD2, 0xF5, 0x40, 0x42, // Synth Bass
+    0x01, 0x01, 0x55, 0x03, 0xE9, 0x90, 0x03, 0x02, // Acoustic Bass
+    0x41, 0x41, 0x89, 0x03, 0xF1, 0xE4, 0xC0, 0x13  // Electric Guitar
+];
+
+// The YMF281 (OPLLP), a YM2413 with a different set of instruments. Nobody has dumped one, so
+// these are Chabin's and plgDavid's estimates, with a few entries that match the YM2413 exactly.
+pub const YMF281_PATCH_TABLE: [u8; 8 * 15] = [
+    0x62, 0x21, 0x1A, 0x07, 0xF0, 0x6F, 0x00, 0x16, // Electric Strings
+    0x40, 0x10, 0x45, 0x00, 0xF6, 0x83, 0x73, 0x63, // Bow Wow
+    0x13, 0x01, 0x99, 0x00, 0xF2, 0xC3, 0x21, 0x23, // Electric Guitar
+    0x01, 0x61, 0x0B, 0x0F, 0xF9, 0x64, 0x70, 0x17, // Organ
+    0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28, // Clarinet
+    0x60, 0x01, 0x82, 0x0E, 0xF9, 0x61, 0x20, 0x27, // Saxophone
+    0x21, 0x61, 0x1C, 0x07, 0x84, 0x81, 0x11, 0x07, // Trumpet
+    0x37, 0x32, 0xC9, 0x01, 0x66, 0x64, 0x40, 0x28, // Street Organ
+    0x01, 0x21, 0x07, 0x03, 0xA5, 0x71, 0x51, 0x07, // Synth Brass
+    0x06, 0x01, 0x5E, 0x07, 0xF3, 0xF3, 0xF6, 0x13, // Electric Piano
+    0x00, 0x00, 0x18, 0x06, 0xF5, 0xF3, 0x20, 0x23, // Bass
+    0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12, // Vibraphone
+    0x35, 0x64, 0x00, 0x00, 0xFF, 0xF3, 0x77, 0xF5, // Chimes
+    0x11, 0x31, 0x00, 0x07, 0xDD, 0xF3, 0xFF, 0xFB, // Tom Tom II
+    0x3A, 0x21, 0x00, 0x07, 0x80, 0x84, 0x0F, 0xF5  // Noise
+];
+
+// Rhythm mode takes over channels 7-9 with these. The VRC7 has no rhythm mode, so every patch
+// set shares the YM2413's.
+pub const RHYTHM_PATCH_TABLE: [u8; 8 * 3] = [
+    0x01, 0x01, 0x18, 0x0F, 0xDF, 0xF8, 0x6A, 0x6D, // Bass Drum
+    0x01, 0x01, 0x00, 0x00, 0xC8, 0xD8, 0xA7, 0x68, // Hi-Hat (modulator) and Snare Drum (carrier)
+    0x05, 0x01, 0x00, 0x00, 0xF8, 0xAA, 0x59, 0x55  // Tom-Tom (modulator) and Top Cymbal (carrier)
+];
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Vrc7PatchSet {
+    Vrc7,
+    Vrc7Estimated,
+    Ym2413,
+    Ymf281,
+}
+
+pub fn vrc7_patch_set_from_name(name: &str) -> Option<Vrc7PatchSet> {
+    match name {
+        "vrc7" => Some(Vrc7PatchSet::Vrc7),
+        "vrc7_2004" => Some(Vrc7PatchSet::Vrc7Estimated),
+        "ym2413" => Some(Vrc7PatchSet::Ym2413),
+        "ymf281" => Some(Vrc7PatchSet::Ymf281),
+        _ => None
+    }
+}
+
+fn builtin_patch_table(patch_set: Vrc7PatchSet) -> &'static [u8] {
+    match patch_set {
+        Vrc7PatchSet::Vrc7 => &DEFAULT_PATCH_TABLE,
+        Vrc7PatchSet::Vrc7Estimated => &ESTIMATED_PATCH_TABLE,
+        Vrc7PatchSet::Ym2413 => &YM2413_PATCH_TABLE,
+        Vrc7PatchSet::Ymf281 => &YMF281_PATCH_TABLE,
+    }
+}
+
 pub const MT_LUT: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
 
+// Envelope steps over eight updates, for each of the four rates that share a shift
 pub const ADSR_RATE_LUT: [u8; 32] = [
     0, 1, 0, 1, 0, 1, 0, 1, // 4 out of 8
     0, 1, 0, 1, 1, 1, 0, 1, // 5 out of 8
     0, 1, 1, 1, 0, 1, 1, 1, // 6 out of 8
-    0, 1, 1, 1, 1, 1, 1, 1  // 7 out of 8 
+    0, 1, 1, 1, 1, 1, 1, 1  // 7 out of 8
+];
+
+// Decay steps for rates 56-59, which move the envelope every sample, sometimes by two
+pub const ADSR_FAST_RATE_LUT: [u8; 32] = [
+    1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 2, 1, 1, 1, 2,
+    1, 2, 1, 2, 1, 2, 1, 2,
+    1, 2, 2, 2, 1, 2, 2, 2
 ];
 
+// Rate used to quickly silence whatever was playing when a new note is keyed on
+const DAMP_RATE: u8 = 12;
+
+pub const MAX_ENV_LEVEL: u8 = 127;
+
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum EnvState {
     Damp,
     Attack,
     Decay,
-    Sustain
+    Sustain,
+    Release
+}
+
+// The order phases are reported in, as timbre, in ADSR research mode
+fn envelope_phase_index(state: EnvState) -> usize {
+    match state {
+        EnvState::Damp => 0,
+        EnvState::Attack => 1,
+        EnvState::Decay => 2,
+        EnvState::Sustain => 3,
+        EnvState::Release => 4,
+    }
+}
+
+// Patch rates run 0-15. The key scales them up, and the result is split into a group, where
+// each group runs twice as fast as the one before, and a step within that group. Rates past
+// the end of the last group stay there.
+fn effective_rate(given_rate: u8, key_scale_rate: u8) -> (u8, u8) {
+    if given_rate == 0 {
+        return (0, 0);
+    }
+    let group = given_rate + (key_scale_rate >> 2);
+    if group > 15 {
+        return (15, key_scale_rate & 0x3);
+    }
+    return (group, key_scale_rate & 0x3);
+}
+
+// Attack is exponential: each step covers a fraction of the distance that's left
+fn attack_step(level: u8, group: u8, step: u8, global_counter: u32) -> u8 {
+    if level == 0 {
+        return 0;
+    }
+    let table_index = (step * 8) as usize;
+    match group {
+        0 | 15 => {}, // Do nothing; the fastest attack is handled at key on
+        12 ..= 14 => {
+            // Every sample, with larger steps as the rate goes up
+            let row_index = ((global_counter & 0xC) >> 1) as usize;
+            let shift = 16 - group - ADSR_RATE_LUT[table_index + row_index];
+            return level - (level >> shift) - 1;
+        },
+        _ => {
+            let shift = 13 - group;
+            let mask = ((1 << shift) - 1) & !3; // ignore lower 2 bits!
+            if (global_counter & mask) == 0 {
+                let row_index = ((global_counter >> shift) & 7) as usize;
+                if ADSR_RATE_LUT[table_index + row_index] == 1 {
+                    return level - (level >> 4) - 1;
+                }
+            }
+        }
+    }
+    return level;
+}
+
+// Decay, sustain and release are linear, and this is how far the level moves this sample
+fn decay_increment(group: u8, step: u8, global_counter: u32) -> u8 {
+    let table_index = (step * 8) as usize;
+    match group {
+        0 => {return 0;},
+        13 => {
+            // The weird 16 sample period
+            let row_index = (((global_counter & 0xC) >> 1) | (global_counter & 1)) as usize;
+            return ADSR_RATE_LUT[table_index + row_index];
+        },
+        14 => {
+            let row_index = ((global_counter & 0xC) >> 1) as usize;
+            return ADSR_FAST_RATE_LUT[table_index + row_index];
+        },
+        15 => {return 2;},
+        _ => {
+            let shift = 13 - group;
+            let mask = (1 << shift) - 1;
+            if (global_counter & mask) == 0 {
+                let row_index = ((global_counter >> shift) & 7) as usize;
+                return ADSR_RATE_LUT[table_index + row_index];
+            }
+            return 0;
+        }
+    }
+}
+
+pub struct Vrc7Envelope {
+    pub attack_rate: u8,
+    pub decay_rate: u8,
+    pub sustain_level: u8,
+    pub release_rate: u8,
+    // The patch's EG type bit: hold at the sustain level for as long as the key is held
+    pub sustained: bool,
+    pub key_scaling: bool,
+
+    pub level: u8,
+    pub state: EnvState,
+    pub key_on: bool,
+}
+
+impl Vrc7Envelope {
+    pub fn new() -> Vrc7Envelope {
+        return Vrc7Envelope {
+            attack_rate: 0,
+            decay_rate: 0,
+            sustain_level: 0,
+            release_rate: 0,
+            sustained: false,
+            key_scaling: false,
+
+            level: MAX_ENV_LEVEL,
+            state: EnvState::Release,
+            key_on: false,
+        };
+    }
+
+    // A new note doesn't start right away: whatever was playing is damped first, and the
+    // operator restarts once that's done. See finished_damping().
+    pub fn handle_key_on(&mut self, new_key_on: bool) {
+        if new_key_on && !self.key_on {
+            self.state = EnvState::Damp;
+        }
+        if !new_key_on && self.key_on {
+            self.state = EnvState::Release;
+        }
+        self.key_on = new_key_on;
+    }
+
+    pub fn finished_damping(&self) -> bool {
+        return self.state == EnvState::Damp && (self.level >> 2) == 0x1F;
+    }
+
+    pub fn start(&mut self, key_scale_rate: u8) {
+        let (group, _) = effective_rate(self.attack_rate, key_scale_rate);
+        if group == 15 {
+            self.state = EnvState::Decay;
+            self.level = 0;
+        } else {
+            self.state = EnvState::Attack;
+        }
+    }
+
+    // Modulators don't release; they hold wherever they were when the key came up. In
+    // rhythm mode the hi-hat and tom-tom are keyed on their own, and act like carriers.
+    pub fn update_envelope(&mut self, global_counter: u32, key_scale_rate: u8, carrier: bool, sustain_mode: bool) {
+        if self.state == EnvState::Attack && self.level == 0 {
+            self.state = EnvState::Decay;
+        } else if self.state == EnvState::Decay && (self.level >> 3) == self.sustain_level {
+            self.state = EnvState::Sustain;
+        }
+
+        let rate = if !self.key_on {
+            if !carrier {
+                0
+            } else if sustain_mode {
+                5
+            } else if self.sustained {
+                self.release_rate
+            } else {
+                7
+            }
+        } else {
+            match self.state {
+                EnvState::Damp => DAMP_RATE,
+                EnvState::Attack => self.attack_rate,
+                EnvState::Decay => self.decay_rate,
+                EnvState::Sustain | EnvState::Release => {
+                    if self.sustained { 0 } else { self.release_rate }
+                }
+            }
+        };
+
+        let (group, step) = effective_rate(rate, key_scale_rate);
+        if self.state == EnvState::Attack && self.key_on {
+            self.level = attack_step(self.level, group, step, global_counter);
+        } else {
+            let new_level = self.level + decay_increment(group, step, global_counter);
+            self.level = if new_level > MAX_ENV_LEVEL {MAX_ENV_LEVEL} else {new_level};
+        }
+    }
 }
 
 pub struct Vrc7AudioChannel {
@@ -410,7 +742,7 @@ pub struct Vrc7AudioChannel {
     exp_lut: Vec<u16>,
     ksl_lut: Vec<u16>,
     am_lut: Vec<u16>,
-    
+
     fnum: u32,
     octave: u32,
     volume: u16,
@@ -418,19 +750,15 @@ pub struct Vrc7AudioChannel {
 
     carrier_phase: u32,
     modulator_phase: u32,
-    
+
     // Register $00
     modulator_tremolo: bool,
     modulator_vibrato: bool,
-    modulator_sustain_enabled: bool,
-    modulator_key_scaling: bool,
     modulator_multiplier: usize,
 
     // Register $01
     carrier_tremolo: bool,
     carrier_vibrato: bool,
-    carrier_sustain_enabled: bool,
-    carrier_key_scaling: bool,
     carrier_multiplier: usize,
 
     // Register $02
@@ -443,34 +771,22 @@ pub struct Vrc7AudioChannel {
     modulator_rectified: bool,
     feedback: u8,
 
-    // Register $04
-    modulator_attack_rate: u8,
-    modulator_decay_rate: u8,
-
-    // Reigster $05
-    carrier_attack_rate: u8,
-    carrier_decay_rate: u8,    
-
-    // Register $06
-    modulator_sustain_level: u8,
-    modulator_release_rate: u8,
-
-    // Register $07
-    carrier_sustain_level: u8,
-    carrier_release_rate: u8,
+    // Registers $00-$01 (sustain and key scaling) and $04-$07
+    modulator_envelope: Vrc7Envelope,
+    carrier_envelope: Vrc7Envelope,
 
     // Internal State
     global_counter: u32,
-    carrier_env_level: u8,
-    carrier_env_state: EnvState,
-    modulator_env_level: u8,
-    modulator_env_state: EnvState,
     modulator_previous_0: i16,
     modulator_previous_1: i16,
 
     key_on: bool,
     sustain_mode: bool,
     channel_index: u8,
+    chip: &'static str,
+    // In rhythm mode, channels 8 and 9 play a drum on each operator, keyed separately
+    rhythm_slots: bool,
+    report_envelope_phase: bool,
 
     output_buffer: RingBuffer,
     edge_buffer: RingBuffer,
@@ -486,7 +802,7 @@ pub struct Vrc7AudioChannel {
 }
 
 impl Vrc7AudioChannel {
-    pub fn new(channel_index: u8) -> Vrc7AudioChannel {
+    pub fn new(channel_index: u8, chip: &'static str) -> Vrc7AudioChannel {
         return Vrc7AudioChannel {
             logsin_lut: generate_logsin_lut(),
             exp_lut: generate_exp_table(),
@@ -503,14 +819,10 @@ impl Vrc7AudioChannel {
 
             modulator_tremolo: false,
             modulator_vibrato: false,
-            modulator_sustain_enabled: false,
-            modulator_key_scaling: false,
             modulator_multiplier: 0,
 
             carrier_tremolo: false,
             carrier_vibrato: false,
-            carrier_sustain_enabled: false,
-            carrier_key_scaling: false,
             carrier_multiplier: 0,
 
             modulator_key_level_scaling: 0,
@@ -522,36 +834,21 @@ impl Vrc7AudioChannel {
             modulator_rectified: true,
             feedback: 0,
 
-            // Register $04
-            modulator_attack_rate: 0,
-            modulator_decay_rate: 0,
-
-            // Reigster $05
-            carrier_attack_rate: 0,
-            carrier_decay_rate: 0,    
-
-            // Register $06
-            modulator_sustain_level: 0,
-            modulator_release_rate: 0,
-
-            // Register $07
-            carrier_sustain_level: 0,
-            carrier_release_rate: 0,
+            modulator_envelope: Vrc7Envelope::new(),
+            carrier_envelope: Vrc7Envelope::new(),
 
             // Internal state
             global_counter: 0,
 
-            carrier_env_level: 127,
-            carrier_env_state: EnvState::Sustain,
-            modulator_env_level: 127,
-            modulator_env_state: EnvState::Sustain,
-
             modulator_previous_0: 0,
             modulator_previous_1: 0,
 
             key_on: false,
             sustain_mode: false,
             channel_index: channel_index,
+            chip: chip,
+            rhythm_slots: false,
+            report_envelope_phase: false,
 
             output_buffer: RingBuffer::new(32768),
             edge_buffer: RingBuffer::new(32768),
@@ -614,17 +911,17 @@ impl Vrc7AudioChannel {
     }
 
     pub fn load_patch(&mut self, patch: &[u8]) {
-        self.modulator_tremolo         = (patch[0] & 0b1000_0000) != 0;
-        self.modulator_vibrato         = (patch[0] & 0b0100_0000) != 0;
-        self.modulator_sustain_enabled = (patch[0] & 0b0010_0000) != 0;
-        self.modulator_key_scaling     = (patch[0] & 0b0001_0000) != 0;
-        self.modulator_multiplier      = (patch[0] & 0b0000_1111) as usize;
-
-        self.carrier_tremolo         = (patch[1] & 0b1000_0000) != 0;
-        self.carrier_vibrato         = (patch[1] & 0b0100_0000) != 0;
-        self.carrier_sustain_enabled = (patch[1] & 0b0010_0000) != 0;
-        self.carrier_key_scaling     = (patch[1] & 0b0001_0000) != 0;
-        self.carrier_multiplier      = (patch[1] & 0b0000_1111) as usize;
+        self.modulator_tremolo                = (patch[0] & 0b1000_0000) != 0;
+        self.modulator_vibrato                = (patch[0] & 0b0100_0000) != 0;
+        self.modulator_envelope.sustained     = (patch[0] & 0b0010_0000) != 0;
+        self.modulator_envelope.key_scaling   = (patch[0] & 0b0001_0000) != 0;
+        self.modulator_multiplier             = (patch[0] & 0b0000_1111) as usize;
+
+        self.carrier_tremolo                = (patch[1] & 0b1000_0000) != 0;
+        self.carrier_vibrato                = (patch[1] & 0b0100_0000) != 0;
+        self.carrier_envelope.sustained     = (patch[1] & 0b0010_0000) != 0;
+        self.carrier_envelope.key_scaling   = (patch[1] & 0b0001_0000) != 0;
+        self.carrier_multiplier             = (patch[1] & 0b0000_1111) as usize;
 
         self.modulator_key_level_scaling = ((patch[2] & 0b1100_0000) >> 6) as usize;
         self.modulator_output_level      =  (patch[2] & 0b0011_1111) as u16;
@@ -634,210 +931,69 @@ impl Vrc7AudioChannel {
         self.modulator_rectified       =  (patch[3] & 0b0000_1000) != 0;
         self.feedback                  =   patch[3] & 0b0000_0111;
 
-        self.modulator_attack_rate = (patch[4] & 0b1111_0000) >> 4;
-        self.modulator_decay_rate  =  patch[4] & 0b0000_1111;
+        self.modulator_envelope.attack_rate = (patch[4] & 0b1111_0000) >> 4;
+        self.modulator_envelope.decay_rate  =  patch[4] & 0b0000_1111;
 
-        self.carrier_attack_rate = (patch[5] & 0b1111_0000) >> 4;
-        self.carrier_decay_rate  =  patch[5] & 0b0000_1111;
+        self.carrier_envelope.attack_rate = (patch[5] & 0b1111_0000) >> 4;
+        self.carrier_envelope.decay_rate  =  patch[5] & 0b0000_1111;
 
-        self.modulator_sustain_level = (patch[6] & 0b1111_0000) >> 4;
-        self.modulator_release_rate  =  patch[6] & 0b0000_1111;
+        self.modulator_envelope.sustain_level = (patch[6] & 0b1111_0000) >> 4;
+        self.modulator_envelope.release_rate  =  patch[6] & 0b0000_1111;
 
-        self.carrier_sustain_level = (patch[7] & 0b1111_0000) >> 4;
-        self.carrier_release_rate  =  patch[7] & 0b0000_1111;
+        self.carrier_envelope.sustain_level = (patch[7] & 0b1111_0000) >> 4;
+        self.carrier_envelope.release_rate  =  patch[7] & 0b0000_1111;
     }
 
-    fn effective_rate(&self, given_rate: u8, ks_enabled: bool) -> u8 {
-        // effective rates 0..3 and 60..63 are special cases
-        if given_rate == 0 {
-            return 0;
-        }
-        if given_rate == 15 {
-            return 63;
-        }
-
+    fn key_scale_rate(&self, ks_enabled: bool) -> u8 {
         let octave_and_fnum_msb = ((self.octave << 1) + (self.fnum >> 8)) as u8;
-        let rate_ks = if ks_enabled {
-            octave_and_fnum_msb
-        } else {
-            octave_and_fnum_msb >> 2
-        };
-
-        let result = given_rate * 4 + rate_ks;
-
-        // I am rather unsure about this!
-        if result > 59 {
-            return 59;
-        }
-        return result;
-    }
-
-    fn advance_attack(&self, given_rate: u8, ks_enabled: bool, current_env_level: u8) -> u8  {
-        let rate = self.effective_rate(given_rate, ks_enabled);
-        match rate / 4 {
-            0 | 15 => {}, // Do nothing
-            1 ..= 11 => {
-                let shift = 13 - (rate / 4);
-                let mask = ((1 << shift) - 1) & !3; // ignore lower 2 bits!
-                if (self.global_counter & mask) == 0 {
-                    let table_index = ((rate & 0x3) * 8) as usize;
-                    let row_index = ((self.global_counter >> shift) & 7) as usize;
-                    if ADSR_RATE_LUT[table_index + row_index] == 1 {
-                        return current_env_level - (current_env_level >> 4) - 1;
-                    }
-                }
-            },
-            12 ..= 14 => {
-                let table_index = ((rate & 0x3) * 8) as usize;
-                let row_index = ((self.global_counter & 0xC) >> 1) as usize;
-                let m = 16 - (rate / 4) - ADSR_RATE_LUT[table_index + row_index];
-                return current_env_level - (current_env_level >> m) - 1;
-            }
-            _ => {} // Should be unreachable
+        if ks_enabled {
+            return octave_and_fnum_msb;
         }
-        return current_env_level;
+        return octave_and_fnum_msb >> 2;
     }
 
-    fn shall_we_advance_the_decay_today(&self, given_rate: u8, ks_enabled: bool) -> bool {
-        let rate = self.effective_rate(given_rate, ks_enabled);
+    fn update_envelopes(&mut self) {
+        let carrier_ksr = self.key_scale_rate(self.carrier_envelope.key_scaling);
+        let modulator_ksr = self.key_scale_rate(self.modulator_envelope.key_scaling);
 
-        let table_index = ((rate & 0x3) * 8) as usize;
-        if rate < 56 {
-            let shift_amount = 13 - (rate / 4);
+        if self.carrier_envelope.finished_damping() {
+            self.carrier_envelope.start(carrier_ksr);
+            // The rhythm section's operators are keyed on their own, and keep their phase
+            if !self.rhythm_slots {
+                self.carrier_phase = 0;
 
-            let mask = (1 << shift_amount) - 1;
-            if (self.global_counter & mask) == 0 {
-                let row_index = ((self.global_counter >> shift_amount) & 0x7) as usize;
-                if ADSR_RATE_LUT[table_index + row_index] == 1 {
-                    return true;
+                // Also reset the modulator here
+                self.modulator_envelope.start(modulator_ksr);
+                if self.modulator_envelope.state == EnvState::Attack {
+                    self.modulator_envelope.level = MAX_ENV_LEVEL;
                 }
-            }
-        } else {
-            // weird 16 sample period
-            let row_index = ((self.global_counter & 0xC >> 1) | (self.global_counter & 1)) as usize;
-            if ADSR_RATE_LUT[table_index + row_index] == 1 {
-                return true;
+                self.modulator_phase = 0;
             }
         }
-        
-        // Not a chance!
-        return false;
-    }
-
-
-    fn update_carrier_adsr(&mut self) {
-        let max_env = (self.carrier_env_level >> 2) ==  0x1F;
-        if self.carrier_env_state == EnvState::Damp && max_env {
-            if self.carrier_attack_rate == 15 { 
-                self.carrier_env_state = EnvState::Decay;
-                self.carrier_env_level = 0;
-            } else { 
-                self.carrier_env_state = EnvState::Attack;
-            };
-            self.carrier_phase = 0;
-
-            // Also reset the modulator here
-            if self.modulator_attack_rate == 15 { 
-                self.modulator_env_state = EnvState::Decay;
-                self.modulator_env_level = 0;
-            } else { 
-                self.modulator_env_state = EnvState::Attack;
-                self.modulator_env_level = 127;
-            };
-            self.modulator_phase = 0;
-        } else if self.carrier_env_state == EnvState::Attack && self.carrier_env_level == 0 {
-            self.carrier_env_state = EnvState::Decay;
-        } else if self.carrier_env_state == EnvState::Decay && ((self.carrier_env_level >> 3) == self.carrier_sustain_level) {
-            self.carrier_env_state = EnvState::Sustain;
+        if self.rhythm_slots && self.modulator_envelope.finished_damping() {
+            self.modulator_envelope.start(modulator_ksr);
         }
 
-        let rate = if self.key_on == false {            
-            // release state
-            if self.sustain_mode  {
-                5
-            } else  if self.carrier_sustain_enabled {
-                self.carrier_release_rate
-            } else {
-                7
-            }
-        } else {
-            match self.carrier_env_state {
-                EnvState::Damp => {12},
-                EnvState::Attack => {self.carrier_attack_rate },
-                EnvState::Decay => {self.carrier_decay_rate },
-                EnvState::Sustain => {
-                    if self.carrier_sustain_enabled { 0 } else { self.carrier_release_rate }
-                }
-            }
-        };
-
-        if (self.carrier_env_state == EnvState::Attack) && self.key_on == true {
-            self.carrier_env_level = self.advance_attack(rate, self.carrier_key_scaling, self.carrier_env_level);
-        } else if self.shall_we_advance_the_decay_today(rate, self.carrier_key_scaling) {
-            if rate == 0 {
-                // Do absolutely nothing
-            } else if rate == 15 {
-                // Increase the envelope two times (capping at 127)
-                self.carrier_env_level += 2;
-                if self.carrier_env_level > 127 {
-                    self.carrier_env_level = 127;
-                }
-            } else if self.carrier_env_level < 127 {
-                // Increase the envelope just once (usual case)
-                self.carrier_env_level += 1;
-            }
-        }
+        let global_counter = self.global_counter;
+        let sustain_mode = self.sustain_mode;
+        self.carrier_envelope.update_envelope(global_counter, carrier_ksr, true, sustain_mode);
+        self.modulator_envelope.update_envelope(global_counter, modulator_ksr, self.rhythm_slots, sustain_mode);
     }
 
-    fn update_modulator_adsr(&mut self) {
-        if self.modulator_env_state == EnvState::Attack && self.modulator_env_level == 0 {
-            self.modulator_env_state = EnvState::Decay;
-        } else if self.modulator_env_state == EnvState::Decay && ((self.modulator_env_level >> 3) == self.modulator_sustain_level) {
-            self.modulator_env_state = EnvState::Sustain;
-        }
-
-        let rate = if self.key_on == false {
-            0
+    // The operators are usually keyed together, from this channel's own key on bit. In rhythm
+    // mode the drums add keys of their own.
+    fn handle_key_on(&mut self, carrier_key_on: bool, modulator_key_on: bool) {
+        self.carrier_envelope.handle_key_on(carrier_key_on);
+        if self.rhythm_slots {
+            self.modulator_envelope.handle_key_on(modulator_key_on);
         } else {
-            match self.modulator_env_state {
-                EnvState::Damp => {12},
-                EnvState::Attack => {self.modulator_attack_rate },
-                EnvState::Decay => {self.modulator_decay_rate },
-                EnvState::Sustain => {
-                    if self.modulator_sustain_enabled { 0 } else { self.modulator_release_rate }
-                }
-            }
-        };
-
-        if (self.modulator_env_state == EnvState::Attack) && self.key_on == true {
-            self.modulator_env_level = self.advance_attack(rate, self.modulator_key_scaling, self.modulator_env_level);
-        } else if self.shall_we_advance_the_decay_today(rate, self.modulator_key_scaling) {
-            if rate == 0 {
-                // Do absolutely nothing
-            } else if rate == 15 {
-                // Increase the envelope two times (capping at 127)
-                self.modulator_env_level += 2;
-                if self.modulator_env_level > 127 {
-                    self.modulator_env_level = 127;
-                }
-            } else if self.modulator_env_level < 127 {
-                // Increase the envelope just once (usual case)
-                self.modulator_env_level += 1;
-            }
-        }
-    }
-
-    fn handle_key_on(&mut self, new_key_on: bool) {
-        // Transition from 0 -> 1 triggers a new note event
-        if self.key_on == false && new_key_on == true {
             // Note: carrier will set modulator state when switching from damp -> attack
-            self.carrier_env_state = EnvState::Damp;
+            self.modulator_envelope.key_on = modulator_key_on;
         }
-        self.key_on = new_key_on;
     }
 
     pub fn update(&mut self) {
-        let carrier_vibrato = if self.carrier_vibrato { 
+        let carrier_vibrato = if self.carrier_vibrato {
             let upper_fnum = (self.fnum >> 6) as usize;
             FM_LFO_LUT[8 * upper_fnum + self.fm_pos]
         } else {
@@ -849,14 +1005,14 @@ impl Vrc7AudioChannel {
         }
         self.carrier_phase = (self.carrier_phase + carrier_step_size) & 0x7FFFF;
 
-        let modulator_vibrato = if self.modulator_vibrato { 
+        let modulator_vibrato = if self.modulator_vibrato {
             let upper_fnum = (self.fnum >> 6) as usize;
             FM_LFO_LUT[8 * upper_fnum + self.fm_pos]
         } else {
             0
         };
-        let modulator_step_size = (((2 * (self.fnum as i16) + modulator_vibrato) as u32 * MT_LUT[self.modulator_multiplier]) << self.octave) >> 2;        
-        self.modulator_phase = (self.modulator_phase + modulator_step_size) & 0x7FFFF;        
+        let modulator_step_size = (((2 * (self.fnum as i16) + modulator_vibrato) as u32 * MT_LUT[self.modulator_multiplier]) << self.octave) >> 2;
+        self.modulator_phase = (self.modulator_phase + modulator_step_size) & 0x7FFFF;
 
         if self.am_counter == 0 {
             self.am_pos += 1;
@@ -878,11 +1034,24 @@ impl Vrc7AudioChannel {
             self.fm_counter -= 1;
         }
 
-        self.update_carrier_adsr();
-        self.update_modulator_adsr();
+        self.update_envelopes();
 
         self.clock_global_counter();
-        self.compute_output();
+    }
+
+    fn modulator_attenuation(&self, level_attenuation: u16) -> u16 {
+        let env_attenuation = 16 * self.modulator_envelope.level as u16;
+        let ksl_attenuation = 16 * self.ksl_lut[(self.modulator_key_level_scaling * 8 * 16) + (self.octave as usize * 16) + (self.fnum >> 5) as usize];
+        let am_attenuation = if self.modulator_tremolo { 16 * self.am_lut[self.am_pos] } else {0};
+        return level_attenuation + env_attenuation + ksl_attenuation + am_attenuation;
+    }
+
+    fn carrier_attenuation(&self) -> u16 {
+        let vol_attenuation = 128 * self.volume;
+        let env_attenuation = 16 * self.carrier_envelope.level as u16;
+        let ksl_attenuation = 16 * self.ksl_lut[(self.carrier_key_level_scaling * 8 * 16) + (self.octave as usize * 16) + (self.fnum >> 5) as usize];
+        let am_attenuation = if self.carrier_tremolo { 16 * self.am_lut[self.am_pos] } else {0};
+        return vol_attenuation + env_attenuation + ksl_attenuation + am_attenuation;
     }
 
     pub fn compute_output(&mut self) {
@@ -891,23 +1060,28 @@ impl Vrc7AudioChannel {
         } else {
             0
         };
-        let effective_mod_phase = ((self.modulator_phase - 1) & 0x7FFFF) as i32;
+        let effective_mod_phase = (self.modulator_phase.wrapping_sub(1) & 0x7FFFF) as i32;
         let mod_logsin = self.lookup_logsin((((effective_mod_phase >> 9) + (feedback as i32)) & 0x7FFFF) as usize, self.modulator_rectified);
-        let mod_output_attenuation = 32 * self.modulator_output_level;
-        let mod_env_attenuation = 16 * self.modulator_env_level as u16;
-        let mod_ksl_attenuation = 16 * self.ksl_lut[(self.modulator_key_level_scaling * 8 * 16) + (self.octave as usize * 16) + (self.fnum >> 5) as usize];
-        let mod_am_attenuation = if self.modulator_tremolo { 16 * self.am_lut[self.am_pos] } else {0};
-        let mod_amount = self.lookup_exp(mod_logsin + mod_output_attenuation + mod_env_attenuation + mod_ksl_attenuation + mod_am_attenuation) >> 1; // drop lowest bit
+        let mod_amount = self.lookup_exp(mod_logsin + self.modulator_attenuation(32 * self.modulator_output_level)) >> 1; // drop lowest bit
         self.modulator_previous_0 = self.modulator_previous_1;
         self.modulator_previous_1 = mod_amount;
 
         let effective_carrier_phase = ((((self.carrier_phase >> 9) as i32) + ((2 * mod_amount as i32)) & 0x7FFFF)) as usize;
         let carrier_logsin = self.lookup_logsin(effective_carrier_phase, self.carrier_rectified);
-        let carrier_vol_attenuation = 128 * self.volume;
-        let carrier_env_attenuation = 16 * self.carrier_env_level as u16;
-        let carrier_ksl_attenuation = 16 * self.ksl_lut[(self.carrier_key_level_scaling * 8 * 16) + (self.octave as usize * 16) + (self.fnum >> 5) as usize];
-        let carrier_am_attenuation = if self.carrier_tremolo { 16 * self.am_lut[self.am_pos] } else {0};
-        self.current_output = self.lookup_exp(carrier_logsin + carrier_vol_attenuation + carrier_env_attenuation + carrier_ksl_attenuation + carrier_am_attenuation) / 16;
+        self.current_output = self.lookup_exp(carrier_logsin + self.carrier_attenuation()) / 16;
+    }
+
+    // The rhythm section plays each operator on its own, at a phase worked out elsewhere. The
+    // modulator's volume comes from the upper half of the volume register, in place of the
+    // instrument.
+    pub fn modulator_slot_output(&self, phase: usize) -> i16 {
+        let logsin = self.lookup_logsin(phase, self.modulator_rectified);
+        return self.lookup_exp(logsin + self.modulator_attenuation(128 * self.instrument_index as u16)) / 16;
+    }
+
+    pub fn carrier_slot_output(&self, phase: usize) -> i16 {
+        let logsin = self.lookup_logsin(phase, self.carrier_rectified);
+        return self.lookup_exp(logsin + self.carrier_attenuation()) / 16;
     }
 
     pub fn output(&self) -> i16 {
@@ -923,217 +1097,329 @@ pub struct Vrc7Audio {
     pub channel4: Vrc7AudioChannel,
     pub channel5: Vrc7AudioChannel,
     pub channel6: Vrc7AudioChannel,
+    // Only used in YM2413 mode
+    pub channel7: Vrc7AudioChannel,
+    pub channel8: Vrc7AudioChannel,
+    pub channel9: Vrc7AudioChannel,
     pub current_channel: usize,
     pub delay_counter: u8,
+
+    pub patch_set: Vrc7PatchSet,
+    // The full YM2413: nine channels, and a rhythm mode which turns the last three into drums
+    pub ym2413_mode: bool,
+    pub rhythm_mode: bool,
+    pub rhythm_key_on: u8,
+    pub noise: u32,
+    pub adsr_research: bool,
 }
 
 impl Vrc7Audio {
     pub fn new() -> Vrc7Audio {
-         let thing = Vrc7Audio {
+        return Vrc7Audio::with_chip("VRC7", false);
+    }
+
+    pub fn new_ym2413() -> Vrc7Audio {
+        let mut chip = Vrc7Audio::with_chip("YM2413", true);
+        chip.patch_set = Vrc7PatchSet::Ym2413;
+        return chip;
+    }
+
+    fn with_chip(chip: &'static str, ym2413_mode: bool) -> Vrc7Audio {
+        return Vrc7Audio {
             custom_patch: [0u8; 8],
-            channel1: Vrc7AudioChannel::new(1),
-            channel2: Vrc7AudioChannel::new(2),
-            channel3: Vrc7AudioChannel::new(3),
-            channel4: Vrc7AudioChannel::new(4),
-            channel5: Vrc7AudioChannel::new(5),
-            channel6: Vrc7AudioChannel::new(6),
+            channel1: Vrc7AudioChannel::new(1, chip),
+            channel2: Vrc7AudioChannel::new(2, chip),
+            channel3: Vrc7AudioChannel::new(3, chip),
+            channel4: Vrc7AudioChannel::new(4, chip),
+            channel5: Vrc7AudioChannel::new(5, chip),
+            channel6: Vrc7AudioChannel::new(6, chip),
+            channel7: Vrc7AudioChannel::new(7, chip),
+            channel8: Vrc7AudioChannel::new(8, chip),
+            channel9: Vrc7AudioChannel::new(9, chip),
             current_channel: 1,
             delay_counter: 0,
+
+            patch_set: Vrc7PatchSet::Vrc7,
+            ym2413_mode: ym2413_mode,
+            rhythm_mode: false,
+            rhythm_key_on: 0,
+            noise: 1,
+            adsr_research: false,
+        };
+    }
+
+    // Back to the power on state, but still the same chip, with the same options
+    pub fn power_cycle(&mut self) {
+        let mut chip = if self.ym2413_mode {
+            Vrc7Audio::new_ym2413()
+        } else {
+            Vrc7Audio::new()
         };
+        chip.patch_set = self.patch_set;
+        chip.set_adsr_research(self.adsr_research);
+        *self = chip;
+    }
+
+    // Swaps the chip for the other one, starting it over. The patch set stays as it was, since
+    // it's chosen separately.
+    pub fn set_ym2413_mode(&mut self, enabled: bool) {
+        if enabled != self.ym2413_mode {
+            self.ym2413_mode = enabled;
+            self.power_cycle();
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        if self.ym2413_mode {
+            return 9;
+        }
+        return 6;
+    }
 
-        //ugly_debug_thing();
+    pub fn channel(&self, index: usize) -> &Vrc7AudioChannel {
+        match index {
+            0 => &self.channel1,
+            1 => &self.channel2,
+            2 => &self.channel3,
+            3 => &self.channel4,
+            4 => &self.channel5,
+            5 => &self.channel6,
+            6 => &self.channel7,
+            7 => &self.channel8,
+            _ => &self.channel9,
+        }
+    }
+
+    pub fn channel_mut(&mut self, index: usize) -> &mut Vrc7AudioChannel {
+        match index {
+            0 => &mut self.channel1,
+            1 => &mut self.channel2,
+            2 => &mut self.channel3,
+            3 => &mut self.channel4,
+            4 => &mut self.channel5,
+            5 => &mut self.channel6,
+            6 => &mut self.channel7,
+            7 => &mut self.channel8,
+            _ => &mut self.channel9,
+        }
+    }
+
+    pub fn channels(&self) -> Vec<& dyn AudioChannelState> {
+        let mut channels: Vec<& dyn AudioChannelState> = vec![
+            &self.channel1, &self.channel2, &self.channel3,
+            &self.channel4, &self.channel5, &self.channel6,
+            &self.channel7, &self.channel8, &self.channel9];
+        channels.truncate(self.channel_count());
+        return channels;
+    }
 
-        return thing;
+    pub fn channels_mut(&mut self) -> Vec<&mut dyn AudioChannelState> {
+        let channel_count = self.channel_count();
+        let mut channels: Vec<&mut dyn AudioChannelState> = vec![
+            &mut self.channel1, &mut self.channel2, &mut self.channel3,
+            &mut self.channel4, &mut self.channel5, &mut self.channel6,
+            &mut self.channel7, &mut self.channel8, &mut self.channel9];
+        channels.truncate(channel_count);
+        return channels;
+    }
+
+    pub fn set_patch_set(&mut self, patch_set: Vrc7PatchSet) {
+        self.patch_set = patch_set;
+        for index in 0 .. self.channel_count() {
+            if self.channel(index).instrument_index != 0 {
+                self.load_patch(index);
+            }
+        }
+    }
+
+    // Report each channel's envelope phase as its timbre, in place of the instrument, and
+    // show notes for as long as the envelope is moving at all
+    pub fn set_adsr_research(&mut self, enabled: bool) {
+        self.adsr_research = enabled;
+        for index in 0 .. 9 {
+            self.channel_mut(index).report_envelope_phase = enabled;
+        }
     }
 
     pub fn clock(&mut self) {
         if self.delay_counter == 0 {
-            match self.current_channel {
-                0 => self.channel1.update(),
-                1 => self.channel2.update(),
-                2 => self.channel3.update(),
-                3 => self.channel4.update(),
-                4 => self.channel5.update(),
-                5 => self.channel6.update(),
-                _ => {} // unreachable
-            }
+            let channel_index = self.current_channel;
+            self.update_channel(channel_index);
             self.current_channel += 1;
-            if self.current_channel >= 6 {
+            if self.current_channel >= self.channel_count() {
                 self.current_channel = 0;
+                if self.ym2413_mode {
+                    self.clock_noise();
+                }
             }
-            self.delay_counter = 5;
+            // The YM2413 runs its nine channels in the time the VRC7 takes for six, so the
+            // sample rate is the same either way
+            self.delay_counter = if self.ym2413_mode {3} else {5};
         } else {
             self.delay_counter -= 1;
         }
     }
 
-    pub fn output(&self) -> i16 {
-        let mut combined_output = 0;
-        if !self.channel1.debug_disable {
-            combined_output += self.channel1.output();   
-        }
-        if !self.channel2.debug_disable {
-            combined_output += self.channel2.output();   
-        }
-        if !self.channel3.debug_disable {
-            combined_output += self.channel3.output();   
+    fn update_channel(&mut self, index: usize) {
+        self.channel_mut(index).update();
+        if self.rhythm_mode && index >= 6 {
+            self.compute_rhythm_output(index);
+        } else {
+            self.channel_mut(index).compute_output();
         }
-        if !self.channel4.debug_disable {
-            combined_output += self.channel4.output();   
+    }
+
+    fn clock_noise(&mut self) {
+        if (self.noise & 1) != 0 {
+            self.noise ^= 0x800302;
         }
-        if !self.channel5.debug_disable {
-            combined_output += self.channel5.output();   
+        self.noise >>= 1;
+    }
+
+    // Each drum is output twice as the chip works through its operators, so they come out
+    // twice as loud as a melodic channel would
+    fn compute_rhythm_output(&mut self, index: usize) {
+        let noise = (self.noise & 1) != 0;
+        // The hi-hat and top cymbal ring-modulate channel 8's modulator with channel 9's carrier,
+        // by way of a few bits of their phase
+        let modulator_phase = (self.channel8.modulator_phase >> 9) as usize;
+        let carrier_phase = (self.channel9.carrier_phase >> 9) as usize;
+        let modulator_bit = |bit: usize| ((modulator_phase >> bit) & 1) != 0;
+        let carrier_bit = |bit: usize| ((carrier_phase >> bit) & 1) != 0;
+        let ring = ((modulator_bit(2) ^ modulator_bit(7)) | modulator_bit(3)) | (carrier_bit(3) ^ carrier_bit(5));
+
+        match index {
+            6 => {
+                self.channel7.compute_output();
+                self.channel7.current_output *= 2;
+            },
+            7 => {
+                let hi_hat_phase = match (ring, noise) {
+                    (true, true) => 0x2D0,
+                    (true, false) => 0x234,
+                    (false, true) => 0x034,
+                    (false, false) => 0x0D0,
+                };
+                let snare_phase = (if modulator_bit(8) {0x200} else {0x100}) ^ (if noise {0x100} else {0});
+                let hi_hat = self.channel8.modulator_slot_output(hi_hat_phase);
+                let snare = self.channel8.carrier_slot_output(snare_phase);
+                self.channel8.current_output = 2 * (hi_hat + snare);
+            },
+            _ => {
+                let tom_phase = (self.channel9.modulator_phase >> 9) as usize;
+                let cymbal_phase = if ring {0x300} else {0x100};
+                let tom = self.channel9.modulator_slot_output(tom_phase);
+                let cymbal = self.channel9.carrier_slot_output(cymbal_phase);
+                self.channel9.current_output = 2 * (tom + cymbal);
+            }
         }
-        if !self.channel6.debug_disable {
-            combined_output += self.channel6.output();   
+    }
+
+    pub fn output(&self) -> i16 {
+        let mut combined_output = 0;
+        for index in 0 .. self.channel_count() {
+            let channel = self.channel(index);
+            if !channel.debug_disable {
+                combined_output += channel.output();
+            }
         }
         return combined_output;
     }
 
-    pub fn refresh_custom_patch(&mut self) {
-        if self.channel1.instrument_index == 0 {
-            self.channel1.load_patch(&self.custom_patch);
-        }
-        if self.channel2.instrument_index == 0 {
-            self.channel2.load_patch(&self.custom_patch);
-        }
-        if self.channel3.instrument_index == 0 {
-            self.channel3.load_patch(&self.custom_patch);
-        }
-        if self.channel4.instrument_index == 0 {
-            self.channel4.load_patch(&self.custom_patch);
-        }
-        if self.channel5.instrument_index == 0 {
-            self.channel5.load_patch(&self.custom_patch);
+    fn load_patch(&mut self, index: usize) {
+        let instrument_index = self.channel(index).instrument_index as usize;
+        let mut patch = [0u8; 8];
+        if self.rhythm_mode && index >= 6 {
+            let patch_index = (index - 6) * 8;
+            patch.copy_from_slice(&RHYTHM_PATCH_TABLE[patch_index .. patch_index + 8]);
+        } else if instrument_index == 0 {
+            patch = self.custom_patch;
+        } else {
+            let patch_index = (instrument_index - 1) * 8;
+            patch.copy_from_slice(&builtin_patch_table(self.patch_set)[patch_index .. patch_index + 8]);
         }
-        if self.channel6.instrument_index == 0 {
-            self.channel6.load_patch(&self.custom_patch);
+        self.channel_mut(index).load_patch(&patch);
+    }
+
+    pub fn refresh_custom_patch(&mut self) {
+        for index in 0 .. self.channel_count() {
+            if self.channel(index).instrument_index == 0 {
+                self.load_patch(index);
+            }
         }
     }
 
+    fn update_key_on(&mut self, index: usize) {
+        let rhythm_key_on = if self.rhythm_mode {self.rhythm_key_on} else {0};
+        // Register $0E, by carrier and modulator
+        let (carrier_bit, modulator_bit) = match index {
+            6 => (0b1_0000, 0b1_0000), // Bass Drum
+            7 => (0b0_1000, 0b0_0001), // Snare Drum, Hi-Hat
+            8 => (0b0_0010, 0b0_0100), // Top Cymbal, Tom-Tom
+            _ => (0, 0)
+        };
+        let channel = self.channel_mut(index);
+        let key_on = channel.key_on;
+        channel.handle_key_on(
+            key_on || (rhythm_key_on & carrier_bit) != 0,
+            key_on || (rhythm_key_on & modulator_bit) != 0);
+    }
+
     pub fn write(&mut self, address: u8, data: u8) {
+        let index = (address & 0x0F) as usize;
+        if (address & 0xF0) != 0 && index >= self.channel_count() {
+            return;
+        }
         match address {
-            0x00 => {self.custom_patch[0] = data; self.refresh_custom_patch()},
-            0x01 => {self.custom_patch[1] = data; self.refresh_custom_patch()},
-            0x02 => {self.custom_patch[2] = data; self.refresh_custom_patch()},
-            0x03 => {self.custom_patch[3] = data; self.refresh_custom_patch()},
-            0x04 => {self.custom_patch[4] = data; self.refresh_custom_patch()},
-            0x05 => {self.custom_patch[5] = data; self.refresh_custom_patch()},
-            0x06 => {self.custom_patch[6] = data; self.refresh_custom_patch()},
-            0x07 => {self.custom_patch[7] = data; self.refresh_custom_patch()},
-            0x10 => {self.channel1.fnum = (self.channel1.fnum & 0xFF00) + (data as u32)},
-            0x11 => {self.channel2.fnum = (self.channel2.fnum & 0xFF00) + (data as u32)},
-            0x12 => {self.channel3.fnum = (self.channel3.fnum & 0xFF00) + (data as u32)},
-            0x13 => {self.channel4.fnum = (self.channel4.fnum & 0xFF00) + (data as u32)},
-            0x14 => {self.channel5.fnum = (self.channel5.fnum & 0xFF00) + (data as u32)},
-            0x15 => {self.channel6.fnum = (self.channel6.fnum & 0xFF00) + (data as u32)},
-            0x20 => {
-                self.channel1.fnum = (self.channel1.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
-                self.channel1.octave = ((data & 0b1110) >> 1) as u32;
-                self.channel1.handle_key_on((data & 0b1_0000) != 0);
-                self.channel1.sustain_mode = (data & 0b10_0000) != 0;
-            },
-            0x21 => {
-                self.channel2.fnum = (self.channel2.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
-                self.channel2.octave = ((data & 0b1110) >> 1) as u32;
-                self.channel2.handle_key_on((data & 0b1_0000) != 0);
-                self.channel2.sustain_mode = (data & 0b10_0000) != 0;
-            },
-            0x22 => {
-                self.channel3.fnum = (self.channel3.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
-                self.channel3.octave = ((data & 0b1110) >> 1) as u32;
-                self.channel3.handle_key_on((data & 0b1_0000) != 0);
-                self.channel3.sustain_mode = (data & 0b10_0000) != 0;
-            },
-            0x23 => {
-                self.channel4.fnum = (self.channel4.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
-                self.channel4.octave = ((data & 0b1110) >> 1) as u32;
-                self.channel4.handle_key_on((data & 0b1_0000) != 0);
-                self.channel4.sustain_mode = (data & 0b10_0000) != 0;
-            },
-            0x24 => {
-                self.channel5.fnum = (self.channel5.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
-                self.channel5.octave = ((data & 0b1110) >> 1) as u32;
-                self.channel5.handle_key_on((data & 0b1_0000) != 0);
-                self.channel5.sustain_mode = (data & 0b10_0000) != 0;
-            },
-            0x25 => {
-                self.channel6.fnum = (self.channel6.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
-                self.channel6.octave = ((data & 0b1110) >> 1) as u32;
-                self.channel6.handle_key_on((data & 0b1_0000) != 0);
-                self.channel6.sustain_mode = (data & 0b10_0000) != 0;
-            },
-            0x30 => {
-                self.channel1.volume = (data & 0xF) as u16;
-                self.channel1.instrument_index = (data & 0xF0) >> 4;
-                if self.channel1.instrument_index == 0 {
-                    self.channel1.load_patch(&self.custom_patch);
-                } else {
-                    let patch_index = ((self.channel1.instrument_index - 1) * 8) as usize;
-                    self.channel1.load_patch(&DEFAULT_PATCH_TABLE[patch_index .. patch_index + 8]);
-                }                
+            0x00 ..= 0x07 => {
+                self.custom_patch[address as usize] = data;
+                self.refresh_custom_patch();
             },
-            0x31 => {
-                self.channel2.volume = (data & 0xF) as u16;
-                self.channel2.instrument_index = (data & 0xF0) >> 4;
-                if self.channel2.instrument_index == 0 {
-                    self.channel2.load_patch(&self.custom_patch);
-                } else {
-                    let patch_index = ((self.channel2.instrument_index - 1) * 8) as usize;
-                    self.channel2.load_patch(&DEFAULT_PATCH_TABLE[patch_index .. patch_index + 8]);
+            0x0E if self.ym2413_mode => {
+                let rhythm_mode = (data & 0b10_0000) != 0;
+                self.rhythm_key_on = data & 0b1_1111;
+                if rhythm_mode != self.rhythm_mode {
+                    self.rhythm_mode = rhythm_mode;
+                    self.channel8.rhythm_slots = rhythm_mode;
+                    self.channel9.rhythm_slots = rhythm_mode;
+                    for index in 6 .. 9 {
+                        self.load_patch(index);
+                    }
                 }
-            },
-            0x32 => {
-                self.channel3.volume = (data & 0xF) as u16;
-                self.channel3.instrument_index = (data & 0xF0) >> 4;
-                if self.channel3.instrument_index == 0 {
-                    self.channel3.load_patch(&self.custom_patch);
-                } else {
-                    let patch_index = ((self.channel3.instrument_index - 1) * 8) as usize;
-                    self.channel3.load_patch(&DEFAULT_PATCH_TABLE[patch_index .. patch_index + 8]);
+                for index in 6 .. 9 {
+                    self.update_key_on(index);
                 }
             },
-            0x33 => {
-                self.channel4.volume = (data & 0xF) as u16;
-                self.channel4.instrument_index = (data & 0xF0) >> 4;
-                if self.channel4.instrument_index == 0 {
-                    self.channel4.load_patch(&self.custom_patch);
-                } else {
-                    let patch_index = ((self.channel4.instrument_index - 1) * 8) as usize;
-                    self.channel4.load_patch(&DEFAULT_PATCH_TABLE[patch_index .. patch_index + 8]);
-                }
+            0x10 ..= 0x18 => {
+                let channel = self.channel_mut(index);
+                channel.fnum = (channel.fnum & 0xFF00) + (data as u32);
             },
-            0x34 => {
-                self.channel5.volume = (data & 0xF) as u16;
-                self.channel5.instrument_index = (data & 0xF0) >> 4;
-                if self.channel5.instrument_index == 0 {
-                    self.channel5.load_patch(&self.custom_patch);
-                } else {
-                    let patch_index = ((self.channel5.instrument_index - 1) * 8) as usize;
-                    self.channel5.load_patch(&DEFAULT_PATCH_TABLE[patch_index .. patch_index + 8]);
+            0x20 ..= 0x28 => {
+                {
+                    let channel = self.channel_mut(index);
+                    channel.fnum = (channel.fnum & 0x00FF) + (((data & 0b1) as u32) << 8);
+                    channel.octave = ((data & 0b1110) >> 1) as u32;
+                    channel.key_on = (data & 0b1_0000) != 0;
+                    channel.sustain_mode = (data & 0b10_0000) != 0;
                 }
+                self.update_key_on(index);
             },
-            0x35 => {
-                self.channel6.volume = (data & 0xF) as u16;
-                self.channel6.instrument_index = (data & 0xF0) >> 4;
-                if self.channel6.instrument_index == 0 {
-                    self.channel6.load_patch(&self.custom_patch);
-                } else {
-                    let patch_index = ((self.channel6.instrument_index - 1) * 8) as usize;
-                    self.channel6.load_patch(&DEFAULT_PATCH_TABLE[patch_index .. patch_index + 8]);
+            0x30 ..= 0x38 => {
+                {
+                    let channel = self.channel_mut(index);
+                    channel.volume = (data & 0xF) as u16;
+                    channel.instrument_index = (data & 0xF0) >> 4;
                 }
+                self.load_patch(index);
             },
             _ => {}
         }
     }
 
     pub fn record_output(&mut self) {
-        self.channel1.record_current_output();
-        self.channel2.record_current_output();
-        self.channel3.record_current_output();
-        self.channel4.record_current_output();
-        self.channel5.record_current_output();
-        self.channel6.record_current_output();
+        for index in 0 .. self.channel_count() {
+            self.channel_mut(index).record_current_output();
+        }
     }
 }
 
@@ -1143,7 +1429,7 @@ impl AudioChannelState for Vrc7AudioChannel {
     }
 
     fn chip(&self) -> String {
-        return "VRC7".to_string();
+        return self.chip.to_string();
     }
 
     fn sample_buffer(&self) -> &RingBuffer {
@@ -1182,9 +1468,14 @@ impl AudioChannelState for Vrc7AudioChannel {
     }
 
     fn playing(&self) -> bool {
-        return 
-            self.fnum > 0 && 
-            self.carrier_env_level < 120 &&
+        if self.report_envelope_phase {
+            return self.fnum > 0 && self.carrier_envelope.level < MAX_ENV_LEVEL;
+        }
+        let envelope_open = self.carrier_envelope.level < 120 ||
+            (self.rhythm_slots && self.modulator_envelope.level < 120);
+        return
+            self.fnum > 0 &&
+            envelope_open &&
             self.volume < 0xF;
     }
 
@@ -1194,12 +1485,93 @@ impl AudioChannelState for Vrc7AudioChannel {
     }
 
     fn volume(&self) -> Option<Volume> {
-        let approximate_volume = self.lookup_exp(self.lookup_logsin(0, false) + 128 * self.volume + 16 * self.carrier_env_level as u16) + 1; // If we're playing at all, display *something*
+        let approximate_volume = self.lookup_exp(self.lookup_logsin(0, false) + 128 * self.volume + 16 * self.carrier_envelope.level as u16) + 1; // If we're playing at all, display *something*
         return Some(Volume::VolumeIndex{ index: approximate_volume as usize, max: 12 }); //  max chosen arbitrary to get a decent-ish relative scale
     }
 
     fn timbre(&self) -> Option<Timbre> {
+        if self.report_envelope_phase {
+            return Some(Timbre::EnvelopePhase{ index: envelope_phase_index(self.carrier_envelope.state), max: 4 });
+        }
         return Some(Timbre::PatchIndex{ index: self.instrument_index as usize, max: 15 });
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ines::test_cartridge;
+
+    // Keys on channel 9 with the first instrument, and reports the loudest it gets
+    fn play_channel_9(chip: &mut Vrc7Audio) -> i16 {
+        chip.write(0x38, 0x10);
+        chip.write(0x18, 0x20);
+        chip.write(0x28, 0x19);
+        let mut loudest = 0;
+        for _ in 0 .. 100_000 {
+            chip.clock();
+            loudest = loudest.max(chip.channel9.output().abs());
+        }
+        return loudest;
+    }
+
+    #[test]
+    fn only_the_ym2413_plays_channels_7_to_9() {
+        let mut vrc7 = Vrc7Audio::new();
+        assert_eq!(vrc7.channels().len(), 6);
+        assert_eq!(play_channel_9(&mut vrc7), 0);
+
+        let mut ym2413 = Vrc7Audio::new_ym2413();
+        assert_eq!(ym2413.channels().len(), 9);
+        assert!(play_channel_9(&mut ym2413) > 0);
+    }
+
+    #[test]
+    fn rhythm_mode_keys_each_drum_on_its_own() {
+        let mut chip = Vrc7Audio::new_ym2413();
+        // Bass drum: both of channel 7's operators
+        chip.write(0x0E, 0b11_0000);
+        assert!(chip.rhythm_mode);
+        assert!(chip.channel7.carrier_envelope.key_on && chip.channel7.modulator_envelope.key_on);
+        assert!(!chip.channel8.carrier_envelope.key_on && !chip.channel8.modulator_envelope.key_on);
+
+        // Hi-hat and top cymbal: channel 8's modulator and channel 9's carrier, and nothing else
+        chip.write(0x0E, 0b10_0011);
+        assert!(!chip.channel7.carrier_envelope.key_on);
+        assert!(chip.channel8.modulator_envelope.key_on && !chip.channel8.carrier_envelope.key_on);
+        assert!(chip.channel9.carrier_envelope.key_on && !chip.channel9.modulator_envelope.key_on);
+
+        // The drums get their own patches, and are heard once keyed
+        chip.write(0x17, 0x20);
+        chip.write(0x27, 0x05);
+        let mut loudest = 0;
+        for _ in 0 .. 100_000 {
+            chip.clock();
+            loudest = loudest.max(chip.channel8.output().abs());
+        }
+        assert!(loudest > 0);
+    }
+
+    #[test]
+    fn the_vrc7_has_no_rhythm_mode() {
+        let mut chip = Vrc7Audio::new();
+        chip.write(0x0E, 0b11_0000);
+        assert!(!chip.rhythm_mode);
+        assert!(!chip.channel7.carrier_envelope.key_on);
+    }
+
+    #[test]
+    fn each_ym2413_channel_gets_an_output() {
+        let mut mapper = Vrc7::from_ines(test_cartridge(85, 2, 1)).unwrap();
+        let mut outputs = Vec::new();
+        mapper.expansion_channel_outputs(&mut outputs);
+        assert_eq!(outputs.len(), 6);
+
+        mapper.vrc7_ym2413_mode(true);
+        outputs.clear();
+        mapper.expansion_channel_outputs(&mut outputs);
+        assert_eq!(outputs.len(), 9);
+        assert_eq!(outputs.len(), mapper.channels().len());
+        assert_eq!(mapper.channels()[8].chip(), "YM2413");
+    }
+}
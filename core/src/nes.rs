@@ -3,9 +3,11 @@ use cartridge;
 use cycle_cpu;
 use cycle_cpu::CpuState;
 use cycle_cpu::Registers;
+use load_error::LoadError;
 use memory;
 use memory::CpuMemory;
 use ppu::PpuState;
+use power_on::PowerOnConfig;
 use mmc::mapper::Mapper;
 use tracked_events::EventTracker;
 
@@ -24,6 +26,7 @@ pub struct NesState {
     pub mapper: Box<dyn Mapper>,
     pub last_frame: u32,
     pub event_tracker: EventTracker,
+    pub power_on_config: PowerOnConfig,
 }
 
 impl NesState {
@@ -43,25 +46,27 @@ impl NesState {
             mapper: m,
             last_frame: 0,
             event_tracker: EventTracker::new(),
+            power_on_config: PowerOnConfig::new(),
         }
     }
 
     #[deprecated(since="0.2.0", note="please use `::new(mapper)` instead")]
-    pub fn from_rom(cart_data: &[u8]) -> Result<NesState, String> {
-        let maybe_mapper = cartridge::mapper_from_file(cart_data);
-        match maybe_mapper {
-            Ok(mapper) => {
-                let mut nes = NesState::new(mapper);
-                nes.power_on();
-                return Ok(nes);
-            },
-            Err(why) => {
-                return Err(why);
-            }
-        }
+    pub fn from_rom(cart_data: &[u8]) -> Result<NesState, LoadError> {
+        let mapper = cartridge::mapper_from_file(cart_data)?;
+        let mut nes = NesState::new(mapper);
+        nes.power_on();
+        return Ok(nes);
     }
 
     pub fn power_on(&mut self) {
+        // Fill memory with whatever the machine configuration says it wakes up holding
+        self.power_on_config.fill_internal_ram(&mut self.memory.iram_raw);
+        self.power_on_config.fill_palette(&mut self.ppu.palette);
+        self.power_on_config.fill_oam(&mut self.ppu.oam);
+        for (index, block) in self.mapper.cart_ram_mut().into_iter().enumerate() {
+            self.power_on_config.fill_cart_ram(index, block);
+        }
+
         // Initialize CPU register state for power-up sequence
         self.registers.a = 0;
         self.registers.y = 0;
@@ -94,12 +99,35 @@ impl NesState {
         // Silence the APU
         memory::write_byte(self, 0x4015, 0);
 
+        self.mapper.reset();
+
         let pc_low = memory::read_byte(self, 0xFFFC);
         let pc_high = memory::read_byte(self, 0xFFFD);
         self.registers.pc = pc_low as u16 + ((pc_high as u16) << 8);
     }
 
+    pub fn power_cycle(&mut self) {
+        // Everything inside the console forgets its state, but the host's audio
+        // configuration (and any expansion port hardware) should carry over
+        let previous_apu = std::mem::replace(&mut self.apu, ApuState::new());
+        self.apu.keep_host_settings(&previous_apu);
+
+        self.cpu = CpuState::new();
+        self.memory = CpuMemory::new();
+        self.ppu = PpuState::new();
+        self.registers = Registers::new();
+        self.master_clock = 0;
+        self.p1_data = 0;
+        self.p2_data = 0;
+        self.input_latch = false;
+        self.last_frame = 0;
+
+        self.mapper.power_cycle();
+        self.power_on();
+    }
+
     pub fn cycle(&mut self) {
+        self.event_tracker.master_clock = self.master_clock;
         cycle_cpu::run_one_clock(self);
         self.master_clock = self.master_clock + 12;
         // Three PPU clocks per every 1 CPU clock
@@ -10,6 +10,12 @@
 // may not perform correctly in Rustico, just as they would fail in most
 // hardware NSF player implementations. This is a feature, not a bug.
 
+// NSFe is a chunk based relative of NSF which adds track titles, lengths
+// and the like: https://wiki.nesdev.com/w/index.php/NSFe
+// NSF2 keeps the classic header, but may carry the same chunks after the
+// program data, and adds a few playback features of its own:
+// https://wiki.nesdev.com/w/index.php/NSF2
+
 use std::io::Read;
 use std::error::Error;
 use std::fmt;
@@ -38,11 +44,41 @@ const NSF_COPYRIGHT_HOLDER: usize = 0x04E;
 const NSF_NTSC_PLAY_SPEED: usize = 0x06E;
 const NSF_BANK_INIT: usize = 0x070;
 const NSF_PAL_PLAY_SPEED: usize = 0x078;
-//const NSF_NTSC_PAL_SELECTION: usize = 0x07A;
+const NSF_NTSC_PAL_SELECTION: usize = 0x07A;
 const NSF_EXPANSION_CHIPS: usize = 0x07B;
-//const NSF2_FLAGS: usize = 0x07C;
+const NSF2_FLAGS: usize = 0x07C;
 const NSF_PRG_LENGTH: usize = 0x07D;
 
+// NSFe files without a RATE chunk play at the usual vblank rates
+const NSFE_DEFAULT_NTSC_SPEED: u16 = 16639;
+const NSFE_DEFAULT_PAL_SPEED: u16 = 19997;
+
+// Devices named by the mixe chunk, and the level (in millibels) that each
+// has when the file doesn't override it
+pub const MIXE_APU_SQUARE: u8 = 0;
+pub const MIXE_APU_TRIANGLE: u8 = 1;
+pub const MIXE_VRC6: u8 = 2;
+pub const MIXE_VRC7: u8 = 3;
+pub const MIXE_FDS: u8 = 4;
+pub const MIXE_MMC5: u8 = 5;
+pub const MIXE_N163: u8 = 6;
+pub const MIXE_S5B: u8 = 7;
+pub const MIXE_DEFAULT_LEVELS: [i16; 8] = [0, -20, 0, 1100, 700, 0, 1100, -130];
+
+pub fn mixe_device_name(device: u8) -> &'static str {
+    return match device {
+        MIXE_APU_SQUARE => "APU Square",
+        MIXE_APU_TRIANGLE => "APU Triangle/Noise/DPCM",
+        MIXE_VRC6 => "VRC6",
+        MIXE_VRC7 => "VRC7",
+        MIXE_FDS => "FDS",
+        MIXE_MMC5 => "MMC5",
+        MIXE_N163 => "N163",
+        MIXE_S5B => "5B",
+        _ => "Unknown"
+    };
+}
+
 impl NsfHeader {
     pub fn from(raw_bytes: &[u8]) -> NsfHeader {
         let mut header = NsfHeader {
@@ -52,6 +88,52 @@ impl NsfHeader {
         return header;
     }
 
+    // Builds a classic header out of the NSFe INFO, BANK and RATE chunks, so the
+    // rest of the player doesn't need to know which format it came from
+    fn from_nsfe(info: &[u8], bank: &[u8], rate: &[u8]) -> Result<NsfHeader, NsfError> {
+        if info.len() < 9 {
+            return Err(NsfError::MalformedChunk{chunk: "INFO".to_string(), reason: format!("expected at least 9 bytes, found {}", info.len())});
+        }
+        let mut header = NsfHeader {
+            raw_bytes: [0u8; 0x80],
+        };
+        header.raw_bytes[NSF_MAGIC_N .. NSF_MAGIC_EOF].copy_from_slice(b"NESM");
+        header.raw_bytes[NSF_MAGIC_EOF] = MSDOS_EOF;
+        header.raw_bytes[NSF_VERSION] = 1;
+        header.raw_bytes[NSF_LOAD_ADDR .. NSF_LOAD_ADDR + 6].copy_from_slice(&info[0 .. 6]);
+        header.raw_bytes[NSF_NTSC_PAL_SELECTION] = info[6];
+        header.raw_bytes[NSF_EXPANSION_CHIPS] = info[7];
+        header.raw_bytes[NSF_TOTAL_SONGS] = info[8];
+        // NSFe counts songs from 0, the NSF header from 1
+        header.raw_bytes[NSF_STARTING_SONG] = info.get(9).cloned().unwrap_or(0).wrapping_add(1);
+
+        let bank_count = bank.len().min(8);
+        header.raw_bytes[NSF_BANK_INIT .. NSF_BANK_INIT + bank_count].copy_from_slice(&bank[0 .. bank_count]);
+
+        header.set_word(NSF_NTSC_PLAY_SPEED, NSFE_DEFAULT_NTSC_SPEED);
+        header.set_word(NSF_PAL_PLAY_SPEED, NSFE_DEFAULT_PAL_SPEED);
+        if rate.len() >= 2 {
+            header.raw_bytes[NSF_NTSC_PLAY_SPEED .. NSF_NTSC_PLAY_SPEED + 2].copy_from_slice(&rate[0 .. 2]);
+        }
+        if rate.len() >= 4 {
+            header.raw_bytes[NSF_PAL_PLAY_SPEED .. NSF_PAL_PLAY_SPEED + 2].copy_from_slice(&rate[2 .. 4]);
+        }
+        return Ok(header);
+    }
+
+    fn set_word(&mut self, offset: usize, value: u16) {
+        self.raw_bytes[offset + 0] = (value & 0x00FF) as u8;
+        self.raw_bytes[offset + 1] = ((value & 0xFF00) >> 8) as u8;
+    }
+
+    // The header strings are 32 bytes, null terminated; longer strings are cut short
+    fn set_string(&mut self, offset: usize, value: &str) {
+        let bytes = value.as_bytes();
+        let length = bytes.len().min(31);
+        self.raw_bytes[offset .. offset + 32].copy_from_slice(&[0u8; 32]);
+        self.raw_bytes[offset .. offset + length].copy_from_slice(&bytes[0 .. length]);
+    }
+
     pub fn magic_header_valid(&self) -> bool {
         return 
             self.raw_bytes[NSF_MAGIC_N] as char == 'N' &&
@@ -133,6 +215,34 @@ impl NsfHeader {
         return addr_low + addr_mid + addr_high;
     }
 
+    fn nsf2_flags(&self) -> u8 {
+        // Older files leave this byte zero, but don't trust the ones that didn't
+        if self.version_number() < 2 {
+            return 0;
+        }
+        return self.raw_bytes[NSF2_FLAGS];
+    }
+
+    // The program may use the NSF2 IRQ timer at $401B-$401D, and sets its own IRQ vector
+    pub fn irq_support(&self) -> bool {
+        return (self.nsf2_flags() & 0b0001_0000) != 0;
+    }
+
+    // INIT may never return; PLAY is then called from NMI instead
+    pub fn non_returning_init(&self) -> bool {
+        return (self.nsf2_flags() & 0b0010_0000) != 0;
+    }
+
+    // The player should not call PLAY at all
+    pub fn suppress_play(&self) -> bool {
+        return (self.nsf2_flags() & 0b0100_0000) != 0;
+    }
+
+    // The metadata holds chunks which must be understood to play the file correctly
+    pub fn metadata_required(&self) -> bool {
+        return (self.nsf2_flags() & 0b1000_0000) != 0;
+    }
+
     pub fn vrc6(&self) -> bool {
         return (self.raw_bytes[NSF_EXPANSION_CHIPS] & 0b0000_0001) != 0;
     }
@@ -174,6 +284,9 @@ impl NsfHeader {
 pub enum NsfError {
     InvalidHeader,
     Unimplemented,
+    MissingChunk{chunk: String},
+    MalformedChunk{chunk: String, reason: String},
+    UnsupportedChunk{chunk: String},
     ReadError{reason: String}
 }
 
@@ -184,6 +297,9 @@ impl fmt::Display for NsfError  {
         match self {
             NsfError::InvalidHeader => {write!(f, "Invalid NSF Header")},
             NsfError::Unimplemented => {write!(f, "Unimplemented (Lazy programmers!!1)")},
+            NsfError::MissingChunk{chunk} => {write!(f, "Missing {} chunk", chunk)},
+            NsfError::MalformedChunk{chunk, reason} => {write!(f, "Malformed {} chunk: {}", chunk, reason)},
+            NsfError::UnsupportedChunk{chunk} => {write!(f, "File requires the {} chunk, which is not supported", chunk)},
             NsfError::ReadError{reason} => {write!(f, "Error reading cartridge: {}", reason)}
         }
     }
@@ -195,6 +311,154 @@ impl From<std::io::Error> for NsfError {
     }
 }
 
+pub struct NsfeChunk {
+    pub id: String,
+    pub data: Vec<u8>,
+}
+
+impl NsfeChunk {
+    // Chunks beginning with a capital letter must be understood by the player;
+    // the rest are optional and can be skipped
+    pub fn is_required(&self) -> bool {
+        return self.id.as_bytes()[0].is_ascii_uppercase();
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    return
+        (bytes[0] as u32) |
+        ((bytes[1] as u32) << 8) |
+        ((bytes[2] as u32) << 16) |
+        ((bytes[3] as u32) << 24);
+}
+
+// Splits NSFe style chunk data, stopping at NEND or the end of the data
+pub fn read_chunks(data: &[u8]) -> Result<Vec<NsfeChunk>, NsfError> {
+    let mut chunks: Vec<NsfeChunk> = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let length = read_u32(&data[offset .. offset + 4]) as usize;
+        let id = String::from_utf8_lossy(&data[offset + 4 .. offset + 8]).into_owned();
+        let start = offset + 8;
+        if length > data.len() - start {
+            return Err(NsfError::ReadError{reason: format!("Chunk {} runs past the end of the file", id)});
+        }
+        if id == "NEND" {
+            break;
+        }
+        chunks.push(NsfeChunk{id: id, data: data[start .. start + length].to_vec()});
+        offset = start + length;
+    }
+    return Ok(chunks);
+}
+
+fn read_strings(data: &[u8]) -> Vec<String> {
+    let mut strings: Vec<String> = data.split(|&b| b == 0).map(|s| String::from_utf8_lossy(s).into_owned()).collect();
+    // A properly terminated list leaves one empty string at the end
+    if data.last() == Some(&0) || data.len() == 0 {
+        strings.pop();
+    }
+    return strings;
+}
+
+// Track lengths and fades are in milliseconds; negative values mean "use the default"
+fn read_durations(data: &[u8]) -> Vec<Option<u32>> {
+    return data.chunks_exact(4).map(|bytes| {
+        let milliseconds = read_u32(bytes) as i32;
+        return if milliseconds >= 0 {Some(milliseconds as u32)} else {None};
+    }).collect();
+}
+
+// Everything in an NSFe file, or NSF2 metadata, beyond what the classic header holds
+#[derive(Clone, Default)]
+pub struct NsfMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+    pub ripper: Option<String>,
+    pub text: Option<String>,
+    pub track_titles: Vec<String>,
+    pub track_lengths: Vec<Option<u32>>,
+    pub track_fades: Vec<Option<u32>>,
+    // Song indices (from 0) in the order they should be played
+    pub playlist: Vec<u8>,
+    // (device, level in millibels) pairs, devices as the MIXE_ constants
+    pub mixing: Vec<(u8, i16)>,
+}
+
+impl NsfMetadata {
+    // Returns false for chunks that aren't metadata
+    fn apply_chunk(&mut self, chunk: &NsfeChunk) -> bool {
+        match chunk.id.as_str() {
+            "auth" => {
+                let mut strings = read_strings(&chunk.data).into_iter();
+                self.title = strings.next();
+                self.artist = strings.next();
+                self.copyright = strings.next();
+                self.ripper = strings.next();
+            },
+            "text" => {self.text = read_strings(&chunk.data).into_iter().next()},
+            "tlbl" => {self.track_titles = read_strings(&chunk.data)},
+            "time" => {self.track_lengths = read_durations(&chunk.data)},
+            "fade" => {self.track_fades = read_durations(&chunk.data)},
+            "plst" => {self.playlist = chunk.data.clone()},
+            "mixe" => {
+                self.mixing = chunk.data.chunks_exact(3).map(|entry| {
+                    return (entry[0], ((entry[1] as u16) | ((entry[2] as u16) << 8)) as i16);
+                }).collect();
+            },
+            _ => {return false;}
+        }
+        return true;
+    }
+
+    // The auth strings take the place of the header's, which are shorter anyway
+    fn apply_to_header(&self, header: &mut NsfHeader) {
+        match self.title {
+            Some(ref title) => header.set_string(NSF_SONG_NAME, title),
+            None => {}
+        }
+        match self.artist {
+            Some(ref artist) => header.set_string(NSF_ARTIST_NAME, artist),
+            None => {}
+        }
+        match self.copyright {
+            Some(ref copyright) => header.set_string(NSF_COPYRIGHT_HOLDER, copyright),
+            None => {}
+        }
+    }
+
+    pub fn track_title(&self, song_index: usize) -> Option<&str> {
+        return match self.track_titles.get(song_index) {
+            Some(title) if title.len() > 0 => Some(title.as_str()),
+            _ => None
+        };
+    }
+
+    pub fn track_length(&self, song_index: usize) -> Option<u32> {
+        return self.track_lengths.get(song_index).cloned().unwrap_or(None);
+    }
+
+    pub fn track_fade(&self, song_index: usize) -> Option<u32> {
+        return self.track_fades.get(song_index).cloned().unwrap_or(None);
+    }
+
+    pub fn mixing_level(&self, device: u8) -> Option<i16> {
+        return self.mixing.iter().rev().find(|entry| entry.0 == device).map(|entry| entry.1);
+    }
+}
+
+// Pulls the metadata out of a list of chunks, refusing any required chunk we don't know
+fn read_metadata(chunks: &[NsfeChunk], known_chunks: &[&str]) -> Result<NsfMetadata, NsfError> {
+    let mut metadata = NsfMetadata::default();
+    for chunk in chunks {
+        if !metadata.apply_chunk(chunk) && chunk.is_required() && !known_chunks.contains(&chunk.id.as_str()) {
+            return Err(NsfError::UnsupportedChunk{chunk: chunk.id.clone()});
+        }
+    }
+    return Ok(metadata);
+}
+
 #[derive(Clone)]
 pub struct NsfFile {
     // Internal strategy is to store each major chunk of the file as
@@ -203,14 +467,50 @@ pub struct NsfFile {
     pub header: NsfHeader,
     pub prg: Vec<u8>,
     pub metadata: Vec<u8>,
+    // Parsed from the NSFe chunks, or from NSF2 metadata
+    pub tags: NsfMetadata,
+    pub is_nsfe: bool,
+}
+
+// Bank switched programs are addressed in 4k banks starting from $8000, so line the
+// program data up with those
+fn align_prg(header: &NsfHeader, prg: Vec<u8>) -> Vec<u8> {
+    if !header.is_bank_switched() {
+        return prg;
+    }
+    // Pad the beginning of this data with zero bytes up to the load address
+    let padding_bytes = (header.load_address() & 0x0FFF) as usize;
+    let mut rom_image = Vec::new();
+    rom_image.resize(padding_bytes, 0);
+    rom_image.extend(prg);
+    // If the final length at this point is not a multiple of 4k, the size of one PRG bank,
+    // then we now additionally extend it to fill out the last bank to this boundary
+    if rom_image.len() % 0x1000 != 0 {
+        let alignment_shortage = 0x1000 - (rom_image.len() % 0x1000);
+        let aligned_size = rom_image.len() + alignment_shortage;
+        rom_image.resize(aligned_size, 0);
+    }
+    return rom_image;
 }
 
 impl NsfFile {
     pub fn from_reader(file_reader: &mut dyn Read) -> Result<NsfFile, NsfError> {
+        let mut magic = [0u8; 4];
+        // A file too short to hold the magic number isn't in this format at all
+        if file_reader.read_exact(&mut magic).is_err() {
+            return Err(NsfError::InvalidHeader);
+        }
+        if &magic == b"NSFE" {
+            return NsfFile::from_nsfe(file_reader);
+        }
+
         let mut header_bytes = [0u8; 0x80];
-        file_reader.read_exact(&mut header_bytes)?;
+        header_bytes[0 .. 4].copy_from_slice(&magic);
+        if file_reader.read_exact(&mut header_bytes[4 ..]).is_err() {
+            return Err(NsfError::InvalidHeader);
+        }
 
-        let header = NsfHeader::from(&header_bytes);
+        let mut header = NsfHeader::from(&header_bytes);
         if !header.magic_header_valid() {
             return Err(NsfError::InvalidHeader);
         }
@@ -230,27 +530,203 @@ impl NsfFile {
             file_reader.read_to_end(&mut metadata)?;
         }
 
-        if header.is_bank_switched() {
-            // Pad the beginning of this data with zero bytes up to the load address
-            let padding_bytes = (header.load_address() & 0x0FFF) as usize;
-            let mut rom_image = Vec::new();
-            rom_image.resize(padding_bytes, 0);
-            rom_image.extend(prg);
-            // If the final length at this point is not a multiple of 4k, the size of one PRG bank,
-            // then we now additionally extend it to fill out the last bank to this boundary
-            if rom_image.len() % 0x1000 != 0 {
-                let alignment_shortage = 0x1000 - (rom_image.len() % 0x1000);
-                let aligned_size = rom_image.len() + alignment_shortage;
-                rom_image.resize(aligned_size, 0);
+        let mut tags = NsfMetadata::default();
+        if header.version_number() >= 2 && metadata.len() > 0 {
+            // NSF2 metadata is a list of NSFe chunks. Unless the header says otherwise, none
+            // of it is essential, so a damaged list shouldn't stop the music.
+            match read_chunks(&metadata).and_then(|chunks| read_metadata(&chunks, &[])) {
+                Ok(parsed_tags) => {tags = parsed_tags;},
+                Err(e) => {
+                    if header.metadata_required() {
+                        return Err(e);
+                    }
+                    println!("Ignoring NSF2 metadata: {}", e);
+                }
             }
-            prg = rom_image;
         }
+        tags.apply_to_header(&mut header);
+
+        return Ok(NsfFile {
+            header: header,
+            prg: align_prg(&header, prg),
+            metadata: metadata,
+            tags: tags,
+            is_nsfe: false,
+        });
+    }
+
+    fn from_nsfe(file_reader: &mut dyn Read) -> Result<NsfFile, NsfError> {
+        let mut chunk_data: Vec<u8> = Vec::new();
+        file_reader.read_to_end(&mut chunk_data)?;
+        let chunks = read_chunks(&chunk_data)?;
+
+        let find_chunk = |id: &str| chunks.iter().find(|chunk| chunk.id == id).map(|chunk| chunk.data.clone());
+        let info = find_chunk("INFO").ok_or(NsfError::MissingChunk{chunk: "INFO".to_string()})?;
+        let prg = find_chunk("DATA").ok_or(NsfError::MissingChunk{chunk: "DATA".to_string()})?;
+        let bank = find_chunk("BANK").unwrap_or(Vec::new());
+        let rate = find_chunk("RATE").unwrap_or(Vec::new());
+
+        let mut header = NsfHeader::from_nsfe(&info, &bank, &rate)?;
+        let tags = read_metadata(&chunks, &["INFO", "DATA", "BANK", "RATE"])?;
+        tags.apply_to_header(&mut header);
 
         return Ok(NsfFile {
             header: header,
-            prg: prg,
-            metadata: metadata
+            prg: align_prg(&header, prg),
+            metadata: Vec::new(),
+            tags: tags,
+            is_nsfe: true,
         });
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(id.as_bytes());
+        bytes.extend(data);
+        return bytes;
+    }
+
+    fn durations(milliseconds: &[i32]) -> Vec<u8> {
+        return milliseconds.iter().flat_map(|value| value.to_le_bytes().to_vec()).collect();
+    }
+
+    // Load $8000, init $8003, play $8006, NTSC, VRC6, three songs starting from the second
+    const INFO: [u8; 10] = [0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x00, 0x01, 3, 1];
+
+    fn nsfe_file(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"NSFE".to_vec();
+        for chunk in chunks {
+            file.extend(chunk);
+        }
+        return file;
+    }
+
+    #[test]
+    fn reads_nsfe_chunks() {
+        let file = nsfe_file(&[
+            chunk("INFO", &INFO),
+            chunk("DATA", &[0xEA, 0xEA, 0x60]),
+            chunk("auth", b"Title\0Artist\0Copyright\0Ripper\0"),
+            chunk("tlbl", b"First\0\0Third\0"),
+            chunk("time", &durations(&[60000, -1, 1500])),
+            chunk("fade", &durations(&[2000])),
+            chunk("plst", &[2, 0, 1]),
+            chunk("mixe", &[MIXE_VRC6, 0x9C, 0xFF, MIXE_APU_SQUARE, 0x32, 0x00, MIXE_VRC6, 0x64, 0x00]),
+            chunk("NEND", &[]),
+            // Anything after NEND isn't part of the file
+            chunk("ZZZZ", &[0xFF]),
+        ]);
+        let nsf = NsfFile::from_reader(&mut file.as_slice()).unwrap();
+        assert!(nsf.is_nsfe);
+        assert_eq!(nsf.header.load_address(), 0x8000);
+        assert_eq!(nsf.header.init_address(), 0x8003);
+        assert_eq!(nsf.header.play_address(), 0x8006);
+        assert!(nsf.header.vrc6());
+        assert_eq!(nsf.header.total_songs(), 3);
+        assert_eq!(nsf.header.starting_song(), 2);
+        assert_eq!(nsf.header.ntsc_playback_speed(), NSFE_DEFAULT_NTSC_SPEED);
+        assert_eq!(nsf.prg, vec![0xEA, 0xEA, 0x60]);
+        assert_eq!(nsf.header.song_name()[0 .. 6].to_vec(), b"Title\0".to_vec());
+        assert_eq!(nsf.tags.ripper.as_ref().map(|s| s.as_str()), Some("Ripper"));
+
+        assert_eq!(nsf.tags.track_title(0), Some("First"));
+        assert_eq!(nsf.tags.track_title(1), None);
+        assert_eq!(nsf.tags.track_title(2), Some("Third"));
+        assert_eq!(nsf.tags.track_length(0), Some(60000));
+        assert_eq!(nsf.tags.track_length(1), None);
+        assert_eq!(nsf.tags.track_length(2), Some(1500));
+        assert_eq!(nsf.tags.track_fade(0), Some(2000));
+        assert_eq!(nsf.tags.track_fade(1), None);
+        assert_eq!(nsf.tags.playlist, vec![2, 0, 1]);
+        // The last entry for a device wins
+        assert_eq!(nsf.tags.mixing_level(MIXE_VRC6), Some(100));
+        assert_eq!(nsf.tags.mixing_level(MIXE_APU_SQUARE), Some(50));
+        assert_eq!(nsf.tags.mixing_level(MIXE_FDS), None);
+    }
+
+    #[test]
+    fn nsfe_needs_info_and_data() {
+        let file = nsfe_file(&[chunk("DATA", &[0x60]), chunk("NEND", &[])]);
+        match NsfFile::from_reader(&mut file.as_slice()) {
+            Err(NsfError::MissingChunk{chunk}) => assert_eq!(chunk, "INFO"),
+            _ => panic!("expected a missing INFO chunk"),
+        }
+        let file = nsfe_file(&[chunk("INFO", &INFO), chunk("NEND", &[])]);
+        match NsfFile::from_reader(&mut file.as_slice()) {
+            Err(NsfError::MissingChunk{chunk}) => assert_eq!(chunk, "DATA"),
+            _ => panic!("expected a missing DATA chunk"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let file = nsfe_file(&[chunk("INFO", &INFO[0 .. 8]), chunk("DATA", &[0x60])]);
+        match NsfFile::from_reader(&mut file.as_slice()) {
+            Err(NsfError::MalformedChunk{chunk, ..}) => assert_eq!(chunk, "INFO"),
+            _ => panic!("expected a malformed INFO chunk"),
+        }
+
+        // A chunk claiming more data than the file holds
+        let mut file = nsfe_file(&[chunk("INFO", &INFO)]);
+        file.extend(&[0x10, 0x00, 0x00, 0x00]);
+        file.extend(b"DATA");
+        file.extend(&[0x60]);
+        match NsfFile::from_reader(&mut file.as_slice()) {
+            Err(NsfError::ReadError{..}) => {},
+            _ => panic!("expected the DATA chunk to run past the end"),
+        }
+
+        // Required chunks we don't know can't be skipped, optional ones can
+        let file = nsfe_file(&[chunk("INFO", &INFO), chunk("DATA", &[0x60]), chunk("ABCD", &[])]);
+        match NsfFile::from_reader(&mut file.as_slice()) {
+            Err(NsfError::UnsupportedChunk{chunk}) => assert_eq!(chunk, "ABCD"),
+            _ => panic!("expected an unsupported chunk"),
+        }
+        let file = nsfe_file(&[chunk("INFO", &INFO), chunk("DATA", &[0x60]), chunk("abcd", &[])]);
+        assert!(NsfFile::from_reader(&mut file.as_slice()).is_ok());
+    }
+
+    fn nsf2_file(flags: u8, metadata: &[u8]) -> Vec<u8> {
+        let program = [0xEA, 0x60];
+        let mut header = [0u8; 0x80];
+        header[0 .. 5].copy_from_slice(b"NESM\x1A");
+        header[NSF_VERSION] = 2;
+        header[NSF_TOTAL_SONGS] = 2;
+        header[NSF_STARTING_SONG] = 1;
+        header[NSF_LOAD_ADDR + 1] = 0x80;
+        header[NSF2_FLAGS] = flags;
+        header[NSF_PRG_LENGTH] = program.len() as u8;
+        let mut file = header.to_vec();
+        file.extend(&program);
+        file.extend(metadata);
+        return file;
+    }
+
+    #[test]
+    fn reads_nsf2_metadata_after_the_program() {
+        let mut metadata = chunk("time", &durations(&[1000, 2000]));
+        metadata.extend(chunk("plst", &[1, 0]));
+        metadata.extend(chunk("NEND", &[]));
+        let nsf = NsfFile::from_reader(&mut nsf2_file(0, &metadata).as_slice()).unwrap();
+        assert!(!nsf.is_nsfe);
+        assert_eq!(nsf.prg, vec![0xEA, 0x60]);
+        assert_eq!(nsf.tags.track_length(1), Some(2000));
+        assert_eq!(nsf.tags.playlist, vec![1, 0]);
+    }
+
+    #[test]
+    fn damaged_nsf2_metadata_only_matters_when_required() {
+        let metadata = chunk("ABCD", &[0x00]);
+        let nsf = NsfFile::from_reader(&mut nsf2_file(0, &metadata).as_slice()).unwrap();
+        assert_eq!(nsf.tags.playlist.len(), 0);
+        match NsfFile::from_reader(&mut nsf2_file(0b1000_0000, &metadata).as_slice()) {
+            Err(NsfError::UnsupportedChunk{chunk}) => assert_eq!(chunk, "ABCD"),
+            _ => panic!("expected the required metadata to be refused"),
+        }
+    }
+}
@@ -0,0 +1,478 @@
+// Soft patching: applies IPS, UPS and BPS patches to a ROM image in memory, before
+// it is handed off to the cartridge loaders. Formats are detailed here:
+// IPS: https://zerosoft.zophar.net/ips.php
+// UPS and BPS: byuu's specifications, which also define the CRC32 footers we validate
+
+use std::error::Error;
+use std::fmt;
+
+use archive::MAX_UNCOMPRESSED_SIZE;
+use hash::crc32;
+
+#[derive(Debug)]
+pub enum PatchError {
+    UnrecognizedFormat,
+    Malformed{reason: String},
+    SourceChecksumMismatch{expected: u32, actual: u32},
+    TargetChecksumMismatch{expected: u32, actual: u32},
+    PatchChecksumMismatch{expected: u32, actual: u32},
+}
+
+impl Error for PatchError {}
+
+impl fmt::Display for PatchError  {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnrecognizedFormat => {write!(f, "Unrecognized patch format")},
+            PatchError::Malformed{reason} => {write!(f, "Malformed patch: {}", reason)},
+            PatchError::SourceChecksumMismatch{expected, actual} => {write!(f, "Patch expects a source file with CRC32 {:08X}, but this file has CRC32 {:08X}", expected, actual)},
+            PatchError::TargetChecksumMismatch{expected, actual} => {write!(f, "Patched file should have CRC32 {:08X}, but the result has CRC32 {:08X}", expected, actual)},
+            PatchError::PatchChecksumMismatch{expected, actual} => {write!(f, "Patch file is corrupt: expected CRC32 {:08X}, found {:08X}", expected, actual)},
+        }
+    }
+}
+
+fn malformed(reason: &str) -> PatchError {
+    return PatchError::Malformed{reason: reason.to_string()};
+}
+
+// Reads bytes sequentially from the patch, failing cleanly if it runs out early
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> PatchReader<'a> {
+        return PatchReader {
+            data: data,
+            position: position,
+        };
+    }
+
+    fn read_u8(&mut self) -> Result<u8, PatchError> {
+        if self.position >= self.data.len() {
+            return Err(malformed("unexpected end of patch"));
+        }
+        let byte = self.data[self.position];
+        self.position += 1;
+        return Ok(byte);
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], PatchError> {
+        if self.position + length > self.data.len() {
+            return Err(malformed("unexpected end of patch"));
+        }
+        let bytes = &self.data[self.position .. self.position + length];
+        self.position += length;
+        return Ok(bytes);
+    }
+
+    fn read_u16_be(&mut self) -> Result<usize, PatchError> {
+        let bytes = self.read_bytes(2)?;
+        return Ok(((bytes[0] as usize) << 8) | (bytes[1] as usize));
+    }
+
+    fn read_u24_be(&mut self) -> Result<usize, PatchError> {
+        let bytes = self.read_bytes(3)?;
+        return Ok(((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | (bytes[2] as usize));
+    }
+
+    // UPS and BPS share this variable length encoding
+    fn read_varint(&mut self) -> Result<usize, PatchError> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.read_u8()?;
+            data = data.checked_add((x & 0x7F) as usize * shift).ok_or(malformed("number too large"))?;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_shl(7).ok_or(malformed("number too large"))?;
+            data = data.checked_add(shift).ok_or(malformed("number too large"))?;
+        }
+    }
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    return
+        (bytes[0] as u32) |
+        ((bytes[1] as u32) << 8) |
+        ((bytes[2] as u32) << 16) |
+        ((bytes[3] as u32) << 24);
+}
+
+// UPS and BPS both end with three CRC32s: source, target, and the patch itself
+fn check_footer(source: &[u8], patch: &[u8]) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 12 {
+        return Err(malformed("patch is too short"));
+    }
+    let footer = &patch[patch.len() - 12 ..];
+    let expected_patch_crc = read_u32_le(&footer[8 .. 12]);
+    let actual_patch_crc = crc32(&patch[.. patch.len() - 4]);
+    if expected_patch_crc != actual_patch_crc {
+        return Err(PatchError::PatchChecksumMismatch{expected: expected_patch_crc, actual: actual_patch_crc});
+    }
+    let expected_source_crc = read_u32_le(&footer[0 .. 4]);
+    let actual_source_crc = crc32(source);
+    if expected_source_crc != actual_source_crc {
+        return Err(PatchError::SourceChecksumMismatch{expected: expected_source_crc, actual: actual_source_crc});
+    }
+    return Ok((expected_source_crc, read_u32_le(&footer[4 .. 8])));
+}
+
+// The size comes from the patch, so don't trust it with an allocation until it looks like a ROM
+fn check_target_size(target_size: usize) -> Result<usize, PatchError> {
+    if target_size > MAX_UNCOMPRESSED_SIZE {
+        return Err(malformed(&format!("patched file would be {} bytes, more than the {} allowed", target_size, MAX_UNCOMPRESSED_SIZE)));
+    }
+    return Ok(target_size);
+}
+
+fn check_target(target: &[u8], expected_target_crc: u32) -> Result<(), PatchError> {
+    let actual_target_crc = crc32(target);
+    if expected_target_crc != actual_target_crc {
+        return Err(PatchError::TargetChecksumMismatch{expected: expected_target_crc, actual: actual_target_crc});
+    }
+    return Ok(());
+}
+
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(b"PATCH") {
+        return Err(PatchError::UnrecognizedFormat);
+    }
+    let mut target = source.to_vec();
+    let mut reader = PatchReader::new(patch, 5);
+    loop {
+        let offset = reader.read_u24_be()?;
+        if offset == 0x454F46 {
+            // "EOF", optionally followed by a length to truncate the file to
+            if patch.len() - reader.position >= 3 {
+                let truncated_length = reader.read_u24_be()?;
+                target.truncate(truncated_length);
+            }
+            return Ok(target);
+        }
+        let length = reader.read_u16_be()?;
+        if length == 0 {
+            // Run length encoded record
+            let run_length = reader.read_u16_be()?;
+            let value = reader.read_u8()?;
+            if target.len() < offset + run_length {
+                target.resize(offset + run_length, 0);
+            }
+            for i in 0 .. run_length {
+                target[offset + i] = value;
+            }
+        } else {
+            let data = reader.read_bytes(length)?;
+            if target.len() < offset + length {
+                target.resize(offset + length, 0);
+            }
+            target[offset .. offset + length].copy_from_slice(data);
+        }
+    }
+}
+
+pub fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(b"UPS1") {
+        return Err(PatchError::UnrecognizedFormat);
+    }
+    let (_, expected_target_crc) = check_footer(source, patch)?;
+    let actions_end = patch.len() - 12;
+
+    let mut reader = PatchReader::new(&patch[.. actions_end], 4);
+    let _source_size = reader.read_varint()?;
+    let target_size = check_target_size(reader.read_varint()?)?;
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut position = 0;
+    while reader.position < actions_end {
+        position += reader.read_varint()?;
+        loop {
+            let x = reader.read_u8()?;
+            if x == 0 {
+                position += 1;
+                break;
+            }
+            if position < target.len() {
+                target[position] ^= x;
+            }
+            position += 1;
+        }
+    }
+
+    check_target(&target, expected_target_crc)?;
+    return Ok(target);
+}
+
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(b"BPS1") {
+        return Err(PatchError::UnrecognizedFormat);
+    }
+    let (_, expected_target_crc) = check_footer(source, patch)?;
+    let actions_end = patch.len() - 12;
+
+    let mut reader = PatchReader::new(&patch[.. actions_end], 4);
+    let _source_size = reader.read_varint()?;
+    let target_size = check_target_size(reader.read_varint()?)?;
+    let metadata_size = reader.read_varint()?;
+    let _metadata = reader.read_bytes(metadata_size)?;
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_relative_offset: isize = 0;
+    let mut target_relative_offset: isize = 0;
+    while reader.position < actions_end {
+        let data = reader.read_varint()?;
+        let command = data & 0b11;
+        let length = (data >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(malformed("patched file is the wrong size"));
+        }
+        match command {
+            0 => {
+                // SourceRead: copy from the same position in the source
+                let start = target.len();
+                if start + length > source.len() {
+                    return Err(malformed("SourceRead past the end of the source"));
+                }
+                target.extend_from_slice(&source[start .. start + length]);
+            },
+            1 => {
+                // TargetRead: literal data from the patch
+                let data = reader.read_bytes(length)?;
+                target.extend_from_slice(data);
+            },
+            2 => {
+                // SourceCopy: copy from anywhere in the source, relative to the last copy
+                let offset = reader.read_varint()?;
+                let delta = (offset >> 1) as isize;
+                source_relative_offset += if offset & 1 != 0 {-delta} else {delta};
+                if source_relative_offset < 0 || source_relative_offset as usize + length > source.len() {
+                    return Err(malformed("SourceCopy outside of the source"));
+                }
+                let start = source_relative_offset as usize;
+                target.extend_from_slice(&source[start .. start + length]);
+                source_relative_offset += length as isize;
+            },
+            _ => {
+                // TargetCopy: copy from earlier in the output, one byte at a time since
+                // the ranges are allowed to overlap
+                let offset = reader.read_varint()?;
+                let delta = (offset >> 1) as isize;
+                target_relative_offset += if offset & 1 != 0 {-delta} else {delta};
+                for _ in 0 .. length {
+                    if target_relative_offset < 0 || target_relative_offset as usize >= target.len() {
+                        return Err(malformed("TargetCopy outside of the target"));
+                    }
+                    let byte = target[target_relative_offset as usize];
+                    target.push(byte);
+                    target_relative_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(malformed("patched file is the wrong size"));
+    }
+    check_target(&target, expected_target_crc)?;
+    return Ok(target);
+}
+
+// Picks the patch format based on its magic number
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        return apply_ips(source, patch);
+    }
+    if patch.starts_with(b"UPS1") {
+        return apply_ups(source, patch);
+    }
+    if patch.starts_with(b"BPS1") {
+        return apply_bps(source, patch);
+    }
+    return Err(PatchError::UnrecognizedFormat);
+}
+
+// File extensions to look for when searching for a patch next to a ROM
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut data: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let x = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                bytes.push(0x80 | x);
+                return bytes;
+            }
+            bytes.push(x);
+            data -= 1;
+        }
+    }
+
+    // Appends the source, target and patch checksums that UPS and BPS end with
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(&crc32(source).to_le_bytes());
+        patch.extend(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend(&patch_crc.to_le_bytes());
+        return patch;
+    }
+
+    fn source() -> Vec<u8> {
+        return (0 .. 64).map(|i| i as u8).collect();
+    }
+
+    fn ups_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        let difference = |i: usize| target[i] ^ source.get(i).cloned().unwrap_or(0);
+        let mut last = 0;
+        let mut i = 0;
+        while i < target.len() {
+            if difference(i) == 0 {
+                i += 1;
+                continue;
+            }
+            // A run of changed bytes, ended by a zero which stands for the next (unchanged) byte
+            patch.extend(varint(i - last));
+            while i < target.len() && difference(i) != 0 {
+                patch.push(difference(i));
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            last = i;
+        }
+        return with_footer(patch, source, target);
+    }
+
+    // Exercises every BPS command: SourceRead, TargetRead, SourceCopy and an overlapping TargetCopy
+    fn bps_patch(source: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut target = source[0 .. 8].to_vec();
+        target.extend(&[0xAA, 0xBB]);
+        target.extend(&source[32 .. 36]);
+        target.extend(&[source[35]; 3]);
+
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(4));
+        patch.extend(b"meta");
+        patch.extend(varint(((8 - 1) << 2) | 0));
+        patch.extend(varint(((2 - 1) << 2) | 1));
+        patch.extend(&[0xAA, 0xBB]);
+        patch.extend(varint(((4 - 1) << 2) | 2));
+        patch.extend(varint(32 << 1));
+        patch.extend(varint(((3 - 1) << 2) | 3));
+        patch.extend(varint(13 << 1));
+        let patch = with_footer(patch, source, &target);
+        return (patch, target);
+    }
+
+    #[test]
+    fn applies_ips_records() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend(&[0x00, 0x00, 0x04, 0x00, 0x02, 0xDE, 0xAD]);
+        // A run length encoded record, reaching past the end of the source
+        patch.extend(&[0x00, 0x00, 0x3E, 0x00, 0x00, 0x00, 0x04, 0x77]);
+        patch.extend(b"EOF");
+        let mut expected = source();
+        expected[4] = 0xDE;
+        expected[5] = 0xAD;
+        expected.truncate(0x3E);
+        expected.extend(&[0x77; 4]);
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), expected);
+
+        // EOF may be followed by a length to cut the file down to
+        patch.extend(&[0x00, 0x00, 0x10]);
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), expected[0 .. 0x10].to_vec());
+    }
+
+    #[test]
+    fn applies_ups_patches() {
+        let mut target = source();
+        target[3] = 0xFF;
+        target[40] = 0x00;
+        target.extend(&[0x11, 0x22]);
+        let patch = ups_patch(&source(), &target);
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn applies_bps_patches() {
+        let (patch, target) = bps_patch(&source());
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn rejects_the_wrong_source() {
+        let mut wrong_source = source();
+        wrong_source[0] = 0xFF;
+        let (bps, _) = bps_patch(&source());
+        let ups = ups_patch(&source(), &[0x55; 16]);
+        for patch in &[bps, ups] {
+            match apply_patch(&wrong_source, patch) {
+                Err(PatchError::SourceChecksumMismatch{expected, actual}) => {
+                    assert_eq!(expected, crc32(&source()));
+                    assert_eq!(actual, crc32(&wrong_source));
+                },
+                _ => panic!("expected a source checksum mismatch")
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_damaged_patch() {
+        let (mut patch, _) = bps_patch(&source());
+        patch[8] ^= 0x01;
+        match apply_patch(&source(), &patch) {
+            Err(PatchError::PatchChecksumMismatch{..}) => {},
+            _ => panic!("expected a patch checksum mismatch")
+        }
+    }
+
+    #[test]
+    fn rejects_the_wrong_target() {
+        // Well formed, but the footer names a different result than the patch produces
+        let (patch, target) = bps_patch(&source());
+        let mut wrong_target = target.clone();
+        wrong_target[0] ^= 0xFF;
+        let patch = with_footer(patch[.. patch.len() - 12].to_vec(), &source(), &wrong_target);
+        match apply_patch(&source(), &patch) {
+            Err(PatchError::TargetChecksumMismatch{expected, actual}) => {
+                assert_eq!(expected, crc32(&wrong_target));
+                assert_eq!(actual, crc32(&target));
+            },
+            _ => panic!("expected a target checksum mismatch")
+        }
+    }
+
+    #[test]
+    fn refuses_an_oversized_target() {
+        let mut ups = b"UPS1".to_vec();
+        ups.extend(varint(source().len()));
+        ups.extend(varint(MAX_UNCOMPRESSED_SIZE + 1));
+        let ups = with_footer(ups, &source(), &[]);
+
+        let mut bps = b"BPS1".to_vec();
+        bps.extend(varint(source().len()));
+        bps.extend(varint(usize::max_value() >> 1));
+        bps.extend(varint(0));
+        let bps = with_footer(bps, &source(), &[]);
+
+        for patch in &[ups, bps] {
+            match apply_patch(&source(), patch) {
+                Err(PatchError::Malformed{..}) => {},
+                _ => panic!("expected the target size to be refused")
+            }
+        }
+    }
+}
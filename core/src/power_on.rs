@@ -0,0 +1,127 @@
+// Power-on contents of the console's RAM chips. Real hardware comes up in a state that
+// depends on the individual chips, temperature, and how long the console was switched off,
+// so software which reads memory before writing it will behave differently from one boot
+// to the next. These policies let us pick a specific state, or a reproducible random one.
+
+use memoryblock::MemoryBlock;
+use ppu;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FillPolicy {
+    Zero,
+    Ones,
+    Pattern,
+    Random,
+}
+
+pub fn fill_policy_name(policy: FillPolicy) -> &'static str {
+    return match policy {
+        FillPolicy::Zero => "zero",
+        FillPolicy::Ones => "ones",
+        FillPolicy::Pattern => "pattern",
+        FillPolicy::Random => "random",
+    };
+}
+
+pub fn fill_policy_from_name(name: &str) -> Option<FillPolicy> {
+    return match name {
+        "zero" => Some(FillPolicy::Zero),
+        "ones" | "ff" => Some(FillPolicy::Ones),
+        "pattern" => Some(FillPolicy::Pattern),
+        "random" => Some(FillPolicy::Random),
+        _ => None
+    };
+}
+
+// Many consoles power up with internal RAM in alternating runs of $00 and $FF,
+// this is the same pattern FCEUX uses by default
+const DRAM_PATTERN: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF];
+
+// Each region gets its own random stream, so changing the size of one
+// doesn't shuffle the contents of the others
+const SALT_INTERNAL_RAM: u64 = 0x1000;
+const SALT_CART_RAM: u64 = 0x2000;
+const SALT_PALETTE: u64 = 0x3000;
+const SALT_OAM: u64 = 0x4000;
+
+// SplitMix64, good enough for garbage memory and trivially reproducible
+pub struct PowerOnRng {
+    state: u64,
+}
+
+impl PowerOnRng {
+    pub fn new(seed: u64) -> PowerOnRng {
+        return PowerOnRng {
+            state: seed,
+        };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        return (self.next_u64() >> 56) as u8;
+    }
+}
+
+fn fill(policy: FillPolicy, seed: u64, pattern: &[u8], buffer: &mut [u8]) {
+    let mut rng = PowerOnRng::new(seed);
+    for i in 0 .. buffer.len() {
+        buffer[i] = match policy {
+            FillPolicy::Zero => 0x00,
+            FillPolicy::Ones => 0xFF,
+            FillPolicy::Pattern => pattern[i % pattern.len()],
+            FillPolicy::Random => rng.next_u8(),
+        };
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PowerOnConfig {
+    pub internal_ram: FillPolicy,
+    pub cart_ram: FillPolicy,
+    pub palette: FillPolicy,
+    pub oam: FillPolicy,
+    pub seed: u64,
+}
+
+impl PowerOnConfig {
+    pub fn new() -> PowerOnConfig {
+        // Matches what the emulator has always done: zeroed memory, and the
+        // boot palette from my own console
+        return PowerOnConfig {
+            internal_ram: FillPolicy::Zero,
+            cart_ram: FillPolicy::Zero,
+            palette: FillPolicy::Pattern,
+            oam: FillPolicy::Zero,
+            seed: 0,
+        };
+    }
+
+    pub fn fill_internal_ram(&self, ram: &mut [u8]) {
+        fill(self.internal_ram, self.seed ^ SALT_INTERNAL_RAM, &DRAM_PATTERN, ram);
+    }
+
+    // Battery backed RAM and ROM are left alone; only volatile chips lose their contents
+    pub fn fill_cart_ram(&self, index: usize, block: &mut MemoryBlock) {
+        fill(self.cart_ram, self.seed ^ (SALT_CART_RAM + index as u64), &DRAM_PATTERN, block.volatile_bytes_mut());
+        block.restore_preload();
+    }
+
+    pub fn fill_palette(&self, palette: &mut [u8]) {
+        fill(self.palette, self.seed ^ SALT_PALETTE, &ppu::debug_default_palette(), palette);
+        // Palette RAM is only 6 bits wide
+        for entry in palette.iter_mut() {
+            *entry = *entry & 0x3F;
+        }
+    }
+
+    pub fn fill_oam(&self, oam: &mut [u8]) {
+        fill(self.oam, self.seed ^ SALT_OAM, &DRAM_PATTERN, oam);
+    }
+}
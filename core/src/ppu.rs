@@ -139,7 +139,7 @@ pub struct PpuState {
     pub recent_writes: Vec<u16>,
 }
 
-fn debug_default_palette() -> Vec<u8> {
+pub fn debug_default_palette() -> Vec<u8> {
     // Completely arbitrary color selection here, a real NES's boot palette
     // is somewhat random, determined by analog effects and RAM decay.
     // My own NES produces this ugly cyan on failed loads, so that's what
@@ -0,0 +1,308 @@
+// Describes a ROM file without running it: what format it is, what its header says,
+// checksums of each part, and whether we can play it. Used by the CLI `info` command
+// to audit collections in bulk.
+
+use archive::extract_rom;
+use archive::is_archive;
+use load_error::LoadError;
+use ines::mapper_name;
+use fds::FdsFile;
+use game_db;
+use hash::crc32;
+use hash::md5;
+use hash::sha1;
+use hash::to_hex;
+use ines::ConsoleType;
+use ines::INesCartridge;
+use ines::console_type_name;
+use ines::expansion_device_name;
+use ines::timing_name;
+use mmc::mapper::mirroring_mode_name;
+use mmc::registry::MapperRegistry;
+use nsf::NsfError;
+use nsf::NsfFile;
+use nsf::mixe_device_name;
+use unif::UnifFile;
+use unif::UnifMirroring;
+
+#[derive(Clone)]
+pub struct RomHashes {
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: String,
+    pub md5: String,
+}
+
+impl RomHashes {
+    pub fn of(data: &[u8]) -> RomHashes {
+        return RomHashes {
+            size: data.len(),
+            crc32: crc32(data),
+            sha1: to_hex(&sha1(data)),
+            md5: to_hex(&md5(data)),
+        };
+    }
+}
+
+#[derive(Clone)]
+pub struct RomInfo {
+    pub format: String,
+    pub fields: Vec<(String, String)>,
+    // Checksums of the whole file first, then PRG, CHR, or each disk side
+    pub hashes: Vec<(String, RomHashes)>,
+    pub mapper_number: Option<u16>,
+    pub submapper_number: Option<u8>,
+    pub mapper_name: Option<String>,
+    pub supported: bool,
+    pub verdict: String,
+}
+
+impl RomInfo {
+    fn new(format: &str, data: &[u8]) -> RomInfo {
+        return RomInfo {
+            format: format.to_string(),
+            fields: Vec::new(),
+            hashes: vec![("file".to_string(), RomHashes::of(data))],
+            mapper_number: None,
+            submapper_number: None,
+            mapper_name: None,
+            supported: false,
+            verdict: String::new(),
+        };
+    }
+
+    fn field<T: ToString>(&mut self, name: &str, value: T) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    fn hash(&mut self, name: &str, data: &[u8]) {
+        self.hashes.push((name.to_string(), RomHashes::of(data)));
+    }
+
+    fn mapper(&mut self, registry: &MapperRegistry, mapper_number: u16, submapper_number: u8) {
+        self.mapper_number = Some(mapper_number);
+        self.submapper_number = Some(submapper_number);
+        self.mapper_name = Some(mapper_name(mapper_number).to_string());
+        self.supported = registry.supports(mapper_number, submapper_number);
+        self.verdict = if self.supported {
+            "Supported".to_string()
+        } else {
+            format!("Unsupported mapper {}, submapper {}", mapper_number, submapper_number)
+        };
+    }
+}
+
+fn fixed_string(bytes: &[u8]) -> String {
+    let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    return String::from_utf8_lossy(&bytes[0 .. length]).into_owned();
+}
+
+fn ines_info(data: &[u8], mut ines: INesCartridge, registry: &MapperRegistry) -> RomInfo {
+    let format = if ines.header.version() == 2 {"NES 2.0"} else {"iNES 1.0"};
+    let mut info = RomInfo::new(format, data);
+    {
+        let header = &ines.header;
+        info.field("mapper", header.mapper_number());
+        info.field("submapper", header.submapper_number());
+        info.field("mirroring", mirroring_mode_name(header.mirroring()));
+        info.field("battery", header.has_sram());
+        info.field("trainer", header.has_trainer());
+        info.field("prg rom size", header.prg_size());
+        info.field("chr rom size", header.chr_rom_size());
+        info.field("prg ram size", header.prg_ram_size());
+        info.field("prg nvram size", header.prg_sram_size());
+        info.field("chr ram size", header.chr_ram_size());
+        info.field("chr nvram size", header.chr_sram_size());
+        info.field("timing", timing_name(header.timing()));
+        info.field("console type", console_type_name(header.console_type()));
+        if header.console_type() == ConsoleType::VsSystem {
+            info.field("vs ppu type", header.vs_ppu_type());
+            info.field("vs hardware type", header.vs_hardware_type());
+        }
+        info.field("misc rom count", header.misc_rom_count());
+        info.field("expansion device", expansion_device_name(header.default_expansion_device()));
+    }
+
+    info.hash("prg", &ines.prg);
+    info.hash("chr", &ines.chr);
+    if ines.trainer.len() > 0 {
+        info.hash("trainer", &ines.trainer);
+    }
+    if ines.misc_rom.len() > 0 {
+        info.hash("misc", &ines.misc_rom);
+    }
+
+    // Report on the header we would actually use, after the database has had its say
+    for correction in game_db::correct_header(&mut ines) {
+        info.field(&format!("database corrects {}", correction.field), format!("{} -> {}", correction.old_value, correction.new_value));
+    }
+    info.mapper(registry, ines.header.mapper_number(), ines.header.submapper_number());
+    return info;
+}
+
+fn nsf_info(data: &[u8], nsf: NsfFile) -> RomInfo {
+    let header = &nsf.header;
+    let tags = &nsf.tags;
+    let format = if nsf.is_nsfe {"NSFe"} else if header.version_number() >= 2 {"NSF2"} else {"NSF"};
+    let mut info = RomInfo::new(format, data);
+    if !nsf.is_nsfe {
+        info.field("version", header.version_number());
+    }
+    // The full strings when the file has them; the header only holds 31 characters
+    info.field("title", tags.title.clone().unwrap_or(fixed_string(&header.song_name())));
+    info.field("artist", tags.artist.clone().unwrap_or(fixed_string(&header.artist_name())));
+    info.field("copyright", tags.copyright.clone().unwrap_or(fixed_string(&header.copyright_holder())));
+    match tags.ripper {
+        Some(ref ripper) => info.field("ripper", ripper),
+        None => {}
+    }
+    info.field("total songs", header.total_songs());
+    info.field("starting song", header.starting_song());
+    info.field("load address", format!("${:04X}", header.load_address()));
+    info.field("init address", format!("${:04X}", header.init_address()));
+    info.field("play address", format!("${:04X}", header.play_address()));
+    info.field("ntsc play speed", header.ntsc_playback_speed());
+    info.field("pal play speed", header.pal_playback_speed());
+    info.field("bank switched", header.is_bank_switched());
+    if header.is_bank_switched() {
+        let banks: Vec<String> = header.initial_banks().iter().map(|bank| bank.to_string()).collect();
+        info.field("initial banks", banks.join(" "));
+    }
+    let mut chips: Vec<&str> = Vec::new();
+    if header.vrc6() {chips.push("VRC6");}
+    if header.vrc7() {chips.push("VRC7");}
+    if header.fds() {chips.push("FDS");}
+    if header.mmc5() {chips.push("MMC5");}
+    if header.n163() {chips.push("N163");}
+    if header.s5b() {chips.push("5B");}
+    info.field("expansion audio", if chips.len() > 0 {chips.join(" ")} else {"none".to_string()});
+    if header.version_number() >= 2 {
+        let mut features: Vec<&str> = Vec::new();
+        if header.irq_support() {features.push("IRQ");}
+        if header.non_returning_init() {features.push("non-returning INIT");}
+        if header.suppress_play() {features.push("no PLAY");}
+        info.field("nsf2 features", if features.len() > 0 {features.join(", ")} else {"none".to_string()});
+    }
+    if tags.playlist.len() > 0 {
+        // The player skips entries past the last song, so they're marked rather than hidden
+        let songs: Vec<String> = tags.playlist.iter().map(|&song| {
+            let number = song as usize + 1;
+            return if song < header.total_songs() {number.to_string()} else {format!("{} (skipped, no such song)", number)};
+        }).collect();
+        info.field("playlist", songs.join(" "));
+    }
+    for song in 0 .. header.total_songs() as usize {
+        let title = tags.track_title(song);
+        let length = tags.track_length(song);
+        if title.is_none() && length.is_none() {
+            continue;
+        }
+        let mut description = title.unwrap_or("").to_string();
+        match length {
+            Some(milliseconds) => {
+                let seconds = milliseconds / 1000;
+                description = format!("{} ({}:{:02})", description, seconds / 60, seconds % 60).trim().to_string();
+            },
+            None => {}
+        }
+        info.field(&format!("track {}", song + 1), description);
+    }
+    for &(device, level) in tags.mixing.iter() {
+        info.field(&format!("{} level", mixe_device_name(device)), format!("{} mB", level));
+    }
+    info.hash("prg", &nsf.prg);
+
+    info.supported = true;
+    info.verdict = "Supported".to_string();
+    return info;
+}
+
+fn fds_info(data: &[u8], fds: FdsFile) -> RomInfo {
+    let mut info = RomInfo::new("FDS", data);
+    info.field("header", if fds.header.magic_header_valid() {"fwNES"} else {"none (raw disk dump)"});
+    info.field("disk sides", fds.disk_sides.len());
+    for (index, side) in fds.disk_sides.iter().enumerate() {
+        info.hash(&format!("side {}", index + 1), side);
+    }
+    info.supported = true;
+    info.verdict = "Supported, requires the FDS BIOS (disksys.rom)".to_string();
+    return info;
+}
+
+fn unif_info(data: &[u8], unif: UnifFile, registry: &MapperRegistry) -> RomInfo {
+    let mut info = RomInfo::new("UNIF", data);
+    info.field("revision", unif.revision);
+    info.field("board", &unif.board_name);
+    if unif.name.len() > 0 {
+        info.field("name", &unif.name);
+    }
+    info.field("mirroring", match unif.mirroring {
+        Some(UnifMirroring::Horizontal) => "Horizontal",
+        Some(UnifMirroring::Vertical) => "Vertical",
+        Some(UnifMirroring::OneScreenLower) => "OneScreenLower",
+        Some(UnifMirroring::OneScreenUpper) => "OneScreenUpper",
+        Some(UnifMirroring::FourScreen) => "FourScreen",
+        Some(UnifMirroring::MapperControlled) => "Mapper Controlled",
+        None => "Unspecified"
+    });
+    info.field("battery", unif.battery);
+    info.field("tv system", match unif.tv_system {
+        Some(0) => "NTSC",
+        Some(1) => "PAL",
+        Some(2) => "Multiple Region",
+        _ => "Unspecified"
+    });
+    info.field("prg rom size", unif.prg().len());
+    info.field("chr rom size", unif.chr().len());
+    info.hash("prg", &unif.prg());
+    info.hash("chr", &unif.chr());
+
+    match unif.board() {
+        Some(board) => {
+            info.mapper(registry, board.mapper, board.submapper);
+        },
+        None => {
+            info.verdict = format!("Unsupported UNIF board {}", unif.board_name);
+        }
+    }
+    // A constructor registered for the board by name takes priority over its mapper number
+    if registry.supports_unif_board(&unif.board_name) {
+        info.supported = true;
+        info.verdict = "Supported".to_string();
+    }
+    return info;
+}
+
+pub fn rom_info(data: &[u8], entry_name: Option<&str>) -> Result<RomInfo, String> {
+    return rom_info_with_registry(data, entry_name, &MapperRegistry::new());
+}
+
+// Identifies the file the same way the loader does: archives are opened first, then
+// each format is tried in turn. Support is judged against the registry the shell loads with.
+pub fn rom_info_with_registry(data: &[u8], entry_name: Option<&str>, registry: &MapperRegistry) -> Result<RomInfo, String> {
+    if is_archive(data) {
+        let entry = extract_rom(data, entry_name).map_err(|e| e.to_string())?;
+        let mut info = rom_info_with_registry(&entry.data, None, registry)?;
+        info.fields.insert(0, ("archive entry".to_string(), entry.name));
+        return Ok(info);
+    }
+    match INesCartridge::from_reader(&mut &data[..]) {
+        Ok(ines) => {return Ok(ines_info(data, ines, registry));},
+        Err(_) => {}
+    }
+    match NsfFile::from_reader(&mut &data[..]) {
+        Ok(nsf) => {return Ok(nsf_info(data, nsf));},
+        Err(NsfError::InvalidHeader) => {},
+        // It is an NSF, just not one we can read
+        Err(e) => {return Err(LoadError::from(e).to_string());}
+    }
+    match FdsFile::from_reader(&mut &data[..]) {
+        Ok(fds) => {return Ok(fds_info(data, fds));},
+        Err(_) => {}
+    }
+    match UnifFile::from_reader(&mut &data[..]) {
+        Ok(unif) => {return Ok(unif_info(data, unif, registry));},
+        Err(_) => {}
+    }
+    return Err("Unrecognized file format".to_string());
+}
@@ -1,3 +1,5 @@
+use vgm::VgmLog;
+
 #[derive(Clone, Copy)]
 pub enum EventType {
     NullEvent,
@@ -22,6 +24,9 @@ pub struct EventTracker {
     pub current_scanline: u16,
     pub current_cycle: u16,
     pub cpu_snoop_list: Vec<u8>,
+    pub master_clock: u64,
+    // Sees every write, not just the ones in the snoop list
+    pub vgm_log: Option<VgmLog>,
 }
 
 const CPU_READ: u8    = 0b0000_0001;
@@ -89,6 +94,8 @@ impl EventTracker {
             current_scanline: 0,
             current_cycle: 0,
             cpu_snoop_list: default_cpu_snoops,
+            master_clock: 0,
+            vgm_log: None,
         }
     }
 
@@ -147,6 +154,10 @@ impl EventTracker {
     }
 
     pub fn snoop_cpu_write(&mut self, program_counter: u16, address: u16, data: u8) {
+        match self.vgm_log {
+            Some(ref mut vgm_log) => vgm_log.record_write(self.master_clock, address, data),
+            None => {}
+        }
         if (self.cpu_snoop_list[address as usize] & CPU_WRITE) != 0 {
             self.track(TrackedEvent{
                 scanline: self.current_scanline,
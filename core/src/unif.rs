@@ -0,0 +1,351 @@
+// UNIF: an older chunk based cartridge format, which names the board instead of
+// assigning it a mapper number. Details here: https://wiki.nesdev.com/w/index.php/UNIF
+// Rather than teach every mapper a second format, we translate the board name into
+// an equivalent NES 2.0 header and construct the usual iNES cartridge from it.
+
+use std::io::Read;
+use std::error::Error;
+use std::fmt;
+
+use ines::INesCartridge;
+use ines::INesHeader;
+use ines::size_to_shift_count;
+
+const UNIF_HEADER_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum UnifError {
+    InvalidHeader,
+    UnknownBoard{name: String},
+    MissingChunk{chunk: String},
+    ReadError{reason: String}
+}
+
+impl Error for UnifError {}
+
+impl fmt::Display for UnifError  {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnifError::InvalidHeader => {write!(f, "Invalid UNIF Header")},
+            UnifError::UnknownBoard{name} => {write!(f, "Unsupported UNIF board: {}", name)},
+            UnifError::MissingChunk{chunk} => {write!(f, "Missing {} chunk", chunk)},
+            UnifError::ReadError{reason} => {write!(f, "Error reading cartridge: {}", reason)}
+        }
+    }
+}
+
+impl From<std::io::Error> for UnifError {
+    fn from(error: std::io::Error) -> Self {
+        return UnifError::ReadError{reason: error.to_string()};
+    }
+}
+
+// How a UNIF board maps onto our iNES mappers
+#[derive(Copy, Clone)]
+pub struct UnifBoard {
+    pub name: &'static str,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_ram_size: usize,
+}
+
+const fn board(name: &'static str, mapper: u16, submapper: u8, prg_ram_size: usize) -> UnifBoard {
+    return UnifBoard{name: name, mapper: mapper, submapper: submapper, prg_ram_size: prg_ram_size};
+}
+
+// Board names, with any "NES-", "HVC-", "UNL-" style prefix removed. This only covers
+// boards whose iNES equivalent we can already run.
+pub const UNIF_BOARDS: &[UnifBoard] = &[
+    board("NROM", 0, 0, 0),
+    board("NROM-128", 0, 0, 0),
+    board("NROM-256", 0, 0, 0),
+    board("RROM", 0, 0, 0),
+    board("RROM-128", 0, 0, 0),
+    board("SROM", 0, 0, 0),
+
+    board("SAROM", 1, 0, 0x2000),
+    board("SBROM", 1, 0, 0),
+    board("SCROM", 1, 0, 0),
+    board("SEROM", 1, 5, 0),
+    board("SFROM", 1, 0, 0),
+    board("SGROM", 1, 0, 0),
+    board("SHROM", 1, 5, 0),
+    board("SJROM", 1, 0, 0x2000),
+    board("SKROM", 1, 0, 0x2000),
+    board("SLROM", 1, 0, 0),
+    board("SL1ROM", 1, 0, 0),
+    board("SNROM", 1, 0, 0x2000),
+    board("SOROM", 1, 0, 0x4000),
+    board("SUROM", 1, 0, 0x2000),
+    board("SXROM", 1, 0, 0x8000),
+
+    board("UNROM", 2, 0, 0),
+    board("UOROM", 2, 0, 0),
+
+    board("CNROM", 3, 0, 0),
+
+    board("TBROM", 4, 0, 0),
+    board("TEROM", 4, 0, 0),
+    board("TFROM", 4, 0, 0),
+    board("TGROM", 4, 0, 0),
+    board("TKROM", 4, 0, 0x2000),
+    board("TLROM", 4, 0, 0),
+    board("TL1ROM", 4, 0, 0),
+    board("TNROM", 4, 0, 0x2000),
+    board("TR1ROM", 4, 0, 0),
+    board("TSROM", 4, 0, 0x2000),
+    board("TVROM", 4, 0, 0),
+    board("B4", 4, 0, 0),
+
+    board("EKROM", 5, 0, 0x2000),
+    board("ELROM", 5, 0, 0),
+    board("ETROM", 5, 0, 0x4000),
+    board("EWROM", 5, 0, 0x8000),
+
+    board("AMROM", 7, 0, 0),
+    board("ANROM", 7, 0, 0),
+    board("AN1ROM", 7, 0, 0),
+    board("AOROM", 7, 0, 0),
+
+    board("PNROM", 9, 0, 0),
+    board("PEEOROM", 9, 0, 0),
+
+    board("ACTION53", 28, 0, 0),
+
+    board("BNROM", 34, 2, 0),
+
+    board("GNROM", 66, 0, 0),
+    board("MHROM", 66, 0, 0),
+
+    board("NTBROM", 68, 0, 0x2000),
+
+    board("BTR", 69, 0, 0x2000),
+    board("JLROM", 69, 0, 0),
+    board("JSROM", 69, 0, 0x2000),
+];
+
+const BOARD_PREFIXES: &[&str] = &["NES-", "HVC-", "UNL-", "BMC-", "BTL-", "KONAMI-", "IREM-", "TAITO-", "SUNSOFT-"];
+
+// Board names are compared without their prefix, ignoring case
+pub fn normalize_board_name(board_name: &str) -> String {
+    let name = board_name.trim().to_uppercase();
+    for prefix in BOARD_PREFIXES {
+        if name.starts_with(prefix) {
+            return name[prefix.len() ..].to_string();
+        }
+    }
+    return name;
+}
+
+pub fn find_board(board_name: &str) -> Option<UnifBoard> {
+    let name = normalize_board_name(board_name);
+    for board in UNIF_BOARDS {
+        if board.name == name {
+            return Some(*board);
+        }
+    }
+    return None;
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnifMirroring {
+    Horizontal,
+    Vertical,
+    OneScreenLower,
+    OneScreenUpper,
+    FourScreen,
+    MapperControlled,
+}
+
+#[derive(Clone)]
+pub struct UnifFile {
+    pub revision: u32,
+    pub board_name: String,
+    pub name: String,
+    pub prg_chunks: Vec<Vec<u8>>,
+    pub chr_chunks: Vec<Vec<u8>>,
+    pub mirroring: Option<UnifMirroring>,
+    pub battery: bool,
+    pub tv_system: Option<u8>,
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    return
+        (bytes[0] as u32) |
+        ((bytes[1] as u32) << 8) |
+        ((bytes[2] as u32) << 16) |
+        ((bytes[3] as u32) << 24);
+}
+
+fn read_string(bytes: &[u8]) -> String {
+    let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    return String::from_utf8_lossy(&bytes[0 .. length]).into_owned();
+}
+
+fn hex_digit(c: u8) -> Option<usize> {
+    return match c {
+        b'0' ..= b'9' => Some((c - b'0') as usize),
+        b'A' ..= b'F' => Some((c - b'A') as usize + 10),
+        _ => None
+    };
+}
+
+// Round up to a whole number of banks by repeating the data, the same as
+// a smaller chip would appear to the mapper on real hardware
+fn pad_to_multiple(data: &[u8], bank_size: usize) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    if data.len() == 0 {
+        return padded;
+    }
+    let mut i = 0;
+    while padded.len() % bank_size != 0 {
+        padded.push(data[i % data.len()]);
+        i += 1;
+    }
+    return padded;
+}
+
+impl UnifFile {
+    pub fn from_reader(file_reader: &mut dyn Read) -> Result<UnifFile, UnifError> {
+        let mut header = [0u8; UNIF_HEADER_SIZE];
+        // A file too short to hold the header isn't in this format at all
+        if file_reader.read_exact(&mut header).is_err() {
+            return Err(UnifError::InvalidHeader);
+        }
+        if &header[0 .. 4] != b"UNIF" {
+            return Err(UnifError::InvalidHeader);
+        }
+
+        let mut unif = UnifFile {
+            revision: read_u32(&header[4 .. 8]),
+            board_name: String::new(),
+            name: String::new(),
+            prg_chunks: vec![Vec::new(); 16],
+            chr_chunks: vec![Vec::new(); 16],
+            mirroring: None,
+            battery: false,
+            tv_system: None,
+        };
+
+        let mut chunk_data: Vec<u8> = Vec::new();
+        file_reader.read_to_end(&mut chunk_data)?;
+
+        let mut offset = 0;
+        while offset + 8 <= chunk_data.len() {
+            let chunk_id = &chunk_data[offset .. offset + 4];
+            let length = read_u32(&chunk_data[offset + 4 .. offset + 8]) as usize;
+            let start = offset + 8;
+            // A huge length could wrap around on 32-bit targets, which is no less past the end
+            let end = match start.checked_add(length) {
+                Some(end) if end <= chunk_data.len() => end,
+                _ => {return Err(UnifError::ReadError{reason: format!("Chunk {} runs past the end of the file", read_string(chunk_id))});}
+            };
+            let data = &chunk_data[start .. end];
+            match chunk_id {
+                b"MAPR" => {unif.board_name = read_string(data)},
+                b"NAME" => {unif.name = read_string(data)},
+                b"BATR" => {unif.battery = true},
+                b"TVCI" => {unif.tv_system = data.get(0).cloned()},
+                b"MIRR" => {
+                    unif.mirroring = match data.get(0) {
+                        Some(0) => Some(UnifMirroring::Horizontal),
+                        Some(1) => Some(UnifMirroring::Vertical),
+                        Some(2) => Some(UnifMirroring::OneScreenLower),
+                        Some(3) => Some(UnifMirroring::OneScreenUpper),
+                        Some(4) => Some(UnifMirroring::FourScreen),
+                        Some(5) => Some(UnifMirroring::MapperControlled),
+                        _ => None
+                    };
+                },
+                _ => {
+                    // PRG0 - PRGF and CHR0 - CHRF, in whatever order the file stores them
+                    match (&chunk_id[0 .. 3], hex_digit(chunk_id[3])) {
+                        (b"PRG", Some(index)) => {unif.prg_chunks[index] = data.to_vec()},
+                        (b"CHR", Some(index)) => {unif.chr_chunks[index] = data.to_vec()},
+                        _ => {/* CRC, DINF, READ, CTRL and friends are informational */}
+                    }
+                }
+            }
+            offset = end;
+        }
+
+        if unif.board_name.len() == 0 {
+            // Without a board name, we can't tell which mapper this is
+            return Err(UnifError::MissingChunk{chunk: "MAPR".to_string()});
+        }
+
+        return Ok(unif);
+    }
+
+    pub fn prg(&self) -> Vec<u8> {
+        return self.prg_chunks.concat();
+    }
+
+    pub fn chr(&self) -> Vec<u8> {
+        return self.chr_chunks.concat();
+    }
+
+    pub fn board(&self) -> Option<UnifBoard> {
+        return find_board(&self.board_name);
+    }
+
+    pub fn to_ines(&self) -> Result<INesCartridge, UnifError> {
+        let board = match self.board() {
+            Some(board) => board,
+            None => {return Err(UnifError::UnknownBoard{name: self.board_name.clone()})}
+        };
+        println!("UNIF board {} loaded as mapper {}, submapper {}", self.board_name, board.mapper, board.submapper);
+        return self.to_ines_as(board);
+    }
+
+    // Builds the iNES equivalent using the given board's mapper number and RAM size
+    pub fn to_ines_as(&self, board: UnifBoard) -> Result<INesCartridge, UnifError> {
+        let prg = pad_to_multiple(&self.prg(), 0x4000);
+        let chr = pad_to_multiple(&self.chr(), 0x2000);
+        if prg.len() == 0 {
+            return Err(UnifError::MissingChunk{chunk: "PRG0".to_string()});
+        }
+
+        let prg_banks = prg.len() / 0x4000;
+        let chr_banks = chr.len() / 0x2000;
+        let chr_ram_size = if chr.len() == 0 {0x2000} else {0};
+
+        let mut raw_bytes = [0u8; 16];
+        raw_bytes[0 .. 4].copy_from_slice(b"NES\x1A");
+        raw_bytes[4] = (prg_banks & 0xFF) as u8;
+        raw_bytes[5] = (chr_banks & 0xFF) as u8;
+
+        let mut flags_6 = ((board.mapper & 0x0F) << 4) as u8;
+        match self.mirroring {
+            Some(UnifMirroring::Vertical) => {flags_6 |= 0b0000_0001},
+            Some(UnifMirroring::FourScreen) => {flags_6 |= 0b0000_1000},
+            _ => {}
+        }
+        if self.battery {
+            flags_6 |= 0b0000_0010;
+        }
+        raw_bytes[6] = flags_6;
+        // Upper nybble of the mapper, plus the NES 2.0 identifier
+        raw_bytes[7] = ((board.mapper & 0xF0) as u8) | 0b0000_1000;
+        raw_bytes[8] = (board.submapper << 4) | (((board.mapper & 0xF00) >> 8) as u8);
+        raw_bytes[9] = ((((chr_banks >> 8) & 0x0F) << 4) | ((prg_banks >> 8) & 0x0F)) as u8;
+
+        let ram_shift = size_to_shift_count(board.prg_ram_size);
+        raw_bytes[10] = if self.battery {ram_shift << 4} else {ram_shift};
+        raw_bytes[11] = size_to_shift_count(chr_ram_size);
+        // TVCI uses the same values as NES 2.0: 0 = NTSC, 1 = PAL, 2 = either
+        raw_bytes[12] = match self.tv_system {
+            Some(1) => 1,
+            Some(2) => 2,
+            _ => 0
+        };
+
+        return Ok(INesCartridge {
+            header: INesHeader::from(&raw_bytes),
+            trainer: Vec::new(),
+            prg: prg,
+            chr: chr,
+            misc_rom: Vec::new(),
+        });
+    }
+}
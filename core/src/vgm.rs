@@ -0,0 +1,315 @@
+// Logs writes to the sound chips as a VGM file, which hardware players and the various
+// archival sites understand. Format reference: https://vgmrips.net/wiki/VGM_Specification
+
+// VGM knows the 2A03 (with the FDS as an add-on), the YM2413 (which the VRC7 is a cut down
+// version of), the AY-3-8910 family (the Sunsoft 5B is a YM2149F inside) and the YM2608
+// (the EPSM, on the expansion port). The other expansion chips have no place in the format,
+// so their writes are left out.
+
+use nes::NesState;
+
+const VGM_VERSION: u32 = 0x0000_0171;
+const VGM_HEADER_SIZE: usize = 0x100;
+const VGM_SAMPLE_RATE: u64 = 44100;
+
+const MASTER_CLOCKS_PER_CPU_CYCLE: u64 = 12;
+const YM2413_CLOCK: u32 = 3_579_545;
+// Bit 31 asks for the VRC7's built-in patch set rather than the YM2413's
+const YM2413_VRC7_MODE: u32 = 0x8000_0000;
+const AY8910_TYPE_YM2149: u8 = 0x10;
+const AY8910_FLAGS_DEFAULT: u8 = 0x01;
+const NES_APU_FDS_ADDON: u32 = 0x8000_0000;
+// The EPSM runs its YM2608 from an 8 MHz oscillator of its own
+const YM2608_CLOCK: u32 = 8_000_000;
+
+const COMMAND_AY8910_WRITE: u8 = 0xA0;
+const COMMAND_YM2413_WRITE: u8 = 0x51;
+const COMMAND_YM2608_PORT_0_WRITE: u8 = 0x56;
+const COMMAND_YM2608_PORT_1_WRITE: u8 = 0x57;
+const COMMAND_NES_APU_WRITE: u8 = 0xB4;
+const COMMAND_WAIT: u8 = 0x61;
+const COMMAND_SHORT_WAIT: u8 = 0x70;
+const COMMAND_DATA_BLOCK: u8 = 0x67;
+const COMMAND_END_OF_DATA: u8 = 0x66;
+const DATA_BLOCK_NES_APU_RAM: u8 = 0xC2;
+
+pub struct VgmLog {
+    commands: Vec<u8>,
+    elapsed_master_clocks: u64,
+    last_master_clock: u64,
+    samples_written: u64,
+    cpu_clock_rate: u64,
+    fds: bool,
+    vrc7: bool,
+    // The VRC7's core running as a full YM2413, which plays its own instruments
+    ym2413: bool,
+    s5b: bool,
+    epsm: bool,
+    vrc7_register: u8,
+    s5b_register: u8,
+    epsm_registers: [u8; 2],
+    dmc_address: u8,
+    dmc_length: u8,
+    // What the player has been sent for $8000-$FFFF, so samples are only sent again when they change
+    logged_sample_memory: Vec<Option<u8>>,
+}
+
+fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset .. offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl VgmLog {
+    // Chips are named as AudioChannelState::chip() names them
+    pub fn new(master_clock: u64, cpu_clock_rate: u64, chips: &[String]) -> VgmLog {
+        let has_chip = |name: &str| chips.iter().any(|chip| chip == name);
+        for unsupported in ["VRC6", "MMC5", "N163"].iter() {
+            if has_chip(unsupported) {
+                println!("VGM has no support for the {}, its writes will be left out of the log", unsupported);
+            }
+        }
+        return VgmLog {
+            commands: Vec::new(),
+            elapsed_master_clocks: 0,
+            last_master_clock: master_clock,
+            samples_written: 0,
+            cpu_clock_rate: cpu_clock_rate,
+            fds: has_chip("FDS"),
+            vrc7: has_chip("VRC7") || has_chip("YM2413"),
+            ym2413: has_chip("YM2413"),
+            s5b: has_chip("YM2149F"),
+            epsm: has_chip("EPSM"),
+            vrc7_register: 0,
+            s5b_register: 0,
+            epsm_registers: [0; 2],
+            dmc_address: 0,
+            dmc_length: 0,
+            logged_sample_memory: vec![None; 0x8000],
+        };
+    }
+
+    // Brings the log up to the present with wait commands
+    fn wait_until(&mut self, master_clock: u64) {
+        if master_clock >= self.last_master_clock {
+            self.elapsed_master_clocks += master_clock - self.last_master_clock;
+        } else {
+            // The console was power cycled, which resets the clock
+            self.elapsed_master_clocks += master_clock;
+        }
+        self.last_master_clock = master_clock;
+
+        let cpu_cycles = self.elapsed_master_clocks / MASTER_CLOCKS_PER_CPU_CYCLE;
+        let current_sample = cpu_cycles * VGM_SAMPLE_RATE / self.cpu_clock_rate;
+        let mut remaining = current_sample - self.samples_written;
+        self.samples_written = current_sample;
+        while remaining > 0 {
+            let wait = remaining.min(0xFFFF);
+            if wait <= 16 {
+                self.commands.push(COMMAND_SHORT_WAIT + (wait - 1) as u8);
+            } else {
+                self.commands.push(COMMAND_WAIT);
+                self.commands.extend(&(wait as u16).to_le_bytes());
+            }
+            remaining -= wait;
+        }
+    }
+
+    pub fn record_write(&mut self, master_clock: u64, address: u16, data: u8) {
+        let command = match address {
+            0x4000 ..= 0x4013 | 0x4015 | 0x4017 => Some((COMMAND_NES_APU_WRITE, (address - 0x4000) as u8)),
+            0x4023 if self.fds => Some((COMMAND_NES_APU_WRITE, 0x3F)),
+            0x4040 ..= 0x407F if self.fds => Some((COMMAND_NES_APU_WRITE, (address - 0x4040 + 0x40) as u8)),
+            0x4080 ..= 0x409E if self.fds => Some((COMMAND_NES_APU_WRITE, (address - 0x4080 + 0x20) as u8)),
+            0x9010 if self.vrc7 => {
+                self.vrc7_register = data;
+                None
+            },
+            0x9030 if self.vrc7 => Some((COMMAND_YM2413_WRITE, self.vrc7_register)),
+            0xC000 ..= 0xDFFF if self.s5b => {
+                self.s5b_register = data & 0x0F;
+                None
+            },
+            // Registers $E and $F are I/O ports, which have nothing to do with sound
+            0xE000 ..= 0xFFFF if self.s5b && self.s5b_register < 0x0E => Some((COMMAND_AY8910_WRITE, self.s5b_register)),
+            0x401C if self.epsm => {
+                self.epsm_registers[0] = data;
+                None
+            },
+            0x401D if self.epsm => Some((COMMAND_YM2608_PORT_0_WRITE, self.epsm_registers[0])),
+            0x401E if self.epsm => {
+                self.epsm_registers[1] = data;
+                None
+            },
+            0x401F if self.epsm => Some((COMMAND_YM2608_PORT_1_WRITE, self.epsm_registers[1])),
+            _ => None
+        };
+        match address {
+            0x4012 => {self.dmc_address = data},
+            0x4013 => {self.dmc_length = data},
+            _ => {}
+        }
+        match command {
+            Some((opcode, register)) => {
+                self.wait_until(master_clock);
+                self.commands.push(opcode);
+                self.commands.push(register);
+                self.commands.push(data);
+            },
+            None => {}
+        }
+    }
+
+    // The range of memory the DMC might play once this write lands, if it's one that starts or
+    // changes a sample
+    pub fn dmc_sample_range(&self, address: u16, data: u8) -> Option<(u16, usize)> {
+        let (sample_address, sample_length) = match address {
+            0x4012 => (data, self.dmc_length),
+            0x4013 => (self.dmc_address, data),
+            0x4015 if (data & 0x10) != 0 => (self.dmc_address, self.dmc_length),
+            _ => return None
+        };
+        return Some((0xC000 + (sample_address as u16) * 64, (sample_length as usize) * 16 + 1));
+    }
+
+    // Sends the player any sample bytes which differ from what it already has
+    pub fn record_dmc_samples(&mut self, start_address: u16, samples: &[u8]) {
+        let mut changed_block: Option<(u16, Vec<u8>)> = None;
+        for (i, sample) in samples.iter().enumerate() {
+            // Sample playback wraps around from $FFFF to $8000
+            let address = 0x8000 | (start_address as usize + i) as u16;
+            let index = (address - 0x8000) as usize;
+            let unchanged = self.logged_sample_memory[index] == Some(*sample);
+            self.logged_sample_memory[index] = Some(*sample);
+            changed_block = match changed_block {
+                Some((block_start, mut block)) => {
+                    if unchanged || address == 0x8000 {
+                        self.record_data_block(block_start, &block);
+                        if unchanged {None} else {Some((address, vec![*sample]))}
+                    } else {
+                        block.push(*sample);
+                        Some((block_start, block))
+                    }
+                },
+                None => if unchanged {None} else {Some((address, vec![*sample]))}
+            };
+        }
+        match changed_block {
+            Some((block_start, block)) => self.record_data_block(block_start, &block),
+            None => {}
+        }
+    }
+
+    fn record_data_block(&mut self, start_address: u16, data: &[u8]) {
+        self.commands.push(COMMAND_DATA_BLOCK);
+        self.commands.push(COMMAND_END_OF_DATA);
+        self.commands.push(DATA_BLOCK_NES_APU_RAM);
+        self.commands.extend(&((data.len() + 2) as u32).to_le_bytes());
+        self.commands.extend(&start_address.to_le_bytes());
+        self.commands.extend(data);
+    }
+
+    // Ends the log at the given time and produces the finished file
+    pub fn to_bytes(&mut self, master_clock: u64) -> Vec<u8> {
+        self.wait_until(master_clock);
+
+        let mut header = vec![0u8; VGM_HEADER_SIZE];
+        let file_length = VGM_HEADER_SIZE + self.commands.len() + 1;
+        header[0 .. 4].copy_from_slice(b"Vgm ");
+        write_u32(&mut header, 0x04, (file_length - 0x04) as u32);
+        write_u32(&mut header, 0x08, VGM_VERSION);
+        if self.vrc7 {
+            let mode = if self.ym2413 {0} else {YM2413_VRC7_MODE};
+            write_u32(&mut header, 0x10, YM2413_CLOCK | mode);
+        }
+        write_u32(&mut header, 0x18, self.samples_written as u32);
+        write_u32(&mut header, 0x34, (VGM_HEADER_SIZE - 0x34) as u32);
+        if self.epsm {
+            write_u32(&mut header, 0x48, YM2608_CLOCK);
+        }
+        if self.s5b {
+            // The 5B divides its clock by 2 before the AY-style divide by 16
+            write_u32(&mut header, 0x74, (self.cpu_clock_rate / 2) as u32);
+            header[0x78] = AY8910_TYPE_YM2149;
+            header[0x79] = AY8910_FLAGS_DEFAULT;
+        }
+        let apu_clock = self.cpu_clock_rate as u32 | if self.fds {NES_APU_FDS_ADDON} else {0};
+        write_u32(&mut header, 0x84, apu_clock);
+
+        let mut file = header;
+        file.extend(&self.commands);
+        file.push(COMMAND_END_OF_DATA);
+        return file;
+    }
+}
+
+pub fn start_logging(nes: &mut NesState) {
+    let chips: Vec<String> = nes.apu.channels().iter().chain(nes.mapper.channels().iter()).map(|channel| channel.chip()).collect();
+    nes.event_tracker.vgm_log = Some(VgmLog::new(nes.master_clock, nes.apu.cpu_clock_rate, &chips));
+}
+
+// Returns the finished file, if a log was running
+pub fn stop_logging(nes: &mut NesState) -> Option<Vec<u8>> {
+    let master_clock = nes.master_clock;
+    return nes.event_tracker.vgm_log.take().map(|mut log| log.to_bytes(master_clock));
+}
+
+// DMC samples are played straight out of cartridge memory, which the VGM player doesn't have,
+// so the log carries its own copy. This runs before the write itself is logged, so that the
+// samples arrive ahead of the write that plays them.
+pub fn snoop_dmc_samples(nes: &mut NesState, address: u16, data: u8) {
+    let range = match nes.event_tracker.vgm_log {
+        Some(ref log) => log.dmc_sample_range(address, data),
+        None => None
+    };
+    match range {
+        Some((start_address, length)) => {
+            let samples: Vec<u8> = (0 .. length).map(|i| {
+                let sample_address = 0x8000 | (start_address as usize + i) as u16;
+                return nes.mapper.debug_read_cpu(sample_address).unwrap_or(0);
+            }).collect();
+            match nes.event_tracker.vgm_log {
+                Some(ref mut log) => log.record_dmc_samples(start_address, &samples),
+                None => {}
+            }
+        },
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_epsm_writes_to_both_ports() {
+        let mut log = VgmLog::new(0, 1_789_773, &["EPSM".to_string()]);
+        log.record_write(0, 0x401C, 0x28);
+        log.record_write(0, 0x401D, 0xF0);
+        log.record_write(0, 0x401E, 0xB4);
+        log.record_write(0, 0x401F, 0xC0);
+        let file = log.to_bytes(0);
+        assert_eq!(&file[0x48 .. 0x4C], &YM2608_CLOCK.to_le_bytes());
+        assert_eq!(&file[VGM_HEADER_SIZE ..], &[
+            COMMAND_YM2608_PORT_0_WRITE, 0x28, 0xF0,
+            COMMAND_YM2608_PORT_1_WRITE, 0xB4, 0xC0,
+            COMMAND_END_OF_DATA]);
+    }
+
+    #[test]
+    fn logs_a_ym2413_without_the_vrc7_patches() {
+        let mut log = VgmLog::new(0, 1_789_773, &["YM2413".to_string()]);
+        log.record_write(0, 0x9010, 0x0E);
+        log.record_write(0, 0x9030, 0x30);
+        let file = log.to_bytes(0);
+        assert_eq!(&file[0x10 .. 0x14], &YM2413_CLOCK.to_le_bytes());
+        assert_eq!(&file[VGM_HEADER_SIZE ..], &[COMMAND_YM2413_WRITE, 0x0E, 0x30, COMMAND_END_OF_DATA]);
+    }
+
+    #[test]
+    fn waits_are_timed_against_the_apu_clock() {
+        // One second of a PAL console's CPU
+        let mut log = VgmLog::new(0, 1_662_607, &[]);
+        let file = log.to_bytes(1_662_607 * MASTER_CLOCKS_PER_CPU_CYCLE);
+        assert_eq!(&file[0x18 .. 0x1C], &(VGM_SAMPLE_RATE as u32).to_le_bytes());
+        assert_eq!(&file[0x84 .. 0x88], &1_662_607u32.to_le_bytes());
+    }
+}
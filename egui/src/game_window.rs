@@ -5,6 +5,7 @@ use app::ShellEvent;
 
 use eframe::egui;
 use rfd::FileDialog;
+use rustico_core::load_error::LoadError;
 use rustico_ui_common::events;
 
 use std::collections::VecDeque;
@@ -20,6 +21,7 @@ pub struct GameWindow {
     pub game_window_scale: usize,
     pub sram_path: PathBuf,
     pub has_sram: bool,
+    pub vgm_logging: bool,
 }
 
 impl GameWindow {
@@ -34,6 +36,7 @@ impl GameWindow {
             game_window_scale: 2,
             sram_path: PathBuf::new(),
             has_sram: false,
+            vgm_logging: false,
         };
     }
 
@@ -74,9 +77,30 @@ impl GameWindow {
         let _ = runtime_tx.send(events::Event::RequestSramSave(self.sram_path.clone().to_string_lossy().into_owned()));
     }
 
+    fn start_vgm_log(&mut self, runtime_tx: &mut Sender<events::Event>) {
+        let _ = runtime_tx.send(events::Event::StartVgmLog);
+        self.vgm_logging = true;
+    }
+
+    fn stop_vgm_log_dialog(&mut self, runtime_tx: &mut Sender<events::Event>) {
+        let file = FileDialog::new()
+            .add_filter("VGM files", &["vgm"])
+            .set_file_name("log.vgm")
+            .save_file();
+        match file {
+            Some(file_path) => {
+                let _ = runtime_tx.send(events::Event::RequestVgmSave(file_path.to_string_lossy().into_owned()));
+                self.vgm_logging = false;
+            },
+            None => {
+                println!("User canceled the dialog, still logging.");
+            }
+        }
+    }
+
     fn open_cartridge_dialog(&mut self, runtime_tx: &mut Sender<events::Event>) {
         let files = FileDialog::new()
-            .add_filter("compatible files", &["nes", "nsf"])
+            .add_filter("compatible files", &["nes", "nsf", "nsfe", "fds", "unf", "unif"])
             .pick_file();
         match files {
             Some(file_path) => {
@@ -93,6 +117,8 @@ impl GameWindow {
         self.request_sram_save(runtime_tx);
 
         self.sram_path = cartridge_path.with_extension("sav");
+        // The runtime throws away any log in progress when the cartridge changes
+        self.vgm_logging = false;
         let cartridge_path_as_str = cartridge_path.clone().to_string_lossy().into_owned();
         let cartridge_load_event = match std::fs::read(cartridge_path) {
             Ok(cartridge_data) => {
@@ -109,8 +135,7 @@ impl GameWindow {
                 }
             },
             Err(reason) => {
-                println!("{}", reason);
-                rustico_ui_common::Event::LoadFailed(reason.to_string())
+                rustico_ui_common::Event::CartridgeRejected(cartridge_path_as_str, LoadError::Unreadable{reason: reason.to_string()})
             }
         };
         let _ = runtime_tx.send(cartridge_load_event);
@@ -131,6 +156,18 @@ impl GameWindow {
                         ui.close_menu();
                     }
                     ui.separator();
+                    if self.vgm_logging {
+                        if ui.button("Stop VGM Log...").clicked() {
+                            self.stop_vgm_log_dialog(runtime_tx);
+                            ui.close_menu();
+                        }
+                    } else {
+                        if ui.button("Start VGM Log").clicked() {
+                            self.start_vgm_log(runtime_tx);
+                            ui.close_menu();
+                        }
+                    }
+                    ui.separator();
                     if ui.button("Exit").clicked() {
                         ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         ui.close_menu();
@@ -170,6 +207,37 @@ impl GameWindow {
                             ui.close_menu();
                         }
                     });
+                    ui.menu_button("Audio", |ui| {
+                        let mut stereo_checked = settings.get_boolean("audio.stereo".into()).unwrap_or(false);
+                        if ui.checkbox(&mut stereo_checked, "Stereo").clicked() {
+                            let _ = runtime_tx.send(events::Event::ToggleBooleanSetting("audio.stereo".into()));
+                            ui.close_menu();
+                        }
+                        ui.separator();
+                        let synthesis = settings.get_string("audio.synthesis".into()).unwrap_or("hq".into());
+                        if ui.radio(synthesis == "hq", "High Quality").clicked() {
+                            let _ = runtime_tx.send(events::Event::StoreStringSetting("audio.synthesis".into(), "hq".into()));
+                            ui.close_menu();
+                        }
+                        if ui.radio(synthesis == "lq", "Low Quality").clicked() {
+                            let _ = runtime_tx.send(events::Event::StoreStringSetting("audio.synthesis".into(), "lq".into()));
+                            ui.close_menu();
+                        }
+                        if ui.radio(synthesis == "blip", "Band-Limited Steps (Fastest)").clicked() {
+                            let _ = runtime_tx.send(events::Event::StoreStringSetting("audio.synthesis".into(), "blip".into()));
+                            ui.close_menu();
+                        }
+                        ui.separator();
+                        let sync = settings.get_string("audio.sync".into()).unwrap_or("audio".into());
+                        if ui.radio(sync == "audio", "Sync to Audio").clicked() {
+                            let _ = runtime_tx.send(events::Event::StoreStringSetting("audio.sync".into(), "audio".into()));
+                            ui.close_menu();
+                        }
+                        if ui.radio(sync == "video", "Sync to Video").clicked() {
+                            let _ = runtime_tx.send(events::Event::StoreStringSetting("audio.sync".into(), "video".into()));
+                            ui.close_menu();
+                        }
+                    });
                     ui.separator();
                     if ui.button("Preferences").clicked() {
                         ui.close_menu();
@@ -4,6 +4,8 @@ use rustico_ui_common::application::RuntimeState as RusticoRuntimeState;
 use rustico_ui_common::events;
 use rustico_ui_common::game_window::GameWindow;
 use rustico_ui_common::panel::Panel;
+use rustico_ui_common::sync::SyncMode;
+use rustico_ui_common::sync::frame_duration;
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use std::collections::VecDeque;
@@ -13,6 +15,7 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 
 lazy_static! {
@@ -33,24 +36,32 @@ struct Worker {
     // We need to keep the audio stream around so that it continues to run, but
     // we never need to read it directly. Rust complains about this. :)
     _audio_stream: Box<dyn StreamTrait>,
+    audio_channels: usize,
     runtime_state: RusticoRuntimeState,
     game_window: GameWindow,
+    // When video locked, the worker has no display of its own to wait on, so it keeps time
+    next_frame_at: Instant,
 
     exit_requested: bool,
 }
 
 impl Worker {
     pub fn new(runtime_rx: Receiver<events::Event>, shell_tx: Sender<app::ShellEvent>) -> Worker {
-        let audio_stream = setup_audio_stream();
-        let runtime_state = RusticoRuntimeState::new();
+        let (audio_stream, sample_rate) = setup_audio_stream(1);
+        let mut runtime_state = RusticoRuntimeState::new();
+        // The stream runs at whatever rate the device prefers, so the APU has to match it
+        runtime_state.set_host_sample_rate(sample_rate);
+        runtime_state.rate_control.target_latency = 1024;
         let game_window = GameWindow::new();
 
         return Worker{
             runtime_rx: runtime_rx,
             shell_tx: shell_tx,
             _audio_stream: audio_stream,
+            audio_channels: 1,
             runtime_state: runtime_state,
             game_window: game_window,
+            next_frame_at: Instant::now(),
             exit_requested: false
         };
     }
@@ -97,14 +108,33 @@ impl Worker {
                 let has_sram = self.runtime_state.nes.mapper.has_sram();
                 let _ = self.shell_tx.send(app::ShellEvent::HasSram(has_sram));
             }
+            rustico_ui_common::Event::CartridgeRejected(cart_id, reason) => {
+                println!("WORKER: cartridge {} could not be played: {}", cart_id, reason);
+            },
             rustico_ui_common::Event::SaveSram(sram_id, sram_data) => {
                 self.save_sram(sram_id, &sram_data);
             },
+            rustico_ui_common::Event::SaveVgm(vgm_path, vgm_data) => {
+                match std::fs::write(&vgm_path, vgm_data.as_slice()) {
+                    Err(why) => {println!("Couldn't write {}: {}", vgm_path, why)},
+                    Ok(_) => {println!("Wrote VGM log to: {}", vgm_path)}
+                }
+            },
             rustico_ui_common::Event::CloseApplication => {
                 println!("WORKER: application close requested, will exit after processing remaining events...");
                 self.exit_requested = true;
             },
             rustico_ui_common::Event::ApplyBooleanSetting(_,_) => {
+                // The runtime has already switched the APU over, if this was audio.stereo
+                let audio_channels = self.runtime_state.nes.apu.output_channels();
+                if audio_channels != self.audio_channels {
+                    AUDIO_OUTPUT_BUFFER.lock().expect("wat").clear();
+                    let (audio_stream, sample_rate) = setup_audio_stream(audio_channels as u16);
+                    self._audio_stream = audio_stream;
+                    // Not every device offers the same rate for both
+                    self.runtime_state.set_host_sample_rate(sample_rate);
+                    self.audio_channels = audio_channels;
+                }
                 let _ = self.shell_tx.send(app::ShellEvent::SettingsUpdated(
                     Arc::new(self.runtime_state.settings.clone())
                 ));
@@ -142,32 +172,72 @@ impl Worker {
         };
     }
 
-    pub fn step_emulator(&mut self) {
-        // Quickly poll the length of the audio buffer
+    // Everything waiting to be played, in sample frames
+    fn queued_audio_frames(&self) -> usize {
         let audio_output_buffer = AUDIO_OUTPUT_BUFFER.lock().expect("wat");
-        let mut output_buffer_len = audio_output_buffer.len();
-        drop(audio_output_buffer); // immediately free the mutex, so running the emulator doesn't starve the audio thread
+        let queued_samples = audio_output_buffer.len() + self.runtime_state.nes.apu.samples_queued();
+        drop(audio_output_buffer); // immediately free the mutex, so we don't starve the audio thread
+        return queued_samples / self.audio_channels;
+    }
+
+    // Runs one scanline and hands its audio over to the stream. Returns true if that finished a frame.
+    fn run_scanline(&mut self) -> bool {
+        let mut frame_finished = false;
+        self.dispatch_event(events::Event::NesRunScanline);
+        if self.runtime_state.nes.ppu.current_scanline == 242 {
+            // we just finished a game frame, so have the game window repaint itself
+            self.dispatch_event(events::Event::RequestFrame);
+            frame_finished = true;
+        }
+        let samples_i16 = self.runtime_state.nes.apu.consume_samples();
+        let samples_float: Vec<f32> = samples_i16.into_iter().map(|x| <i16 as Into<f32>>::into(x) / 32767.0).collect();
+        let mut audio_output_buffer = AUDIO_OUTPUT_BUFFER.lock().expect("wat");
+        audio_output_buffer.extend(samples_float);
+        drop(audio_output_buffer);
+        return frame_finished;
+    }
 
-        // Now we do fun stuff: as long as we are under the audio threshold, run one scanline. If we happen
-        // to complete a frame while doing this, update the game window texture (and later, call "draw" on all
-        // active subwindows so they know to repaint)
-        // (2048 is arbitrary, make this configurable later!)
+    pub fn step_emulator(&mut self) {
         let mut repaint_needed = false;
-        while output_buffer_len < 512 {
-            self.dispatch_event(events::Event::NesRunScanline);
-            if self.runtime_state.nes.ppu.current_scanline == 242 {
-                // we just finished a game frame, so have the game window repaint itself
-                self.dispatch_event(events::Event::RequestFrame);
-                repaint_needed = true;
+        match self.runtime_state.rate_control.mode {
+            SyncMode::AudioLocked => {
+                let queued = self.queued_audio_frames();
+                self.runtime_state.rate_control.update(&mut self.runtime_state.nes.apu, queued);
+                // As long as we are under the audio threshold, run one scanline. If we happen
+                // to complete a frame while doing this, update the game window texture (and later,
+                // call "draw" on all active subwindows so they know to repaint)
+                while self.runtime_state.rate_control.wants_frame(self.queued_audio_frames(), 0) {
+                    if self.run_scanline() {
+                        repaint_needed = true;
+                    }
+                }
+            },
+            SyncMode::VideoLocked => {
+                let now = Instant::now();
+                if now < self.next_frame_at {
+                    return;
+                }
+                // PAL and Dendy cartridges show fewer, longer frames
+                let frame_length = frame_duration(self.runtime_state.region);
+                self.next_frame_at += frame_length;
+                if self.next_frame_at < now {
+                    // We fell a whole frame behind (the window was being dragged, perhaps), so
+                    // rather than racing to catch up, start counting again from here
+                    self.next_frame_at = now + frame_length;
+                }
+                let queued = self.queued_audio_frames();
+                self.runtime_state.rate_control.update(&mut self.runtime_state.nes.apu, queued);
+                let silence = self.runtime_state.rate_control.silence_needed(queued);
+                if silence > 0 {
+                    let mut audio_output_buffer = AUDIO_OUTPUT_BUFFER.lock().expect("wat");
+                    audio_output_buffer.extend(vec![0.0f32; silence * self.audio_channels]);
+                    drop(audio_output_buffer);
+                }
+                if self.runtime_state.rate_control.wants_frame(queued, 0) {
+                    while !self.run_scanline() {}
+                    repaint_needed = true;
+                }
             }
-            let samples_i16 = self.runtime_state.nes.apu.consume_samples();
-            let samples_float: Vec<f32> = samples_i16.into_iter().map(|x| <i16 as Into<f32>>::into(x) / 32767.0).collect();
-            // Apply those samples to the audio buffer AND recheck our count
-            // (keep going until we rise above the threshold)
-            let mut audio_output_buffer = AUDIO_OUTPUT_BUFFER.lock().expect("wat");
-            audio_output_buffer.extend(samples_float);
-            output_buffer_len = audio_output_buffer.len();
-            drop(audio_output_buffer);
         }
 
         if repaint_needed {
@@ -185,7 +255,9 @@ impl Worker {
     }
 }
 
-pub fn setup_audio_stream() -> Box<dyn StreamTrait> {
+// Samples in AUDIO_OUTPUT_BUFFER are interleaved, when there's more than one channel
+// Returns the stream, and the sample rate it settled on
+pub fn setup_audio_stream(channels: u16) -> (Box<dyn StreamTrait>, u64) {
     // Setup the audio callback, which will ultimately be in charge of trying to step emulation
     let host = cpal::default_host();
     let device = host.default_output_device().expect("no output device available");
@@ -197,8 +269,9 @@ pub fn setup_audio_stream() -> Box<dyn StreamTrait> {
 
     let mut stream_config: cpal::StreamConfig = default_output_config.into();
     stream_config.buffer_size = cpal::BufferSize::Fixed(256);
-    stream_config.channels = 1;
+    stream_config.channels = channels;
     println!("stream config will be: {:?}", stream_config);
+    let sample_rate = stream_config.sample_rate.0 as u64;
 
     let stream = device.build_output_stream(
         &stream_config.into(),
@@ -223,7 +296,7 @@ pub fn setup_audio_stream() -> Box<dyn StreamTrait> {
 
     stream.play().unwrap();
 
-    return Box::new(stream);
+    return (Box::new(stream), sample_rate);
 }
 
 pub fn worker_main(runtime_rx: Receiver<events::Event>, shell_tx: Sender<app::ShellEvent>) {
@@ -3,11 +3,19 @@ use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use rustico_core::archive::extract_rom;
+use rustico_core::load_error::LoadError;
+use rustico_core::archive::is_archive;
+use rustico_core::archive::split_entry_path;
+use rustico_core::patch::apply_patch;
+use rustico_core::patch::PATCH_EXTENSIONS;
 use rustico_ui_common;
 
 pub struct CartridgeManager {
   pub game_path: String,
   pub sram_path: String,
+  pub vgm_path: String,
+  pub vgm_logging: bool,
 }
 
 impl CartridgeManager {
@@ -15,29 +23,90 @@ impl CartridgeManager {
     return CartridgeManager {
       game_path: String::from(""),
       sram_path: String::from(""),
+      vgm_path: String::from(""),
+      vgm_logging: false,
+    }
+  }
+
+  // Look for a patch with the same name as the ROM (game.nes -> game.ips, etc) and apply
+  // the first one we find. A patch that doesn't fit is reported, and the ROM loads unpatched.
+  pub fn apply_matching_patch(&self, cartridge_path: &PathBuf, cartridge_data: Vec<u8>) -> Vec<u8> {
+    for extension in PATCH_EXTENSIONS.iter() {
+      let patch_path = cartridge_path.with_extension(extension);
+      match std::fs::read(&patch_path) {
+        Ok(patch_data) => {
+          match apply_patch(&cartridge_data, &patch_data) {
+            Ok(patched_data) => {
+              println!("Applied patch: {}", patch_path.to_str().unwrap());
+              return patched_data;
+            },
+            Err(reason) => {
+              println!("Failed to apply patch {}: {}", patch_path.to_str().unwrap(), reason);
+              println!("Continuing with the unpatched ROM.");
+              return cartridge_data;
+            }
+          }
+        },
+        Err(_) => {}
+      }
+    }
+    return cartridge_data;
+  }
+
+  // Games inside an archive are identified as "games.zip#game.nes". Saves and patches are
+  // keyed on the entry rather than the archive, so each game in a multi-ROM archive gets
+  // its own: games.zip#game.nes -> games.game.sav
+  pub fn save_key_path(cart_id: &str) -> PathBuf {
+    let (file_path, entry_name) = split_entry_path(cart_id);
+    let archive_path = PathBuf::from(&file_path);
+    match entry_name {
+      Some(entry_name) => {
+        let entry_file_name = PathBuf::from(&entry_name).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        if entry_file_name.is_empty() {
+          // Unnamed gzip contents: game.nes.gz -> game.nes
+          return archive_path.with_extension("");
+        }
+        let archive_stem = archive_path.file_stem().unwrap().to_string_lossy().into_owned();
+        return archive_path.with_file_name(format!("{}.{}", archive_stem, entry_file_name));
+      },
+      None => return archive_path
     }
   }
 
   pub fn open_cartridge_with_sram(&mut self, file_path: &str) -> rustico_ui_common::Event {
-    match std::fs::read(file_path) {
-      Ok(cartridge_data) => {
-        let cartridge_path = PathBuf::from(file_path);
+    let (archive_path, entry_name) = split_entry_path(file_path);
+    match std::fs::read(&archive_path) {
+      Ok(file_data) => {
+        let (cart_id, cartridge_data) = if is_archive(&file_data) {
+          match extract_rom(&file_data, entry_name.as_ref().map(|name| name.as_str())) {
+            Ok(entry) => {
+              println!("Extracted {} from {}", entry.name, archive_path);
+              (format!("{}#{}", archive_path, entry.name), entry.data)
+            },
+            Err(reason) => {
+              return rustico_ui_common::Event::CartridgeRejected(file_path.to_string(), LoadError::Unreadable{reason: reason.to_string()});
+            }
+          }
+        } else {
+          (file_path.to_string(), file_data)
+        };
+        let cartridge_path = CartridgeManager::save_key_path(&cart_id);
+        let cartridge_data = self.apply_matching_patch(&cartridge_path, cartridge_data);
         let sram_path = cartridge_path.with_extension("sav");
         match std::fs::read(&sram_path.to_str().unwrap()) {
           Ok(sram_data) => {
-            return rustico_ui_common::Event::LoadCartridge(file_path.to_string(), Arc::new(cartridge_data), Arc::new(sram_data));
+            return rustico_ui_common::Event::LoadCartridge(cart_id, Arc::new(cartridge_data), Arc::new(sram_data));
           },
           Err(reason) => {
             println!("Failed to load SRAM: {}", reason);
             println!("Continuing anyway.");
             let bucket_of_nothing: Vec<u8> = Vec::new();
-            return rustico_ui_common::Event::LoadCartridge(file_path.to_string(), Arc::new(cartridge_data), Arc::new(bucket_of_nothing));
+            return rustico_ui_common::Event::LoadCartridge(cart_id, Arc::new(cartridge_data), Arc::new(bucket_of_nothing));
           }
         }
       },
       Err(reason) => {
-        println!("{}", reason);
-        return rustico_ui_common::Event::LoadFailed(reason.to_string());
+        return rustico_ui_common::Event::CartridgeRejected(file_path.to_string(), LoadError::Unreadable{reason: reason.to_string()});
       }
     }
   }
@@ -53,7 +122,7 @@ impl CartridgeManager {
       },
       Err(reason) => {
         println!("Failed to load FDS BIOS: {}", reason);
-        return rustico_ui_common::Event::LoadFailed(reason.to_string());
+        return rustico_ui_common::Event::CartridgeRejected(self.game_path.clone(), LoadError::BiosRequired);
       }
     }
   }
@@ -71,6 +140,16 @@ impl CartridgeManager {
     };
   }
 
+  // Logs go next to the game, and are saved when logging is toggled off
+  pub fn toggle_vgm_log(&mut self) -> rustico_ui_common::Event {
+    self.vgm_logging = !self.vgm_logging;
+    if self.vgm_logging {
+      println!("Logging VGM, press L again to save to: {}", self.vgm_path);
+      return rustico_ui_common::Event::StartVgmLog;
+    }
+    return rustico_ui_common::Event::RequestVgmSave(self.vgm_path.clone());
+  }
+
   pub fn handle_event(&mut self, event: rustico_ui_common::Event) -> Vec<rustico_ui_common::Event> {
     let mut responses: Vec<rustico_ui_common::Event> = Vec::new();
     match event {
@@ -82,7 +161,6 @@ impl CartridgeManager {
           },
           Err(reason) => {
             println!("{}", reason);
-            responses.push(rustico_ui_common::Event::LoadFailed(reason));
           }
         }
       },
@@ -91,11 +169,11 @@ impl CartridgeManager {
       },
       rustico_ui_common::Event::CartridgeLoaded(cart_id) => {
         self.game_path = cart_id.to_string();
-        self.sram_path = PathBuf::from(cart_id).with_extension("sav").to_str().unwrap().to_string();
+        self.sram_path = CartridgeManager::save_key_path(&cart_id).with_extension("sav").to_str().unwrap().to_string();
         println!("Cartridge loading success! Storing save path as: {}", self.sram_path);
-      },
-      rustico_ui_common::Event::LoadFailed(reason) => {
-        println!("Loading failed: {}", reason);
+        self.vgm_path = CartridgeManager::save_key_path(&cart_id).with_extension("vgm").to_str().unwrap().to_string();
+        // Any log in progress was discarded along with the old cartridge
+        self.vgm_logging = false;
       },
       rustico_ui_common::Event::CartridgeRejected(cart_id, reason) => {
         println!("Cartridge {} could not be played: {}", cart_id, reason);
@@ -103,6 +181,12 @@ impl CartridgeManager {
       rustico_ui_common::Event::SaveSram(sram_id, sram_data) => {
         self.save_sram(sram_id, &sram_data);
       },
+      rustico_ui_common::Event::SaveVgm(vgm_path, vgm_data) => {
+        match std::fs::write(&vgm_path, vgm_data.as_slice()) {
+          Err(why) => {println!("Couldn't write {}: {}", vgm_path, why)},
+          Ok(_) => {println!("Wrote VGM log to: {}", vgm_path)}
+        }
+      },
       _ => {}
     }
     return responses;
@@ -110,7 +194,7 @@ impl CartridgeManager {
 }
 
 pub fn open_file_dialog() -> Result<String, String> {
-  let result = nfd2::dialog().filter("nes,nsf,fds").open().unwrap_or_else(|e| { panic!("{}", e); });
+  let result = nfd2::dialog().filter("nes,nsf,nsfe,fds,unf,unif,zip,gz").open().unwrap_or_else(|e| { panic!("{}", e); });
 
   match result {
     nfd2::Response::Okay(file_path) => {
@@ -12,6 +12,7 @@ extern crate rustico_ui_common;
 mod cartridge_manager;
 mod platform_window;
 
+use sdl2::audio::AudioQueue;
 use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
 use sdl2::event::WindowEvent;
@@ -46,6 +47,12 @@ use rustico_ui_common::ppu_window::PpuWindow;
 use cartridge_manager::CartridgeManager;
 use platform_window::PlatformWindow;
 
+// Everything waiting to be played, in sample frames. The device queue is measured in bytes.
+fn queued_audio_frames(device: &AudioQueue<i16>, runtime_state: &RusticoRuntimeState) -> usize {
+  let queued_samples = device.size() as usize / 2 + runtime_state.nes.apu.samples_queued();
+  return queued_samples / runtime_state.nes.apu.output_channels();
+}
+
 pub fn dispatch_event(windows: &mut Vec<PlatformWindow>, runtime_state: &mut RusticoRuntimeState, cartridge_state: &mut CartridgeManager, event: events::Event) -> Vec<events::Event> {
   let mut responses: Vec<events::Event> = Vec::new();
   for i in 0 .. windows.len() {
@@ -120,7 +127,7 @@ pub fn main() {
   };
 
   // Grab the active audio device and begin playback immediately. Until we fill the buffer, this will "play" silence:
-  let device = audio_subsystem.open_queue::<i16, _>(None, &desired_spec).unwrap();
+  let mut device = audio_subsystem.open_queue::<i16, _>(None, &desired_spec).unwrap();
   device.clear();
   device.resume();
 
@@ -269,9 +276,11 @@ pub fn main() {
                       Keycode::F => {application_events.push(events::Event::ToggleBooleanSetting("video.display_fps".to_string()));},
 
                       Keycode::S => {application_events.push(events::Event::RequestSramSave(cartridge_state.sram_path.clone()));},
+                      Keycode::L => {application_events.push(cartridge_state.toggle_vgm_log());},
 
                       Keycode::P => {application_events.push(events::Event::NesToggleEmulation);}
                       Keycode::R => {application_events.push(events::Event::NesReset);}
+                      Keycode::T => {application_events.push(events::Event::NesPowerCycle);}
                       Keycode::Space => {application_events.push(events::Event::NesRunOpcode);},
                       Keycode::C => {application_events.push(events::Event::NesRunCycle);},
                       Keycode::H => {application_events.push(events::Event::NesRunScanline);},
@@ -306,8 +315,26 @@ pub fn main() {
     // TODO: Move this into some sort of timing manager, deal with real time deltas,
     // and separate these events from the monitor refresh rate.
     let mut new_frames = 0;
+    // The APU switches to interleaved stereo when audio.stereo is set, so the device has to follow
+    let audio_channels = runtime_state.nes.apu.output_channels();
+    if audio_channels != device.spec().channels as usize {
+      let channel_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(audio_channels as u8),
+        samples: Some(256)
+      };
+      device = audio_subsystem.open_queue::<i16, _>(None, &channel_spec).unwrap();
+      device.resume();
+    }
     //println!("device queue: {}, emulator queue: {}", device.size(), runtime_state.nes.apu.samples_queued());
-    while (device.size() as usize) + (runtime_state.nes.apu.samples_queued() * 2) < 4096 {
+    // Either keep the audio fed, or run one frame per vsync and adjust the audio to suit
+    let queued = queued_audio_frames(&device, &runtime_state);
+    runtime_state.rate_control.update(&mut runtime_state.nes.apu, queued);
+    let silence = runtime_state.rate_control.silence_needed(queued);
+    if silence > 0 {
+      _ = device.queue_audio(&vec!(0i16; silence * audio_channels));
+    }
+    while runtime_state.rate_control.wants_frame(queued_audio_frames(&device, &runtime_state), new_frames) {
       new_frames += 1;
       if runtime_state.running {
         // Play Audio (leave this loop when this buffer fills)
@@ -343,7 +370,7 @@ pub fn main() {
         }
       } else {
         // we have to queue up *something*, so let's target around 60 Hz ish of silence
-        let buffer = vec!(0i16; 44100 / 60);
+        let buffer = vec!(0i16; 44100 / 60 * audio_channels);
         _ = device.queue_audio(&buffer);
       }
 
@@ -397,6 +424,8 @@ pub fn main() {
 
   println!("Exiting application! Attempting SRAM save one last time.");
   application_events.push(events::Event::RequestSramSave(cartridge_state.sram_path.clone()));
+  // Does nothing unless a log is running
+  application_events.push(events::Event::RequestVgmSave(cartridge_state.vgm_path.clone()));
   while application_events.len() > 0 {
     let events_to_process = application_events.clone();
     application_events.clear();
@@ -4,12 +4,68 @@ use events::Event;
 use events::StandardControllerButton;
 
 use settings::SettingsState;
+use sync::RateControl;
+use sync::SyncMode;
+use sync::sync_mode_from_name;
 
 use rustico_core::nes::NesState;
 use rustico_core::cartridge::mapper_from_file;
+use rustico_core::cartridge::mapper_from_file_with_registry;
+use rustico_core::load_error::LoadError;
 
 use rustico_core::apu::AudioChannelState;
+use rustico_core::apu::ExpansionMix;
+use rustico_core::apu::expansion_device_from_key;
+use rustico_core::ines::INesCartridge;
+use rustico_core::mmc::registry::MapperRegistry;
+use rustico_core::game_db;
+use rustico_core::game_db::GameDatabase;
+use rustico_core::mmc::vrc7::vrc7_patch_set_from_name;
+use rustico_core::ines::INesHeader;
+use rustico_core::ines::CpuPpuTiming;
+use rustico_core::ines::EXPANSION_DEVICE_STANDARD_CONTROLLERS;
+use rustico_core::ines::EXPANSION_DEVICE_UNSPECIFIED;
+use rustico_core::ines::expansion_device_name;
+use rustico_core::ines::timing_name;
+use rustico_core::power_on::fill_policy_from_name;
+use rustico_core::vgm;
 
+fn cartridge_header(file_data: &[u8]) -> Option<INesHeader> {
+    let mut file_reader = file_data;
+    match INesCartridge::from_reader(&mut file_reader) {
+        Ok(mut ines) => {
+            // The same corrections the cartridge loader makes, which it reports itself
+            game_db::correct_header(&mut ines);
+            return Some(ines.header);
+        },
+        Err(_) => return None
+    }
+}
+
+// Only NTSC timing is emulated, but the shells pace their frames to the cartridge's region
+fn select_region(header: &INesHeader) -> CpuPpuTiming {
+    let timing = header.timing();
+    match timing {
+        CpuPpuTiming::Ntsc | CpuPpuTiming::MultipleRegion => return CpuPpuTiming::Ntsc,
+        _ => {
+            println!("Cartridge expects {} timing, which isn't emulated yet. Running NTSC timing at the {} frame rate.", timing_name(timing), timing_name(timing));
+            return timing;
+        }
+    }
+}
+
+// The shells only have standard controllers to offer, so any other device is kept
+// for display, and the user is told it's missing
+fn select_input_device(header: &INesHeader) -> u8 {
+    let device = header.default_expansion_device();
+    match device {
+        EXPANSION_DEVICE_UNSPECIFIED | EXPANSION_DEVICE_STANDARD_CONTROLLERS => return EXPANSION_DEVICE_STANDARD_CONTROLLERS,
+        _ => {
+            println!("Cartridge expects a {}, using standard controllers instead.", expansion_device_name(device));
+            return device;
+        }
+    }
+}
 
 
 pub struct RuntimeState {
@@ -21,6 +77,25 @@ pub struct RuntimeState {
     pub last_apu_quarter_frame_count: u32,
     pub last_apu_half_frame_count: u32,
     pub settings: SettingsState,
+    pub cartridge_id: String,
+    // Shells with boards of their own can register them here before loading a cartridge
+    pub mapper_registry: MapperRegistry,
+    pub cartridge_header: Option<INesHeader>,
+    pub cartridge_requests_epsm: bool,
+    // From the cartridge's header, NTSC and standard controllers when it has nothing to say
+    pub region: CpuPpuTiming,
+    pub input_device: u8,
+    // audio.expansion.console, and the values set for each chip. These are kept apart
+    // so the settings can arrive in any order.
+    pub expansion_console: String,
+    pub expansion_overrides: Vec<(String, f32)>,
+    // audio.sync; the shell runs it, since only the shell can see its audio queue
+    pub rate_control: RateControl,
+    // cartridge.header_database, kept so the file is only read again when it changes
+    pub header_database_path: String,
+    // Whatever rate the shell's audio device runs at. Kept here, since loading a cartridge
+    // starts the APU over at its default rate.
+    pub host_sample_rate: u64,
 }
 
 impl RuntimeState {
@@ -35,6 +110,17 @@ impl RuntimeState {
             last_apu_quarter_frame_count: 0,
             last_apu_half_frame_count: 0,
             settings: SettingsState::new(),
+            cartridge_id: String::new(),
+            mapper_registry: MapperRegistry::new(),
+            cartridge_header: None,
+            cartridge_requests_epsm: false,
+            region: CpuPpuTiming::Ntsc,
+            input_device: EXPANSION_DEVICE_STANDARD_CONTROLLERS,
+            expansion_console: "famicom".to_string(),
+            expansion_overrides: Vec::new(),
+            rate_control: RateControl::new(SyncMode::AudioLocked, 2048),
+            header_database_path: String::new(),
+            host_sample_rate: 44100,
         };
         state.nes.power_on();
         return state;
@@ -42,12 +128,38 @@ impl RuntimeState {
 
     pub fn load_cartridge(&mut self, cart_id: String, file_data: &[u8]) -> Vec<Event> {
         let mut responses: Vec<Event> = Vec::new();
-        let maybe_mapper = mapper_from_file(file_data);
+        self.cartridge_header = cartridge_header(file_data);
+        self.cartridge_requests_epsm = false;
+        self.region = CpuPpuTiming::Ntsc;
+        self.input_device = EXPANSION_DEVICE_STANDARD_CONTROLLERS;
+        match self.cartridge_header {
+            Some(header) => {
+                self.cartridge_requests_epsm = header.requests_epsm();
+                self.region = select_region(&header);
+                self.input_device = select_input_device(&header);
+            },
+            None => {}
+        }
+        let maybe_mapper = mapper_from_file_with_registry(file_data, &self.mapper_registry);
         match maybe_mapper {
             Ok(mapper) => {
 
-                self.nes = NesState::new(mapper);
+                if self.nes.event_tracker.vgm_log.is_some() {
+                    println!("A new cartridge was loaded while logging VGM, discarding the log");
+                }
+                // The power-on configuration and audio setup belong to the machine, not the cartridge
+                let previous_nes = std::mem::replace(&mut self.nes, NesState::new(mapper));
+                self.nes.power_on_config = previous_nes.power_on_config;
+                self.nes.apu.keep_host_settings(&previous_nes.apu);
+                // The EPSM is plugged in when the setting or the new cartridge asks for it
+                let epsm_setting = self.settings.get_boolean("audio.epsm".to_string()).unwrap_or(false);
+                if epsm_setting || self.cartridge_requests_epsm {
+                    self.nes.apu.enable_epsm();
+                } else {
+                    self.nes.apu.disable_epsm();
+                }
                 self.file_loaded = true;
+                self.cartridge_id = cart_id.clone();
                 responses.push(Event::CartridgeLoaded(cart_id));
                 if self.nes.mapper.needs_bios() {
                     responses.push(Event::RequestBios);
@@ -66,6 +178,11 @@ impl RuntimeState {
         }
     }
 
+    pub fn set_host_sample_rate(&mut self, sample_rate: u64) {
+        self.host_sample_rate = sample_rate;
+        self.nes.apu.set_sample_rate(sample_rate);
+    }
+
     pub fn load_sram(&mut self, file_data: &[u8]) {
         if self.nes.mapper.has_sram() {
             if file_data.len() > 0 {
@@ -74,13 +191,17 @@ impl RuntimeState {
         }
     }
 
-    pub fn load_bios(&mut self, file_data: &[u8]) {
+    pub fn load_bios(&mut self, file_data: &[u8]) -> Vec<Event> {
+        let mut responses: Vec<Event> = Vec::new();
         self.nes.mapper.load_bios(file_data.to_vec());
         // Set ourselves to running (but only if that succeeded)
         if !self.nes.mapper.needs_bios() {
             self.nes.power_on();
             self.running = true;
+        } else {
+            responses.push(Event::CartridgeRejected(self.cartridge_id.clone(), LoadError::BiosRequired));
         }
+        return responses;
     }
 
     pub fn button_press(&mut self, player_index: usize, button: StandardControllerButton) {
@@ -117,6 +238,101 @@ impl RuntimeState {
         *controllers[player_index] = new_controller_byte;
     }
 
+    // audio.channels.<chip>.<name>.pan and .gain, for the stereo mix
+    fn apply_channel_mix_setting(&mut self, path: &str, value: f64) {
+        let components = path.split(".").collect::<Vec<&str>>();
+        if components.len() != 5 || components[0] != "audio" || components[1] != "channels" {
+            return;
+        }
+        let chip = components[2];
+        let name = components[3];
+        match components[4] {
+            "pan" => {self.nes.apu.set_channel_pan(chip, name, value as f32)},
+            "gain" => {self.nes.apu.set_channel_gain(chip, name, value as f32)},
+            _ => {println!("Unrecognized channel setting: {}", path)}
+        }
+    }
+
+    // hq and lq filter at the CPU clock rate; blip skips that for band-limited steps,
+    // which is much cheaper
+    fn apply_synthesis_setting(&mut self, value: &str) {
+        let filter_hq = match value {
+            "hq" => true,
+            "lq" => false,
+            "blip" => {
+                self.nes.apu.enable_blip();
+                return;
+            },
+            _ => {
+                println!("Unrecognized audio synthesis: {}", value);
+                return;
+            }
+        };
+        self.nes.apu.disable_blip();
+        if filter_hq != self.nes.apu.filter_hq {
+            let filter_type = self.nes.apu.filter_type;
+            self.nes.apu.set_filter(filter_type, filter_hq);
+        }
+    }
+
+    // audio.expansion.console picks the output filter, and audio.expansion.<chip> (gain) and
+    // audio.expansion.fds_lowpass (Hz) adjust it
+    fn apply_expansion_setting(&mut self, path: &str, value: f64) {
+        let key = match path.strip_prefix("audio.expansion.") {
+            Some(key) => key,
+            None => return
+        };
+        if key != "fds_lowpass" && expansion_device_from_key(key).is_none() {
+            println!("Unrecognized expansion audio setting: {}", path);
+            return;
+        }
+        self.expansion_overrides.retain(|(existing_key, _)| existing_key != key);
+        self.expansion_overrides.push((key.to_string(), value as f32));
+        self.apply_expansion_mix();
+    }
+
+    // Rebuilds the mix from the current console and overrides, and hands it to the mapper.
+    // Needs to happen again whenever the mapper is replaced.
+    pub fn apply_expansion_mix(&mut self) {
+        let mut mix = match ExpansionMix::for_console(&self.expansion_console) {
+            Some(mix) => mix,
+            None => {
+                println!("Unrecognized expansion audio console: {}, using famicom", self.expansion_console);
+                ExpansionMix::new()
+            }
+        };
+        for (key, value) in self.expansion_overrides.iter() {
+            match expansion_device_from_key(key) {
+                Some(device) => {mix.gains[device as usize] = *value},
+                None => {mix.fds_lowpass = *value}
+            }
+        }
+        if mix.filter_type != self.nes.apu.filter_type {
+            let filter_hq = self.nes.apu.filter_hq;
+            self.nes.apu.set_filter(mix.filter_type, filter_hq);
+        }
+        self.nes.mapper.set_expansion_mix(&mix);
+    }
+
+    fn apply_header_database_setting(&mut self, path: &str) {
+        if path == self.header_database_path {
+            return;
+        }
+        self.header_database_path = path.to_string();
+        if path.is_empty() {
+            game_db::use_database(GameDatabase::embedded());
+            return;
+        }
+        match std::fs::read_to_string(path) {
+            Ok(xml) => {
+                let database = GameDatabase::from_xml(&xml);
+                println!("Loaded {} games from header database {}", database.entries.len(), path);
+                game_db::use_database(database);
+            },
+            Err(why) => {println!("Couldn't read header database {}: {}", path, why)}
+        }
+    }
+
     pub fn collect_timing_events(&mut self) -> Vec<Event> {
         let mut responses: Vec<Event> = Vec::new();
         if self.nes.ppu.current_frame != self.last_frame {
@@ -145,6 +361,75 @@ impl RuntimeState {
             Event::ApplyBooleanSetting(path, value) => {
                 match path.as_str() {
                     "audio.multiplexing" => {self.nes.mapper.audio_multiplexing(value)},
+                    "audio.vrc7.adsr_research" => {self.nes.mapper.vrc7_adsr_research(value)},
+                    "audio.vrc7.ym2413" => {self.nes.mapper.vrc7_ym2413_mode(value)},
+                    "audio.epsm" => {
+                        // The cartridge header can ask for the EPSM too, in which case
+                        // the setting can't turn it back off
+                        if value || self.cartridge_requests_epsm {
+                            self.nes.apu.enable_epsm();
+                        } else {
+                            self.nes.apu.disable_epsm();
+                        }
+                    },
+                    "audio.stereo" => {
+                        if value {
+                            self.nes.apu.enable_stereo();
+                        } else {
+                            self.nes.apu.disable_stereo();
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            Event::ApplyIntegerSetting(path, value) => {
+                match path.as_str() {
+                    "power_on.seed" => {self.nes.power_on_config.seed = value as u64},
+                    _ if path.starts_with("audio.expansion.") => {self.apply_expansion_setting(&path, value as f64)},
+                    _ => {self.apply_channel_mix_setting(&path, value as f64)}
+                }
+            },
+            Event::ApplyFloatSetting(path, value) => {
+                if path.starts_with("audio.expansion.") {
+                    self.apply_expansion_setting(&path, value);
+                } else {
+                    self.apply_channel_mix_setting(&path, value);
+                }
+            },
+            Event::ApplyStringSetting(path, value) => {
+                if path == "audio.expansion.console" {
+                    self.expansion_console = value.clone();
+                    self.apply_expansion_mix();
+                }
+                if path == "audio.synthesis" {
+                    self.apply_synthesis_setting(&value);
+                }
+                if path == "cartridge.header_database" {
+                    self.apply_header_database_setting(&value);
+                }
+                if path == "audio.vrc7.patch_set" {
+                    match vrc7_patch_set_from_name(&value) {
+                        Some(patch_set) => {self.nes.mapper.vrc7_patch_set(patch_set)},
+                        None => {println!("Unrecognized VRC7 patch set: {}", value)}
+                    }
+                }
+                if path == "audio.sync" {
+                    match sync_mode_from_name(&value) {
+                        Some(mode) => {self.rate_control.set_mode(mode)},
+                        None => {println!("Unrecognized audio sync mode: {}", value)}
+                    }
+                }
+                // Fill policies take effect the next time the console is powered on
+                let policy = match path.as_str() {
+                    "power_on.internal_ram" => Some(&mut self.nes.power_on_config.internal_ram),
+                    "power_on.cart_ram" => Some(&mut self.nes.power_on_config.cart_ram),
+                    "power_on.palette" => Some(&mut self.nes.power_on_config.palette),
+                    "power_on.oam" => Some(&mut self.nes.power_on_config.oam),
+                    _ => None
+                };
+                match (policy, fill_policy_from_name(&value)) {
+                    (Some(policy), Some(new_policy)) => {*policy = new_policy},
+                    (Some(_), None) => {println!("Unrecognized fill policy for {}: {}", path, value)},
                     _ => {}
                 }
             },
@@ -182,7 +467,7 @@ impl RuntimeState {
                 responses.extend(self.settings.apply_settings());
             },
             Event::LoadBios(bios_data) => {
-                self.load_bios(&bios_data);
+                responses.extend(self.load_bios(&bios_data));
             },
             Event::LoadSram(sram_data) => {
                 self.load_sram(&sram_data);
@@ -208,6 +493,9 @@ impl RuntimeState {
             Event::NesReset => {
                 self.nes.reset();
             },
+            Event::NesPowerCycle => {
+                self.nes.power_cycle();
+            },
             
             // These three events should ideally move to some sort of FrameTiming manager
             Event::NesPauseEmulation => {
@@ -230,6 +518,16 @@ impl RuntimeState {
                 }
             },
 
+            Event::StartVgmLog => {
+                vgm::start_logging(&mut self.nes);
+            },
+            Event::RequestVgmSave(vgm_path) => {
+                match vgm::stop_logging(&mut self.nes) {
+                    Some(vgm_file) => responses.push(Event::SaveVgm(vgm_path, Arc::new(vgm_file))),
+                    None => {}
+                }
+            },
+
             // Input is due for an overhaul. Ideally the IoBus should handle its own
             // events, rather than doing this here.
             Event::StandardControllerPress(controller_index, button) => {
@@ -255,4 +553,32 @@ pub fn fix_dpad(controller_byte: u8, last_button_pressed: StandardControllerButt
     }
 
     return fixed_byte;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loading_a_cartridge_keeps_the_host_sample_rate() {
+        let mut runtime = RuntimeState::new();
+        runtime.set_host_sample_rate(48000);
+        let responses = runtime.load_cartridge("no_cart".to_string(), include_bytes!("assets/rustico_no_cart.nes"));
+        assert!(responses.iter().any(|event| match *event {Event::CartridgeLoaded(_) => true, _ => false}));
+        assert_eq!(runtime.nes.apu.sample_rate, 48000);
+    }
+
+    #[test]
+    fn loading_a_cartridge_reads_its_region_and_input_device() {
+        // NES 2.0 NROM, PAL timing, expecting a Zapper
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x00, 0x08, 0, 0, 0, 0, 0x01, 0, 0, 0x08];
+        image.extend(vec![0u8; 0x4000 + 0x2000]);
+        let mut runtime = RuntimeState::new();
+        runtime.load_cartridge("pal".to_string(), &image);
+        assert!(runtime.region == CpuPpuTiming::Pal);
+        assert_eq!(runtime.input_device, 0x08);
+
+        runtime.load_cartridge("no_cart".to_string(), include_bytes!("assets/rustico_no_cart.nes"));
+        assert!(runtime.region == CpuPpuTiming::Ntsc);
+        assert_eq!(runtime.input_device, EXPANSION_DEVICE_STANDARD_CONTROLLERS);
+    }
+}
@@ -111,6 +111,8 @@ impl ApuWindow {
                 "A" => {Color::rgb(32, 144, 204)},
                 "B" => {Color::rgb(24, 104, 228)},
                 "C" => {Color::rgb(16, 64, 248)},
+                "Noise" => {Color::rgb(120, 150, 200)},
+                "Envelope" => {Color::rgb(48, 200, 224)},
                 _ => {/*unreachable*/ Color::rgb(192,  192, 192)}
             },
             "VRC6" => match channel.name().as_str() {
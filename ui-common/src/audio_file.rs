@@ -0,0 +1,591 @@
+// Writes the APU's 16-bit output to disk, mono or interleaved stereo. WAV and FLAC are written with headers that
+// carry the sample rate; anything else gets the old headerless big-endian dump.
+// Both formats record their length up front, so the header is patched once we're done.
+
+// FLAC reference: https://xiph.org/flac/format.html
+
+use std::fs::File;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+const FLAC_BLOCK_SIZE: usize = 4096;
+// Enough for a 4th order fixed predictor on 16-bit audio; 15 is the escape code
+const FLAC_MAX_RICE_PARAMETER: u32 = 14;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AudioFormat {
+    Raw,
+    Wav,
+    Flac,
+}
+
+impl AudioFormat {
+    pub fn from_path(path: &str) -> AudioFormat {
+        let lowercase_path = path.to_lowercase();
+        if lowercase_path.ends_with(".wav") {
+            return AudioFormat::Wav;
+        }
+        if lowercase_path.ends_with(".flac") {
+            return AudioFormat::Flac;
+        }
+        return AudioFormat::Raw;
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        return BitWriter {
+            bytes: Vec::new(),
+            accumulator: 0,
+            bit_count: 0,
+        };
+    }
+
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0 .. bits).rev() {
+            self.accumulator = (self.accumulator << 1) | ((value >> i) & 0x1);
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.bytes.push(self.accumulator as u8);
+                self.accumulator = 0;
+                self.bit_count = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write((value as u64) & ((1u64 << bits) - 1), bits);
+    }
+
+    fn write_unary(&mut self, zeroes: u64) {
+        for _ in 0 .. zeroes {
+            self.write(0, 1);
+        }
+        self.write(1, 1);
+    }
+
+    fn align(&mut self) {
+        while self.bit_count != 0 {
+            self.write(0, 1);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0 .. 8 {
+            crc = if (crc & 0x80) != 0 {(crc << 1) ^ 0x07} else {crc << 1};
+        }
+    }
+    return crc;
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0 .. 8 {
+            crc = if (crc & 0x8000) != 0 {(crc << 1) ^ 0x8005} else {crc << 1};
+        }
+    }
+    return crc;
+}
+
+// FLAC numbers its frames with the same variable length scheme as UTF-8
+fn write_utf8_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let mut continuation_bytes = 1;
+    while value >= (1u64 << (6 + 5 * continuation_bytes)) {
+        continuation_bytes += 1;
+    }
+    let leading_ones = (0xFF00u64 >> (continuation_bytes + 1)) & 0xFF;
+    writer.write(leading_ones | (value >> (6 * continuation_bytes)), 8);
+    for i in (0 .. continuation_bytes).rev() {
+        writer.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    return (order .. samples.len()).map(|i| {
+        let s = samples;
+        let prediction = match order {
+            0 => 0,
+            1 => s[i-1],
+            2 => 2*s[i-1] - s[i-2],
+            3 => 3*s[i-1] - 3*s[i-2] + s[i-3],
+            _ => 4*s[i-1] - 6*s[i-2] + 4*s[i-3] - s[i-4],
+        };
+        return s[i] - prediction;
+    }).collect();
+}
+
+fn zigzag(residual: i64) -> u64 {
+    return ((residual << 1) ^ (residual >> 63)) as u64;
+}
+
+// Returns (rice parameter, encoded size in bits) for a single partition
+fn best_rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let mut best = (0, u64::MAX);
+    for parameter in 0 ..= FLAC_MAX_RICE_PARAMETER {
+        let bits: u64 = residual.iter().map(|r| (zigzag(*r) >> parameter) + 1 + parameter as u64).sum();
+        if bits < best.1 {
+            best = (parameter, bits);
+        }
+    }
+    return best;
+}
+
+// Padding bit, subframe type, no wasted bits
+fn write_subframe_header(writer: &mut BitWriter, subframe_type: u64) {
+    writer.write(0, 1);
+    writer.write(subframe_type, 6);
+    writer.write(0, 1);
+}
+
+fn write_subframe(writer: &mut BitWriter, block: &[i64]) {
+    if block.iter().all(|sample| *sample == block[0]) {
+        write_subframe_header(writer, 0b000000);
+        writer.write_signed(block[0], 16);
+        return;
+    }
+
+    let mut best: Option<(usize, u32, u64, Vec<i64>)> = None;
+    for order in 0 ..= 4usize.min(block.len() - 1) {
+        let residual = fixed_residual(block, order);
+        let (parameter, bits) = best_rice_parameter(&residual);
+        let total_bits = bits + (order as u64) * 16;
+        let better = match best {
+            Some((_, _, best_bits, _)) => total_bits < best_bits,
+            None => true
+        };
+        if better {
+            best = Some((order, parameter, total_bits, residual));
+        }
+    }
+
+    match best {
+        Some((order, parameter, total_bits, ref residual)) if total_bits < (block.len() as u64) * 16 => {
+            write_subframe_header(writer, 0b001000 | order as u64);
+            for warmup in &block[0 .. order] {
+                writer.write_signed(*warmup, 16);
+            }
+            // Rice coding with 4-bit parameters, a single partition
+            writer.write(0b00, 2);
+            writer.write(0, 4);
+            writer.write(parameter as u64, 4);
+            for r in residual {
+                let value = zigzag(*r);
+                writer.write_unary(value >> parameter);
+                writer.write(value, parameter);
+            }
+        },
+        _ => {
+            write_subframe_header(writer, 0b000001);
+            for sample in block {
+                writer.write_signed(*sample, 16);
+            }
+        }
+    }
+}
+
+fn encode_flac_frame(frame_number: u64, block: &[i16], channels: usize) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write(0b1111_1111_1111_1000, 16);
+    let block_length = block.len() / channels;
+    let full_block = block_length == FLAC_BLOCK_SIZE;
+    // Block size 4096, or 16 bits at the end of the header. Sample rate from STREAMINFO.
+    writer.write(if full_block {0b1100} else {0b0111}, 4);
+    writer.write(0b0000, 4);
+    // Independent channels, 16 bits per sample
+    writer.write((channels - 1) as u64, 4);
+    writer.write(0b100, 3);
+    writer.write(0, 1);
+    write_utf8_number(&mut writer, frame_number);
+    if !full_block {
+        writer.write((block_length - 1) as u64, 16);
+    }
+    let header_crc = crc8(&writer.bytes);
+    writer.write(header_crc as u64, 8);
+
+    for channel in 0 .. channels {
+        let samples: Vec<i64> = block.iter().skip(channel).step_by(channels).map(|sample| *sample as i64).collect();
+        write_subframe(&mut writer, &samples);
+    }
+    writer.align();
+    let frame_crc = crc16(&writer.bytes);
+    writer.write(frame_crc as u64, 16);
+    return writer.bytes;
+}
+
+fn flac_header(sample_rate: u32, channels: u16, total_samples: u64) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write(u32::from_be_bytes(*b"fLaC") as u64, 32);
+    // STREAMINFO, the last (and only) metadata block
+    writer.write(1, 1);
+    writer.write(0, 7);
+    writer.write(34, 24);
+    writer.write(FLAC_BLOCK_SIZE as u64, 16);
+    writer.write(FLAC_BLOCK_SIZE as u64, 16);
+    // Minimum and maximum frame sizes, unknown
+    writer.write(0, 24);
+    writer.write(0, 24);
+    writer.write(sample_rate as u64, 20);
+    writer.write((channels - 1) as u64, 3);
+    writer.write(15, 5);
+    writer.write(total_samples, 36);
+    // No MD5 signature
+    writer.write(0, 64);
+    writer.write(0, 64);
+    return writer.bytes;
+}
+
+fn wav_header(sample_rate: u32, channels: u16, total_samples: u64) -> Vec<u8> {
+    let block_align = channels * 2;
+    let data_size = (total_samples * block_align as u64) as u32;
+    let mut header = Vec::new();
+    header.extend(b"RIFF");
+    header.extend(&(36 + data_size).to_le_bytes());
+    header.extend(b"WAVE");
+    header.extend(b"fmt ");
+    header.extend(&16u32.to_le_bytes());
+    // PCM
+    header.extend(&1u16.to_le_bytes());
+    header.extend(&channels.to_le_bytes());
+    header.extend(&sample_rate.to_le_bytes());
+    header.extend(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend(&block_align.to_le_bytes());
+    header.extend(&16u16.to_le_bytes());
+    header.extend(b"data");
+    header.extend(&data_size.to_le_bytes());
+    return header;
+}
+
+pub struct AudioFileWriter {
+    file: File,
+    pub format: AudioFormat,
+    pub sample_rate: u32,
+    pub channels: u16,
+    // Counted per channel, so a stereo pair is one sample
+    pub samples_written: u64,
+    pending_samples: Vec<i16>,
+    frames_written: u64,
+    finished: bool,
+}
+
+impl AudioFileWriter {
+    pub fn create(path: &str, sample_rate: u32, channels: u16) -> Result<AudioFileWriter, String> {
+        let format = AudioFormat::from_path(path);
+        let mut file = File::create(path).map_err(|why| format!("Couldn't open {}: {}", path, why))?;
+        // Placeholder headers, rewritten with the real length in finish()
+        let header = match format {
+            AudioFormat::Wav => wav_header(sample_rate, channels, 0),
+            AudioFormat::Flac => flac_header(sample_rate, channels, 0),
+            AudioFormat::Raw => Vec::new(),
+        };
+        file.write_all(&header).map_err(|why| format!("Couldn't write {}: {}", path, why))?;
+        return Ok(AudioFileWriter {
+            file: file,
+            format: format,
+            sample_rate: sample_rate,
+            channels: channels,
+            samples_written: 0,
+            pending_samples: Vec::new(),
+            frames_written: 0,
+            finished: false,
+        });
+    }
+
+    fn write_flac_frame(&mut self, block_length: usize) -> std::io::Result<()> {
+        let channels = self.channels as usize;
+        let block: Vec<i16> = self.pending_samples.drain(0 .. block_length * channels).collect();
+        let frame = encode_flac_frame(self.frames_written, &block, channels);
+        self.frames_written += 1;
+        return self.file.write_all(&frame);
+    }
+
+    // Stereo samples are interleaved, left first
+    pub fn write_samples(&mut self, samples: &[i16]) -> std::io::Result<()> {
+        self.samples_written += (samples.len() / self.channels as usize) as u64;
+        match self.format {
+            AudioFormat::Raw => {
+                let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_be_bytes().to_vec()).collect();
+                return self.file.write_all(&bytes);
+            },
+            AudioFormat::Wav => {
+                let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect();
+                return self.file.write_all(&bytes);
+            },
+            AudioFormat::Flac => {
+                self.pending_samples.extend(samples);
+                while self.pending_samples.len() >= FLAC_BLOCK_SIZE * self.channels as usize {
+                    self.write_flac_frame(FLAC_BLOCK_SIZE)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    // Flushes anything buffered and writes the final length into the header
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let header = match self.format {
+            AudioFormat::Raw => {return Ok(());},
+            AudioFormat::Wav => wav_header(self.sample_rate, self.channels, self.samples_written),
+            AudioFormat::Flac => {
+                let remaining = self.pending_samples.len() / self.channels as usize;
+                if remaining > 0 {
+                    self.write_flac_frame(remaining)?;
+                }
+                flac_header(self.sample_rate, self.channels, self.samples_written)
+            }
+        };
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        return self.file.flush();
+    }
+}
+
+impl Drop for AudioFileWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, bits: u32) -> u64 {
+            let mut value = 0;
+            for _ in 0 .. bits {
+                let bit = (self.bytes[self.position / 8] >> (7 - self.position % 8)) & 0x1;
+                value = (value << 1) | bit as u64;
+                self.position += 1;
+            }
+            return value;
+        }
+
+        fn read_signed(&mut self, bits: u32) -> i64 {
+            let value = self.read(bits) as i64;
+            return (value << (64 - bits)) >> (64 - bits);
+        }
+
+        fn read_unary(&mut self) -> u64 {
+            let mut zeroes = 0;
+            while self.read(1) == 0 {
+                zeroes += 1;
+            }
+            return zeroes;
+        }
+
+        fn byte_position(&self) -> usize {
+            assert_eq!(self.position % 8, 0);
+            return self.position / 8;
+        }
+
+        fn align(&mut self) {
+            self.position = (self.position + 7) & !7;
+        }
+    }
+
+    fn read_utf8_number(reader: &mut BitReader) -> u64 {
+        let first = reader.read(8);
+        let continuation_bytes = (first as u8).leading_ones().saturating_sub(1);
+        let mut value = first & (0x7F >> continuation_bytes);
+        for _ in 0 .. continuation_bytes {
+            value = (value << 6) | (reader.read(8) & 0x3F);
+        }
+        return value;
+    }
+
+    fn read_subframe(reader: &mut BitReader, block_length: usize) -> Vec<i64> {
+        assert_eq!(reader.read(1), 0);
+        let subframe_type = reader.read(6);
+        assert_eq!(reader.read(1), 0, "no wasted bits");
+        if subframe_type == 0b000000 {
+            return vec![reader.read_signed(16); block_length];
+        }
+        if subframe_type == 0b000001 {
+            return (0 .. block_length).map(|_| reader.read_signed(16)).collect();
+        }
+        assert_eq!(subframe_type & 0b111000, 0b001000, "only fixed predictors are written");
+        let order = (subframe_type & 0b000111) as usize;
+        let mut samples: Vec<i64> = (0 .. order).map(|_| reader.read_signed(16)).collect();
+        assert_eq!(reader.read(2), 0b00);
+        assert_eq!(reader.read(4), 0, "a single partition");
+        let parameter = reader.read(4) as u32;
+        for i in order .. block_length {
+            let value = (reader.read_unary() << parameter) | reader.read(parameter);
+            let residual = ((value >> 1) as i64) ^ -((value & 0x1) as i64);
+            let s = &samples;
+            let prediction = match order {
+                0 => 0,
+                1 => s[i-1],
+                2 => 2*s[i-1] - s[i-2],
+                3 => 3*s[i-1] - 3*s[i-2] + s[i-3],
+                _ => 4*s[i-1] - 6*s[i-2] + 4*s[i-3] - s[i-4],
+            };
+            samples.push(prediction + residual);
+        }
+        return samples;
+    }
+
+    // Returns the interleaved samples and the length of each block, checking both CRCs of every frame
+    fn decode_flac_frames(bytes: &[u8], channels: usize) -> (Vec<i16>, Vec<usize>) {
+        let mut reader = BitReader {bytes: bytes, position: 0};
+        let mut samples = Vec::new();
+        let mut block_lengths = Vec::new();
+        while reader.position / 8 < bytes.len() {
+            let frame_start = reader.byte_position();
+            assert_eq!(reader.read(16), 0b1111_1111_1111_1000);
+            let block_size_code = reader.read(4);
+            assert_eq!(reader.read(4), 0b0000);
+            assert_eq!(reader.read(4) as usize, channels - 1);
+            assert_eq!(reader.read(3), 0b100);
+            assert_eq!(reader.read(1), 0);
+            assert_eq!(read_utf8_number(&mut reader), block_lengths.len() as u64);
+            let block_length = match block_size_code {
+                0b1100 => FLAC_BLOCK_SIZE,
+                0b0111 => reader.read(16) as usize + 1,
+                _ => panic!("Unexpected block size code {:04b}", block_size_code),
+            };
+            let header_end = reader.byte_position();
+            assert_eq!(reader.read(8) as u8, crc8(&bytes[frame_start .. header_end]));
+
+            let decoded: Vec<Vec<i64>> = (0 .. channels).map(|_| read_subframe(&mut reader, block_length)).collect();
+            reader.align();
+            let frame_end = reader.byte_position();
+            assert_eq!(reader.read(16) as u16, crc16(&bytes[frame_start .. frame_end]));
+
+            for i in 0 .. block_length {
+                for channel in &decoded {
+                    samples.push(channel[i] as i16);
+                }
+            }
+            block_lengths.push(block_length);
+        }
+        return (samples, block_lengths);
+    }
+
+    // Two full blocks and a partial one: a smooth left channel over noise, then a silent left channel over
+    // full scale noise that can only be stored verbatim
+    fn test_signal() -> Vec<i16> {
+        let mut seed = 0x1234_5678u32;
+        let mut samples = Vec::new();
+        for i in 0 .. FLAC_BLOCK_SIZE * 2 + 1000 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let noise = (seed >> 16) as i16;
+            let (left, right) = match i / FLAC_BLOCK_SIZE {
+                0 => ((((i as f64) * 0.01).sin() * 20000.0) as i16, noise / 64),
+                1 => (1234, noise),
+                _ => ((i as i16).wrapping_mul(37), i16::MIN + (i % 3) as i16),
+            };
+            samples.push(left);
+            samples.push(right);
+        }
+        return samples;
+    }
+
+    fn temp_path(extension: &str) -> String {
+        let path = std::env::temp_dir().join(format!("audio_file_test_{}.{}", std::process::id(), extension));
+        return path.to_str().unwrap().to_string();
+    }
+
+    fn write_in_chunks(path: &str, samples: &[i16]) -> Vec<u8> {
+        let mut writer = AudioFileWriter::create(path, 44100, 2).unwrap();
+        // Uneven chunks, so blocks are assembled across several writes
+        for chunk in samples.chunks(777 * 2) {
+            writer.write_samples(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        return bytes;
+    }
+
+    #[test]
+    fn crcs_match_the_flac_check_values() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn flac_output_round_trips_stereo_with_a_partial_last_block() {
+        let samples = test_signal();
+        let bytes = write_in_chunks(&temp_path("flac"), &samples);
+
+        assert_eq!(&bytes[0 .. 4], b"fLaC");
+        let mut reader = BitReader {bytes: &bytes[4 .. 42], position: 0};
+        assert_eq!(reader.read(1), 1, "STREAMINFO is the last metadata block");
+        assert_eq!(reader.read(7), 0);
+        assert_eq!(reader.read(24), 34);
+        assert_eq!(reader.read(16) as usize, FLAC_BLOCK_SIZE);
+        assert_eq!(reader.read(16) as usize, FLAC_BLOCK_SIZE);
+        reader.read(48);
+        assert_eq!(reader.read(20), 44100);
+        assert_eq!(reader.read(3), 1);
+        assert_eq!(reader.read(5), 15);
+        assert_eq!(reader.read(36) as usize, samples.len() / 2);
+
+        let (decoded, block_lengths) = decode_flac_frames(&bytes[42 ..], 2);
+        assert_eq!(block_lengths, vec![FLAC_BLOCK_SIZE, FLAC_BLOCK_SIZE, 1000]);
+        assert!(decoded == samples, "decoded samples differ from the input");
+    }
+
+    #[test]
+    fn short_flac_frames_round_trip() {
+        // A single sample leaves no room for a predictor, and two frame numbers need multi-byte encodings
+        for &(frame_number, ref block) in &[(0u64, vec![5i16, -5]), (200, vec![1, 2, 3, 4, 5, 6]), (70000, vec![-32768, 32767])] {
+            let frame = encode_flac_frame(frame_number, block, 2);
+            let mut reader = BitReader {bytes: &frame, position: 32};
+            assert_eq!(read_utf8_number(&mut reader), frame_number);
+            let (decoded, block_lengths) = decode_flac_frames(&encode_flac_frame(0, block, 2), 2);
+            assert_eq!(block_lengths, vec![block.len() / 2]);
+            assert_eq!(&decoded, block);
+        }
+    }
+
+    #[test]
+    fn wav_output_round_trips_stereo() {
+        let samples = test_signal();
+        let bytes = write_in_chunks(&temp_path("wav"), &samples);
+        let data_size = samples.len() * 2;
+
+        assert_eq!(bytes.len(), 44 + data_size);
+        assert_eq!(&bytes[0 .. 4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize, 36 + data_size);
+        assert_eq!(&bytes[8 .. 16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), 2);
+        assert_eq!(u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]), 44100);
+        assert_eq!(u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]), 44100 * 4);
+        assert_eq!(u16::from_le_bytes([bytes[32], bytes[33]]), 4);
+        assert_eq!(&bytes[36 .. 40], b"data");
+        assert_eq!(u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]) as usize, data_size);
+
+        let decoded: Vec<i16> = bytes[44 ..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert!(decoded == samples, "decoded samples differ from the input");
+    }
+}
@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use rustico_core::load_error::LoadError;
+
 #[derive(Clone, Debug)]
 pub enum StandardControllerButton {
     A,
@@ -21,7 +23,7 @@ pub enum Event {
     CloseApplication,
     CloseWindow,
     CartridgeLoaded(String),
-    CartridgeRejected(String, String),
+    CartridgeRejected(String, LoadError),
     ChangeDisk(usize, usize),
     GameToggleOverscan,
     GameIncreaseScale,
@@ -29,7 +31,6 @@ pub enum Event {
     LoadCartridge(String, Arc<Vec<u8>>,Arc<Vec<u8>>),
     LoadSram(Arc<Vec<u8>>),
     LoadBios(Arc<Vec<u8>>),
-    LoadFailed(String),
     MouseMove(i32, i32),
     MouseClick(i32, i32),
     MouseRelease,
@@ -47,6 +48,7 @@ pub enum Event {
     NesRenderNTSC(usize),
     NesResumeEmulation,
     NesReset,
+    NesPowerCycle,
     NesRunCycle,
     NesRunFrame,
     NesRunOpcode,
@@ -55,8 +57,10 @@ pub enum Event {
     RequestFrame,
     RequestCartridgeDialog,
     RequestSramSave(String),
+    RequestVgmSave(String),
     RequestBios,
     SaveSram(String, Arc<Vec<u8>>),
+    SaveVgm(String, Arc<Vec<u8>>),
     ShowApuWindow,
     ShowCpuWindow,
     ShowGameWindow,
@@ -67,6 +71,7 @@ pub enum Event {
     ShowTestWindow,
     StandardControllerPress(usize, StandardControllerButton),
     StandardControllerRelease(usize, StandardControllerButton),
+    StartVgmLog,
     StoreBooleanSetting(String, bool),
     StoreFloatSetting(String, f64),
     StoreIntegerSetting(String, i64),
@@ -5,7 +5,9 @@ extern crate rustico_core;
 extern crate toml;
 
 pub mod application;
+pub mod audio_file;
 pub mod events;
+pub mod midi_file;
 pub mod panel;
 pub mod drawing;
 
@@ -19,4 +21,5 @@ pub mod memory_window;
 pub mod test_window;
 pub mod piano_roll_window;
 pub mod ppu_window;
-pub mod settings;
\ No newline at end of file
+pub mod settings;
+pub mod sync;
\ No newline at end of file
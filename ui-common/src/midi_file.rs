@@ -0,0 +1,306 @@
+// Turns what each audio channel is doing, sampled once per frame, into a Standard MIDI File
+// for transcription in a DAW. Every channel gets its own track, named after the chip and
+// channel, so arrangements can be pulled apart and reassigned freely.
+
+// Pitched channels become notes at the nearest semitone, with velocity from the channel's
+// volume and a program change whenever the duty cycle or patch changes. Program numbers are
+// the raw timbre index, not anything from General MIDI; they're only there to mark where the
+// timbre changes. Noise channels go to the percussion channel, and sample playback (the DMC)
+// is a fixed note which marks where samples play.
+
+// SMF reference: https://www.midi.org/specifications/file-format-specifications/standard-midi-files
+
+use rustico_core::apu::AudioChannelState;
+use rustico_core::apu::PlaybackRate;
+use rustico_core::apu::Timbre;
+use rustico_core::apu::Volume;
+
+const TICKS_PER_QUARTER_NOTE: u64 = 480;
+// 120 BPM, so a tick is a little over a millisecond
+const MICROSECONDS_PER_QUARTER_NOTE: u64 = 500_000;
+const CPU_CLOCK_RATE: u64 = 1_789_773;
+const MASTER_CLOCKS_PER_CPU_CYCLE: u64 = 12;
+
+const PERCUSSION_CHANNEL: u8 = 9;
+// Kick drum up to high tom, which covers the usual spread of noise channel pitches
+const LOWEST_PERCUSSION_KEY: u8 = 35;
+const PERCUSSION_KEY_RANGE: usize = 16;
+const SAMPLE_KEY: u8 = 60;
+const DEFAULT_VELOCITY: u8 = 100;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const EXPRESSION_CONTROLLER: u8 = 11;
+
+// The note a channel is playing right now, in MIDI terms
+#[derive(Copy, Clone, PartialEq)]
+struct ChannelNote {
+    key: u8,
+    volume: f32,
+    program: Option<u8>,
+}
+
+struct MidiTrack {
+    chip: String,
+    name: String,
+    midi_channel: u8,
+    // (tick, message) in the order they happened
+    events: Vec<(u64, Vec<u8>)>,
+    current_note: Option<ChannelNote>,
+    current_program: Option<u8>,
+    // The velocity the current note started with, which expression is measured against
+    note_velocity: u8,
+}
+
+fn midi_key(frequency: f32) -> Option<u8> {
+    if frequency <= 0.0 {
+        return None;
+    }
+    let key = (69.0 + 12.0 * (frequency / 440.0).log2()).round();
+    if !(0.0 ..= 127.0).contains(&key) {
+        return None;
+    }
+    return Some(key as u8);
+}
+
+fn volume_level(channel: &dyn AudioChannelState) -> f32 {
+    match channel.volume() {
+        Some(Volume::VolumeIndex{index, max}) => {
+            if max == 0 {
+                return 1.0;
+            }
+            return index as f32 / max as f32;
+        },
+        None => {return DEFAULT_VELOCITY as f32 / 127.0}
+    }
+}
+
+fn level_to_velocity(level: f32) -> u8 {
+    return ((level * 127.0).round() as u8).clamp(1, 127);
+}
+
+fn channel_note(channel: &dyn AudioChannelState) -> Option<ChannelNote> {
+    if !channel.playing() || channel.muted() {
+        return None;
+    }
+    let volume = volume_level(channel);
+    if volume <= 0.0 {
+        return None;
+    }
+    let key = match channel.rate() {
+        PlaybackRate::FundamentalFrequency{frequency} => midi_key(frequency)?,
+        PlaybackRate::LfsrRate{index, max} => {
+            // Shorter periods sound higher, so they get the higher drums
+            let position = (max - index.min(max)) * PERCUSSION_KEY_RANGE / (max + 1);
+            LOWEST_PERCUSSION_KEY + position as u8
+        },
+        PlaybackRate::SampleRate{frequency: _} => SAMPLE_KEY
+    };
+    let program = match channel.timbre() {
+        Some(Timbre::DutyIndex{index, max: _}) => Some(index.min(127) as u8),
+        Some(Timbre::PatchIndex{index, max: _}) => Some(index.min(127) as u8),
+        // The noise mode only picks a different drum sound, which the key already covers
+        Some(Timbre::LsfrMode{index: _, max: _}) => None,
+        // Envelope phases change within a note, which isn't what a program is for
+        Some(Timbre::EnvelopePhase{index: _, max: _}) => None,
+        None => None
+    };
+    return Some(ChannelNote{key: key, volume: volume, program: program});
+}
+
+fn write_variable_length(buffer: &mut Vec<u8>, value: u64) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut remaining = value >> 7;
+    while remaining > 0 {
+        groups.push(0x80 | (remaining & 0x7F) as u8);
+        remaining >>= 7;
+    }
+    groups.reverse();
+    buffer.extend(groups);
+}
+
+fn track_chunk(events: &[(u64, Vec<u8>)], end_tick: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut last_tick = 0;
+    for (tick, message) in events {
+        write_variable_length(&mut data, tick - last_tick);
+        data.extend(message);
+        last_tick = *tick;
+    }
+    // End of track
+    write_variable_length(&mut data, end_tick - last_tick);
+    data.extend(&[0xFF, 0x2F, 0x00]);
+
+    let mut chunk = Vec::new();
+    chunk.extend(b"MTrk");
+    chunk.extend(&(data.len() as u32).to_be_bytes());
+    chunk.extend(data);
+    return chunk;
+}
+
+fn meta_text(meta_type: u8, text: &str) -> Vec<u8> {
+    let mut message = vec![0xFF, meta_type];
+    write_variable_length(&mut message, text.len() as u64);
+    message.extend(text.as_bytes());
+    return message;
+}
+
+impl MidiTrack {
+    fn new(chip: &str, name: &str, midi_channel: u8) -> MidiTrack {
+        let track_name = format!("{} {}", chip, name);
+        return MidiTrack {
+            chip: chip.to_string(),
+            name: name.to_string(),
+            midi_channel: midi_channel,
+            events: vec![(0, meta_text(0x03, &track_name))],
+            current_note: None,
+            current_program: None,
+            note_velocity: 0,
+        };
+    }
+
+    fn note_off(&mut self, tick: u64) {
+        match self.current_note.take() {
+            Some(note) => self.events.push((tick, vec![NOTE_OFF | self.midi_channel, note.key, 0])),
+            None => {}
+        }
+    }
+
+    fn change_program(&mut self, tick: u64, program: Option<u8>) {
+        if program == self.current_program || self.midi_channel == PERCUSSION_CHANNEL {
+            return;
+        }
+        match program {
+            Some(program_number) => {
+                self.events.push((tick, vec![PROGRAM_CHANGE | self.midi_channel, program_number]));
+                self.current_program = program;
+            },
+            None => {}
+        }
+    }
+
+    fn note_on(&mut self, tick: u64, note: ChannelNote) {
+        self.change_program(tick, note.program);
+        // Volume changes within the note are expression, so put that back to full first
+        self.events.push((tick, vec![CONTROL_CHANGE | self.midi_channel, EXPRESSION_CONTROLLER, 127]));
+        self.note_velocity = level_to_velocity(note.volume);
+        self.events.push((tick, vec![NOTE_ON | self.midi_channel, note.key, self.note_velocity]));
+        self.current_note = Some(note);
+    }
+
+    fn update(&mut self, tick: u64, new_note: Option<ChannelNote>) {
+        match (self.current_note, new_note) {
+            (None, None) => {},
+            (Some(_), None) => self.note_off(tick),
+            (None, Some(note)) => self.note_on(tick, note),
+            (Some(old_note), Some(note)) => {
+                // NES envelopes mostly decay, so getting louder means the note was struck again
+                if note.key != old_note.key || note.volume > old_note.volume {
+                    self.note_off(tick);
+                    self.note_on(tick, note);
+                    return;
+                }
+                // Duty and patch changes within a note are usually an effect, so they don't retrigger
+                self.change_program(tick, note.program);
+                if note.volume != old_note.volume {
+                    let expression = (note.volume * 127.0 * 127.0 / self.note_velocity as f32).round().min(127.0);
+                    self.events.push((tick, vec![CONTROL_CHANGE | self.midi_channel, EXPRESSION_CONTROLLER, expression as u8]));
+                }
+                self.current_note = Some(note);
+            }
+        }
+    }
+}
+
+pub struct MidiRecorder {
+    tracks: Vec<MidiTrack>,
+    elapsed_master_clocks: u64,
+    last_master_clock: u64,
+}
+
+impl MidiRecorder {
+    pub fn new(master_clock: u64) -> MidiRecorder {
+        return MidiRecorder {
+            tracks: Vec::new(),
+            elapsed_master_clocks: 0,
+            last_master_clock: master_clock,
+        };
+    }
+
+    fn current_tick(&mut self, master_clock: u64) -> u64 {
+        if master_clock >= self.last_master_clock {
+            self.elapsed_master_clocks += master_clock - self.last_master_clock;
+        } else {
+            // The console was power cycled, which resets the clock
+            self.elapsed_master_clocks += master_clock;
+        }
+        self.last_master_clock = master_clock;
+        let microseconds = (self.elapsed_master_clocks / MASTER_CLOCKS_PER_CPU_CYCLE) * 1_000_000 / CPU_CLOCK_RATE;
+        return microseconds * TICKS_PER_QUARTER_NOTE / MICROSECONDS_PER_QUARTER_NOTE;
+    }
+
+    fn next_midi_channel(&self, channel: &dyn AudioChannelState) -> u8 {
+        match channel.rate() {
+            PlaybackRate::LfsrRate{index: _, max: _} => {return PERCUSSION_CHANNEL},
+            _ => {}
+        }
+        // There are only 15 melodic channels to go around. Past that they're shared, which
+        // the separate tracks keep apart.
+        let melodic_tracks = self.tracks.iter().filter(|track| track.midi_channel != PERCUSSION_CHANNEL).count() as u8;
+        let midi_channel = melodic_tracks % 15;
+        if midi_channel >= PERCUSSION_CHANNEL {
+            return midi_channel + 1;
+        }
+        return midi_channel;
+    }
+
+    // Call once per frame with every channel, as the piano roll collects them
+    pub fn record_frame(&mut self, master_clock: u64, channels: &[&dyn AudioChannelState]) {
+        let tick = self.current_tick(master_clock);
+        for channel in channels {
+            let chip = channel.chip();
+            let name = channel.name();
+            let existing_track = self.tracks.iter().position(|track| track.chip == chip && track.name == name);
+            let track_index = match existing_track {
+                Some(index) => index,
+                None => {
+                    let midi_channel = self.next_midi_channel(*channel);
+                    self.tracks.push(MidiTrack::new(&chip, &name, midi_channel));
+                    self.tracks.len() - 1
+                }
+            };
+            self.tracks[track_index].update(tick, channel_note(*channel));
+        }
+    }
+
+    // Ends any notes still playing and produces the finished file
+    pub fn to_bytes(&mut self, master_clock: u64) -> Vec<u8> {
+        let end_tick = self.current_tick(master_clock);
+        for track in self.tracks.iter_mut() {
+            track.note_off(end_tick);
+        }
+
+        let mut file = Vec::new();
+        file.extend(b"MThd");
+        file.extend(&6u32.to_be_bytes());
+        // Format 1: a tempo track, then one track per channel
+        file.extend(&1u16.to_be_bytes());
+        file.extend(&((self.tracks.len() + 1) as u16).to_be_bytes());
+        file.extend(&(TICKS_PER_QUARTER_NOTE as u16).to_be_bytes());
+
+        let tempo = MICROSECONDS_PER_QUARTER_NOTE.to_be_bytes();
+        let tempo_events = vec![
+            (0, meta_text(0x03, "Rustico")),
+            (0, vec![0xFF, 0x51, 0x03, tempo[5], tempo[6], tempo[7]]),
+            // 4/4, with the usual 24 clocks per metronome tick and 8 32nds per quarter
+            (0, vec![0xFF, 0x58, 0x04, 4, 2, 24, 8]),
+        ];
+        file.extend(track_chunk(&tempo_events, end_tick));
+        for track in &self.tracks {
+            file.extend(track_chunk(&track.events, end_tick));
+        }
+        return file;
+    }
+}
@@ -247,6 +247,18 @@ fn midi_index(note_name: &str) -> Result<u32, String> {
      }
 }
 
+// Chips reporting envelope phases as timbre are colored by phase, whatever their usual colors:
+// damp, attack, decay, sustain, then release
+fn envelope_phase_color(index: usize) -> Color {
+    match index {
+        0 => Color::rgb(0x90, 0x90, 0x90),
+        1 => Color::rgb(0xFF, 0x60, 0x40),
+        2 => Color::rgb(0xFF, 0xC0, 0x30),
+        3 => Color::rgb(0x50, 0xD0, 0x60),
+        _ => Color::rgb(0x50, 0x90, 0xFF),
+    }
+}
+
 pub fn default_channel_settings() -> HashMap<String, HashMap<String, ChannelSettings>> {
     let mut channel_settings: HashMap<String, HashMap<String, ChannelSettings>> = HashMap::new();
 
@@ -349,6 +361,15 @@ pub fn default_channel_settings() -> HashMap<String, HashMap<String, ChannelSett
         hidden: false,
         colors: vec!(Color::rgb(16, 64, 248))
     });
+    s5b_settings.insert("Noise".to_string(), ChannelSettings{
+        hidden: false,
+        colors: vec!(Color::rgb(120, 150, 200))
+    });
+    // Shaded by envelope shape
+    s5b_settings.insert("Envelope".to_string(), ChannelSettings{
+        hidden: false,
+        colors: vec!(Color::rgb(48, 200, 224), Color::rgb(160, 96, 240))
+    });
 
     let mut n163_settings: HashMap<String, ChannelSettings> = HashMap::new();
     // TODO: Fix these. Even for defaults they're too dark and ugly.
@@ -391,6 +412,46 @@ pub fn default_channel_settings() -> HashMap<String, HashMap<String, ChannelSett
     vrc7_settings.insert("FM 5".to_string(), ChannelSettings{ hidden: false, colors: patch_colors.clone()});
     vrc7_settings.insert("FM 6".to_string(), ChannelSettings{ hidden: false, colors: patch_colors.clone()});
 
+    // The same FM core, run as a full YM2413
+    let mut ym2413_settings: HashMap<String, ChannelSettings> = HashMap::new();
+    for channel_index in 1 ..= 9 {
+        ym2413_settings.insert(format!("FM {}", channel_index), ChannelSettings{ hidden: false, colors: patch_colors.clone()});
+    }
+
+    let mut epsm_settings: HashMap<String, ChannelSettings> = HashMap::new();
+    let algorithm_colors = vec!(
+        Color::rgb(0xA0, 0xE0, 0xFF), // 1 -> 2 -> 3 -> 4
+        Color::rgb(0x70, 0xF0, 0xE0), // (1 + 2) -> 3 -> 4
+        Color::rgb(0x6C, 0xEB, 0x9E), // (1 + (2 -> 3)) -> 4
+        Color::rgb(0x8C, 0xEB, 0x76), // ((1 -> 2) + 3) -> 4
+        Color::rgb(0xFA, 0xE0, 0x58), // (1 -> 2) + (3 -> 4)
+        Color::rgb(0xFA, 0xA8, 0x4B), // 1 -> (2 + 3 + 4)
+        Color::rgb(0xF5, 0x6E, 0x4A), // (1 -> 2) + 3 + 4
+        Color::rgb(0xFF, 0x8C, 0xC6), // 1 + 2 + 3 + 4
+    );
+    epsm_settings.insert("FM 1".to_string(), ChannelSettings{ hidden: false, colors: algorithm_colors.clone()});
+    epsm_settings.insert("FM 2".to_string(), ChannelSettings{ hidden: false, colors: algorithm_colors.clone()});
+    epsm_settings.insert("FM 3".to_string(), ChannelSettings{ hidden: false, colors: algorithm_colors.clone()});
+    epsm_settings.insert("FM 4".to_string(), ChannelSettings{ hidden: false, colors: algorithm_colors.clone()});
+    epsm_settings.insert("FM 5".to_string(), ChannelSettings{ hidden: false, colors: algorithm_colors.clone()});
+    epsm_settings.insert("FM 6".to_string(), ChannelSettings{ hidden: false, colors: algorithm_colors.clone()});
+    epsm_settings.insert("SSG A".to_string(), ChannelSettings{
+        hidden: false,
+        colors: vec!(Color::rgb(32, 204, 176))
+    });
+    epsm_settings.insert("SSG B".to_string(), ChannelSettings{
+        hidden: false,
+        colors: vec!(Color::rgb(24, 180, 140))
+    });
+    epsm_settings.insert("SSG C".to_string(), ChannelSettings{
+        hidden: false,
+        colors: vec!(Color::rgb(16, 156, 104))
+    });
+    epsm_settings.insert("Rhythm".to_string(), ChannelSettings{
+        hidden: false,
+        colors: vec!(Color::rgb(220, 220, 160))
+    });
+
     let mut fds_settings: HashMap<String, ChannelSettings> = HashMap::new();
     fds_settings.insert("Wavetable".to_string(), ChannelSettings{
         hidden: false,
@@ -411,6 +472,8 @@ pub fn default_channel_settings() -> HashMap<String, HashMap<String, ChannelSett
     channel_settings.insert("YM2149F".to_string(), s5b_settings);
     channel_settings.insert("N163".to_string(), n163_settings);
     channel_settings.insert("VRC7".to_string(), vrc7_settings);
+    channel_settings.insert("YM2413".to_string(), ym2413_settings);
+    channel_settings.insert("EPSM".to_string(), epsm_settings);
     channel_settings.insert("FDS".to_string(), fds_settings);
     channel_settings.insert("APU".to_string(), final_mix_settings);
 
@@ -858,6 +921,9 @@ impl PianoRollWindow {
                 let weight = index as f32 / (max + 1) as f32;
                 color = drawing::apply_gradient(colors, weight);  
             }
+            Some(Timbre::EnvelopePhase{index, max: _}) => {
+                color = envelope_phase_color(index);
+            },
             None => {},
         }
         return color;
@@ -909,6 +975,9 @@ impl PianoRollWindow {
                 let weight = index as f32 / (max + 1) as f32;
                 color = drawing::apply_gradient(colors, weight);  
             }
+            Some(Timbre::EnvelopePhase{index, max: _}) => {
+                color = envelope_phase_color(index);
+            },
             None => {},
         }
 
@@ -1149,14 +1218,16 @@ impl PianoRollWindow {
     }
 
     pub fn find_edge(edge_buffer: &RingBuffer, window_size: usize) -> usize {
-        let start_index = (edge_buffer.index() - window_size) % edge_buffer.buffer().len();
+        // Adding the length first keeps these from going below zero near the start of the buffer
+        let length = edge_buffer.buffer().len();
+        let start_index = (edge_buffer.index() + length - window_size) % length;
         let mut current_index = start_index;
         for _i in 0 .. (window_size * 4) {
             if edge_buffer.buffer()[current_index] != 0 {
                 // center the window on this sample
-                return (current_index - (window_size / 2)) % edge_buffer.buffer().len();
+                return (current_index + length - (window_size / 2)) % length;
             }
-            current_index = (current_index - 1) % edge_buffer.buffer().len();
+            current_index = (current_index + length - 1) % length;
         }
         // couldn't find an edge, so return the most recent slice
         return start_index;
@@ -1237,15 +1308,16 @@ impl PianoRollWindow {
         let speed = 4;
         let first_sample_index = PianoRollWindow::find_edge(channel.edge_buffer(), (width * speed) as usize);
         let sample_min = channel.min_sample();
-        let sample_max = channel.max_sample() + 1; // ???
-        let range = (sample_max as u32) - (sample_min as u32);
+        // In i32, since the range of a signed channel doesn't fit in its own sample type
+        let sample_max = channel.max_sample() as i32 + 1; // ???
+        let range = sample_max - (sample_min as i32);
         let sample_buffer = channel.sample_buffer().buffer();
-        let mut last_y = ((sample_buffer[first_sample_index] - sample_min) as f32 * height as f32) / range as f32;
+        let mut last_y = ((sample_buffer[first_sample_index] as i32 - sample_min as i32) as f32 * height as f32) / range as f32;
         for i in 0 .. width {
             let dx = x + i;
             let sample_index = (first_sample_index + (i * speed) as usize) % sample_buffer.len();
             let sample = sample_buffer[sample_index];
-            let current_y = ((sample - sample_min) as f32 * height as f32) / range as f32;
+            let current_y = ((sample as i32 - sample_min as i32) as f32 * height as f32) / range as f32;
             let mut top_edge = current_y;
             let mut bottom_edge = last_y;
             if last_y < current_y {
@@ -7,6 +7,8 @@ use events::Event;
 use panel::Panel;
 
 use rustico_core::mmc::mapper::Mapper;
+use rustico_core::mmc::mirroring::NametableSource;
+use rustico_core::mmc::mirroring::nametable_source_name;
 use rustico_core::nes::NesState;
 use rustico_core::ppu;
 use rustico_core::palettes::NTSC_PAL;
@@ -169,6 +171,26 @@ impl PpuWindow {
         }
     }
 
+    pub fn draw_nametable_mapping(&mut self, mapper: &dyn Mapper, dx: u32, dy: u32) {
+        // Label each quadrant with wherever the mapper is currently pointing it
+        let mapping = mapper.nametable_mapping();
+        for quadrant in 0 .. 4 {
+            let source = mapping.quadrants[quadrant];
+            let label = nametable_source_name(source);
+            let label_color = match source {
+                NametableSource::CiramA => Color::rgb(192, 255, 192),
+                NametableSource::CiramB => Color::rgb(192, 192, 255),
+                NametableSource::CartRam(_) => Color::rgb(255, 255, 160),
+                NametableSource::Rom(_) => Color::rgb(255, 192, 160),
+                NametableSource::Fill{..} => Color::rgb(224, 224, 224),
+            };
+            let qx = dx + (quadrant as u32 % 2) * 256 + 2;
+            let qy = dy + (quadrant as u32 / 2) * 240 + 2;
+            drawing::blend_rect(&mut self.canvas, qx, qy, label.len() as u32 * 8 + 4, 12, Color::rgba(0, 0, 0, 192));
+            drawing::text(&mut self.canvas, &self.font, qx + 2, qy + 2, &label, label_color);
+        }
+    }
+
     pub fn draw_palettes(&mut self, dx: u32, dy: u32) {
         // Global Background (just once)
         let color = Color::from_slice(&self.palette_cache[0][0 .. 4]);
@@ -279,6 +301,7 @@ impl PpuWindow {
         self.draw_sprites(nes, 0, 170);
         // Right Panel: Entire nametable
         self.generate_nametables(& *nes.mapper, &nes.ppu, 280, 0);
+        self.draw_nametable_mapping(& *nes.mapper, 280, 0);
     }
 }
 
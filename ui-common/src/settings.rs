@@ -11,6 +11,52 @@ use events::Event;
 
 
 const DEFAULT_CONFIG: &str = r###"
+# synthesis is "hq", "lq" (faster, somewhat muffled) or "blip" (fastest)
+# sync is "audio" (emulation keeps the audio fed) or "video" (one frame per displayed frame,
+# with the sample rate adjusted slightly to keep the audio fed)
+[audio]
+epsm = false
+stereo = false
+synthesis = "hq"
+sync = "audio"
+
+# Pan runs from -1.0 (left) to 1.0 (right). These only apply to the stereo mix.
+[audio.channels.2A03."Pulse 1"]
+pan = -0.3
+gain = 1.0
+
+[audio.channels.2A03."Pulse 2"]
+pan = 0.3
+gain = 1.0
+
+# console is "famicom", or "nes" for the front loader's output filter. A front loader only
+# plays expansion audio through a mod, and how loud depends on the mod, so set the chips to
+# match: vrc6, vrc7, fds, mmc5, n163 and s5b are gains, where 1.0 is the Famicom level, and
+# fds_lowpass is the FDS filter cutoff in Hz. An NSF's own mixe levels take precedence.
+[audio.expansion]
+console = "famicom"
+
+# patch_set picks the VRC7's built-in instruments: "vrc7" (dumped from the chip), "vrc7_2004"
+# (the older estimates), "ym2413" or "ymf281". adsr_research colors the piano roll by envelope
+# phase instead of by instrument. ym2413 runs the chip as a full YM2413, with three more
+# channels and rhythm mode; pair it with patch_set = "ym2413" for that chip's instruments.
+[audio.vrc7]
+patch_set = "vrc7"
+adsr_research = false
+ym2413 = false
+
+# An NES 2.0 XML database (nes20db.xml) to correct known bad headers with. Leave it empty
+# to use the small one built into the emulator. Takes effect on the next cartridge load.
+[cartridge]
+header_database = ""
+
+[power_on]
+internal_ram = "zero"
+cart_ram = "zero"
+palette = "pattern"
+oam = "zero"
+seed = 0
+
 [video]
 ntsc_filter = false
 simulate_overscan = false
@@ -126,6 +172,13 @@ static = "rgb(24, 104, 228)"
 [piano_roll.settings.YM2149F.C]
 static = "rgb(16, 64, 248)"
 
+[piano_roll.settings.YM2149F.Noise]
+static = "rgb(120, 150, 200)"
+
+[piano_roll.settings.YM2149F.Envelope]
+gradient_low = "rgb(48, 200, 224)"
+gradient_high = "rgb(160, 96, 240)"
+
 [piano_roll.settings.VRC7."FM 1"]
 patch0 = "hsv(310, 40%, 100%)"
 patch1 = "hsv(131, 77%, 94%)"
@@ -234,6 +287,78 @@ patchD = "hsv(258, 75%, 87%)"
 patchE = "hsv(192, 77%, 94%)"
 patchF = "hsv(110, 50%, 94%)"
 
+[piano_roll.settings.EPSM."FM 1"]
+patch0 = "hsv(200, 45%, 100%)"
+patch1 = "hsv(170, 55%, 95%)"
+patch2 = "hsv(140, 55%, 92%)"
+patch3 = "hsv(110, 50%, 92%)"
+patch4 = "hsv(50, 65%, 98%)"
+patch5 = "hsv(30, 70%, 98%)"
+patch6 = "hsv(10, 70%, 96%)"
+patch7 = "hsv(330, 45%, 100%)"
+
+[piano_roll.settings.EPSM."FM 2"]
+patch0 = "hsv(200, 45%, 100%)"
+patch1 = "hsv(170, 55%, 95%)"
+patch2 = "hsv(140, 55%, 92%)"
+patch3 = "hsv(110, 50%, 92%)"
+patch4 = "hsv(50, 65%, 98%)"
+patch5 = "hsv(30, 70%, 98%)"
+patch6 = "hsv(10, 70%, 96%)"
+patch7 = "hsv(330, 45%, 100%)"
+
+[piano_roll.settings.EPSM."FM 3"]
+patch0 = "hsv(200, 45%, 100%)"
+patch1 = "hsv(170, 55%, 95%)"
+patch2 = "hsv(140, 55%, 92%)"
+patch3 = "hsv(110, 50%, 92%)"
+patch4 = "hsv(50, 65%, 98%)"
+patch5 = "hsv(30, 70%, 98%)"
+patch6 = "hsv(10, 70%, 96%)"
+patch7 = "hsv(330, 45%, 100%)"
+
+[piano_roll.settings.EPSM."FM 4"]
+patch0 = "hsv(200, 45%, 100%)"
+patch1 = "hsv(170, 55%, 95%)"
+patch2 = "hsv(140, 55%, 92%)"
+patch3 = "hsv(110, 50%, 92%)"
+patch4 = "hsv(50, 65%, 98%)"
+patch5 = "hsv(30, 70%, 98%)"
+patch6 = "hsv(10, 70%, 96%)"
+patch7 = "hsv(330, 45%, 100%)"
+
+[piano_roll.settings.EPSM."FM 5"]
+patch0 = "hsv(200, 45%, 100%)"
+patch1 = "hsv(170, 55%, 95%)"
+patch2 = "hsv(140, 55%, 92%)"
+patch3 = "hsv(110, 50%, 92%)"
+patch4 = "hsv(50, 65%, 98%)"
+patch5 = "hsv(30, 70%, 98%)"
+patch6 = "hsv(10, 70%, 96%)"
+patch7 = "hsv(330, 45%, 100%)"
+
+[piano_roll.settings.EPSM."FM 6"]
+patch0 = "hsv(200, 45%, 100%)"
+patch1 = "hsv(170, 55%, 95%)"
+patch2 = "hsv(140, 55%, 92%)"
+patch3 = "hsv(110, 50%, 92%)"
+patch4 = "hsv(50, 65%, 98%)"
+patch5 = "hsv(30, 70%, 98%)"
+patch6 = "hsv(10, 70%, 96%)"
+patch7 = "hsv(330, 45%, 100%)"
+
+[piano_roll.settings.EPSM."SSG A"]
+static = "rgb(32, 204, 176)"
+
+[piano_roll.settings.EPSM."SSG B"]
+static = "rgb(24, 180, 140)"
+
+[piano_roll.settings.EPSM."SSG C"]
+static = "rgb(16, 156, 104)"
+
+[piano_roll.settings.EPSM.Rhythm]
+static = "rgb(220, 220, 160)"
+
 [piano_roll.settings.APU."Final Mix"]
 static = "rgb(224, 224, 224)"
 hidden = true
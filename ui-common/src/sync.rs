@@ -0,0 +1,119 @@
+// Keeps the emulator and the host's audio device in step. The NES runs at its own rate (about
+// 60.1 frames and 1.79 MHz worth of samples per second) and the host's display and audio
+// clocks never quite agree with it, or with each other, so something has to give.
+
+// Audio locked: emulation runs whenever the audio queue runs low. Sound is perfect, and frames
+// are shown as they come, which can occasionally mean one is shown twice or skipped.
+
+// Video locked: emulation runs one frame per displayed frame (or per tick of the shell's own
+// frame timer). The audio queue will then slowly fill up or drain, so we nudge the APU's sample
+// rate by up to half a percent to hold it steady. That's well under what anyone can hear as a
+// change in pitch. (This is the same dynamic rate control RetroArch uses.)
+
+use rustico_core::apu::ApuState;
+use rustico_core::ines::CpuPpuTiming;
+
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncMode {
+    AudioLocked,
+    VideoLocked,
+}
+
+pub fn sync_mode_from_name(name: &str) -> Option<SyncMode> {
+    match name {
+        "audio" => Some(SyncMode::AudioLocked),
+        "video" => Some(SyncMode::VideoLocked),
+        _ => None
+    }
+}
+
+// How long one frame lasts in the cartridge's region, for pacing video locked shells
+pub fn frame_duration(region: CpuPpuTiming) -> Duration {
+    return match region {
+        // 33247.5 CPU cycles at 1.662607 MHz. The Dendy's 35464 at 1.773448 MHz come out the same.
+        CpuPpuTiming::Pal | CpuPpuTiming::Dendy => Duration::from_nanos(19_997_209),
+        // 29780.5 CPU cycles at 1.789773 MHz
+        CpuPpuTiming::Ntsc | CpuPpuTiming::MultipleRegion => Duration::from_nanos(16_639_267),
+    };
+}
+
+// How far the sample rate is allowed to stray from nominal
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+// How much of each new measurement goes into the running average. The queue depth jumps by a
+// frame's worth of samples every frame, which we don't want to hear as a wobble in pitch.
+const DEPTH_SMOOTHING: f64 = 0.05;
+// How quickly a lasting difference between the clocks is learned. Without this the queue would
+// settle above or below the target, in proportion to the difference, and latency would wander.
+const DRIFT_LEARNING_RATE: f64 = 0.0005;
+
+pub struct RateControl {
+    pub mode: SyncMode,
+    // Sample frames (one per channel) we'd like waiting between the emulator and the speakers.
+    // Shells set this to suit their audio backend.
+    pub target_latency: usize,
+    average_depth: f64,
+    // The correction the clocks need in the long run, as a fraction of the maximum
+    drift: f64,
+}
+
+impl RateControl {
+    pub fn new(mode: SyncMode, target_latency: usize) -> RateControl {
+        return RateControl {
+            mode: mode,
+            target_latency: target_latency,
+            average_depth: target_latency as f64,
+            drift: 0.0,
+        };
+    }
+
+    pub fn set_mode(&mut self, mode: SyncMode) {
+        self.mode = mode;
+        self.average_depth = self.target_latency as f64;
+        self.drift = 0.0;
+    }
+
+    // Call once per displayed frame, with everything queued up but not yet played: the shell's
+    // own queue and the APU's buffers both, in sample frames.
+    pub fn update(&mut self, apu: &mut ApuState, queued_frames: usize) {
+        match self.mode {
+            SyncMode::AudioLocked => {
+                apu.set_rate_adjustment(1.0);
+            },
+            SyncMode::VideoLocked => {
+                self.average_depth += (queued_frames as f64 - self.average_depth) * DEPTH_SMOOTHING;
+                let target = self.target_latency as f64;
+                let deviation = ((self.average_depth - target) / target).max(-1.0).min(1.0);
+                self.drift = (self.drift + deviation * DRIFT_LEARNING_RATE).max(-1.0).min(1.0);
+                let correction = (deviation + self.drift).max(-1.0).min(1.0);
+                // Too much queued up means we're making samples too quickly, so make fewer
+                apu.set_rate_adjustment(1.0 - correction * MAX_RATE_ADJUSTMENT);
+            }
+        }
+    }
+
+    // Whether the shell should run another frame now. frames_run is how many it has already
+    // run since it last displayed one.
+    pub fn wants_frame(&self, queued_frames: usize, frames_run: usize) -> bool {
+        match self.mode {
+            SyncMode::AudioLocked => {
+                return queued_frames < self.target_latency;
+            },
+            SyncMode::VideoLocked => {
+                // A display running well faster than the NES would flood the queue; rate control
+                // can't absorb that, so hold off and let the audio set the pace instead
+                return frames_run == 0 && queued_frames < self.target_latency * 2;
+            }
+        }
+    }
+
+    // When video locked, a queue that's run nearly dry (at startup, or after a stall) is topped
+    // up with silence, which is far quicker than waiting for rate control to refill it
+    pub fn silence_needed(&self, queued_frames: usize) -> usize {
+        if self.mode == SyncMode::VideoLocked && queued_frames < self.target_latency / 2 {
+            return self.target_latency - queued_frames;
+        }
+        return 0;
+    }
+}
@@ -188,9 +188,9 @@ pub fn set_p2_input(keystate: u8) {
 #[wasm_bindgen]
 pub fn set_audio_samplerate(sample_rate: u32) {
   let mut runtime = RUNTIME.lock().expect("wat");
+  runtime.set_host_sample_rate(sample_rate as u64);
   let nes = &mut runtime.nes;
   
-  nes.apu.set_sample_rate(sample_rate as u64);
   // while we're here, set the filter to low quality
   nes.apu.set_filter(FilterType::FamiCom, false);
   // and if this happens to be an N163 ROM, tell it not